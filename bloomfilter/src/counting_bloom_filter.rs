@@ -0,0 +1,178 @@
+use crate::{Filter, FilterError, hash, indexes, Reader, size_for_rate, write_varint};
+
+const MAGIC: &[u8; 4] = b"CBF\x01";
+// a counter that reached it no longer knows how many keys are behind it, so it is never decremented again
+const SATURATED: u8 = 0xf;
+
+// a bloom filter that can remove keys. every bit is a 4 bit counter, two of them share a byte
+pub struct CountingBloomFilter {
+    counters: Vec<u8>,
+    size: usize,
+    hashes: u32,
+}
+
+impl CountingBloomFilter {
+    // a filter of `size` counters and two hash functions
+    pub fn new(size: usize) -> Self {
+        Self::with_hashes(size.max(1), 2)
+    }
+
+    // a filter sized for `expected_items` keys with a `fp_rate` chance of false positives, it doesn't grow
+    pub fn with_rate(expected_items: usize, fp_rate: f64) -> Self {
+        assert!(fp_rate > 0.0 && fp_rate < 1.0, "false positive rate has to be between 0 and 1");
+        let (size, hashes) = size_for_rate(expected_items.max(1), fp_rate);
+        Self::with_hashes(size, hashes)
+    }
+
+    fn with_hashes(size: usize, hashes: u32) -> Self {
+        Self { counters: vec![0; size.div_ceil(2)], size, hashes }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn counter(&self, index: usize) -> u8 {
+        (self.counters[index / 2] >> (index % 2 * 4)) & SATURATED
+    }
+
+    fn set_counter(&mut self, index: usize, value: u8) {
+        let shift = index % 2 * 4;
+        self.counters[index / 2] = (self.counters[index / 2] & !(SATURATED << shift)) | (value << shift);
+    }
+
+    // restores a filter written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FilterError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(FilterError::Corrupted("unknown format"));
+        }
+        let size = reader.varint()? as usize;
+        let hashes = u32::try_from(reader.varint()?).map_err(|_| FilterError::Corrupted("too many hash functions"))?;
+        if size == 0 || hashes == 0 {
+            return Err(FilterError::Corrupted("empty filter"));
+        }
+        let counters = reader.take(size.div_ceil(2))?.to_vec();
+        if !reader.bytes.is_empty() {
+            return Err(FilterError::Corrupted("invalid length"));
+        }
+
+        Ok(Self { counters, size, hashes })
+    }
+}
+
+impl Filter for CountingBloomFilter {
+    fn insert(&mut self, data: &[u8]) -> Result<(), FilterError> {
+        for index in indexes(hash(data), self.size, self.hashes) {
+            let counter = self.counter(index);
+            if counter < SATURATED {
+                self.set_counter(index, counter + 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, data: &[u8]) -> bool {
+        indexes(hash(data), self.size, self.hashes).all(|index| self.counter(index) > 0)
+    }
+
+    fn remove(&mut self, data: &[u8]) -> bool {
+        // a key can hit a counter more than once, every hit has to be there before anything is decremented
+        let mut hits: Vec<(usize, u8)> = Vec::new();
+        for index in indexes(hash(data), self.size, self.hashes) {
+            match hits.iter_mut().find(|(hit, _)| *hit == index) {
+                Some((_, count)) => *count += 1,
+                None => hits.push((index, 1)),
+            }
+        }
+        let present = hits.iter().all(|(index, count)| {
+            let counter = self.counter(*index);
+            counter == SATURATED || counter >= *count
+        });
+        if !present {
+            return false;
+        }
+
+        for (index, count) in hits {
+            let counter = self.counter(index);
+            if counter < SATURATED {
+                self.set_counter(index, counter - count);
+            }
+        }
+        true
+    }
+
+    // the size, the hash functions and the packed counters
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        write_varint(&mut bytes, self.size as u64);
+        write_varint(&mut bytes, self.hashes as u64);
+        bytes.extend_from_slice(&self.counters);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counting_bloom_filter_removes_data() {
+        let mut filter = CountingBloomFilter::new(100);
+        filter.insert(b"test data").unwrap();
+
+        assert!(filter.remove(b"test data"));
+        assert!(!filter.check(b"test data"));
+    }
+
+    #[test]
+    fn counting_bloom_filter_keeps_data_sharing_counters() {
+        let mut filter = CountingBloomFilter::new(1); // every key uses the same counter
+        filter.insert(b"test data").unwrap();
+        filter.insert(b"other data").unwrap();
+
+        assert!(filter.remove(b"test data"));
+        assert!(filter.check(b"other data"));
+        assert!(filter.remove(b"other data"));
+        assert!(!filter.check(b"other data"));
+    }
+
+    #[test]
+    fn counting_bloom_filter_refuses_to_remove_data_it_does_not_have() {
+        let mut filter = CountingBloomFilter::new(100);
+        filter.insert(b"test data").unwrap();
+
+        assert!(!filter.remove(b"other data"));
+        assert!(filter.remove(b"test data"));
+        assert!(!filter.remove(b"test data"));
+        assert!(!filter.check(b"test data"));
+    }
+
+    #[test]
+    fn counting_bloom_filter_never_decrements_saturated_counters() {
+        let mut filter = CountingBloomFilter::new(1);
+        for _ in 0..20 {
+            filter.insert(b"test data").unwrap();
+        }
+        for _ in 0..20 {
+            assert!(filter.remove(b"test data"));
+        }
+
+        assert!(filter.check(b"test data"));
+    }
+
+    #[test]
+    fn counting_bloom_filter_restores_from_bytes() {
+        let mut filter = CountingBloomFilter::with_rate(100, 0.01);
+        filter.insert(b"test data").unwrap();
+        filter.insert(b"more data").unwrap();
+
+        let mut restored = CountingBloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+
+        assert_eq!(restored.size(), filter.size());
+        assert!(restored.remove(b"test data"));
+        assert!(!restored.check(b"test data"));
+        assert!(restored.check(b"more data"));
+        assert!(CountingBloomFilter::from_bytes(&filter.to_bytes()[1..]).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{Filter, FilterError, hash, Reader, write_varint};
+
+const MAGIC: &[u8; 4] = b"CKF\x01";
+const BUCKET_SIZE: usize = 4;
+// fingerprints moved to their other bucket before an insert gives up
+const MAX_KICKS: usize = 500;
+// an empty slot, no fingerprint is 0
+const EMPTY: u16 = 0;
+
+// keeps a 16 bit fingerprint of every key in one of two buckets. unlike a counting bloom filter it takes about as
+// much memory as a plain one and removes keys exactly, but it can be full
+pub struct CuckooFilter {
+    buckets: Vec<[u16; BUCKET_SIZE]>,
+    // the fingerprint the last failed insert couldn't place, the filter is full while it is set
+    victim: Option<(usize, u16)>,
+    // picks the fingerprints that are kicked out
+    random: u64,
+}
+
+impl CuckooFilter {
+    // a filter with room for about `capacity` keys
+    pub fn new(capacity: usize) -> Self {
+        let buckets = capacity.div_ceil(BUCKET_SIZE).next_power_of_two();
+        Self { buckets: vec![[EMPTY; BUCKET_SIZE]; buckets], victim: None, random: 0x9e37_79b9_7f4a_7c15 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buckets.len() * BUCKET_SIZE
+    }
+
+    // the fingerprint of a key and its first bucket
+    fn fingerprint(&self, data: &[u8]) -> (u16, usize) {
+        let (h1, h2) = hash(data);
+        let fingerprint = match (h2 >> 48) as u16 {
+            EMPTY => 1,
+            fingerprint => fingerprint,
+        };
+        (fingerprint, h1 as usize & (self.buckets.len() - 1))
+    }
+
+    // the other bucket of a fingerprint, the same function leads back from it
+    fn alternate(&self, index: usize, fingerprint: u16) -> usize {
+        (index ^ xxh3_64(&fingerprint.to_le_bytes()) as usize) & (self.buckets.len() - 1)
+    }
+
+    fn put(&mut self, index: usize, fingerprint: u16) -> bool {
+        match self.buckets[index].iter_mut().find(|slot| **slot == EMPTY) {
+            Some(slot) => {
+                *slot = fingerprint;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn take(&mut self, index: usize, fingerprint: u16) -> bool {
+        match self.buckets[index].iter_mut().find(|slot| **slot == fingerprint) {
+            Some(slot) => {
+                *slot = EMPTY;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn next_random(&mut self) -> usize {
+        // xorshift, the kicks only have to be spread out
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random as usize
+    }
+
+    // restores a filter written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FilterError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(FilterError::Corrupted("unknown format"));
+        }
+        let buckets = reader.varint()? as usize;
+        if !buckets.is_power_of_two() {
+            return Err(FilterError::Corrupted("invalid number of buckets"));
+        }
+        let victim = match reader.take(1)?[0] {
+            0 => None,
+            1 => Some((reader.varint()? as usize, u16::from_le_bytes(reader.take(2)?.try_into().unwrap()))),
+            _ => return Err(FilterError::Corrupted("invalid victim")),
+        };
+        if victim.is_some_and(|(index, fingerprint)| index >= buckets || fingerprint == EMPTY) {
+            return Err(FilterError::Corrupted("invalid victim"));
+        }
+
+        let mut filter = Self::new(buckets * BUCKET_SIZE);
+        let slots = reader.take(buckets.checked_mul(BUCKET_SIZE * 2).ok_or(FilterError::Corrupted("too many buckets"))?)?;
+        for (slot, fingerprint) in slots.chunks_exact(2).enumerate() {
+            filter.buckets[slot / BUCKET_SIZE][slot % BUCKET_SIZE] = u16::from_le_bytes([fingerprint[0], fingerprint[1]]);
+        }
+        if !reader.bytes.is_empty() {
+            return Err(FilterError::Corrupted("invalid length"));
+        }
+        filter.victim = victim;
+
+        Ok(filter)
+    }
+}
+
+impl Filter for CuckooFilter {
+    fn insert(&mut self, data: &[u8]) -> Result<(), FilterError> {
+        if self.victim.is_some() {
+            return Err(FilterError::Full);
+        }
+
+        let (mut fingerprint, first) = self.fingerprint(data);
+        let second = self.alternate(first, fingerprint);
+        if self.put(first, fingerprint) || self.put(second, fingerprint) {
+            return Ok(());
+        }
+
+        let mut index = if self.next_random().is_multiple_of(2) { first } else { second };
+        for _ in 0..MAX_KICKS {
+            let slot = self.next_random() % BUCKET_SIZE;
+            std::mem::swap(&mut fingerprint, &mut self.buckets[index][slot]);
+            index = self.alternate(index, fingerprint);
+            if self.put(index, fingerprint) {
+                return Ok(());
+            }
+        }
+        // the key is in, but the fingerprint it kicked out last has no bucket left
+        self.victim = Some((index, fingerprint));
+        Ok(())
+    }
+
+    fn check(&self, data: &[u8]) -> bool {
+        let (fingerprint, first) = self.fingerprint(data);
+        let second = self.alternate(first, fingerprint);
+        self.buckets[first].contains(&fingerprint)
+            || self.buckets[second].contains(&fingerprint)
+            || self.victim.is_some_and(|victim| victim == (first, fingerprint) || victim == (second, fingerprint))
+    }
+
+    fn remove(&mut self, data: &[u8]) -> bool {
+        let (fingerprint, first) = self.fingerprint(data);
+        let second = self.alternate(first, fingerprint);
+        if self.victim.is_some_and(|victim| victim == (first, fingerprint) || victim == (second, fingerprint)) {
+            self.victim = None;
+            return true;
+        }
+        if !self.take(first, fingerprint) && !self.take(second, fingerprint) {
+            return false;
+        }
+
+        // a slot is free again, the victim might fit now
+        if let Some((index, fingerprint)) = self.victim {
+            let alternate = self.alternate(index, fingerprint);
+            if self.put(index, fingerprint) || self.put(alternate, fingerprint) {
+                self.victim = None;
+            }
+        }
+        true
+    }
+
+    // the number of buckets, the victim and the fingerprints of every bucket
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        write_varint(&mut bytes, self.buckets.len() as u64);
+        match self.victim {
+            Some((index, fingerprint)) => {
+                bytes.push(1);
+                write_varint(&mut bytes, index as u64);
+                bytes.extend_from_slice(&fingerprint.to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes.extend(self.buckets.iter().flatten().flat_map(|fingerprint| fingerprint.to_le_bytes()));
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cuckoo_filter_adds_checks_and_removes_data() {
+        let mut filter = CuckooFilter::new(100);
+        filter.insert(b"test data").unwrap();
+
+        assert!(filter.check(b"test data"));
+        assert!(!filter.check(b"other data"));
+        assert!(!filter.remove(b"other data"));
+        assert!(filter.remove(b"test data"));
+        assert!(!filter.check(b"test data"));
+    }
+
+    #[test]
+    fn cuckoo_filter_keeps_every_key_until_it_is_full() {
+        let mut filter = CuckooFilter::new(1000);
+        let inserted = (0..2000).take_while(|i| filter.insert(format!("key {}", i).as_bytes()).is_ok()).count();
+
+        assert!(inserted >= 900);
+        assert!(inserted < 2000);
+        assert!((0..inserted).all(|i| filter.check(format!("key {}", i).as_bytes())));
+
+        // removing the keys makes room again
+        assert!((0..inserted).all(|i| filter.remove(format!("key {}", i).as_bytes())));
+        assert!(filter.insert(b"key 0").is_ok());
+    }
+
+    #[test]
+    fn cuckoo_filter_has_few_false_positives() {
+        let mut filter = CuckooFilter::new(1000);
+        for i in 0..900 {
+            filter.insert(format!("key {}", i).as_bytes()).unwrap();
+        }
+
+        let false_positives = (0..10000).filter(|i| filter.check(format!("other {}", i).as_bytes())).count();
+        assert!(false_positives < 10);
+    }
+
+    #[test]
+    fn cuckoo_filter_restores_from_bytes() {
+        let mut filter = CuckooFilter::new(4);
+        let inserted = (0..10).take_while(|i| filter.insert(format!("key {}", i).as_bytes()).is_ok()).count();
+
+        let restored = CuckooFilter::from_bytes(&filter.to_bytes()).unwrap();
+
+        assert_eq!(restored.capacity(), 4);
+        assert!((0..inserted).all(|i| restored.check(format!("key {}", i).as_bytes())));
+        assert!(CuckooFilter::from_bytes(&filter.to_bytes()[..10]).is_err());
+    }
+
+    #[test]
+    fn filters_can_be_used_behind_the_trait() {
+        let mut filters: Vec<Box<dyn Filter>> = vec![Box::new(CuckooFilter::new(100)), Box::new(crate::counting_bloom_filter::CountingBloomFilter::new(100))];
+        for filter in &mut filters {
+            filter.insert(b"test data").unwrap();
+            assert!(filter.check(b"test data"));
+            assert!(filter.remove(b"test data"));
+            assert!(!filter.check(b"test data"));
+        }
+    }
+}
@@ -1,93 +1,253 @@
-use std::collections::HashMap;
-use num::{BigInt, Num, ToPrimitive};
-use sha2::{Digest, Sha256};
+use thiserror::Error;
+use xxhash_rust::xxh3::xxh3_128;
 
-pub struct BloomFilter {
-    md5_hasher: Md5Hasher,
-    sha_256_hasher: Sha256Hasher,
-    bitmap: Vec<bool>,
-    size: usize,
-    counter: HashMap<usize, i32>
+pub mod counting_bloom_filter;
+pub mod cuckoo_filter;
+
+// every layer a scalable filter adds holds that many times the keys of the layer before
+const GROWTH_FACTOR: usize = 2;
+// and has that much of its false positive rate, so the rate of the whole filter stays below the one asked for
+const TIGHTENING_RATIO: f64 = 0.5;
+// the first bytes of a filter written by `to_bytes`, the last one is the version of the format.
+// version 1 also had the counters `remove` used before it moved to `CountingBloomFilter`
+const MAGIC: &[u8; 4] = b"BLF\x02";
+const MAGIC_V1: &[u8; 4] = b"BLF\x01";
+
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("Corrupted filter: {0}")]
+    Corrupted(&'static str),
+    #[error("Filters of different sizes can't be merged")]
+    Incompatible,
+    #[error("Filter is full")]
+    Full,
 }
 
-struct Md5Hasher {}
+// a filter that can remove keys again, like `CountingBloomFilter` and `CuckooFilter`
+pub trait Filter {
+    // fails if the filter has no room left for the key
+    fn insert(&mut self, data: &[u8]) -> Result<(), FilterError>;
+    fn check(&self, data: &[u8]) -> bool;
+    // removes a key that was inserted. if the filter doesn't have the key nothing is changed and false is returned
+    fn remove(&mut self, data: &[u8]) -> bool;
+    fn to_bytes(&self) -> Vec<u8>;
+}
+
+// the two hashes of a key, the bits of a key are `h1 + i * h2` for every hash function i
+fn hash(data: impl AsRef<[u8]>) -> (u64, u64) {
+    let hash = xxh3_128(data.as_ref());
+    (hash as u64, (hash >> 64) as u64 | 1)
+}
 
-impl Md5Hasher {
-    fn new() -> Self {
-        Self {}
+fn indexes((h1, h2): (u64, u64), size: usize, hashes: u32) -> impl Iterator<Item = usize> {
+    (0..hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % size as u64) as usize)
+}
+
+// the bits and hash functions that keep `capacity` keys with a `fp_rate` chance of false positives
+fn size_for_rate(capacity: usize, fp_rate: f64) -> (usize, u32) {
+    let ln2 = std::f64::consts::LN_2;
+    let size = (-(capacity as f64) * fp_rate.ln() / (ln2 * ln2)).ceil().max(1.0) as usize;
+    let hashes = (size as f64 / capacity as f64 * ln2).round().max(1.0) as u32;
+    (size, hashes)
+}
+
+// a bloom filter of a fixed size, a scalable filter is a list of them
+struct Layer {
+    bits: Vec<u64>,
+    size: usize,
+    hashes: u32,
+    // keys the layer is sized for, a scalable filter adds a layer once the last has that many
+    capacity: usize,
+    len: usize,
+}
+
+impl Layer {
+    fn new(size: usize, hashes: u32, capacity: usize) -> Self {
+        Self { bits: vec![0; size.div_ceil(64)], size, hashes, capacity, len: 0 }
     }
-    fn hash(&self, data: impl AsRef<[u8]>) -> BigInt {
-        let digest = md5::compute(data);
-        let decimal_hex = format!("{:x}", &digest);
-        BigInt::from_str_radix(&decimal_hex, 16).unwrap()
+
+    // the layer that keeps `capacity` keys with a `fp_rate` chance of false positives
+    fn with_rate(capacity: usize, fp_rate: f64) -> Self {
+        let (size, hashes) = size_for_rate(capacity, fp_rate);
+        Self::new(size, hashes, capacity)
     }
-}
 
-struct Sha256Hasher {}
+    fn bit(&self, index: usize) -> bool {
+        self.bits[index / 64] & (1 << (index % 64)) != 0
+    }
 
-impl Sha256Hasher {
-    fn new() -> Self {
-        Self {}
+    fn insert(&mut self, hash: (u64, u64)) {
+        for index in indexes(hash, self.size, self.hashes) {
+            self.bits[index / 64] |= 1 << (index % 64);
+        }
+        self.len += 1;
     }
-    fn hash(&self, data: impl AsRef<[u8]>) -> BigInt {
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let result = hasher.finalize();
-        let target = format!("{:x}", result);
-        BigInt::from_str_radix(&target, 16).unwrap()
+
+    fn check(&self, hash: (u64, u64)) -> bool {
+        indexes(hash, self.size, self.hashes).all(|index| self.bit(index))
     }
 }
 
+// a bloom filter that can't remove keys, see `CountingBloomFilter` for one that can
+pub struct BloomFilter {
+    layers: Vec<Layer>,
+    // false positive rate of the last layer, None if the filter has a fixed size
+    fp_rate: Option<f64>,
+}
+
 impl BloomFilter {
+    // a filter of `size` bits and two hash functions that never grows
     pub fn new(size: usize) -> Self {
-        let bitmap = vec![false; size];
-        BloomFilter { md5_hasher: Md5Hasher::new(), sha_256_hasher: Sha256Hasher::new(), bitmap, size, counter: HashMap::new() }
+        BloomFilter { layers: vec![Layer::new(size, 2, usize::MAX)], fp_rate: None }
+    }
+
+    // a filter sized for `expected_items` keys with a `fp_rate` chance of false positives. once more keys are
+    // inserted it grows by adding bigger layers, so the chance stays below `fp_rate`
+    pub fn with_rate(expected_items: usize, fp_rate: f64) -> Self {
+        assert!(fp_rate > 0.0 && fp_rate < 1.0, "false positive rate has to be between 0 and 1");
+        let fp_rate = fp_rate * (1.0 - TIGHTENING_RATIO);
+        BloomFilter { layers: vec![Layer::with_rate(expected_items.max(1), fp_rate)], fp_rate: Some(fp_rate) }
     }
 
     pub fn insert(&mut self, data: impl AsRef<[u8]>) {
-        let insert_index = (self.md5_hasher.hash(&data) % self.size).to_u32().unwrap() as usize;
-        self.bitmap[insert_index] = true;
-        self.counter.insert(insert_index, 1 + if self.counter.contains_key(&insert_index) { self.counter[&insert_index] } else { 0 });
-        let insert_index = (self.sha_256_hasher.hash(&data) % self.size).to_u32().unwrap() as usize;
-        self.bitmap[insert_index] = true;
-        self.counter.insert(insert_index, 1 + if self.counter.contains_key(&insert_index) { self.counter[&insert_index] } else { 0 });
+        let last = self.layers.last().unwrap();
+        if let Some(fp_rate) = self.fp_rate {
+            if last.len >= last.capacity {
+                let fp_rate = fp_rate * TIGHTENING_RATIO;
+                let capacity = last.capacity.saturating_mul(GROWTH_FACTOR);
+                self.layers.push(Layer::with_rate(capacity, fp_rate));
+                self.fp_rate = Some(fp_rate);
+            }
+        }
+        self.layers.last_mut().unwrap().insert(hash(data));
     }
 
     pub fn check(&self, data: impl AsRef<[u8]>) -> bool {
-        let insert_index = self.md5_hasher.hash(&data) % self.size;
-        if !self.bitmap[insert_index.to_u32().unwrap() as usize] {
-            return false;
+        let hash = hash(data);
+        self.layers.iter().any(|layer| layer.check(hash))
+    }
+
+    // bits of all layers
+    pub fn size(&self) -> usize {
+        self.layers.iter().map(|layer| layer.size).sum()
+    }
+
+    // adds the keys of `other`, which has to be made with the same arguments and grown to the same layers
+    pub fn union(&mut self, other: &BloomFilter) -> Result<(), FilterError> {
+        let same_layers = self.layers.len() == other.layers.len() && self.layers.iter().zip(&other.layers)
+            .all(|(layer, other)| (layer.size, layer.hashes, layer.capacity) == (other.size, other.hashes, other.capacity));
+        if self.fp_rate != other.fp_rate || !same_layers {
+            return Err(FilterError::Incompatible);
         }
-        let insert_index = self.sha_256_hasher.hash(&data) % self.size;
-        if !self.bitmap[insert_index.to_u32().unwrap() as usize] {
-            return false;
+
+        for (layer, other) in self.layers.iter_mut().zip(&other.layers) {
+            layer.bits.iter_mut().zip(&other.bits).for_each(|(word, other)| *word |= other);
+            layer.len += other.len;
         }
+        Ok(())
+    }
 
-        true
+    // the filter in a stable format, the bitmap of every layer is packed into bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        match self.fp_rate {
+            Some(fp_rate) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&fp_rate.to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+        write_varint(&mut bytes, self.layers.len() as u64);
+        for layer in &self.layers {
+            write_varint(&mut bytes, layer.size as u64);
+            write_varint(&mut bytes, layer.hashes as u64);
+            write_varint(&mut bytes, layer.capacity as u64);
+            write_varint(&mut bytes, layer.len as u64);
+            let bitmap_start = bytes.len();
+            bytes.extend(layer.bits.iter().flat_map(|word| word.to_le_bytes()));
+            bytes.truncate(bitmap_start + layer.size.div_ceil(8));
+        }
+        bytes
     }
 
-    pub fn remove(&mut self, data: impl AsRef<[u8]>) {
-        let insert_index = (self.md5_hasher.hash(&data) % self.size).to_u32().unwrap() as usize;
+    // restores a filter written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FilterError> {
+        let mut reader = Reader { bytes };
+        let with_counters = match reader.take(MAGIC.len())? {
+            magic if magic == MAGIC => false,
+            magic if magic == MAGIC_V1 => true,
+            _ => return Err(FilterError::Corrupted("unknown format")),
+        };
+        let fp_rate = match reader.take(1)?[0] {
+            0 => None,
+            1 => Some(f64::from_le_bytes(reader.take(8)?.try_into().unwrap())),
+            _ => return Err(FilterError::Corrupted("invalid false positive rate")),
+        };
+
+        let mut layers = Vec::new();
+        for _ in 0..reader.varint()? {
+            let size = reader.varint()? as usize;
+            let hashes = u32::try_from(reader.varint()?).map_err(|_| FilterError::Corrupted("too many hash functions"))?;
+            let capacity = reader.varint()? as usize;
+            let len = reader.varint()? as usize;
+            if size == 0 || hashes == 0 {
+                return Err(FilterError::Corrupted("empty layer"));
+            }
 
-        if self.counter.contains_key(&insert_index) {
-            let mut el = self.counter[&insert_index];
-            el -= 1;
-            if el <= 0 {
-                el = 0;
-                self.bitmap[insert_index] = false;
+            let mut layer = Layer::new(size, hashes, capacity);
+            layer.len = len;
+            for (index, byte) in reader.take(size.div_ceil(8))?.iter().enumerate() {
+                layer.bits[index / 8] |= (*byte as u64) << (index % 8 * 8);
             }
-            *self.counter.get_mut(&insert_index).unwrap() = el;
+            if with_counters {
+                for _ in 0..reader.varint()? {
+                    reader.varint()?;
+                    reader.varint()?;
+                }
+            }
+            layers.push(layer);
+        }
+        if layers.is_empty() || !reader.bytes.is_empty() {
+            return Err(FilterError::Corrupted("invalid length"));
+        }
+
+        Ok(BloomFilter { layers, fp_rate })
+    }
+}
+
+// unsigned leb128, so small numbers take one byte
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FilterError> {
+        if self.bytes.len() < len {
+            return Err(FilterError::Corrupted("unexpected end"));
         }
-        let insert_index = (self.sha_256_hasher.hash(&data) % self.size).to_u32().unwrap() as usize;
-        if self.counter.contains_key(&insert_index) {
-            let mut el = self.counter[&insert_index];
-            el -= 1;
-            if el <= 0 {
-                el = 0;
-                self.bitmap[insert_index] = false;
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn varint(&mut self) -> Result<u64, FilterError> {
+        let mut value = 0_u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
             }
-            *self.counter.get_mut(&insert_index).unwrap() = el;
         }
+        Err(FilterError::Corrupted("varint too long"))
     }
 }
 
@@ -98,37 +258,131 @@ mod tests {
     #[test]
     fn bloom_filter_starts_empty() {
         let filter = BloomFilter::new(100);
-        assert_eq!(filter.check("test data"), false);
+        assert!(!filter.check("test data"));
     }
 
     #[test]
     fn bloom_filter_adds_and_checks_data() {
         let mut filter = BloomFilter::new(100);
         filter.insert("test data");
-        assert_eq!(filter.check("test data"), true);
+        assert!(filter.check("test data"));
     }
 
     #[test]
     fn bloom_filter_does_not_contain_not_inserted_data() {
         let mut filter = BloomFilter::new(100);
         filter.insert("test data");
-        assert_eq!(filter.check("other data"), false);
+        assert!(!filter.check("other data"));
     }
 
     #[test]
-    fn bloom_filter_removes_data() {
+    fn bloom_filter_handles_collision() {
+        let mut filter = BloomFilter::new(1); // Force a collision
+        filter.insert("test data");
+        filter.insert("other data");
+        assert!(filter.check("test data"));
+        assert!(filter.check("other data"));
+    }
+
+    #[test]
+    fn bloom_filter_restores_from_bytes() {
         let mut filter = BloomFilter::new(100);
         filter.insert("test data");
-        filter.remove("test data");
-        assert_eq!(filter.check("test data"), false);
+        filter.insert("more data");
+
+        let restored = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+
+        assert!(restored.check("test data"));
+        assert!(restored.check("more data"));
+        assert!(!restored.check("other data"));
     }
 
     #[test]
-    fn bloom_filter_handles_collision() {
-        let mut filter = BloomFilter::new(1); // Force a collision
+    fn bloom_filter_reads_bytes_with_counters() {
+        // a filter of one bit with one key written by version 1, the bit was set twice
+        let mut bytes = b"BLF\x01\x00\x01\x01\x02".to_vec();
+        write_varint(&mut bytes, usize::MAX as u64);
+        bytes.extend_from_slice(&[1, 0x01, 1, 0, 2]);
+
+        let filter = BloomFilter::from_bytes(&bytes).unwrap();
+
+        assert!(filter.check("test data"));
+        assert_eq!(filter.size(), 1);
+    }
+
+    #[test]
+    fn bloom_filter_restores_grown_filter_from_bytes() {
+        let mut filter = BloomFilter::with_rate(10, 0.01);
+        for i in 0..100 {
+            filter.insert(format!("key {}", i));
+        }
+
+        let mut restored = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+        restored.insert("key 100");
+
+        assert_eq!(restored.size(), filter.size());
+        assert!((0..=100).all(|i| restored.check(format!("key {}", i))));
+    }
+
+    #[test]
+    fn bloom_filter_rejects_corrupted_bytes() {
+        let mut filter = BloomFilter::new(100);
         filter.insert("test data");
-        filter.insert("other data");
-        assert_eq!(filter.check("test data"), true);
-        assert_eq!(filter.check("other data"), true);
+        let bytes = filter.to_bytes();
+
+        assert!(BloomFilter::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(BloomFilter::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(BloomFilter::from_bytes(b"other").is_err());
+    }
+
+    #[test]
+    fn bloom_filter_union_has_keys_of_both() {
+        let mut filter = BloomFilter::with_rate(100, 0.01);
+        let mut other = BloomFilter::with_rate(100, 0.01);
+        filter.insert("test data");
+        other.insert("other data");
+
+        filter.union(&other).unwrap();
+
+        assert!(filter.check("test data"));
+        assert!(filter.check("other data"));
+        assert!(filter.union(&BloomFilter::with_rate(200, 0.01)).is_err());
+    }
+
+    fn false_positive_rate(filter: &BloomFilter) -> f64 {
+        let false_positives = (0..10000).filter(|i| filter.check(format!("other {}", i))).count();
+        false_positives as f64 / 10000.0
+    }
+
+    #[test]
+    fn bloom_filter_with_rate_keeps_false_positive_rate() {
+        let mut filter = BloomFilter::with_rate(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(format!("key {}", i));
+        }
+
+        assert!((0..1000).all(|i| filter.check(format!("key {}", i))));
+        assert!(false_positive_rate(&filter) <= 0.02);
+    }
+
+    #[test]
+    fn bloom_filter_with_rate_derives_size_from_expected_items() {
+        let filter = BloomFilter::with_rate(1000, 0.01);
+
+        // 1000 keys at a rate of 0.005 for the first layer take about 11000 bits
+        assert!((10000..12500).contains(&filter.size()));
+    }
+
+    #[test]
+    fn bloom_filter_with_rate_grows_when_expected_items_are_exceeded() {
+        let mut filter = BloomFilter::with_rate(100, 0.01);
+        let size = filter.size();
+        for i in 0..2000 {
+            filter.insert(format!("key {}", i));
+        }
+
+        assert!(filter.size() > size);
+        assert!((0..2000).all(|i| filter.check(format!("key {}", i))));
+        assert!(false_positive_rate(&filter) <= 0.02);
     }
 }
@@ -1,11 +1,14 @@
 use std::env;
+use std::ops::Bound;
+use std::path::Path;
 use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::{Parser, Subcommand};
 
 use indexengine::index::Index;
-use indexengine::no_index::NoIndex;
+use storageengine::operations::DbOperations;
+use storageengine::file_handler::FileHandler;
 
 #[derive(Subcommand, PartialEq, Debug)]
 enum Action {
@@ -13,12 +16,45 @@ enum Action {
     Update { key: String, value: String },
     Get { key: String },
     Delete { key: String },
+    Vacuum,
+    // prints the documents from `from` (inclusive) to `to` (exclusive) ordered by key
+    Scan {
+        #[arg(long)]
+        from: Option<String>,
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long, conflicts_with_all = ["from", "to"])]
+        prefix: Option<String>,
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    // encrypts the storage file, its wal, its transaction file and the wal archive with the key in `new_key_file`
+    RotateKey {
+        #[arg(long)]
+        new_key_file: String,
+    },
+    // copies the storage file to `backup_file` and archives the wal from then on
+    Backup {
+        #[arg(long)]
+        backup_file: String,
+    },
+    // recreates the storage file from `backup_file` and the records in `wal_archive_dir` up to transaction `to_txid`
+    Restore {
+        #[arg(long)]
+        backup_file: String,
+        // the archive of the database the backup was taken of, it can't be the archive of the restored file
+        #[arg(long)]
+        wal_archive_dir: String,
+        #[arg(long)]
+        to_txid: Option<u64>,
+    },
 }
 
 #[derive(Debug, Clone)]
 enum IndexEngine {
     BTree,
     LSMTree,
+    LSMValueTree,
     NoIndex,
     HashMap,
 }
@@ -30,6 +66,7 @@ impl FromStr for IndexEngine {
         match s {
             "BTree" => Ok(IndexEngine::BTree),
             "LSMTree" => Ok(IndexEngine::LSMTree),
+            "LSMValueTree" => Ok(IndexEngine::LSMValueTree),
             "NoIndex" => Ok(IndexEngine::NoIndex),
             "HashMap" => Ok(IndexEngine::HashMap),
             _ => Err("no match"),
@@ -44,6 +81,25 @@ struct Args {
     file: String,
     #[arg(short, long, default_value = "BTree")]
     index_engine: IndexEngine,
+    // defaults to `<file>.sstables`
+    #[arg(long)]
+    ss_table_dir: Option<String>,
+    #[arg(long, default_value_t = indexengine::DEFAULT_MEMTABLE_SIZE)]
+    memtable_size: usize,
+    #[arg(long, default_value_t = indexengine::DEFAULT_BLOOM_FILTER_SIZE)]
+    bloom_filter_size: usize,
+    // bincode, json, msgpack or cbor, defaults to the codec the database was created with
+    #[arg(long)]
+    codec: Option<indexengine::codec::CodecKind>,
+    // none, lz4 or zstd, can only be chosen for a new database
+    #[arg(long)]
+    compression: Option<storageengine::compression::Compression>,
+    // aes-gcm or chacha20poly1305, can only be chosen for a new database and not with the LSM index engines
+    #[arg(long)]
+    encryption: Option<storageengine::encrypted_file_handler::Encryption>,
+    // holds the hex encoded key, defaults to the PATRICKDB_ENCRYPTION_KEY env var
+    #[arg(long)]
+    key_file: Option<String>,
     #[command(subcommand)]
     action: Action,
 }
@@ -51,14 +107,98 @@ struct Args {
 fn main() -> Result<()> {
     let args = Args::parse();
     let file_name = env::var("FILE").ok().unwrap_or(args.file);
-    let file_handler = storageengine::file_handler::FileHandlerImpl::new(&file_name)?;
-    let operations = storageengine::operations::DbOperationsImpl::new(Box::new(file_handler));
-    let mut index_engine: Box<dyn Index<String, String>> = match args.index_engine {
-        IndexEngine::BTree => indexengine::new_index_engine(indexengine::IndexEngine::BTree, Box::new(operations)).expect("failed to create btree"),
-        IndexEngine::LSMTree => indexengine::new_index_engine(indexengine::IndexEngine::LSM, Box::new(operations)).expect("failed to create lsm"),
-        IndexEngine::NoIndex => Box::new(NoIndex::new(Box::new(operations))),
-        IndexEngine::HashMap => indexengine::new_index_engine(indexengine::IndexEngine::HashMap, Box::new(operations)).expect("failed to create hashmap"),
+    let key = storageengine::encrypted_file_handler::EncryptionKey::load(args.key_file.as_deref())?;
+    // a server that has the database open holds the lock, it is backed up with the `Backup` RPC instead
+    let _lock = storageengine::file_handler::lock(&file_name)?;
+    if let Action::RotateKey { new_key_file } = &args.action {
+        let key = key.ok_or_else(|| anyhow::anyhow!("the current key is needed to rotate it"))?;
+        let new_key = storageengine::encrypted_file_handler::EncryptionKey::load(Some(new_key_file))?.expect("key file is given");
+        let mut file_names = vec![file_name.clone(), storageengine::wal::wal_file_name(&file_name), storageengine::transaction::transaction_file_name(&file_name)];
+        file_names.extend(storageengine::backup::segment_file_names(&storageengine::backup::wal_archive_dir_name(&file_name))?);
+        let bplus_tree_file_name = indexengine::bplus_tree::bplus_tree_file_name(&file_name);
+        file_names.extend([storageengine::wal::wal_file_name(&bplus_tree_file_name), bplus_tree_file_name].into_iter().filter(|file_name| Path::new(file_name).exists()));
+        for file_name in file_names {
+            storageengine::encrypted_file_handler::rotate_key(&file_name, &key, &new_key)?;
+        }
+        println!("rotated key of {}", file_name);
+        return Ok(());
+    }
+    if args.encryption.is_some() && matches!(args.index_engine, IndexEngine::LSMTree | IndexEngine::LSMValueTree) {
+        bail!("the sstables of the {:?} index engine can't be encrypted, use another index engine", args.index_engine);
+    }
+    let is_lsm_value_tree = matches!(args.index_engine, IndexEngine::LSMValueTree);
+    if is_lsm_value_tree && matches!(args.action, Action::Backup { .. } | Action::Restore { .. }) {
+        bail!("the LSMValueTree index engine keeps the documents in its tables, they are not in a backup of the storage file");
+    }
+    if let Action::Restore { backup_file, wal_archive_dir, to_txid } = &args.action {
+        // the restored database starts without a wal and its index files are rebuilt from the rows
+        let ss_table_dir = args.ss_table_dir.clone().unwrap_or_else(|| indexengine::ss_table_dir_name(&file_name));
+        for existing in [
+            file_name.clone(),
+            storageengine::wal::wal_file_name(&file_name),
+            storageengine::transaction::transaction_file_name(&file_name),
+            storageengine::backup::wal_archive_dir_name(&file_name),
+            indexengine::bplus_tree::bplus_tree_file_name(&file_name),
+            ss_table_dir,
+        ] {
+            if Path::new(&existing).exists() {
+                bail!("{} already exists, move it away before restoring", existing);
+            }
+        }
+        if !Path::new(backup_file).exists() {
+            bail!("backup {} doesn't exist", backup_file);
+        }
+
+        let encryption = storageengine::file_handler::FileHandlerImpl::new(backup_file)?.header().encryption
+            .and_then(storageengine::encrypted_file_handler::Encryption::from_id);
+        let mut target = storageengine::encrypted_file_handler::open_file_handler(&file_name, args.encryption.or(encryption), key.as_ref())?;
+        match storageengine::backup::restore(backup_file, wal_archive_dir, key.as_ref(), target.as_mut(), *to_txid)? {
+            Some(transaction_id) => println!("restored {} up to transaction {}", file_name, transaction_id),
+            None => println!("restored {}", file_name),
+        }
+        return Ok(());
+    }
+    let mut file_handler = storageengine::encrypted_file_handler::open_file_handler(&file_name, args.encryption, key.as_ref())?;
+    let mut index_engine_config = indexengine::IndexEngineConfig::new(&file_name);
+    if let Some(ss_table_dir) = args.ss_table_dir {
+        index_engine_config.ss_table_dir = ss_table_dir;
+    }
+    index_engine_config.memtable_size = args.memtable_size;
+    index_engine_config.bloom_filter_size = args.bloom_filter_size;
+    index_engine_config.codec = args.codec;
+    index_engine_config.key = key.clone();
+    let index_engine = match args.index_engine {
+        IndexEngine::BTree => indexengine::IndexEngine::BTree,
+        IndexEngine::LSMTree => indexengine::IndexEngine::LSM(indexengine::LsmMode::Offsets),
+        IndexEngine::LSMValueTree => indexengine::IndexEngine::LSM(indexengine::LsmMode::Values),
+        IndexEngine::NoIndex => indexengine::IndexEngine::NoIndex,
+        IndexEngine::HashMap => indexengine::IndexEngine::HashMap,
     };
+    indexengine::open_file_header(file_handler.as_mut(), &index_engine, &mut index_engine_config)?;
+    storageengine::compression::open_compression(file_handler.as_mut(), args.compression)?;
+    let encryption = file_handler.header().encryption.and_then(storageengine::encrypted_file_handler::Encryption::from_id);
+    let wal_file_handler = storageengine::encrypted_file_handler::open_log_file_handler(&storageengine::wal::wal_file_name(&file_name), args.encryption, key.as_ref())?;
+    let mut wal = storageengine::wal::Wal::new(wal_file_handler)?;
+    // archiving starts with the first backup
+    let wal_archive_dir = storageengine::backup::wal_archive_dir_name(&file_name);
+    if let Some(wal_archive) = storageengine::backup::WalArchive::open_existing(&wal_archive_dir, key.clone())? {
+        wal.set_archive(wal_archive);
+    }
+    let transaction_file_handler = storageengine::encrypted_file_handler::open_file_handler(&storageengine::transaction::transaction_file_name(&file_name), args.encryption, key.as_ref())?;
+    let transaction_manager = storageengine::transaction::TransactionManager::new(transaction_file_handler)?;
+    let mut operations = storageengine::operations::DbOperationsImpl::new(file_handler, wal, transaction_manager, storageengine::buffer_pool::DEFAULT_BUFFER_POOL_PAGES)?;
+    if let Action::Backup { backup_file } = &args.action {
+        if Path::new(backup_file).exists() {
+            bail!("{} already exists", backup_file);
+        }
+        // the backup is encrypted like the storage file
+        let mut target = storageengine::encrypted_file_handler::open_file_handler(backup_file, encryption, key.as_ref())?;
+        let manifest = operations.backup(target.as_mut(), Some(storageengine::backup::WalArchive::create(&wal_archive_dir, key.clone())?))?;
+        manifest.save(backup_file)?;
+        println!("backed up {} to {} before transaction {}", file_name, backup_file, manifest.transaction_id);
+        return Ok(());
+    }
+    let mut index_engine: Box<dyn Index<String, String>> = indexengine::new_index_engine(index_engine, Box::new(operations), &index_engine_config).expect("failed to create index engine");
 
     match args.action {
         Action::Add { key, value } => {
@@ -92,6 +232,25 @@ fn main() -> Result<()> {
                 Err(e) => println!("failed to update: {}", e),
             }
         }
+        Action::Vacuum => {
+            match index_engine.vacuum() {
+                Ok(_) => println!("vacuumed {}", file_name),
+                Err(e) => println!("failed to vacuum: {}", e),
+            }
+        }
+        Action::RotateKey { .. } => unreachable!("the key is rotated before the database is opened"),
+        Action::Backup { .. } => unreachable!("the backup is taken before the index engine is opened"),
+        Action::Restore { .. } => unreachable!("the database is restored before it is opened"),
+        Action::Scan { from, to, prefix, limit } => {
+            let documents = match prefix {
+                Some(prefix) => index_engine.scan_prefix(&prefix, limit),
+                None => index_engine.scan((from.map_or(Bound::Unbounded, Bound::Included), to.map_or(Bound::Unbounded, Bound::Excluded)), limit),
+            };
+            match documents {
+                Ok(documents) => documents.iter().for_each(|document| println!("{}, {}", document.id, document.value)),
+                Err(e) => println!("failed to scan: {}", e),
+            }
+        }
     }
 
     Ok(())
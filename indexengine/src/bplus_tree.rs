@@ -0,0 +1,783 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use storageengine::buffer_pool::{BufferPool, DEFAULT_BUFFER_POOL_PAGES};
+use storageengine::file_handler::FileHandler;
+use storageengine::operations::StorageError;
+use storageengine::page::{PAGE_SIZE, page_offset};
+use storageengine::wal::{self, Wal, WalWrite};
+
+const META_PAGE_ID: u64 = 0;
+// crc32c (4) + length (4) of the serialized node
+const NODE_HEADER_SIZE: usize = 8;
+const NODE_CAPACITY: usize = PAGE_SIZE - NODE_HEADER_SIZE;
+// an entry of at most this size always leaves a split with two halves that fit into a page
+const MAX_ENTRY_SIZE: usize = NODE_CAPACITY / 4;
+// nodes smaller than this are merged with a sibling if both fit into one page
+const MIN_NODE_SIZE: usize = NODE_CAPACITY / 4;
+// once the wal grows beyond this size the tree file is synced and the wal is truncated
+const WAL_CHECKPOINT_SIZE: u64 = 4 * 1024 * 1024;
+
+pub fn bplus_tree_file_name(storage_file_name: &str) -> String {
+    format!("{}.btree", storage_file_name)
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct Meta {
+    root: u64,
+    // head of the list of pages freed by merges, they are reused before the file grows
+    first_free_page: Option<u64>,
+    len: u64,
+    // set before the indexed data changes and cleared together with the matching tree change,
+    // a tree that is still dirty when it is opened might have missed a change
+    dirty: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum Node<K, V> {
+    // `next` is the right sibling, so range scans walk the leaves without going back up the tree
+    Leaf { entries: Vec<(K, V)>, next: Option<u64> },
+    // `children[i]` holds the keys below `keys[i]`, the last child the keys from the last key on
+    Internal { keys: Vec<K>, children: Vec<u64> },
+    Free { next: Option<u64> },
+}
+
+// first key and page of the new right node if a node had to be split
+type Split<K> = Option<(K, u64)>;
+
+// maps keys to values in a file of pages, every change is logged to a wal before it touches the file,
+// so opening the file needs neither a scan nor a rebuild
+pub struct BPlusTree<K, V> {
+    buffer_pool: BufferPool,
+    wal: Wal,
+    meta: Meta,
+    // nodes changed by the running operation, they are logged and written together with the meta page
+    staged_nodes: BTreeMap<u64, Node<K, V>>,
+    page_count: u64,
+}
+
+impl<K, V> BPlusTree<K, V> where K: Serialize + DeserializeOwned + Ord + Clone, V: Serialize + DeserializeOwned + Clone {
+    // a new file starts out dirty, it is up to the caller to fill it through `rebuild`
+    pub fn new(mut file_handler: Box<dyn FileHandler>, mut wal: Wal) -> Result<Self> {
+        for record in wal.records()? {
+            wal::apply(file_handler.as_mut(), &record)?;
+        }
+        file_handler.sync()?;
+        wal.truncate()?;
+
+        let buffer_pool = BufferPool::new(file_handler, DEFAULT_BUFFER_POOL_PAGES)?;
+        let page_count = buffer_pool.page_count();
+        let mut tree = Self {
+            buffer_pool,
+            wal,
+            meta: Meta {
+                root: META_PAGE_ID + 1,
+                first_free_page: None,
+                len: 0,
+                dirty: true,
+            },
+            staged_nodes: BTreeMap::new(),
+            page_count,
+        };
+
+        if page_count == 0 {
+            tree.atomically(|tree| {
+                tree.page_count = META_PAGE_ID + 1;
+                let root = tree.allocate()?;
+                tree.staged_nodes.insert(root, Node::Leaf { entries: Vec::new(), next: None });
+                Ok(())
+            })?;
+        } else {
+            tree.meta = decode_page(META_PAGE_ID, tree.buffer_pool.fetch(META_PAGE_ID)?.as_bytes())?;
+        }
+
+        Ok(tree)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.meta.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meta.len == 0
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.meta.dirty
+    }
+
+    // call before the data the tree points into changes, the next change of the tree clears the flag again
+    pub fn mark_dirty(&mut self) -> Result<()> {
+        if self.meta.dirty {
+            return Ok(());
+        }
+
+        self.atomically(|tree| {
+            tree.meta.dirty = true;
+            Ok(())
+        })
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        let mut page_id = self.meta.root;
+        loop {
+            match self.node(page_id)? {
+                Node::Leaf { entries, .. } => {
+                    return Ok(entries.binary_search_by(|(k, _)| k.cmp(key)).ok()
+                        .map(|i| entries[i].1.clone()));
+                }
+                Node::Internal { keys, children } => page_id = children[child_index(&keys, key)],
+                Node::Free { .. } => bail!("b+tree page {} is free but still referenced", page_id),
+            }
+        }
+    }
+
+    // returns the value the key had before
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        self.atomically(|tree| {
+            tree.meta.dirty = false;
+            tree.put(key, value)
+        })
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>> {
+        self.atomically(|tree| {
+            tree.meta.dirty = false;
+            tree.delete(key)
+        })
+    }
+
+    // puts (`Some`) and removes (`None`) all keys, either all or none of the changes survive a crash
+    pub fn apply(&mut self, changes: Vec<(K, Option<V>)>) -> Result<()> {
+        self.atomically(|tree| {
+            tree.meta.dirty = false;
+            for (key, value) in changes {
+                match value {
+                    Some(value) => tree.put(key, value)?,
+                    None => tree.delete(&key)?,
+                };
+            }
+            Ok(())
+        })
+    }
+
+    // iterates over the entries in range ordered by key
+    pub fn range(&mut self, range: (Bound<K>, Bound<K>)) -> Result<Range<'_, K, V>> {
+        let mut page_id = self.meta.root;
+        loop {
+            match self.node(page_id)? {
+                Node::Leaf { entries, next } => {
+                    return Ok(Range {
+                        tree: self,
+                        range,
+                        entries: entries.into_iter(),
+                        next_leaf: next,
+                    });
+                }
+                Node::Internal { keys, children } => {
+                    page_id = match &range.0 {
+                        Bound::Included(start) | Bound::Excluded(start) => children[child_index(&keys, start)],
+                        Bound::Unbounded => children[0],
+                    };
+                }
+                Node::Free { .. } => bail!("b+tree page {} is free but still referenced", page_id),
+            }
+        }
+    }
+
+    // replaces the whole tree with `entries`, which have to be ordered by key. the leaves are packed
+    // and written into a new file that is swapped in atomically
+    pub fn rebuild(&mut self, entries: impl IntoIterator<Item=(K, V)>) -> Result<()> {
+        let mut nodes = Vec::new();
+        // first key and page of every node of the level that is built next
+        let mut level: Vec<(Option<K>, u64)> = Vec::new();
+        let mut len = 0;
+
+        let empty_leaf_size = empty_leaf_size::<K, V>()?;
+        let mut leaf = Vec::new();
+        let mut leaf_size = empty_leaf_size;
+        for (key, value) in entries {
+            let entry_size = check_entry_size(&key, &value)?;
+            if leaf_size + entry_size > NODE_CAPACITY {
+                level.push((Some(leaf_first_key(&leaf)), META_PAGE_ID + 1 + nodes.len() as u64));
+                nodes.push(Node::Leaf { entries: std::mem::take(&mut leaf), next: Some(META_PAGE_ID + 2 + nodes.len() as u64) });
+                leaf_size = empty_leaf_size;
+            }
+            leaf_size += entry_size;
+            leaf.push((key, value));
+            len += 1;
+        }
+        level.push((leaf.first().map(|(key, _)| key.clone()), META_PAGE_ID + 1 + nodes.len() as u64));
+        nodes.push(Node::Leaf { entries: leaf, next: None });
+
+        let empty_internal_size = empty_internal_size::<K, V>()?;
+        while level.len() > 1 {
+            let mut parents = Vec::new();
+            let mut children = level.into_iter();
+            let (mut first_key, first_child) = children.next().unwrap();
+            let mut keys = Vec::new();
+            let mut child_pages = vec![first_child];
+            let mut size = empty_internal_size;
+            for (key, child) in children {
+                let key = key.ok_or_else(|| anyhow!("only the first leaf can be empty"))?;
+                let key_size = child_size(&key)?;
+                if size + key_size > NODE_CAPACITY {
+                    parents.push((first_key, META_PAGE_ID + 1 + nodes.len() as u64));
+                    nodes.push(Node::Internal { keys: std::mem::take(&mut keys), children: std::mem::take(&mut child_pages) });
+                    first_key = Some(key);
+                    child_pages.push(child);
+                    size = empty_internal_size;
+                    continue;
+                }
+                size += key_size;
+                keys.push(key);
+                child_pages.push(child);
+            }
+            parents.push((first_key, META_PAGE_ID + 1 + nodes.len() as u64));
+            nodes.push(Node::Internal { keys, children: child_pages });
+            level = parents;
+        }
+
+        let meta = Meta {
+            root: level[0].1,
+            first_free_page: None,
+            len,
+            dirty: false,
+        };
+        let mut pages = vec![encode_page(&meta)?];
+        for node in &nodes {
+            pages.push(encode_page(node)?);
+        }
+
+        // a wal record replayed against the new file would corrupt it, so the wal has to be empty first
+        self.checkpoint()?;
+        self.buffer_pool.rewrite(&mut |_, replacement| {
+            for page in &pages {
+                replacement.append(page)?;
+            }
+            Ok(())
+        })?;
+        self.staged_nodes.clear();
+        self.page_count = self.buffer_pool.page_count();
+        self.meta = meta;
+
+        Ok(())
+    }
+
+    // runs `operation` on staged nodes and logs all nodes it changed as one wal record
+    // before any of them touches the tree file
+    fn atomically<T>(&mut self, operation: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let meta = self.meta.clone();
+        let result = operation(self).and_then(|value| {
+            self.flush_staged()?;
+            Ok(value)
+        });
+        if result.is_err() {
+            self.staged_nodes.clear();
+            self.page_count = self.buffer_pool.page_count();
+            self.meta = meta;
+        }
+
+        result
+    }
+
+    fn flush_staged(&mut self) -> Result<()> {
+        let mut writes = vec![WalWrite {
+            offset: page_offset(META_PAGE_ID),
+            data: encode_page(&self.meta)?,
+        }];
+        for (page_id, node) in std::mem::take(&mut self.staged_nodes) {
+            writes.push(WalWrite {
+                offset: page_offset(page_id),
+                data: encode_page(&node)?,
+            });
+        }
+
+        let record = self.wal.log(writes)?;
+        self.buffer_pool.apply(&record)?;
+
+        if self.wal.size()? >= WAL_CHECKPOINT_SIZE {
+            self.checkpoint()?;
+        }
+
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<()> {
+        self.buffer_pool.sync()?;
+        self.wal.truncate()
+    }
+
+    // returns the staged copy if the running operation already changed the node
+    fn node(&mut self, page_id: u64) -> Result<Node<K, V>> {
+        if let Some(node) = self.staged_nodes.get(&page_id) {
+            return Ok(node.clone());
+        }
+
+        decode_page(page_id, self.buffer_pool.fetch(page_id)?.as_bytes())
+    }
+
+    fn allocate(&mut self) -> Result<u64> {
+        let page_id = match self.meta.first_free_page {
+            Some(page_id) => page_id,
+            None => {
+                self.page_count += 1;
+                return Ok(self.page_count - 1);
+            }
+        };
+
+        match self.node(page_id)? {
+            Node::Free { next } => self.meta.first_free_page = next,
+            _ => bail!("b+tree page {} is on the free list but still in use", page_id),
+        }
+
+        Ok(page_id)
+    }
+
+    fn free(&mut self, page_id: u64) {
+        self.staged_nodes.insert(page_id, Node::Free { next: self.meta.first_free_page });
+        self.meta.first_free_page = Some(page_id);
+    }
+
+    fn put(&mut self, key: K, value: V) -> Result<Option<V>> {
+        check_entry_size(&key, &value)?;
+
+        let root = self.meta.root;
+        let (previous, split) = self.put_into(root, key, value)?;
+        if let Some((separator, right)) = split {
+            let new_root = self.allocate()?;
+            self.staged_nodes.insert(new_root, Node::Internal { keys: vec![separator], children: vec![root, right] });
+            self.meta.root = new_root;
+        }
+        if previous.is_none() {
+            self.meta.len += 1;
+        }
+
+        Ok(previous)
+    }
+
+    // returns the previous value and whether the node had to be split
+    fn put_into(&mut self, page_id: u64, key: K, value: V) -> Result<(Option<V>, Split<K>)> {
+        match self.node(page_id)? {
+            Node::Leaf { mut entries, next } => {
+                let previous = match entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+                    Ok(i) => Some(std::mem::replace(&mut entries[i].1, value)),
+                    Err(i) => {
+                        entries.insert(i, (key, value));
+                        None
+                    }
+                };
+                let split = self.store_leaf(page_id, entries, next)?;
+
+                Ok((previous, split))
+            }
+            Node::Internal { mut keys, mut children } => {
+                let i = child_index(&keys, &key);
+                let (previous, split) = self.put_into(children[i], key, value)?;
+                let split = match split {
+                    Some((separator, right)) => {
+                        keys.insert(i, separator);
+                        children.insert(i + 1, right);
+                        self.store_internal(page_id, keys, children)?
+                    }
+                    None => None,
+                };
+
+                Ok((previous, split))
+            }
+            Node::Free { .. } => bail!("b+tree page {} is free but still referenced", page_id),
+        }
+    }
+
+    fn store_leaf(&mut self, page_id: u64, mut entries: Vec<(K, V)>, next: Option<u64>) -> Result<Split<K>> {
+        let sizes = entries.iter()
+            .map(|(key, value)| entry_size(key, value))
+            .collect::<Result<Vec<_>>>()?;
+        if empty_leaf_size::<K, V>()? + sizes.iter().sum::<usize>() <= NODE_CAPACITY {
+            self.staged_nodes.insert(page_id, Node::Leaf { entries, next });
+            return Ok(None);
+        }
+
+        let right_entries = entries.split_off(split_point(&sizes));
+        let right = self.allocate()?;
+        let separator = leaf_first_key(&right_entries);
+        self.staged_nodes.insert(right, Node::Leaf { entries: right_entries, next });
+        self.staged_nodes.insert(page_id, Node::Leaf { entries, next: Some(right) });
+
+        Ok(Some((separator, right)))
+    }
+
+    fn store_internal(&mut self, page_id: u64, mut keys: Vec<K>, mut children: Vec<u64>) -> Result<Split<K>> {
+        let sizes = keys.iter()
+            .map(child_size)
+            .collect::<Result<Vec<_>>>()?;
+        if empty_internal_size::<K, V>()? + sizes.iter().sum::<usize>() <= NODE_CAPACITY {
+            self.staged_nodes.insert(page_id, Node::Internal { keys, children });
+            return Ok(None);
+        }
+
+        // the key in the middle moves up into the parent
+        let mut right_keys = keys.split_off(split_point(&sizes));
+        let separator = right_keys.remove(0);
+        let right_children = children.split_off(keys.len() + 1);
+        let right = self.allocate()?;
+        self.staged_nodes.insert(right, Node::Internal { keys: right_keys, children: right_children });
+        self.staged_nodes.insert(page_id, Node::Internal { keys, children });
+
+        Ok(Some((separator, right)))
+    }
+
+    fn delete(&mut self, key: &K) -> Result<Option<V>> {
+        let root = self.meta.root;
+        let removed = self.delete_from(root, key)?;
+        if removed.is_none() {
+            return Ok(None);
+        }
+
+        self.meta.len -= 1;
+        // merges can leave the root with a single child, the child becomes the new root
+        if let Node::Internal { children, .. } = self.node(root)? {
+            if children.len() == 1 {
+                self.free(root);
+                self.meta.root = children[0];
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn delete_from(&mut self, page_id: u64, key: &K) -> Result<Option<V>> {
+        match self.node(page_id)? {
+            Node::Leaf { mut entries, next } => match entries.binary_search_by(|(k, _)| k.cmp(key)) {
+                Ok(i) => {
+                    let (_, value) = entries.remove(i);
+                    self.staged_nodes.insert(page_id, Node::Leaf { entries, next });
+                    Ok(Some(value))
+                }
+                Err(_) => Ok(None),
+            },
+            Node::Internal { mut keys, mut children } => {
+                let i = child_index(&keys, key);
+                let removed = self.delete_from(children[i], key)?;
+                if removed.is_some() && children.len() > 1 && serialized_size(&self.node(children[i])?)? < MIN_NODE_SIZE {
+                    let sibling = if i + 1 < children.len() { i } else { i - 1 };
+                    if self.merge(&mut keys, &mut children, sibling)? {
+                        self.staged_nodes.insert(page_id, Node::Internal { keys, children });
+                    }
+                }
+
+                Ok(removed)
+            }
+            Node::Free { .. } => bail!("b+tree page {} is free but still referenced", page_id),
+        }
+    }
+
+    // merges `children[left]` with its right sibling if both fit into one page
+    fn merge(&mut self, keys: &mut Vec<K>, children: &mut Vec<u64>, left: usize) -> Result<bool> {
+        let merged = match (self.node(children[left])?, self.node(children[left + 1])?) {
+            (Node::Leaf { mut entries, .. }, Node::Leaf { entries: right_entries, next }) => {
+                entries.extend(right_entries);
+                Node::Leaf { entries, next }
+            }
+            (Node::Internal { keys: mut left_keys, children: mut left_children }, Node::Internal { keys: right_keys, children: right_children }) => {
+                left_keys.push(keys[left].clone());
+                left_keys.extend(right_keys);
+                left_children.extend(right_children);
+                Node::Internal { keys: left_keys, children: left_children }
+            }
+            _ => bail!("siblings {} and {} are on different levels", children[left], children[left + 1]),
+        };
+        if serialized_size(&merged)? > NODE_CAPACITY {
+            return Ok(false);
+        }
+
+        self.staged_nodes.insert(children[left], merged);
+        self.free(children[left + 1]);
+        keys.remove(left);
+        children.remove(left + 1);
+
+        Ok(true)
+    }
+}
+
+// walks the leaves along their sibling pointers, so only one leaf is held in memory
+pub struct Range<'a, K, V> {
+    tree: &'a mut BPlusTree<K, V>,
+    range: (Bound<K>, Bound<K>),
+    entries: std::vec::IntoIter<(K, V)>,
+    next_leaf: Option<u64>,
+}
+
+impl<K, V> Iterator for Range<'_, K, V> where K: Serialize + DeserializeOwned + Ord + Clone, V: Serialize + DeserializeOwned + Clone {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, value)) = self.entries.next() {
+                let before_start = match &self.range.0 {
+                    Bound::Included(start) => key < *start,
+                    Bound::Excluded(start) => key <= *start,
+                    Bound::Unbounded => false,
+                };
+                if before_start {
+                    continue;
+                }
+
+                let after_end = match &self.range.1 {
+                    Bound::Included(end) => key > *end,
+                    Bound::Excluded(end) => key >= *end,
+                    Bound::Unbounded => false,
+                };
+                if after_end {
+                    self.entries = Vec::new().into_iter();
+                    self.next_leaf = None;
+                    return None;
+                }
+
+                return Some(Ok((key, value)));
+            }
+
+            let page_id = self.next_leaf.take()?;
+            match self.tree.node(page_id) {
+                Ok(Node::Leaf { entries, next }) => {
+                    self.entries = entries.into_iter();
+                    self.next_leaf = next;
+                }
+                Ok(_) => return Some(Err(anyhow!("b+tree page {} is not a leaf", page_id))),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn child_index<K: Ord>(keys: &[K], key: &K) -> usize {
+    keys.partition_point(|k| k <= key)
+}
+
+fn leaf_first_key<K: Clone, V>(entries: &[(K, V)]) -> K {
+    entries[0].0.clone()
+}
+
+// index of the first element of the right half, so both halves get about the same number of bytes
+fn split_point(sizes: &[usize]) -> usize {
+    let total: usize = sizes.iter().sum();
+    let mut left = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        if left >= total / 2 {
+            return i.clamp(1, sizes.len() - 1);
+        }
+        left += size;
+    }
+
+    sizes.len() - 1
+}
+
+// a leaf without entries, the sizes of its entries add up to the size of the whole leaf
+fn empty_leaf_size<K: Serialize, V: Serialize>() -> Result<usize> {
+    serialized_size(&Node::<K, V>::Leaf { entries: Vec::new(), next: Some(0) })
+}
+
+// an internal node with only its first child, every further child adds `child_size` of its key
+fn empty_internal_size<K: Serialize, V: Serialize>() -> Result<usize> {
+    serialized_size(&Node::<K, V>::Internal { keys: Vec::new(), children: vec![0] })
+}
+
+fn child_size<K: Serialize>(key: &K) -> Result<usize> {
+    Ok(serialized_size(key)? + std::mem::size_of::<u64>())
+}
+
+fn entry_size<K: Serialize, V: Serialize>(key: &K, value: &V) -> Result<usize> {
+    Ok(serialized_size(key)? + serialized_size(value)?)
+}
+
+// returns the size of the entry, or fails if it is too big to be put into the tree
+pub fn check_entry_size<K: Serialize, V: Serialize>(key: &K, value: &V) -> Result<usize> {
+    let size = entry_size(key, value)?;
+    if size > MAX_ENTRY_SIZE {
+        bail!("b+tree entry of {} bytes is bigger than the maximum of {} bytes", size, MAX_ENTRY_SIZE);
+    }
+    Ok(size)
+}
+
+fn serialized_size<T: Serialize>(value: &T) -> Result<usize> {
+    Ok(bincode::serialized_size(value)? as usize)
+}
+
+fn encode_page<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(value)?;
+    if payload.len() > NODE_CAPACITY {
+        bail!("b+tree node of {} bytes does not fit into a page", payload.len());
+    }
+
+    let mut data = Vec::with_capacity(PAGE_SIZE);
+    data.extend_from_slice(&crc32c::crc32c(&payload).to_le_bytes());
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(&payload);
+    data.resize(PAGE_SIZE, 0);
+
+    Ok(data)
+}
+
+fn decode_page<T: DeserializeOwned>(page_id: u64, data: &[u8]) -> Result<T> {
+    let corrupted = || StorageError::Corrupted { offset: page_offset(page_id) };
+
+    let checksum = data.get(0..4).ok_or_else(corrupted)?;
+    let length = u32::from_le_bytes(data.get(4..8).ok_or_else(corrupted)?.try_into()?) as usize;
+    let payload = data.get(NODE_HEADER_SIZE..NODE_HEADER_SIZE + length).ok_or_else(corrupted)?;
+    if crc32c::crc32c(payload).to_le_bytes() != checksum {
+        return Err(corrupted().into());
+    }
+
+    bincode::deserialize(payload).map_err(|_| corrupted().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use storageengine::file_handler::FileHandlerImpl;
+    use storageengine::wal::wal_file_name;
+
+    use super::*;
+
+    #[test]
+    fn insert_splits_nodes_and_keeps_all_entries() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut tree = open_tree(&dir, "insert_splits_nodes_and_keeps_all_entries")?;
+
+        for i in (0..400).rev() {
+            assert_eq!(tree.insert(key(i), i)?, None);
+        }
+        assert_eq!(tree.insert(key(7), 8)?, Some(7));
+
+        assert_eq!(tree.len(), 400);
+        assert!(matches!(tree.node(tree.meta.root)?, Node::Internal { .. }));
+        assert_eq!(tree.get(&key(7))?, Some(8));
+        assert_eq!(tree.get(&key(399))?, Some(399));
+        assert_eq!(tree.get(&key(400))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn remove_merges_nodes_and_reuses_their_pages() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut tree = open_tree(&dir, "remove_merges_nodes_and_reuses_their_pages")?;
+        for i in 0..400 {
+            tree.insert(key(i), i)?;
+        }
+        let page_count = tree.page_count;
+
+        for i in 10..400 {
+            assert_eq!(tree.remove(&key(i))?, Some(i));
+        }
+        assert_eq!(tree.remove(&key(10))?, None);
+
+        assert_eq!(tree.len(), 10);
+        assert!(matches!(tree.node(tree.meta.root)?, Node::Leaf { .. }));
+        assert!(tree.meta.first_free_page.is_some());
+        assert_eq!(tree.get(&key(9))?, Some(9));
+
+        for i in 10..400 {
+            tree.insert(key(i), i)?;
+        }
+        assert_eq!(tree.page_count, page_count);
+
+        Ok(())
+    }
+
+    #[test]
+    fn range_walks_leaves_in_key_order() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut tree = open_tree(&dir, "range_walks_leaves_in_key_order")?;
+        for i in (0..400).rev() {
+            tree.insert(key(i), i)?;
+        }
+
+        let values = tree.range((Bound::Excluded(key(100)), Bound::Included(key(300))))?
+            .map(|entry| entry.map(|(_, value)| value))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, (101..=300).collect::<Vec<_>>());
+
+        let values = tree.range((Bound::Unbounded, Bound::Excluded(key(3))))?
+            .map(|entry| entry.map(|(_, value)| value))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, vec![0, 1, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_keeps_entries_and_dirty_flag() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut tree = open_tree(&dir, "reopen_keeps_entries_and_dirty_flag")?;
+        assert!(tree.is_dirty());
+        tree.apply(vec![(key(1), Some(1)), (key(2), Some(2)), (key(1), None)])?;
+        assert!(!tree.is_dirty());
+        drop(tree);
+
+        let mut tree = open_tree(&dir, "reopen_keeps_entries_and_dirty_flag")?;
+        assert!(!tree.is_dirty());
+        assert_eq!(tree.get(&key(1))?, None);
+        assert_eq!(tree.get(&key(2))?, Some(2));
+
+        tree.mark_dirty()?;
+        drop(tree);
+
+        let tree = open_tree(&dir, "reopen_keeps_entries_and_dirty_flag")?;
+        assert!(tree.is_dirty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_packs_sorted_entries() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut tree = open_tree(&dir, "rebuild_packs_sorted_entries")?;
+        tree.insert(key(900), 900)?;
+
+        tree.rebuild((0..600).map(|i| (key(i), i)))?;
+
+        assert!(!tree.is_dirty());
+        assert_eq!(tree.len(), 600);
+        assert_eq!(tree.get(&key(900))?, None);
+        assert_eq!(tree.get(&key(599))?, Some(599));
+        assert_eq!(tree.range((Bound::Unbounded, Bound::Unbounded))?.count(), 600);
+
+        tree.insert(key(600), 600)?;
+        drop(tree);
+
+        let mut tree = open_tree(&dir, "rebuild_packs_sorted_entries")?;
+        assert_eq!(tree.len(), 601);
+        assert_eq!(tree.get(&key(300))?, Some(300));
+        assert_eq!(tree.get(&key(600))?, Some(600));
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_rejects_oversized_entries() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut tree = open_tree(&dir, "insert_rejects_oversized_entries")?;
+
+        assert!(tree.insert("k".repeat(MAX_ENTRY_SIZE), 0).is_err());
+        assert!(tree.is_empty());
+
+        Ok(())
+    }
+
+    // long keys fill pages quickly, so a few hundred entries already build a tree of three levels
+    fn key(i: u64) -> String {
+        format!("key{:0>200}", i)
+    }
+
+    fn open_tree(dir: &tempfile::TempDir, name: &str) -> Result<BPlusTree<String, u64>> {
+        let file_name = dir.path().join(name).to_str().unwrap().to_string();
+        let file_handler = FileHandlerImpl::new(&file_name)?;
+        let wal = Wal::new(Box::new(FileHandlerImpl::new(&wal_file_name(&file_name))?))?;
+
+        BPlusTree::new(Box::new(file_handler), wal)
+    }
+}
@@ -1,85 +1,153 @@
-use std::collections::BTreeMap;
 use std::hash::Hash;
 use std::marker::PhantomData;
+use std::ops::Bound;
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
 
-use storageengine::operations::{DbOperations, NONE_SENTINEL, OffsetSize};
-
-use crate::index::{Document, Index, IndexError};
+use storageengine::backup::{BackupManifest, WalArchive};
+use storageengine::encrypted_file_handler::{self, Encryption, EncryptionKey};
+use storageengine::file_handler::FileHandler;
+use storageengine::file_header::FileHeader;
+use storageengine::operations::{DbOperations, NEVER_EXPIRES, OffsetSize, RowWrite};
+use storageengine::transaction::Snapshot;
+use storageengine::wal::Wal;
+
+use crate::bplus_tree::BPlusTree;
+use crate::codec::{Codec, CodecKind};
+use crate::index::{Change, check_conflict, check_update_id, decode_unexpired, Document, expired_ids, Index, IndexError, is_empty_range, newest_versions, pin_version, read_unexpired, repoint, repoint_if_kept, resolve_writes, row_expires_at, row_version, StagedWrite, Transaction};
+
+// where the newest version of a document is, deleted documents are kept until vacuum drops them
+// because snapshots might still see them
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct Location {
+    offset_size: OffsetSize,
+    deleted: bool,
+}
 
 pub struct BTree<K, V> {
-    map: BTreeMap<K, OffsetSize>,
+    tree: BPlusTree<K, Location>,
     db_operations: Box<dyn DbOperations>,
-    transaction_id: u64,
+    codec: CodecKind,
     phatom: PhantomData<(K, V)>,
 }
 
-impl<K, V> BTree<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned {
-    pub fn new(mut db_operations: Box<dyn DbOperations>) -> Result<Self> {
-        let mut map = BTreeMap::new();
-        let mut offset = 0;
-        let rows = db_operations.read_all()?;
-        for row in rows {
-            // deleted
-            if row.header.cmax != NONE_SENTINEL {
-                offset += row.header.tuple_length;
-                continue;
+impl<K, V> BTree<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned {
+    // `file_name` is the file of the b+tree, it is only built from the rows if it is new or missed a change. it is
+    // encrypted like the data file if there is an `encryption`
+    pub fn new(db_operations: Box<dyn DbOperations>, file_name: &str, codec: CodecKind, encryption: Option<Encryption>, key: Option<&EncryptionKey>) -> Result<Self> {
+        let (file_handler, wal_file_handler) = encrypted_file_handler::open_derived_file_handlers(file_name, encryption, key)?;
+
+        let mut btree = Self {
+            tree: BPlusTree::new(file_handler, Wal::new(wal_file_handler)?)?,
+            db_operations,
+            codec,
+            phatom: PhantomData,
+        };
+        if btree.tree.is_dirty() {
+            btree.rebuild()?;
+        }
+
+        Ok(btree)
+    }
+
+    fn rebuild(&mut self) -> Result<()> {
+        let versions = newest_versions::<K, V>(self.db_operations.as_mut(), self.codec)?;
+        self.tree.rebuild(versions.into_iter().map(|(id, (offset_size, deleted))| (id, Location { offset_size, deleted })))
+    }
+
+    fn live(&mut self, id: &K) -> Result<Option<OffsetSize>> {
+        Ok(self.tree.get(id)?
+            .filter(|location| !location.deleted)
+            .map(|location| location.offset_size))
+    }
+
+    fn deleted(&mut self, id: &K) -> Result<Option<OffsetSize>> {
+        Ok(self.tree.get(id)?
+            .filter(|location| location.deleted)
+            .map(|location| location.offset_size))
+    }
+
+    fn apply_writes(&mut self, writes: Vec<StagedWrite<K, V>>, snapshot: &Snapshot, expires_at: u64) -> Result<()> {
+        for write in &writes {
+            if let Some(location) = self.tree.get(write.id())? {
+                check_conflict(self.db_operations.as_mut(), &location.offset_size, snapshot)?;
             }
+        }
 
-            let doc: Document<K, V> = bincode::deserialize(&row.data)?;
+        let changes = resolve_writes(writes, |id| Ok(self.live(id)?.is_some()))?;
+        if changes.is_empty() {
+            return Ok(());
+        }
 
-            map.insert(doc.id, OffsetSize {
-                offset,
-                size: row.header.tuple_length,
+        let mut row_writes = Vec::with_capacity(changes.len());
+        for change in &changes {
+            row_writes.push(match change {
+                Change::Insert(document) => match self.deleted(&document.id)? {
+                    Some(deleted_offset_size) => RowWrite::Reinsert { deleted_offset_size, data: self.codec.encode(document)? },
+                    None => RowWrite::Insert { data: self.codec.encode(document)? },
+                },
+                Change::Update(document) => RowWrite::Update { old_offset_size: self.live(&document.id)?.ok_or(IndexError::NotFound)?, data: self.codec.encode(document)? },
+                Change::Delete(id) => RowWrite::Delete { offset_size: self.live(id)?.ok_or(IndexError::NotFound)? },
             });
-
-            offset += row.header.tuple_length;
         }
+        let transaction_id = self.db_operations.begin()?;
+        self.tree.mark_dirty()?;
+        let offset_sizes = self.db_operations.write_batch(row_writes, transaction_id, expires_at)?;
+
+        let tree_changes = changes.into_iter().zip(offset_sizes)
+            .map(|(change, offset_size)| match change {
+                Change::Insert(document) | Change::Update(document) => (document.id, Some(Location { offset_size, deleted: false })),
+                Change::Delete(id) => (id, Some(Location { offset_size, deleted: true })),
+            })
+            .collect();
+
+        self.tree.apply(tree_changes)
+    }
 
-        let map_len = map.len();
+    // a single write that expires, the snapshot is taken right before it so it never conflicts
+    fn write_expiring(&mut self, write: StagedWrite<K, V>, expires_at: u64) -> Result<()> {
+        let snapshot = self.db_operations.snapshot();
+        let result = self.apply_writes(vec![write], &snapshot, expires_at);
+        self.db_operations.release_snapshot(&snapshot);
 
-        Ok(Self {
-            map,
-            db_operations,
-            transaction_id: map_len as u64,
-            phatom: PhantomData,
-        })
+        result
     }
 }
 
-impl<K, V> Index<K, V> for BTree<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
+impl<K, V> Index<K, V> for BTree<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
     fn insert(&mut self, document: Document<K, V>) -> Result<()> {
-        if self.search(&document.id).is_ok() {
+        // an expired document is hidden from `search`, but it is still there
+        if self.live(&document.id)?.is_some() {
             return Err(IndexError::AlreadyExists.into());
         }
 
-        let data = bincode::serialize(&document)?;
-        let offset_size = self.db_operations.insert(data, self.transaction_id)?;
-        self.map.insert(document.id, offset_size);
-        self.transaction_id += 1;
+        let data = self.codec.encode(&document)?;
+        let transaction_id = self.db_operations.begin()?;
+        let deleted_offset_size = self.deleted(&document.id)?;
+        self.tree.mark_dirty()?;
+        let offset_size = match deleted_offset_size {
+            Some(deleted_offset_size) => self.db_operations.reinsert_with_offset(&deleted_offset_size, data, transaction_id)?,
+            None => self.db_operations.insert(data, transaction_id)?,
+        };
+        self.tree.insert(document.id, Location { offset_size, deleted: false })?;
         Ok(())
     }
 
     fn search(&mut self, id: &K) -> Result<Document<K, V>> {
-        match self.map.get(id) {
-            Some(offset_size) => {
-                let row = self.db_operations.read_with_offset(offset_size)?;
-                let doc: Document<K, V> = bincode::deserialize(&row.data)?;
-                Ok(doc)
-            }
-            None => Err(IndexError::NotFound.into()),
-        }
+        let offset_size = self.live(id)?.ok_or(IndexError::NotFound)?;
+        let row = self.db_operations.read_with_offset(&offset_size)?;
+        decode_unexpired(self.codec, row)?.ok_or_else(|| IndexError::NotFound.into())
     }
 
     fn delete(&mut self, id: &K) -> Result<()> {
-        match self.map.get(id) {
+        match self.live(id)? {
             Some(offset_size) => {
-                self.db_operations.delete_with_offset(offset_size, self.transaction_id)?;
-                self.map.remove(id);
-                self.transaction_id += 1;
+                let transaction_id = self.db_operations.begin()?;
+                self.tree.mark_dirty()?;
+                self.db_operations.delete_with_offset(&offset_size, transaction_id)?;
+                self.tree.insert(id.clone(), Location { offset_size, deleted: true })?;
                 Ok(())
             }
             None => Err(IndexError::NotFound.into()),
@@ -87,27 +155,143 @@ impl<K, V> Index<K, V> for BTree<K, V> where K: Serialize + DeserializeOwned + H
     }
 
     fn update(&mut self, id: &K, document: Document<K, V>) -> Result<()> {
-        match self.map.get(id) {
+        match self.live(id)? {
             Some(offset_size) => {
-                let data = bincode::serialize(&document)?;
-                let new_offset_size = self.db_operations.update_with_offset(offset_size, data, self.transaction_id)?;
-                self.map.insert(document.id, new_offset_size);
-                self.transaction_id += 1;
+                let data = self.codec.encode(&document)?;
+                let transaction_id = self.db_operations.begin()?;
+                self.tree.mark_dirty()?;
+                let new_offset_size = self.db_operations.update_with_offset(&offset_size, data, transaction_id)?;
+                self.tree.insert(document.id, Location { offset_size: new_offset_size, deleted: false })?;
                 Ok(())
             }
             None => Err(IndexError::NotFound.into()),
         }
     }
+
+    fn scan(&mut self, range: (Bound<K>, Bound<K>), limit: Option<usize>) -> Result<Vec<Document<K, V>>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+
+        let offset_sizes = self.tree.range(range)?
+            .filter(|entry| !matches!(entry, Ok((_, location)) if location.deleted))
+            .map(|entry| entry.map(|(_, location)| location.offset_size));
+
+        read_unexpired(self.db_operations.as_mut(), self.codec, offset_sizes, limit)
+    }
+
+    fn search_at(&mut self, id: &K, snapshot: &Snapshot) -> Result<Document<K, V>> {
+        let offset_size = match self.tree.get(id)? {
+            Some(location) => location.offset_size,
+            None => return Err(IndexError::NotFound.into()),
+        };
+
+        match self.db_operations.read_visible(&offset_size, snapshot)? {
+            Some(row) => decode_unexpired(self.codec, row)?.ok_or_else(|| IndexError::NotFound.into()),
+            None => Err(IndexError::NotFound.into()),
+        }
+    }
+
+    fn snapshot(&mut self) -> Snapshot {
+        self.db_operations.snapshot()
+    }
+
+    fn release_snapshot(&mut self, snapshot: Snapshot) {
+        self.db_operations.release_snapshot(&snapshot)
+    }
+
+    fn commit(&mut self, transaction: Transaction<K, V>) -> Result<()> {
+        let (snapshot, writes) = transaction.into_parts();
+        let result = self.apply_writes(writes, &snapshot, NEVER_EXPIRES);
+        self.db_operations.release_snapshot(&snapshot);
+
+        result
+    }
+
+    // the tree stays dirty if vacuum fails halfway, so it is built from the rows the next time
+    fn vacuum(&mut self) -> Result<()> {
+        self.tree.mark_dirty()?;
+        let mut remapping = self.db_operations.vacuum()?;
+
+        let mut locations = Vec::with_capacity(self.tree.len() as usize);
+        for entry in self.tree.range((Bound::Unbounded, Bound::Unbounded))? {
+            let (id, mut location) = entry?;
+            if !location.deleted {
+                repoint(&mut location.offset_size, &mut remapping)?;
+            } else if !repoint_if_kept(&mut location.offset_size, &mut remapping) {
+                continue;
+            }
+            locations.push((id, location));
+        }
+
+        self.tree.rebuild(locations)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.db_operations.sync()
+    }
+
+    fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest> {
+        self.db_operations.backup(target, archive)
+    }
+
+    fn header(&self) -> FileHeader {
+        self.db_operations.header()
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.db_operations.set_header(header)
+    }
+
+    fn insert_expiring(&mut self, document: Document<K, V>, expires_at: u64) -> Result<()> {
+        self.write_expiring(StagedWrite::Insert(document), expires_at)
+    }
+
+    fn update_expiring(&mut self, id: &K, document: Document<K, V>, expires_at: u64) -> Result<()> {
+        check_update_id(id, &document)?;
+        self.write_expiring(StagedWrite::Update(document), expires_at)
+    }
+
+    fn can_expire(&self) -> bool {
+        true
+    }
+
+    fn expired(&mut self, now: u64, limit: usize) -> Result<Vec<K>> {
+        expired_ids::<K, V>(self.db_operations.as_mut(), self.codec, now, limit)
+    }
+
+    fn search_including_expired(&mut self, id: &K) -> Result<Document<K, V>> {
+        let offset_size = self.live(id)?.ok_or(IndexError::NotFound)?;
+        let row = self.db_operations.read_with_offset(&offset_size)?;
+        self.codec.decode(&row.data)
+    }
+
+    fn version(&mut self, id: &K) -> Result<u64> {
+        let offset_size = self.live(id)?.ok_or(IndexError::NotFound)?;
+        row_version(self.db_operations.as_mut(), &offset_size)
+    }
+
+    fn pin_version(&mut self, version: Option<u64>) -> Result<()> {
+        pin_version(self.db_operations.as_mut(), version)
+    }
+
+    fn expires_at(&mut self, id: &K) -> Result<u64> {
+        let offset_size = self.live(id)?.ok_or(IndexError::NotFound)?;
+        row_expires_at(self.db_operations.as_mut(), &offset_size)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use anyhow::Result;
 
     #[cfg(test)]
     use mockall::mock;
     use mockall::predicate;
-    use storageengine::operations::{Header, Row};
+    use storageengine::operations::{Header, NONE_SENTINEL, Row, RowBatch};
+    use tempfile::TempDir;
 
     use super::*;
 
@@ -116,9 +300,21 @@ mod tests {
         impl DbOperations for DbOperationsImpl {
             fn insert(&mut self, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
             fn read_with_offset(&mut self, offset_size: &OffsetSize) -> Result<Row>;
-            fn read_all(&mut self) -> Result<Vec<Row>>;
+            fn read_page(&mut self, page_id: u64) -> Result<RowBatch>;
             fn update_with_offset(&mut self, old_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
             fn delete_with_offset(&mut self, offset_size: &OffsetSize, transaction_id: u64) -> Result<()>;
+            fn reinsert_with_offset(&mut self, deleted_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
+            fn read_visible(&mut self, offset_size: &OffsetSize, snapshot: &Snapshot) -> Result<Option<Row>>;
+            fn write_batch(&mut self, writes: Vec<RowWrite>, transaction_id: u64, expires_at: u64) -> Result<Vec<OffsetSize>>;
+            fn begin(&mut self) -> Result<u64>;
+            fn pin_transaction_id(&mut self, transaction_id: Option<u64>) -> Result<()>;
+            fn snapshot(&mut self) -> Snapshot;
+            fn release_snapshot(&mut self, snapshot: &Snapshot);
+            fn vacuum(&mut self) -> Result<HashMap<u64, OffsetSize>>;
+            fn sync(&mut self) -> Result<()>;
+            fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest>;
+            fn header(&self) -> FileHeader;
+            fn set_header(&mut self, header: FileHeader) -> Result<()>;
         }
     }
 
@@ -128,20 +324,21 @@ mod tests {
         let data = bincode::serialize(&document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
             .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
 
-        let mut btree = setup_btree(mock)?;
+        let (mut btree, _dir) = setup_btree(mock)?;
 
         let res = btree.insert(document.clone());
 
         assert!(res.is_ok());
-        assert!(btree.map.get(&document.id).is_some());
+        assert!(btree.live(&document.id)?.is_some());
 
-        let offset_size = btree.map.get(&document.id).unwrap();
+        let offset_size = btree.live(&document.id)?.unwrap();
         assert_eq!(offset_size.offset, 0);
         assert_eq!(offset_size.size, 3);
 
@@ -154,7 +351,8 @@ mod tests {
         let data = bincode::serialize(&document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -168,14 +366,14 @@ mod tests {
                     cmax: NONE_SENTINEL,
                     xmax: NONE_SENTINEL,
                     tuple_length: 3,
-                    table_oid: 0,
+                    expires_at: 0,
                     ctid: 0,
                     cmin: 0,
                 },
                 data: data.clone(),
             }));
 
-        let mut btree = setup_btree(mock)?;
+        let (mut btree, _dir) = setup_btree(mock)?;
         btree.insert(document.clone())?;
 
         let doc = btree.search(&document.id)?;
@@ -188,9 +386,10 @@ mod tests {
     #[test]
     fn search_returns_error_if_not_found() -> Result<()> {
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
 
-        let mut btree: BTree<String, i32> = setup_btree(mock)?;
+        let (mut btree, _dir): (BTree<String, i32>, _) = setup_btree(mock)?;
 
         let result = btree.search(&"1".to_string());
 
@@ -205,7 +404,8 @@ mod tests {
         let data = bincode::serialize(&document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -215,11 +415,11 @@ mod tests {
             .times(1)
             .returning(move |_, _| Ok(()));
 
-        let mut btree = setup_btree(mock)?;
+        let (mut btree, _dir) = setup_btree(mock)?;
         btree.insert(document.clone())?;
         btree.delete(&document.id)?;
 
-        assert!(btree.map.get(&document.id).is_none());
+        assert!(btree.live(&document.id)?.is_none());
 
         Ok(())
     }
@@ -227,9 +427,10 @@ mod tests {
     #[test]
     fn delete_returns_error_if_not_found() -> Result<()> {
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
 
-        let mut btree: BTree<String, i32> = setup_btree(mock)?;
+        let (mut btree, _dir): (BTree<String, i32>, _) = setup_btree(mock)?;
 
         let result = btree.delete(&"1".to_string());
 
@@ -247,7 +448,8 @@ mod tests {
         let updated_data = bincode::serialize(&updated_document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -257,14 +459,14 @@ mod tests {
             .times(1)
             .returning(move |_, _, _| Ok(OffsetSize { offset: 0, size: 3 }));
 
-        let mut btree = setup_btree(mock)?;
+        let (mut btree, _dir) = setup_btree(mock)?;
         btree.insert(document.clone())?;
 
         btree.update(&updated_document.id, updated_document.clone())?;
 
-        assert!(btree.map.get(&updated_document.id).is_some());
+        assert!(btree.live(&updated_document.id)?.is_some());
 
-        let offset_size = btree.map.get(&updated_document.id).unwrap();
+        let offset_size = btree.live(&updated_document.id)?.unwrap();
         assert_eq!(offset_size.offset, 0);
         assert_eq!(offset_size.size, 3);
 
@@ -276,9 +478,10 @@ mod tests {
         let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
 
-        let mut btree = setup_btree(mock)?;
+        let (mut btree, _dir) = setup_btree(mock)?;
 
         let res = btree.update(&document.id, document.clone());
         assert!(res.is_err());
@@ -286,10 +489,377 @@ mod tests {
         Ok(())
     }
 
-    fn setup_btree<K, V>(mock_db_operations_impl: MockDbOperationsImpl) -> Result<BTree<K, V>>
-        where K: Serialize + DeserializeOwned + Hash + Eq + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync
+    #[test]
+    fn vacuum_repoints_documents() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 4096, size: 3 }));
+        mock.expect_vacuum()
+            .times(1)
+            .returning(move || Ok(HashMap::from([(4096, OffsetSize { offset: 0, size: 3 })])));
+
+        let (mut btree, _dir) = setup_btree(mock)?;
+        btree.insert(document.clone())?;
+        btree.vacuum()?;
+
+        assert_eq!(btree.live(&document.id)?, Some(OffsetSize { offset: 0, size: 3 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_fails_if_row_was_lost() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 4096, size: 3 }));
+        mock.expect_vacuum()
+            .times(1)
+            .returning(move || Ok(HashMap::new()));
+
+        let (mut btree, _dir) = setup_btree(mock)?;
+        btree.insert(document)?;
+
+        assert!(btree.vacuum().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn search_at_finds_deleted_document_in_older_snapshot() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+        let data = bincode::serialize(&document)?;
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_snapshot()
+            .times(1)
+            .returning(|| Snapshot { xmax: 1 });
+        mock.expect_delete_with_offset()
+            .with(predicate::eq(&OffsetSize { offset: 0, size: 3 }), predicate::eq(1_u64))
+            .times(1)
+            .returning(move |_, _| Ok(()));
+        mock.expect_read_visible()
+            .with(predicate::eq(&OffsetSize { offset: 0, size: 3 }), predicate::eq(&Snapshot { xmax: 1 }))
+            .times(1)
+            .returning(move |_, _| Ok(Some(Row {
+                header: Header {
+                    xmin: 0,
+                    xmax: 1,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                    cmax: 1,
+                },
+                data: data.clone(),
+            })));
+
+        let (mut btree, _dir) = setup_btree(mock)?;
+        btree.insert(document.clone())?;
+        let snapshot = btree.snapshot();
+        btree.delete(&document.id)?;
+
+        assert!(btree.search(&document.id).is_err());
+        assert_eq!(btree.search_at(&document.id, &snapshot)?, document);
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_after_delete_continues_version_chain() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_delete_with_offset()
+            .times(1)
+            .returning(move |_, _| Ok(()));
+        mock.expect_reinsert_with_offset()
+            .with(predicate::eq(&OffsetSize { offset: 0, size: 3 }), predicate::always(), predicate::eq(2_u64))
+            .times(1)
+            .returning(move |_, _, _| Ok(OffsetSize { offset: 1, size: 3 }));
+
+        let (mut btree, _dir) = setup_btree(mock)?;
+        btree.insert(document.clone())?;
+        btree.delete(&document.id)?;
+        btree.insert(document.clone())?;
+
+        assert_eq!(btree.live(&document.id)?, Some(OffsetSize { offset: 1, size: 3 }));
+        assert!(btree.deleted(&document.id)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_forgets_deleted_documents_it_dropped() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_delete_with_offset()
+            .times(1)
+            .returning(move |_, _| Ok(()));
+        mock.expect_vacuum()
+            .times(1)
+            .returning(move || Ok(HashMap::new()));
+
+        let (mut btree, _dir) = setup_btree(mock)?;
+        btree.insert(document.clone())?;
+        btree.delete(&document.id)?;
+        btree.vacuum()?;
+
+        assert!(btree.deleted(&document.id)?.is_none());
+
+        Ok(())
+    }
+
+    fn setup_btree<K, V>(mock_db_operations_impl: MockDbOperationsImpl) -> Result<(BTree<K, V>, TempDir)>
+        where K: Serialize + DeserializeOwned + Hash + Eq + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync
     {
+        let dir = tempfile::tempdir()?;
         let db_operations = Box::new(mock_db_operations_impl);
-        BTree::new(db_operations)
+        let btree = BTree::new(db_operations, dir.path().join("btree").to_str().unwrap(), CodecKind::Bincode, None, None)?;
+        Ok((btree, dir))
+    }
+
+    #[test]
+    fn scan_returns_documents_in_range_ordered_by_id() -> Result<()> {
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        let mut offset = 0;
+        mock.expect_insert()
+            .times(5)
+            .returning(move |_, _| {
+                offset += 1;
+                Ok(OffsetSize { offset: offset - 1, size: 3 })
+            });
+        // documents are inserted in reverse order
+        mock.expect_read_with_offset()
+            .returning(move |offset_size| Ok(Row {
+                header: Header {
+                    xmin: 0,
+                    cmax: NONE_SENTINEL,
+                    xmax: NONE_SENTINEL,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                },
+                data: bincode::serialize(&Document { id: format!("key{}", 4 - offset_size.offset), value: 0 }).unwrap(),
+            }));
+
+        let (mut btree, _dir): (BTree<String, i32>, _) = setup_btree(mock)?;
+        for i in (0..5).rev() {
+            btree.insert(Document { id: format!("key{}", i), value: 0 })?;
+        }
+
+        let ids = |documents: Vec<Document<String, i32>>| documents.into_iter().map(|document| document.id).collect::<Vec<_>>();
+        let documents = btree.scan((Bound::Included("key1".to_string()), Bound::Excluded("key4".to_string())), Some(2))?;
+        assert_eq!(ids(documents), vec!["key1", "key2"]);
+        let documents = btree.scan((Bound::Excluded("key2".to_string()), Bound::Unbounded), None)?;
+        assert_eq!(ids(documents), vec!["key3", "key4"]);
+        let documents = btree.scan_prefix(&"key".to_string(), Some(4))?;
+        assert_eq!(ids(documents), vec!["key0", "key1", "key2", "key3"]);
+        assert!(btree.scan((Bound::Included("key3".to_string()), Bound::Excluded("key1".to_string())), None)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn commit_writes_all_changes_in_one_batch() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+        let updated_document = Document { id: "1".to_string(), value: vec![4, 5, 6] };
+        let new_document = Document { id: "2".to_string(), value: vec![7, 8, 9] };
+        let data = bincode::serialize(&document)?;
+        let expected_writes = vec![
+            RowWrite::Update { old_offset_size: OffsetSize { offset: 0, size: 3 }, data: bincode::serialize(&updated_document)? },
+            RowWrite::Insert { data: bincode::serialize(&new_document)? },
+        ];
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_snapshot()
+            .times(1)
+            .returning(|| Snapshot { xmax: 1 });
+        mock.expect_read_with_offset()
+            .with(predicate::eq(&OffsetSize { offset: 0, size: 3 }))
+            .times(1)
+            .returning(move |_| Ok(Row {
+                header: Header {
+                    xmin: 0,
+                    cmax: NONE_SENTINEL,
+                    xmax: NONE_SENTINEL,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                },
+                data: data.clone(),
+            }));
+        mock.expect_write_batch()
+            .with(predicate::eq(expected_writes), predicate::eq(1_u64), predicate::eq(NEVER_EXPIRES))
+            .times(1)
+            .returning(|_, _, _| Ok(vec![OffsetSize { offset: 3, size: 3 }, OffsetSize { offset: 6, size: 3 }]));
+        mock.expect_release_snapshot()
+            .with(predicate::eq(&Snapshot { xmax: 1 }))
+            .times(1)
+            .returning(|_| ());
+
+        let (mut btree, _dir) = setup_btree(mock)?;
+        btree.insert(document)?;
+        let mut transaction = btree.begin();
+        transaction.update(updated_document);
+        transaction.insert(new_document);
+        btree.commit(transaction)?;
+
+        assert_eq!(btree.live(&"1".to_string())?, Some(OffsetSize { offset: 3, size: 3 }));
+        assert_eq!(btree.live(&"2".to_string())?, Some(OffsetSize { offset: 6, size: 3 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn commit_fails_if_key_was_written_after_begin() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+        let data = bincode::serialize(&document)?;
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_snapshot()
+            .times(1)
+            .returning(|| Snapshot { xmax: 0 });
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_read_with_offset()
+            .times(1)
+            .returning(move |_| Ok(Row {
+                header: Header {
+                    xmin: 0,
+                    cmax: NONE_SENTINEL,
+                    xmax: NONE_SENTINEL,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                },
+                data: data.clone(),
+            }));
+        mock.expect_write_batch().times(0);
+        mock.expect_release_snapshot().times(1).returning(|_| ());
+
+        let (mut btree, _dir) = setup_btree(mock)?;
+        let mut transaction = btree.begin();
+        btree.insert(document)?;
+        transaction.delete("1".to_string());
+        let result = btree.commit(transaction);
+
+        assert!(matches!(result.unwrap_err().downcast::<IndexError>(), Ok(IndexError::Conflict)));
+        assert!(btree.live(&"1".to_string())?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_reopens_tree_without_reading_rows() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+
+        let (mut btree, dir) = setup_btree(mock)?;
+        btree.insert(document.clone())?;
+        drop(btree);
+
+        let mut mock = MockDbOperationsImpl::new();
+        mock.expect_read_page().times(0);
+        let mut btree: BTree<String, Vec<i32>> = BTree::new(Box::new(mock), dir.path().join("btree").to_str().unwrap(), CodecKind::Bincode, None, None)?;
+
+        assert_eq!(btree.live(&document.id)?, Some(OffsetSize { offset: 0, size: 3 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_rebuilds_tree_that_missed_a_write() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+        let data = bincode::serialize(&document)?;
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        // the write fails after the tree was marked dirty, as if the process crashed in between
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Err(anyhow::anyhow!("crash")));
+
+        let (mut btree, dir) = setup_btree::<String, Vec<i32>>(mock)?;
+        assert!(btree.insert(document.clone()).is_err());
+        drop(btree);
+
+        let mut mock = MockDbOperationsImpl::new();
+        mock.expect_read_page()
+            .with(predicate::eq(0))
+            .times(1)
+            .returning(move |_| Ok(RowBatch {
+                rows: vec![(OffsetSize { offset: 0, size: 3 }, Row {
+                    header: Header {
+                        xmin: 0,
+                        cmax: NONE_SENTINEL,
+                        xmax: NONE_SENTINEL,
+                        tuple_length: 3,
+                        expires_at: 0,
+                        ctid: NONE_SENTINEL,
+                        cmin: 0,
+                    },
+                    data: data.clone(),
+                })],
+                next_page_id: None,
+            }));
+        let mut btree: BTree<String, Vec<i32>> = BTree::new(Box::new(mock), dir.path().join("btree").to_str().unwrap(), CodecKind::Bincode, None, None)?;
+
+        assert_eq!(btree.live(&document.id)?, Some(OffsetSize { offset: 0, size: 3 }));
+
+        Ok(())
+    }
+
+    fn expect_begin(mock: &mut MockDbOperationsImpl) {
+        let mut transaction_id = 0;
+        mock.expect_begin().returning(move || {
+            transaction_id += 1;
+            Ok(transaction_id - 1)
+        });
     }
 }
@@ -0,0 +1,218 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// the first bytes of a codec file, the last one is the version of the format
+const MAGIC: &[u8; 4] = b"PCD\x01";
+
+pub fn codec_file_name(storage_file_name: &str) -> String {
+    format!("{}.codec", storage_file_name)
+}
+
+// turns documents into the bytes that are stored and back
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+}
+
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    // structs are written as maps, so other tools see the field names
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+}
+
+// the codec of a database, the indexes keep it and pass every document through it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodecKind {
+    // databases written before the codec could be chosen use bincode
+    #[default]
+    Bincode,
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl CodecKind {
+    pub fn id(self) -> u8 {
+        match self {
+            CodecKind::Bincode => 0,
+            CodecKind::Json => 1,
+            CodecKind::MessagePack => 2,
+            CodecKind::Cbor => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CodecKind::Bincode),
+            1 => Some(CodecKind::Json),
+            2 => Some(CodecKind::MessagePack),
+            3 => Some(CodecKind::Cbor),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(CodecKind::Bincode),
+            "json" => Ok(CodecKind::Json),
+            "msgpack" => Ok(CodecKind::MessagePack),
+            "cbor" => Ok(CodecKind::Cbor),
+            _ => Err("no match"),
+        }
+    }
+}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            CodecKind::Bincode => Bincode.encode(value),
+            CodecKind::Json => Json.encode(value),
+            CodecKind::MessagePack => MessagePack.encode(value),
+            CodecKind::Cbor => Cbor.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            CodecKind::Bincode => Bincode.decode(bytes),
+            CodecKind::Json => Json.decode(bytes),
+            CodecKind::MessagePack => MessagePack.decode(bytes),
+            CodecKind::Cbor => Cbor.decode(bytes),
+        }
+    }
+}
+
+// the codec recorded in `file_name`, databases kept it there before the data file had a header
+pub fn read_codec_file(file_name: &str) -> Result<Option<CodecKind>> {
+    match fs::read(file_name) {
+        Ok(bytes) => match bytes.as_slice() {
+            [magic @ .., id] if magic == MAGIC => match CodecKind::from_id(*id) {
+                Some(codec) => Ok(Some(codec)),
+                None => bail!("{} names an unknown codec {}", file_name, id),
+            },
+            _ => bail!("{} is not a codec file", file_name),
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// the codec a database uses, `recorded` wins and has to match `requested`. `is_new` tells whether the database
+// has no documents, the documents of an older database that recorded no codec are bincode
+pub fn choose_codec(recorded: Option<CodecKind>, requested: Option<CodecKind>, is_new: bool) -> Result<CodecKind> {
+    match (recorded, requested) {
+        (Some(recorded), Some(requested)) if recorded != requested => bail!("database uses the {:?} codec, not {:?}", recorded, requested),
+        (Some(recorded), _) => Ok(recorded),
+        (None, Some(requested)) if !is_new && requested != CodecKind::Bincode => bail!("database already has bincode documents, it can't use the {:?} codec", requested),
+        (None, requested) => Ok(requested.unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::index::Document;
+
+    use super::*;
+
+    #[test]
+    fn every_codec_round_trips_documents() -> Result<()> {
+        let document = Document { id: "key".to_string(), value: vec![1_u8, 2, 3] };
+
+        for codec in [CodecKind::Bincode, CodecKind::Json, CodecKind::MessagePack, CodecKind::Cbor] {
+            let decoded: Document<String, Vec<u8>> = codec.decode(&codec.encode(&document)?)?;
+            assert_eq!(decoded.id, document.id);
+            assert_eq!(decoded.value, document.value);
+        }
+
+        assert_eq!(Json.encode(&document)?, br#"{"id":"key","value":[1,2,3]}"#);
+
+        Ok(())
+    }
+
+    #[test]
+    fn choose_codec_keeps_recorded_codec() -> Result<()> {
+        assert_eq!(choose_codec(None, Some(CodecKind::Json), true)?, CodecKind::Json);
+        assert_eq!(choose_codec(Some(CodecKind::Json), None, false)?, CodecKind::Json);
+        assert!(choose_codec(Some(CodecKind::Json), Some(CodecKind::Cbor), false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn choose_codec_keeps_bincode_for_older_databases() -> Result<()> {
+        assert!(choose_codec(None, Some(CodecKind::Json), false).is_err());
+        assert_eq!(choose_codec(None, None, false)?, CodecKind::Bincode);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_codec_file_reads_codec_of_older_databases() -> Result<()> {
+        let dir = tempdir()?;
+        let file_name = dir.path().join("patrick.db.codec").to_str().unwrap().to_string();
+
+        assert_eq!(read_codec_file(&file_name)?, None);
+        fs::write(&file_name, b"PCD\x01\x02")?;
+        assert_eq!(read_codec_file(&file_name)?, Some(CodecKind::MessagePack));
+        fs::write(&file_name, b"PCD\x01\x09")?;
+        assert!(read_codec_file(&file_name).is_err());
+
+        Ok(())
+    }
+}
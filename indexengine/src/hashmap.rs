@@ -1,85 +1,149 @@
 use std::collections::HashMap;
 use std::hash::Hash;
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 
 use anyhow::Result;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use storageengine::operations::{DbOperations, NONE_SENTINEL, OffsetSize};
+use storageengine::backup::{BackupManifest, WalArchive};
+use storageengine::file_handler::FileHandler;
+use storageengine::file_header::FileHeader;
+use storageengine::operations::{DbOperations, NEVER_EXPIRES, NONE_SENTINEL, OffsetSize, RowIterator, RowWrite};
+use storageengine::transaction::Snapshot;
 
-use crate::index::{Document, Index, IndexError};
+use crate::codec::{Codec, CodecKind};
+use crate::index::{Change, check_conflict, check_update_id, decode_unexpired, Document, expired_ids, Index, IndexError, pin_version, read_unexpired, repoint, repoint_if_kept, resolve_writes, row_expires_at, row_version, StagedWrite, Transaction};
 
 pub struct HashMapIndex<K, V> {
     map: HashMap<K, OffsetSize>,
+    // newest version of deleted documents, snapshots might still see them until vacuum drops them
+    deleted: HashMap<K, OffsetSize>,
     db_operations: Box<dyn DbOperations>,
-    transaction_id: u64,
+    codec: CodecKind,
     phatom: PhantomData<(K, V)>,
 }
 
 impl<K, V> HashMapIndex<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
-    pub fn new(mut db_operations: Box<dyn DbOperations>) -> Result<Self> {
+    pub fn new(mut db_operations: Box<dyn DbOperations>, codec: CodecKind) -> Result<Self> {
         let mut map = HashMap::new();
-        let mut offset = 0;
-        let rows = db_operations.read_all()?;
-        for row in rows {
-            // deleted
-            if row.header.cmax != NONE_SENTINEL {
-                offset += row.header.tuple_length;
+        let mut deleted: HashMap<K, (u64, OffsetSize)> = HashMap::new();
+        for row in RowIterator::new(db_operations.as_mut()) {
+            let (offset_size, row) = row?;
+            let doc: Document<K, V> = codec.decode(&row.data)?;
+
+            if row.header.xmax == NONE_SENTINEL {
+                map.insert(doc.id, offset_size);
                 continue;
             }
 
-            let doc: Document<K, V> = bincode::deserialize(&row.data)?;
-
-            map.insert(doc.id, OffsetSize {
-                offset,
-                size: row.header.tuple_length,
-            });
-
-            offset += row.header.tuple_length;
+            // deleted or replaced by a newer version, the newest of them is where the chain of a deleted document starts
+            match deleted.get(&doc.id) {
+                Some((xmin, _)) if *xmin > row.header.xmin => {}
+                _ => {
+                    deleted.insert(doc.id, (row.header.xmin, offset_size));
+                }
+            }
         }
 
-        let map_len = map.len();
+        let deleted = deleted.into_iter()
+            .filter(|(id, _)| !map.contains_key(id))
+            .map(|(id, (_, offset_size))| (id, offset_size))
+            .collect();
 
         Ok(Self {
             map,
+            deleted,
             db_operations,
-            transaction_id: map_len as u64,
+            codec,
             phatom: PhantomData,
         })
     }
+
+    fn apply_writes(&mut self, writes: Vec<StagedWrite<K, V>>, snapshot: &Snapshot, expires_at: u64) -> Result<()> {
+        for write in &writes {
+            if let Some(offset_size) = self.map.get(write.id()).or_else(|| self.deleted.get(write.id())) {
+                check_conflict(self.db_operations.as_mut(), offset_size, snapshot)?;
+            }
+        }
+
+        let changes = resolve_writes(writes, |id| Ok(self.map.contains_key(id)))?;
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let row_writes = changes.iter()
+            .map(|change| Ok(match change {
+                Change::Insert(document) => match self.deleted.get(&document.id) {
+                    Some(deleted_offset_size) => RowWrite::Reinsert { deleted_offset_size: deleted_offset_size.clone(), data: self.codec.encode(document)? },
+                    None => RowWrite::Insert { data: self.codec.encode(document)? },
+                },
+                Change::Update(document) => RowWrite::Update { old_offset_size: self.map[&document.id].clone(), data: self.codec.encode(document)? },
+                Change::Delete(id) => RowWrite::Delete { offset_size: self.map[id].clone() },
+            }))
+            .collect::<Result<Vec<_>>>()?;
+        let transaction_id = self.db_operations.begin()?;
+        let offset_sizes = self.db_operations.write_batch(row_writes, transaction_id, expires_at)?;
+
+        for (change, offset_size) in changes.into_iter().zip(offset_sizes) {
+            match change {
+                Change::Insert(document) | Change::Update(document) => {
+                    self.deleted.remove(&document.id);
+                    self.map.insert(document.id, offset_size);
+                }
+                Change::Delete(id) => {
+                    self.map.remove(&id);
+                    self.deleted.insert(id, offset_size);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // a single write that expires, the snapshot is taken right before it so it never conflicts
+    fn write_expiring(&mut self, write: StagedWrite<K, V>, expires_at: u64) -> Result<()> {
+        let snapshot = self.db_operations.snapshot();
+        let result = self.apply_writes(vec![write], &snapshot, expires_at);
+        self.db_operations.release_snapshot(&snapshot);
+
+        result
+    }
 }
 
-impl<K, V> Index<K, V> for HashMapIndex<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
+impl<K, V> Index<K, V> for HashMapIndex<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
     fn insert(&mut self, document: Document<K, V>) -> Result<()> {
-        if self.search(&document.id).is_ok() {
+        // an expired document is hidden from `search`, but it is still there
+        if self.map.contains_key(&document.id) {
             return Err(IndexError::AlreadyExists.into());
         }
 
-        let data = bincode::serialize(&document)?;
-        let offset_size = self.db_operations.insert(data, self.transaction_id)?;
+        let data = self.codec.encode(&document)?;
+        let transaction_id = self.db_operations.begin()?;
+        let offset_size = match self.deleted.get(&document.id) {
+            Some(deleted_offset_size) => self.db_operations.reinsert_with_offset(deleted_offset_size, data, transaction_id)?,
+            None => self.db_operations.insert(data, transaction_id)?,
+        };
+        self.deleted.remove(&document.id);
         self.map.insert(document.id, offset_size);
-        self.transaction_id += 1;
         Ok(())
     }
 
     fn search(&mut self, id: &K) -> Result<Document<K, V>> {
-        match self.map.get(id) {
-            Some(offset_size) => {
-                let row = self.db_operations.read_with_offset(offset_size)?;
-                let doc: Document<K, V> = bincode::deserialize(&row.data)?;
-                Ok(doc)
-            }
-            None => Err(IndexError::NotFound.into()),
-        }
+        let offset_size = self.map.get(id).ok_or(IndexError::NotFound)?;
+        let row = self.db_operations.read_with_offset(offset_size)?;
+        decode_unexpired(self.codec, row)?.ok_or_else(|| IndexError::NotFound.into())
     }
 
     fn delete(&mut self, id: &K) -> Result<()> {
         match self.map.get(id) {
             Some(offset_size) => {
-                self.db_operations.delete_with_offset(offset_size, self.transaction_id)?;
-                self.map.remove(id);
-                self.transaction_id += 1;
+                let transaction_id = self.db_operations.begin()?;
+                self.db_operations.delete_with_offset(offset_size, transaction_id)?;
+                if let Some((id, offset_size)) = self.map.remove_entry(id) {
+                    self.deleted.insert(id, offset_size);
+                }
                 Ok(())
             }
             None => Err(IndexError::NotFound.into()),
@@ -89,15 +153,117 @@ impl<K, V> Index<K, V> for HashMapIndex<K, V> where K: Serialize + DeserializeOw
     fn update(&mut self, id: &K, document: Document<K, V>) -> Result<()> {
         match self.map.get(id) {
             Some(offset_size) => {
-                let data = bincode::serialize(&document)?;
-                let new_offset_size = self.db_operations.update_with_offset(offset_size, data, self.transaction_id)?;
+                let data = self.codec.encode(&document)?;
+                let transaction_id = self.db_operations.begin()?;
+                let new_offset_size = self.db_operations.update_with_offset(offset_size, data, transaction_id)?;
                 self.map.insert(document.id, new_offset_size);
-                self.transaction_id += 1;
                 Ok(())
             }
             None => Err(IndexError::NotFound.into()),
         }
     }
+
+    // the map has no order, so every id is checked and the matches are sorted
+    fn scan(&mut self, range: (Bound<K>, Bound<K>), limit: Option<usize>) -> Result<Vec<Document<K, V>>> {
+        let mut offset_sizes: Vec<(&K, &OffsetSize)> = self.map.iter()
+            .filter(|(id, _)| range.contains(*id))
+            .collect();
+        offset_sizes.sort_by_key(|(id, _)| *id);
+
+        let offset_sizes = offset_sizes.into_iter().map(|(_, offset_size)| Ok(offset_size.clone()));
+        read_unexpired(self.db_operations.as_mut(), self.codec, offset_sizes, limit)
+    }
+
+    fn search_at(&mut self, id: &K, snapshot: &Snapshot) -> Result<Document<K, V>> {
+        let offset_size = match self.map.get(id).or_else(|| self.deleted.get(id)) {
+            Some(offset_size) => offset_size,
+            None => return Err(IndexError::NotFound.into()),
+        };
+
+        match self.db_operations.read_visible(offset_size, snapshot)? {
+            Some(row) => decode_unexpired(self.codec, row)?.ok_or_else(|| IndexError::NotFound.into()),
+            None => Err(IndexError::NotFound.into()),
+        }
+    }
+
+    fn snapshot(&mut self) -> Snapshot {
+        self.db_operations.snapshot()
+    }
+
+    fn release_snapshot(&mut self, snapshot: Snapshot) {
+        self.db_operations.release_snapshot(&snapshot)
+    }
+
+    fn commit(&mut self, transaction: Transaction<K, V>) -> Result<()> {
+        let (snapshot, writes) = transaction.into_parts();
+        let result = self.apply_writes(writes, &snapshot, NEVER_EXPIRES);
+        self.db_operations.release_snapshot(&snapshot);
+
+        result
+    }
+
+    fn vacuum(&mut self) -> Result<()> {
+        let mut remapping = self.db_operations.vacuum()?;
+        for offset_size in self.map.values_mut() {
+            repoint(offset_size, &mut remapping)?;
+        }
+        self.deleted.retain(|_, offset_size| repoint_if_kept(offset_size, &mut remapping));
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.db_operations.sync()
+    }
+
+    fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest> {
+        self.db_operations.backup(target, archive)
+    }
+
+    fn header(&self) -> FileHeader {
+        self.db_operations.header()
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.db_operations.set_header(header)
+    }
+
+    fn insert_expiring(&mut self, document: Document<K, V>, expires_at: u64) -> Result<()> {
+        self.write_expiring(StagedWrite::Insert(document), expires_at)
+    }
+
+    fn update_expiring(&mut self, id: &K, document: Document<K, V>, expires_at: u64) -> Result<()> {
+        check_update_id(id, &document)?;
+        self.write_expiring(StagedWrite::Update(document), expires_at)
+    }
+
+    fn can_expire(&self) -> bool {
+        true
+    }
+
+    fn expired(&mut self, now: u64, limit: usize) -> Result<Vec<K>> {
+        expired_ids::<K, V>(self.db_operations.as_mut(), self.codec, now, limit)
+    }
+
+    fn search_including_expired(&mut self, id: &K) -> Result<Document<K, V>> {
+        let offset_size = self.map.get(id).ok_or(IndexError::NotFound)?;
+        let row = self.db_operations.read_with_offset(offset_size)?;
+        self.codec.decode(&row.data)
+    }
+
+    fn version(&mut self, id: &K) -> Result<u64> {
+        let offset_size = self.map.get(id).ok_or(IndexError::NotFound)?;
+        row_version(self.db_operations.as_mut(), offset_size)
+    }
+
+    fn pin_version(&mut self, version: Option<u64>) -> Result<()> {
+        pin_version(self.db_operations.as_mut(), version)
+    }
+
+    fn expires_at(&mut self, id: &K) -> Result<u64> {
+        let offset_size = self.map.get(id).ok_or(IndexError::NotFound)?;
+        row_expires_at(self.db_operations.as_mut(), offset_size)
+    }
 }
 
 #[cfg(test)]
@@ -107,7 +273,7 @@ mod tests {
     #[cfg(test)]
     use mockall::mock;
     use mockall::predicate;
-    use storageengine::operations::{Header, Row};
+    use storageengine::operations::{Header, Row, RowBatch};
 
     use super::*;
 
@@ -116,9 +282,21 @@ mod tests {
         impl DbOperations for DbOperationsImpl {
             fn insert(&mut self, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
             fn read_with_offset(&mut self, offset_size: &OffsetSize) -> Result<Row>;
-            fn read_all(&mut self) -> Result<Vec<Row>>;
+            fn read_page(&mut self, page_id: u64) -> Result<RowBatch>;
             fn update_with_offset(&mut self, old_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
             fn delete_with_offset(&mut self, offset_size: &OffsetSize, transaction_id: u64) -> Result<()>;
+            fn reinsert_with_offset(&mut self, deleted_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
+            fn read_visible(&mut self, offset_size: &OffsetSize, snapshot: &Snapshot) -> Result<Option<Row>>;
+            fn write_batch(&mut self, writes: Vec<RowWrite>, transaction_id: u64, expires_at: u64) -> Result<Vec<OffsetSize>>;
+            fn begin(&mut self) -> Result<u64>;
+            fn pin_transaction_id(&mut self, transaction_id: Option<u64>) -> Result<()>;
+            fn snapshot(&mut self) -> Snapshot;
+            fn release_snapshot(&mut self, snapshot: &Snapshot);
+            fn vacuum(&mut self) -> Result<HashMap<u64, OffsetSize>>;
+            fn sync(&mut self) -> Result<()>;
+            fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest>;
+            fn header(&self) -> FileHeader;
+            fn set_header(&mut self, header: FileHeader) -> Result<()>;
         }
     }
 
@@ -128,7 +306,8 @@ mod tests {
         let data = bincode::serialize(&document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -139,7 +318,7 @@ mod tests {
         let res = hashmap.insert(document.clone());
 
         assert!(res.is_ok());
-        assert!(hashmap.map.get(&document.id).is_some());
+        assert!(hashmap.map.contains_key(&document.id));
 
         let offset_size = hashmap.map.get(&document.id).unwrap();
         assert_eq!(offset_size.offset, 0);
@@ -154,7 +333,8 @@ mod tests {
         let data = bincode::serialize(&document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -168,7 +348,7 @@ mod tests {
                     cmax: NONE_SENTINEL,
                     xmax: NONE_SENTINEL,
                     tuple_length: 3,
-                    table_oid: 0,
+                    expires_at: 0,
                     ctid: 0,
                     cmin: 0,
                 },
@@ -188,7 +368,8 @@ mod tests {
     #[test]
     fn search_returns_error_if_not_found() -> Result<()> {
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
 
         let mut hashmap: HashMapIndex<String, i32> = setup_hashmap(mock)?;
 
@@ -205,7 +386,8 @@ mod tests {
         let data = bincode::serialize(&document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -219,7 +401,7 @@ mod tests {
         hashmap.insert(document.clone())?;
         hashmap.delete(&document.id)?;
 
-        assert!(hashmap.map.get(&document.id).is_none());
+        assert!(!hashmap.map.contains_key(&document.id));
 
         Ok(())
     }
@@ -227,7 +409,8 @@ mod tests {
     #[test]
     fn delete_returns_error_if_not_found() -> Result<()> {
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
 
         let mut hashmap: HashMapIndex<String, i32> = setup_hashmap(mock)?;
 
@@ -247,7 +430,8 @@ mod tests {
         let updated_data = bincode::serialize(&updated_document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -262,7 +446,7 @@ mod tests {
 
         hashmap.update(&updated_document.id, updated_document.clone())?;
 
-        assert!(hashmap.map.get(&updated_document.id).is_some());
+        assert!(hashmap.map.contains_key(&updated_document.id));
 
         let offset_size = hashmap.map.get(&updated_document.id).unwrap();
         assert_eq!(offset_size.offset, 0);
@@ -276,7 +460,8 @@ mod tests {
         let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
 
         let mut hashmap = setup_hashmap(mock)?;
 
@@ -286,9 +471,306 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn vacuum_repoints_documents() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 4096, size: 3 }));
+        mock.expect_vacuum()
+            .times(1)
+            .returning(move || Ok(HashMap::from([(4096, OffsetSize { offset: 0, size: 3 })])));
+
+        let mut hashmap = setup_hashmap(mock)?;
+        hashmap.insert(document.clone())?;
+        hashmap.vacuum()?;
+
+        assert_eq!(hashmap.map.get(&document.id), Some(&OffsetSize { offset: 0, size: 3 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_fails_if_row_was_lost() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 4096, size: 3 }));
+        mock.expect_vacuum()
+            .times(1)
+            .returning(move || Ok(HashMap::new()));
+
+        let mut hashmap = setup_hashmap(mock)?;
+        hashmap.insert(document)?;
+
+        assert!(hashmap.vacuum().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn search_at_finds_deleted_document_in_older_snapshot() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+        let data = bincode::serialize(&document)?;
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_snapshot()
+            .times(1)
+            .returning(|| Snapshot { xmax: 1 });
+        mock.expect_delete_with_offset()
+            .with(predicate::eq(&OffsetSize { offset: 0, size: 3 }), predicate::eq(1_u64))
+            .times(1)
+            .returning(move |_, _| Ok(()));
+        mock.expect_read_visible()
+            .with(predicate::eq(&OffsetSize { offset: 0, size: 3 }), predicate::eq(&Snapshot { xmax: 1 }))
+            .times(1)
+            .returning(move |_, _| Ok(Some(Row {
+                header: Header {
+                    xmin: 0,
+                    xmax: 1,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                    cmax: 1,
+                },
+                data: data.clone(),
+            })));
+
+        let mut hashmap = setup_hashmap(mock)?;
+        hashmap.insert(document.clone())?;
+        let snapshot = hashmap.snapshot();
+        hashmap.delete(&document.id)?;
+
+        assert!(hashmap.search(&document.id).is_err());
+        assert_eq!(hashmap.search_at(&document.id, &snapshot)?, document);
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_after_delete_continues_version_chain() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_delete_with_offset()
+            .times(1)
+            .returning(move |_, _| Ok(()));
+        mock.expect_reinsert_with_offset()
+            .with(predicate::eq(&OffsetSize { offset: 0, size: 3 }), predicate::always(), predicate::eq(2_u64))
+            .times(1)
+            .returning(move |_, _, _| Ok(OffsetSize { offset: 1, size: 3 }));
+
+        let mut hashmap = setup_hashmap(mock)?;
+        hashmap.insert(document.clone())?;
+        hashmap.delete(&document.id)?;
+        hashmap.insert(document.clone())?;
+
+        assert_eq!(hashmap.map.get(&document.id), Some(&OffsetSize { offset: 1, size: 3 }));
+        assert!(hashmap.deleted.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_forgets_deleted_documents_it_dropped() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_delete_with_offset()
+            .times(1)
+            .returning(move |_, _| Ok(()));
+        mock.expect_vacuum()
+            .times(1)
+            .returning(move || Ok(HashMap::new()));
+
+        let mut hashmap = setup_hashmap(mock)?;
+        hashmap.insert(document.clone())?;
+        hashmap.delete(&document.id)?;
+        hashmap.vacuum()?;
+
+        assert!(hashmap.deleted.is_empty());
+
+        Ok(())
+    }
+
     fn setup_hashmap<K, V>(mock_db_operations_impl: MockDbOperationsImpl) -> Result<HashMapIndex<K, V>> where K: Serialize + DeserializeOwned + Hash + Eq + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
         let db_operations = Box::new(mock_db_operations_impl);
-        HashMapIndex::new(db_operations)
+        HashMapIndex::new(db_operations, CodecKind::Bincode)
     }
-}
 
+    #[test]
+    fn scan_returns_documents_in_range_ordered_by_id() -> Result<()> {
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        let mut offset = 0;
+        mock.expect_insert()
+            .times(5)
+            .returning(move |_, _| {
+                offset += 1;
+                Ok(OffsetSize { offset: offset - 1, size: 3 })
+            });
+        // documents are inserted in reverse order
+        mock.expect_read_with_offset()
+            .returning(move |offset_size| Ok(Row {
+                header: Header {
+                    xmin: 0,
+                    cmax: NONE_SENTINEL,
+                    xmax: NONE_SENTINEL,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                },
+                data: bincode::serialize(&Document { id: format!("key{}", 4 - offset_size.offset), value: 0 }).unwrap(),
+            }));
+
+        let mut hashmap: HashMapIndex<String, i32> = setup_hashmap(mock)?;
+        for i in (0..5).rev() {
+            hashmap.insert(Document { id: format!("key{}", i), value: 0 })?;
+        }
+
+        let ids = |documents: Vec<Document<String, i32>>| documents.into_iter().map(|document| document.id).collect::<Vec<_>>();
+        let documents = hashmap.scan((Bound::Included("key1".to_string()), Bound::Excluded("key4".to_string())), Some(2))?;
+        assert_eq!(ids(documents), vec!["key1", "key2"]);
+        let documents = hashmap.scan((Bound::Excluded("key2".to_string()), Bound::Unbounded), None)?;
+        assert_eq!(ids(documents), vec!["key3", "key4"]);
+        let documents = hashmap.scan_prefix(&"key".to_string(), Some(4))?;
+        assert_eq!(ids(documents), vec!["key0", "key1", "key2", "key3"]);
+        assert!(hashmap.scan((Bound::Included("key3".to_string()), Bound::Excluded("key1".to_string())), None)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn commit_writes_all_changes_in_one_batch() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+        let updated_document = Document { id: "1".to_string(), value: vec![4, 5, 6] };
+        let new_document = Document { id: "2".to_string(), value: vec![7, 8, 9] };
+        let data = bincode::serialize(&document)?;
+        let expected_writes = vec![
+            RowWrite::Update { old_offset_size: OffsetSize { offset: 0, size: 3 }, data: bincode::serialize(&updated_document)? },
+            RowWrite::Insert { data: bincode::serialize(&new_document)? },
+        ];
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_snapshot()
+            .times(1)
+            .returning(|| Snapshot { xmax: 1 });
+        mock.expect_read_with_offset()
+            .with(predicate::eq(&OffsetSize { offset: 0, size: 3 }))
+            .times(1)
+            .returning(move |_| Ok(Row {
+                header: Header {
+                    xmin: 0,
+                    cmax: NONE_SENTINEL,
+                    xmax: NONE_SENTINEL,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                },
+                data: data.clone(),
+            }));
+        mock.expect_write_batch()
+            .with(predicate::eq(expected_writes), predicate::eq(1_u64), predicate::eq(NEVER_EXPIRES))
+            .times(1)
+            .returning(|_, _, _| Ok(vec![OffsetSize { offset: 3, size: 3 }, OffsetSize { offset: 6, size: 3 }]));
+        mock.expect_release_snapshot()
+            .with(predicate::eq(&Snapshot { xmax: 1 }))
+            .times(1)
+            .returning(|_| ());
+
+        let mut hashmap = setup_hashmap(mock)?;
+        hashmap.insert(document)?;
+        let mut transaction = hashmap.begin();
+        transaction.update(updated_document);
+        transaction.insert(new_document);
+        hashmap.commit(transaction)?;
+
+        assert_eq!(hashmap.map.get("1"), Some(&OffsetSize { offset: 3, size: 3 }));
+        assert_eq!(hashmap.map.get("2"), Some(&OffsetSize { offset: 6, size: 3 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn commit_fails_if_key_was_written_after_begin() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+        let data = bincode::serialize(&document)?;
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_snapshot()
+            .times(1)
+            .returning(|| Snapshot { xmax: 0 });
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_read_with_offset()
+            .times(1)
+            .returning(move |_| Ok(Row {
+                header: Header {
+                    xmin: 0,
+                    cmax: NONE_SENTINEL,
+                    xmax: NONE_SENTINEL,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                },
+                data: data.clone(),
+            }));
+        mock.expect_write_batch().times(0);
+        mock.expect_release_snapshot().times(1).returning(|_| ());
+
+        let mut hashmap = setup_hashmap(mock)?;
+        let mut transaction = hashmap.begin();
+        hashmap.insert(document)?;
+        transaction.delete("1".to_string());
+        let result = hashmap.commit(transaction);
+
+        assert!(matches!(result.unwrap_err().downcast::<IndexError>(), Ok(IndexError::Conflict)));
+        assert!(hashmap.map.contains_key("1"));
+
+        Ok(())
+    }
+
+    fn expect_begin(mock: &mut MockDbOperationsImpl) {
+        let mut transaction_id = 0;
+        mock.expect_begin().returning(move || {
+            transaction_id += 1;
+            Ok(transaction_id - 1)
+        });
+    }
+}
@@ -1,5 +1,16 @@
-use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+use anyhow::{anyhow, bail, ensure, Result};
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use storageengine::backup::{BackupManifest, WalArchive};
+use storageengine::file_handler::FileHandler;
+use storageengine::file_header::FileHeader;
+use storageengine::operations::{DbOperations, NEVER_EXPIRES, NONE_SENTINEL, OffsetSize, Row, RowIterator, unix_millis};
+use storageengine::transaction::Snapshot;
+
+use crate::codec::{Codec, CodecKind};
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Document<K, V> {
@@ -13,11 +24,379 @@ pub enum IndexError {
     NotFound,
     #[error("AlreadyExists")]
     AlreadyExists,
+    #[error("Conflict")]
+    Conflict,
+    #[error("VersionMismatch")]
+    VersionMismatch,
+}
+
+// a write staged by a transaction, nothing is written before the transaction is committed
+#[derive(PartialEq, Debug, Clone)]
+pub enum StagedWrite<K, V> {
+    Insert(Document<K, V>),
+    Update(Document<K, V>),
+    Delete(K),
+}
+
+impl<K, V> StagedWrite<K, V> {
+    pub fn id(&self) -> &K {
+        match self {
+            StagedWrite::Insert(document) | StagedWrite::Update(document) => &document.id,
+            StagedWrite::Delete(id) => id,
+        }
+    }
+}
+
+// what a committed transaction does to one key compared to the state before it
+#[derive(PartialEq, Debug)]
+pub enum Change<K, V> {
+    Insert(Document<K, V>),
+    Update(Document<K, V>),
+    Delete(K),
+}
+
+pub struct Transaction<K, V> {
+    snapshot: Snapshot,
+    writes: Vec<StagedWrite<K, V>>,
+}
+
+impl<K, V> Transaction<K, V> {
+    pub fn new(snapshot: Snapshot) -> Self {
+        Self {
+            snapshot,
+            writes: Vec::new(),
+        }
+    }
+
+    // reads of the transaction should use this snapshot, see `Index::search_at`
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    pub fn insert(&mut self, document: Document<K, V>) {
+        self.writes.push(StagedWrite::Insert(document));
+    }
+
+    pub fn update(&mut self, document: Document<K, V>) {
+        self.writes.push(StagedWrite::Update(document));
+    }
+
+    pub fn delete(&mut self, id: K) {
+        self.writes.push(StagedWrite::Delete(id));
+    }
+
+    pub fn into_parts(self) -> (Snapshot, Vec<StagedWrite<K, V>>) {
+        (self.snapshot, self.writes)
+    }
 }
 
+// documents `Index::scan_prefix` asks for at once
+const SCAN_PREFIX_BATCH_SIZE: usize = 100;
+
 pub trait Index<K, V>: Send + Sync {
     fn insert(&mut self, document: Document<K, V>) -> Result<()>;
     fn search(&mut self, id: &K) -> Result<Document<K, V>>;
     fn delete(&mut self, id: &K) -> Result<()>;
     fn update(&mut self, id: &K, document: Document<K, V>) -> Result<()>;
+    // returns up to `limit` documents with an id in `range`, ordered by id
+    fn scan(&mut self, range: (Bound<K>, Bound<K>), limit: Option<usize>) -> Result<Vec<Document<K, V>>>;
+    // returns up to `limit` documents with an id starting with `prefix`, ordered by id
+    fn scan_prefix(&mut self, prefix: &K, limit: Option<usize>) -> Result<Vec<Document<K, V>>> where K: AsRef<[u8]> + Clone {
+        let mut documents = Vec::new();
+        let mut start = Bound::Included(prefix.clone());
+        loop {
+            let batch = self.scan((std::mem::replace(&mut start, Bound::Unbounded), Bound::Unbounded), Some(SCAN_PREFIX_BATCH_SIZE))?;
+            let batch_len = batch.len();
+            for document in batch {
+                if limit == Some(documents.len()) || !document.id.as_ref().starts_with(prefix.as_ref()) {
+                    return Ok(documents);
+                }
+                start = Bound::Excluded(document.id.clone());
+                documents.push(document);
+            }
+            if batch_len < SCAN_PREFIX_BATCH_SIZE {
+                return Ok(documents);
+            }
+        }
+    }
+    // returns the version of the document that is visible in the snapshot
+    fn search_at(&mut self, id: &K, snapshot: &Snapshot) -> Result<Document<K, V>>;
+    // a snapshot keeps the versions it can see from being vacuumed until it is released
+    fn snapshot(&mut self) -> Snapshot;
+    fn release_snapshot(&mut self, snapshot: Snapshot);
+    // starts a transaction that stages its writes until it is committed or rolled back
+    fn begin(&mut self) -> Transaction<K, V> {
+        Transaction::new(self.snapshot())
+    }
+    // applies all staged writes atomically, fails with `IndexError::Conflict` if one of the keys
+    // was written by someone else after the transaction started
+    fn commit(&mut self, transaction: Transaction<K, V>) -> Result<()>;
+    fn rollback(&mut self, transaction: Transaction<K, V>) {
+        self.release_snapshot(transaction.snapshot)
+    }
+    // compacts the data file and repoints the index to the moved rows
+    fn vacuum(&mut self) -> Result<()>;
+    // makes every write done so far durable, see `DbOperations::sync`
+    fn sync(&mut self) -> Result<()>;
+    // copies the data file into the empty `target` while the index engine is open, see `DbOperations::backup`.
+    // the index is not in the backup, it is rebuilt from the rows once the backup is restored
+    fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest>;
+    // the header of the data file, see `DbOperations::header`
+    fn header(&self) -> FileHeader;
+    fn set_header(&mut self, header: FileHeader) -> Result<()>;
+    // like `insert` and `update`, but reads don't see the document once `expires_at` (milliseconds since the unix
+    // epoch) has passed. writes still see it until it is deleted, see `expired`. a plain `update` keeps it for good
+    fn insert_expiring(&mut self, _document: Document<K, V>, _expires_at: u64) -> Result<()> {
+        bail!("the index engine can't expire documents")
+    }
+    fn update_expiring(&mut self, _id: &K, _document: Document<K, V>, _expires_at: u64) -> Result<()> {
+        bail!("the index engine can't expire documents")
+    }
+    // false if `insert_expiring` and `update_expiring` always fail
+    fn can_expire(&self) -> bool {
+        false
+    }
+    // returns the ids of up to `limit` documents that expired at `now` and still have to be deleted. reads every row,
+    // the server only calls it to build its expiry index once
+    fn expired(&mut self, _now: u64, _limit: usize) -> Result<Vec<K>> {
+        Ok(Vec::new())
+    }
+    // like `search`, but also finds a document that expired and wasn't deleted yet
+    fn search_including_expired(&mut self, id: &K) -> Result<Document<K, V>> {
+        self.search(id)
+    }
+    // when the document expires, `NEVER_EXPIRES` if it doesn't. finds it like `search_including_expired`
+    fn expires_at(&mut self, id: &K) -> Result<u64> {
+        self.search_including_expired(id).map(|_| NEVER_EXPIRES)
+    }
+    // changes with every write of the document and is never 0, fails with `IndexError::NotFound` like `search`
+    fn version(&mut self, id: &K) -> Result<u64>;
+    // the next writes get `version` until it is unpinned with None, so a follower stores a replicated write with the
+    // version the leader wrote it with
+    fn pin_version(&mut self, version: Option<u64>) -> Result<()>;
+    // updates the document, or deletes it if `document` is None, if its version is still `expected_version`.
+    // fails with `IndexError::VersionMismatch` otherwise. with `expires_at` like `update_expiring`
+    fn compare_and_swap(&mut self, id: &K, expected_version: u64, document: Option<Document<K, V>>, expires_at: u64) -> Result<()> {
+        if self.version(id)? != expected_version {
+            return Err(IndexError::VersionMismatch.into());
+        }
+
+        match document {
+            None => self.delete(id),
+            Some(document) if expires_at == NEVER_EXPIRES => self.update(id, document),
+            Some(document) => self.update_expiring(id, document, expires_at),
+        }
+    }
+}
+
+// points `offset_size` to where vacuum moved its row, for rows that have to survive vacuum
+pub fn repoint(offset_size: &mut OffsetSize, remapping: &mut HashMap<u64, OffsetSize>) -> Result<()> {
+    *offset_size = remapping.remove(&offset_size.offset)
+        .ok_or_else(|| anyhow!("vacuum lost the row at offset {}", offset_size.offset))?;
+
+    Ok(())
+}
+
+// like `repoint`, but returns false if vacuum dropped the row
+pub fn repoint_if_kept(offset_size: &mut OffsetSize, remapping: &mut HashMap<u64, OffsetSize>) -> bool {
+    match remapping.remove(&offset_size.offset) {
+        Some(new_offset_size) => {
+            *offset_size = new_offset_size;
+            true
+        }
+        None => false,
+    }
+}
+
+// reads all rows and returns where the newest version of every document is and whether it is deleted.
+// deleted documents are kept because snapshots might still see them, their chain starts at the newest version
+pub fn newest_versions<K, V>(db_operations: &mut dyn DbOperations, codec: CodecKind) -> Result<BTreeMap<K, (OffsetSize, bool)>> where K: DeserializeOwned + Ord, V: DeserializeOwned {
+    let mut versions: BTreeMap<K, (u64, OffsetSize, bool)> = BTreeMap::new();
+    for row in RowIterator::new(db_operations) {
+        let (offset_size, row) = row?;
+        let doc: Document<K, V> = codec.decode(&row.data)?;
+
+        if row.header.xmax == NONE_SENTINEL {
+            versions.insert(doc.id, (NONE_SENTINEL, offset_size, false));
+            continue;
+        }
+
+        // deleted or replaced by a newer version
+        match versions.get(&doc.id) {
+            Some((xmin, _, _)) if *xmin > row.header.xmin => {}
+            _ => {
+                versions.insert(doc.id, (row.header.xmin, offset_size, true));
+            }
+        }
+    }
+
+    Ok(versions.into_iter().map(|(id, (_, offset_size, deleted))| (id, (offset_size, deleted))).collect())
+}
+
+// the document of a row, None if the row expired and reads must not see it
+pub fn decode_unexpired<K, V>(codec: CodecKind, row: Row) -> Result<Option<Document<K, V>>> where K: DeserializeOwned, V: DeserializeOwned {
+    if row.header.is_expired(unix_millis()) {
+        return Ok(None);
+    }
+    Ok(Some(codec.decode(&row.data)?))
+}
+
+// the documents at `offset_sizes` in order, up to `limit` of them that didn't expire
+pub fn read_unexpired<K, V>(db_operations: &mut dyn DbOperations, codec: CodecKind, offset_sizes: impl IntoIterator<Item=Result<OffsetSize>>, limit: Option<usize>) -> Result<Vec<Document<K, V>>> where K: DeserializeOwned, V: DeserializeOwned {
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut documents = Vec::new();
+    for offset_size in offset_sizes {
+        if documents.len() >= limit {
+            break;
+        }
+        let row = db_operations.read_with_offset(&offset_size?)?;
+        documents.extend(decode_unexpired(codec, row)?);
+    }
+    Ok(documents)
+}
+
+// the version of the document in the row at `offset_size`, one more than the transaction that wrote it
+pub fn row_version(db_operations: &mut dyn DbOperations, offset_size: &OffsetSize) -> Result<u64> {
+    let header = db_operations.read_with_offset(offset_size)?.header;
+    if header.is_expired(unix_millis()) {
+        return Err(IndexError::NotFound.into());
+    }
+    Ok(header.xmin + 1)
+}
+
+// writes get `version` through the transaction id their rows are created with, see `row_version`
+pub fn pin_version(db_operations: &mut dyn DbOperations, version: Option<u64>) -> Result<()> {
+    ensure!(version != Some(0), "version 0 is never written");
+    db_operations.pin_transaction_id(version.map(|version| version - 1))
+}
+
+// an update writes the document under the id it looks up, so both have to be the same
+pub fn check_update_id<K: PartialEq, V>(id: &K, document: &Document<K, V>) -> Result<()> {
+    ensure!(*id == document.id, "a document can't be updated under another id");
+    Ok(())
+}
+
+// when the document in the row at `offset_size` expires
+pub fn row_expires_at(db_operations: &mut dyn DbOperations, offset_size: &OffsetSize) -> Result<u64> {
+    Ok(db_operations.read_with_offset(offset_size)?.header.expires_at)
+}
+
+// reads all rows for the ids of up to `limit` live documents that expired at `now`
+pub fn expired_ids<K, V>(db_operations: &mut dyn DbOperations, codec: CodecKind, now: u64, limit: usize) -> Result<Vec<K>> where K: DeserializeOwned, V: DeserializeOwned {
+    let mut ids = Vec::new();
+    for row in RowIterator::new(db_operations) {
+        let (_, row) = row?;
+        if ids.len() >= limit {
+            break;
+        }
+        if row.header.xmax == NONE_SENTINEL && row.header.is_expired(now) {
+            let doc: Document<K, V> = codec.decode(&row.data)?;
+            ids.push(doc.id);
+        }
+    }
+    Ok(ids)
+}
+
+// `BTreeMap::range` panics on ranges that end before they start
+pub fn is_empty_range<K>(range: &(Bound<K>, Bound<K>)) -> bool where K: Ord {
+    match range {
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) if start > end => true,
+        (Bound::Excluded(start), Bound::Excluded(end)) => start == end,
+        _ => false,
+    }
+}
+
+// whether `id` is not past `end`, the end of a range
+pub fn is_before_end<K>(id: &K, end: &Bound<K>) -> bool where K: Ord {
+    match end {
+        Bound::Included(end) => id <= end,
+        Bound::Excluded(end) => id < end,
+        Bound::Unbounded => true,
+    }
+}
+
+// checks the newest version of a key written by the transaction, every version committed after
+// the snapshot was taken has an id of at least `snapshot.xmax`
+pub fn check_conflict(db_operations: &mut dyn DbOperations, offset_size: &OffsetSize, snapshot: &Snapshot) -> Result<()> {
+    let header = db_operations.read_with_offset(offset_size)?.header;
+    if header.xmin >= snapshot.xmax || (header.xmax != NONE_SENTINEL && header.xmax >= snapshot.xmax) {
+        return Err(IndexError::Conflict.into());
+    }
+
+    Ok(())
+}
+
+// folds the writes of a transaction into one change per key, `exists` tells whether a key exists
+// before the transaction; fails like the single writes would if a write doesn't fit the state before it
+pub fn resolve_writes<K, V>(writes: Vec<StagedWrite<K, V>>, mut exists: impl FnMut(&K) -> Result<bool>) -> Result<Vec<Change<K, V>>> where K: Eq {
+    // whether the key existed before and the write that decides its state now
+    let mut keys: Vec<(bool, StagedWrite<K, V>)> = Vec::new();
+    for write in writes {
+        let position = keys.iter().position(|(_, current)| current.id() == write.id());
+        let is_present = match position {
+            Some(position) => !matches!(keys[position].1, StagedWrite::Delete(_)),
+            None => exists(write.id())?,
+        };
+
+        match (&write, is_present) {
+            (StagedWrite::Insert(_), true) => return Err(IndexError::AlreadyExists.into()),
+            (StagedWrite::Update(_), false) | (StagedWrite::Delete(_), false) => return Err(IndexError::NotFound.into()),
+            _ => {}
+        }
+
+        match position {
+            Some(position) => keys[position].1 = write,
+            None => keys.push((is_present, write)),
+        }
+    }
+
+    Ok(keys.into_iter()
+        .filter_map(|(existed, write)| match (existed, write) {
+            (false, StagedWrite::Delete(_)) => None,
+            (false, StagedWrite::Insert(document) | StagedWrite::Update(document)) => Some(Change::Insert(document)),
+            (true, StagedWrite::Insert(document) | StagedWrite::Update(document)) => Some(Change::Update(document)),
+            (true, StagedWrite::Delete(id)) => Some(Change::Delete(id)),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_writes_folds_writes_per_key() -> Result<()> {
+        let document = |id: &str, value: i32| Document { id: id.to_string(), value };
+        let writes = vec![
+            StagedWrite::Update(document("a", 1)),
+            StagedWrite::Insert(document("b", 2)),
+            StagedWrite::Delete("a".to_string()),
+            StagedWrite::Update(document("b", 3)),
+            StagedWrite::Delete("c".to_string()),
+            StagedWrite::Insert(document("c", 4)),
+            StagedWrite::Insert(document("d", 5)),
+            StagedWrite::Delete("d".to_string()),
+        ];
+
+        let changes = resolve_writes(writes, |id| Ok(id == "a" || id == "c"))?;
+
+        assert_eq!(changes, vec![
+            Change::Delete("a".to_string()),
+            Change::Insert(document("b", 3)),
+            Change::Update(document("c", 4)),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_writes_rejects_writes_that_do_not_fit() {
+        let document = Document { id: "a".to_string(), value: 1 };
+
+        let result = resolve_writes(vec![StagedWrite::Delete("a".to_string()), StagedWrite::Update(document.clone())], |_| Ok(true));
+        assert!(matches!(result.unwrap_err().downcast::<IndexError>(), Ok(IndexError::NotFound)));
+
+        let result = resolve_writes(vec![StagedWrite::Insert(document.clone()), StagedWrite::Insert(document)], |_| Ok(false));
+        assert!(matches!(result.unwrap_err().downcast::<IndexError>(), Ok(IndexError::AlreadyExists)));
+    }
 }
@@ -1,42 +1,338 @@
+use std::fs;
 use std::hash::Hash;
+use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, ensure, Result};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use storageengine::encrypted_file_handler::{Encryption, EncryptionKey};
+use storageengine::file_handler::FileHandler;
 
+pub mod codec;
 pub mod index;
 pub mod btree;
+pub mod bplus_tree;
+pub mod lsm_store;
 pub mod lsm_tree;
+pub mod lsm_value_tree;
+pub mod ss_table;
 pub mod no_index;
 pub mod hashmap;
 
 #[derive(Debug, Clone)]
 pub enum IndexEngine {
     BTree,
-    LSM,
+    LSM(LsmMode),
     NoIndex,
     HashMap,
 }
 
-pub fn new_index_engine<K, V>(index_engine: IndexEngine, db_operations: Box<dyn storageengine::operations::DbOperations>) -> Result<Box<dyn index::Index<K, V>>>
+impl IndexEngine {
+    // recorded in the header of the data file
+    pub fn id(&self) -> u8 {
+        match self {
+            IndexEngine::BTree => 0,
+            IndexEngine::LSM(LsmMode::Offsets) => 1,
+            IndexEngine::LSM(LsmMode::Values) => 2,
+            IndexEngine::NoIndex => 3,
+            IndexEngine::HashMap => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(IndexEngine::BTree),
+            1 => Some(IndexEngine::LSM(LsmMode::Offsets)),
+            2 => Some(IndexEngine::LSM(LsmMode::Values)),
+            3 => Some(IndexEngine::NoIndex),
+            4 => Some(IndexEngine::HashMap),
+            _ => None,
+        }
+    }
+}
+
+// what the tables of the lsm tree hold
+#[derive(Debug, Clone)]
+pub enum LsmMode {
+    // where the rows of the documents are in the data file
+    Offsets,
+    // the documents themselves, the data file stays empty
+    Values,
+}
+
+pub const DEFAULT_MEMTABLE_SIZE: usize = 100;
+pub const DEFAULT_BLOOM_FILTER_SIZE: usize = 10000;
+
+pub fn ss_table_dir_name(storage_file_name: &str) -> String {
+    format!("{}.sstables", storage_file_name)
+}
+
+// where the index engines keep their files and how the lsm tree is sized
+#[derive(Debug, Clone)]
+pub struct IndexEngineConfig {
+    pub storage_file_name: String,
+    pub ss_table_dir: String,
+    // entries the memtable holds before it is flushed into a table
+    pub memtable_size: usize,
+    // minimum number of bits of the bloom filter of a table
+    pub bloom_filter_size: usize,
+    // None uses the codec the database was created with, or bincode for a new one. `open_file_header` fills it in
+    pub codec: Option<codec::CodecKind>,
+    // the index files are encrypted like the data file, `open_file_header` fills in its encryption
+    pub encryption: Option<Encryption>,
+    pub key: Option<EncryptionKey>,
+}
+
+impl IndexEngineConfig {
+    pub fn new(storage_file_name: &str) -> Self {
+        Self {
+            storage_file_name: storage_file_name.to_string(),
+            ss_table_dir: ss_table_dir_name(storage_file_name),
+            memtable_size: DEFAULT_MEMTABLE_SIZE,
+            bloom_filter_size: DEFAULT_BLOOM_FILTER_SIZE,
+            codec: None,
+            encryption: None,
+            key: None,
+        }
+    }
+}
+
+// checks that the data file was written by `index_engine` and sets the codec and the encryption of `config` to the
+// ones it was written with. a new data file, or one of an older version, records the codec and the index engine in
+// its header
+pub fn open_file_header(file_handler: &mut dyn FileHandler, index_engine: &IndexEngine, config: &mut IndexEngineConfig) -> Result<()> {
+    let mut header = file_handler.header();
+    if let Some(id) = header.index_engine {
+        match IndexEngine::from_id(id) {
+            Some(recorded) if recorded.id() == index_engine.id() => {}
+            Some(recorded) => bail!("data file was written by the {:?} index engine, not {:?}", recorded, index_engine),
+            None => bail!("data file was written by an unknown index engine {}", id),
+        }
+    }
+    config.encryption = match header.encryption {
+        Some(id) => Some(Encryption::from_id(id).ok_or_else(|| anyhow!("data file names an unknown encryption {}", id))?),
+        None => None,
+    };
+    // the sstables are written without a file handler
+    if config.encryption.is_some() && matches!(index_engine, IndexEngine::LSM(_)) {
+        bail!("the tables of the {:?} index engine can't be encrypted, use another index engine for an encrypted database", index_engine);
+    }
+
+    let codec_file_name = codec::codec_file_name(&config.storage_file_name);
+    let recorded = match header.codec {
+        Some(id) => Some(codec::CodecKind::from_id(id).ok_or_else(|| anyhow!("data file names an unknown codec {}", id))?),
+        None => codec::read_codec_file(&codec_file_name)?,
+    };
+    // the value mode of the lsm tree keeps its documents in the tables instead of the data file
+    let is_new = file_handler.size()? == 0 && !Path::new(&config.ss_table_dir).exists();
+    let codec = codec::choose_codec(recorded, config.codec, is_new)?;
+
+    header.codec = Some(codec.id());
+    header.index_engine = Some(index_engine.id());
+    if header != file_handler.header() {
+        file_handler.set_header(header)?;
+    }
+    // the header replaces the codec file of older databases
+    if Path::new(&codec_file_name).exists() {
+        fs::remove_file(&codec_file_name)?;
+    }
+    config.codec = Some(codec);
+    Ok(())
+}
+
+pub fn new_index_engine<K, V>(index_engine: IndexEngine, db_operations: Box<dyn storageengine::operations::DbOperations>, config: &IndexEngineConfig) -> Result<Box<dyn index::Index<K, V>>>
     where K: Serialize + DeserializeOwned + Hash + Eq + std::convert::AsRef<[u8]> + Clone + std::cmp::Ord + 'static + Send + Sync, V: Serialize + DeserializeOwned + 'static + Send + Sync
 {
+    ensure!(config.memtable_size > 0, "memtable size has to be at least 1");
+    let codec = config.codec.unwrap_or_default();
     match index_engine {
         IndexEngine::BTree => {
-            let b_tree = btree::BTree::new(db_operations)?;
+            let b_tree = btree::BTree::new(db_operations, &bplus_tree::bplus_tree_file_name(&config.storage_file_name), codec, config.encryption, config.key.as_ref())?;
             Ok(Box::new(b_tree))
         }
-        IndexEngine::LSM => {
-            let lsm_tree = lsm_tree::LsmTree::new(db_operations, "./ss_tables".to_string(), 100, 10000)?;
+        IndexEngine::LSM(LsmMode::Offsets) => {
+            let lsm_tree = lsm_tree::LsmTree::new(db_operations, config.ss_table_dir.clone(), config.memtable_size, config.bloom_filter_size, codec)?;
             Ok(Box::new(lsm_tree))
         }
+        IndexEngine::LSM(LsmMode::Values) => {
+            let lsm_value_tree = lsm_value_tree::LsmValueTree::new(db_operations, config.ss_table_dir.clone(), config.memtable_size, config.bloom_filter_size, codec)?;
+            Ok(Box::new(lsm_value_tree))
+        }
         IndexEngine::NoIndex => {
-            let no_index = no_index::NoIndex::new(db_operations);
+            let no_index = no_index::NoIndex::new(db_operations, codec);
             Ok(Box::new(no_index))
         }
         IndexEngine::HashMap => {
-            let hashmap = hashmap::HashMapIndex::new(db_operations)?;
+            let hashmap = hashmap::HashMapIndex::new(db_operations, codec)?;
             Ok(Box::new(hashmap))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use storageengine::file_handler::FileHandlerImpl;
+    use storageengine::memory_file_handler::MemoryFileHandler;
+    use storageengine::operations::{DbOperationsImpl, NEVER_EXPIRES};
+    use storageengine::transaction::TransactionManager;
+    use storageengine::wal::Wal;
+
+    use super::*;
+
+    // `index_engine` over a data file, wal and transaction file in memory, its other files go to `dir`
+    fn memory_index_engine(index_engine: IndexEngine, dir: &Path) -> Result<Box<dyn index::Index<String, String>>> {
+        let config = IndexEngineConfig::new(dir.join("patrick.db").to_str().unwrap());
+        let wal = Wal::new(Box::new(MemoryFileHandler::new()))?;
+        let transaction_manager = TransactionManager::new(Box::new(MemoryFileHandler::new()))?;
+        let db_operations = DbOperationsImpl::new(Box::new(MemoryFileHandler::new()), wal, transaction_manager, 16)?;
+        new_index_engine(index_engine, Box::new(db_operations), &config)
+    }
+
+    #[test]
+    fn config_keeps_ss_tables_next_to_storage_file() {
+        let config = IndexEngineConfig::new("data/patrick.db");
+
+        assert_eq!(config.ss_table_dir, "data/patrick.db.sstables");
+        assert_eq!(config.memtable_size, DEFAULT_MEMTABLE_SIZE);
+        assert_eq!(config.bloom_filter_size, DEFAULT_BLOOM_FILTER_SIZE);
+        assert_ne!(IndexEngineConfig::new("other.db").ss_table_dir, config.ss_table_dir);
+    }
+
+    #[test]
+    fn open_file_header_records_index_engine_and_codec() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        let mut file_handler = FileHandlerImpl::new(&file_name)?;
+        let mut config = IndexEngineConfig::new(&file_name);
+        config.codec = Some(codec::CodecKind::Json);
+
+        open_file_header(&mut file_handler, &IndexEngine::HashMap, &mut config)?;
+        drop(file_handler);
+
+        let mut file_handler = FileHandlerImpl::new(&file_name)?;
+        let mut config = IndexEngineConfig::new(&file_name);
+        open_file_header(&mut file_handler, &IndexEngine::HashMap, &mut config)?;
+        assert_eq!(config.codec, Some(codec::CodecKind::Json));
+        assert!(open_file_header(&mut file_handler, &IndexEngine::BTree, &mut IndexEngineConfig::new(&file_name)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_file_header_moves_codec_file_into_header() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        fs::write(codec::codec_file_name(&file_name), b"PCD\x01\x03")?;
+        let mut file_handler = FileHandlerImpl::new(&file_name)?;
+        let mut config = IndexEngineConfig::new(&file_name);
+
+        open_file_header(&mut file_handler, &IndexEngine::BTree, &mut config)?;
+
+        assert_eq!(config.codec, Some(codec::CodecKind::Cbor));
+        assert_eq!(file_handler.header().codec, Some(codec::CodecKind::Cbor.id()));
+        assert!(!Path::new(&codec::codec_file_name(&file_name)).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_file_header_records_encryption_and_rejects_encrypted_lsm_tree() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        let key = EncryptionKey::new([1; storageengine::encrypted_file_handler::KEY_SIZE]);
+        let mut file_handler = storageengine::encrypted_file_handler::open_file_handler(&file_name, Some(Encryption::Aes256Gcm), Some(&key))?;
+        let mut config = IndexEngineConfig::new(&file_name);
+
+        assert!(open_file_header(file_handler.as_mut(), &IndexEngine::LSM(LsmMode::Offsets), &mut config).is_err());
+        open_file_header(file_handler.as_mut(), &IndexEngine::BTree, &mut config)?;
+        assert_eq!(config.encryption, Some(Encryption::Aes256Gcm));
+
+        Ok(())
+    }
+
+    #[test]
+    fn expired_documents_are_hidden_until_deleted() -> Result<()> {
+        for index_engine in [IndexEngine::BTree, IndexEngine::LSM(LsmMode::Offsets), IndexEngine::NoIndex, IndexEngine::HashMap] {
+            let dir = tempfile::tempdir()?;
+            let mut index = memory_index_engine(index_engine.clone(), dir.path())?;
+
+            assert!(index.can_expire(), "{:?}", index_engine);
+            index.insert_expiring(index::Document { id: "a".to_string(), value: "expired".to_string() }, 1)?;
+            index.insert(index::Document { id: "b".to_string(), value: "kept".to_string() })?;
+            index.insert_expiring(index::Document { id: "c".to_string(), value: "later".to_string() }, u64::MAX)?;
+
+            assert!(index.search(&"a".to_string()).is_err(), "{:?}", index_engine);
+            assert_eq!(index.search_including_expired(&"a".to_string())?.value, "expired", "{:?}", index_engine);
+            assert_eq!(index.scan((Bound::Unbounded, Bound::Unbounded), None)?.len(), 2, "{:?}", index_engine);
+            assert_eq!(index.expired(storageengine::operations::unix_millis(), 10)?, vec!["a".to_string()], "{:?}", index_engine);
+            assert_eq!(index.expires_at(&"a".to_string())?, 1, "{:?}", index_engine);
+            assert_eq!(index.expires_at(&"b".to_string())?, NEVER_EXPIRES, "{:?}", index_engine);
+            assert!(index.insert(index::Document { id: "a".to_string(), value: "again".to_string() }).is_err(), "{:?}", index_engine);
+            assert!(index.update_expiring(&"b".to_string(), index::Document { id: "c".to_string(), value: "moved".to_string() }, u64::MAX).is_err(), "{:?}", index_engine);
+            assert_eq!(index.search(&"c".to_string())?.value, "later", "{:?}", index_engine);
+
+            index.delete(&"a".to_string())?;
+            assert!(index.expired(storageengine::operations::unix_millis(), 10)?.is_empty(), "{:?}", index_engine);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn lsm_value_tree_rejects_expiring_documents() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut index = memory_index_engine(IndexEngine::LSM(LsmMode::Values), dir.path())?;
+
+        assert!(!index.can_expire());
+        assert!(index.insert_expiring(index::Document { id: "a".to_string(), value: "later".to_string() }, u64::MAX).is_err());
+        assert!(index.search(&"a".to_string()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_and_swap_checks_version() -> Result<()> {
+        for index_engine in [IndexEngine::BTree, IndexEngine::LSM(LsmMode::Offsets), IndexEngine::LSM(LsmMode::Values), IndexEngine::NoIndex, IndexEngine::HashMap] {
+            let dir = tempfile::tempdir()?;
+            let mut index = memory_index_engine(index_engine.clone(), dir.path())?;
+            let id = "a".to_string();
+            let document = |value: &str| index::Document { id: "a".to_string(), value: value.to_string() };
+
+            index.insert(document("first"))?;
+            let version = index.version(&id)?;
+            assert_ne!(version, 0, "{:?}", index_engine);
+            index.compare_and_swap(&id, version, Some(document("second")), NEVER_EXPIRES)?;
+            let err = index.compare_and_swap(&id, version, Some(document("third")), NEVER_EXPIRES).unwrap_err();
+            assert!(matches!(err.downcast_ref::<index::IndexError>(), Some(index::IndexError::VersionMismatch)), "{:?}", index_engine);
+            assert_eq!(index.search(&id)?.value, "second", "{:?}", index_engine);
+
+            let version = index.version(&id)?;
+            index.compare_and_swap(&id, version, None, NEVER_EXPIRES)?;
+            assert!(index.version(&id).is_err(), "{:?}", index_engine);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn pinned_version_is_written_by_every_engine() -> Result<()> {
+        for index_engine in [IndexEngine::BTree, IndexEngine::LSM(LsmMode::Offsets), IndexEngine::LSM(LsmMode::Values), IndexEngine::NoIndex, IndexEngine::HashMap] {
+            let dir = tempfile::tempdir()?;
+            let mut index = memory_index_engine(index_engine.clone(), dir.path())?;
+            let document = |id: &str| index::Document { id: id.to_string(), value: "value".to_string() };
+
+            index.pin_version(Some(42))?;
+            index.insert(document("a"))?;
+            index.pin_version(None)?;
+            index.insert(document("b"))?;
+
+            assert_eq!(index.version(&"a".to_string())?, 42, "{:?}", index_engine);
+            assert_eq!(index.version(&"b".to_string())?, 43, "{:?}", index_engine);
+            assert!(index.pin_version(Some(0)).is_err(), "{:?}", index_engine);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,582 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, mpsc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use storageengine::file_handler::FileHandlerImpl;
+use storageengine::wal::{Wal, WalWrite};
+
+use crate::ss_table::{MergeIter, ss_table_id, SsTable, SsTableWriter};
+
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const LOG_FILE_NAME: &str = "memtable.log";
+// a level is merged into one table of the next level once it has that many tables
+const COMPACTION_THRESHOLD: usize = 4;
+
+pub type Table<K, E> = Arc<SsTable<K, E>>;
+pub type EntryIter<'a, K, E> = Box<dyn Iterator<Item = Result<(K, E)>> + 'a>;
+
+// what the memtable and the tables of an lsm tree keep for every key
+pub trait LsmEntry: Serialize + DeserializeOwned + Clone + Send + Sync + 'static {
+    // written to the manifest, tables of one kind of entry are never opened as another
+    const KIND: &'static str;
+
+    // called on the newest entry of a key while tables are merged. `horizon` is the xmax of the oldest
+    // snapshot in use and `bottom` is true if no older table can have the key; None drops the key
+    fn compact(self, horizon: u64, bottom: bool) -> Option<Self>;
+}
+
+#[derive(Serialize, Deserialize)]
+enum LogRecord<K, E> {
+    // the data file is about to change, if no entries follow the memtable might have missed the change
+    Pending,
+    Entries(Vec<(K, E)>),
+}
+
+// the ids of the tables of every level, written to a temporary file and renamed so it is never torn
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    kind: String,
+    levels: Vec<Vec<u64>>,
+    next_table_id: u64,
+}
+
+// level 0 has the tables flushed from the memtable, merging a full level makes a table of the next level.
+// every table of a level is newer than the ones of the levels after it, in a level newer tables come last
+struct Levels<K, E> {
+    levels: Vec<Vec<Table<K, E>>>,
+    next_table_id: u64,
+}
+
+impl<K, E> Levels<K, E> {
+    fn newest_first(&self) -> Vec<Table<K, E>> {
+        self.levels.iter()
+            .flat_map(|level| level.iter().rev().cloned())
+            .collect()
+    }
+}
+
+// the part of the store the compaction thread works on
+struct Shared<K, E> {
+    path: PathBuf,
+    tree_size: usize,
+    bloom_filter_size: usize,
+    levels: Mutex<Levels<K, E>>,
+    // held while tables are merged, so compaction and `LsmStore::rewrite` never work on the same tables
+    compaction: Mutex<()>,
+    compaction_error: Mutex<Option<anyhow::Error>>,
+    horizon: AtomicU64,
+}
+
+impl<K, E> Shared<K, E> where K: Serialize + DeserializeOwned + Clone + Ord + AsRef<[u8]>, E: LsmEntry {
+    fn tables(&self) -> Vec<Table<K, E>> {
+        self.levels.lock().unwrap().newest_first()
+    }
+
+    fn write_table(&self, entries: impl Iterator<Item = Result<(K, E)>>, expected_len: usize) -> Result<Table<K, E>> {
+        let id = {
+            let mut levels = self.levels.lock().unwrap();
+            levels.next_table_id += 1;
+            levels.next_table_id - 1
+        };
+
+        let mut writer = SsTableWriter::new(&self.path, id, expected_len, self.bloom_filter_size)?;
+        for entry in entries {
+            let (key, entry) = entry?;
+            writer.add(key, entry)?;
+        }
+        Ok(Arc::new(writer.finish()?))
+    }
+
+    // puts `table` into `level` instead of the `replaced` tables, an empty table is dropped
+    fn install(&self, level: usize, table: Table<K, E>, replaced: &[Table<K, E>]) -> Result<()> {
+        let mut levels = self.levels.lock().unwrap();
+        let mut new_levels = levels.levels.clone();
+        for tables in &mut new_levels {
+            tables.retain(|table| !replaced.iter().any(|replaced| replaced.id() == table.id()));
+        }
+        if table.is_empty() {
+            table.mark_obsolete();
+        } else {
+            if new_levels.len() <= level {
+                new_levels.resize_with(level + 1, Vec::new);
+            }
+            new_levels[level].push(table);
+        }
+
+        let manifest = Manifest {
+            kind: E::KIND.to_string(),
+            levels: new_levels.iter().map(|tables| tables.iter().map(|table| table.id()).collect()).collect(),
+            next_table_id: levels.next_table_id,
+        };
+        write_manifest(&self.path, &manifest)?;
+        levels.levels = new_levels;
+        replaced.iter().for_each(|table| table.mark_obsolete());
+
+        Ok(())
+    }
+
+    // writes `entries` into one table that replaces the `replaced` tables, when they are all there is
+    fn replace(&self, entries: impl Iterator<Item = Result<(K, E)>>, expected_len: usize, replaced: &[Table<K, E>]) -> Result<()> {
+        let table = self.write_table(entries, expected_len)?;
+        self.install(self.level_for(table.len() as usize), table, replaced)
+    }
+
+    // the level whose tables have about `len` entries
+    fn level_for(&self, len: usize) -> usize {
+        let mut level = 0;
+        let mut level_size = self.tree_size;
+        while level_size < len {
+            level_size = level_size.saturating_mul(COMPACTION_THRESHOLD);
+            level += 1;
+        }
+        level
+    }
+
+    // merges every level that is full, until none is left
+    fn compact(&self) -> Result<()> {
+        let _compaction = self.compaction.lock().unwrap();
+        loop {
+            let (level, inputs, bottom) = {
+                let levels = self.levels.lock().unwrap();
+                match levels.levels.iter().position(|tables| tables.len() >= COMPACTION_THRESHOLD) {
+                    Some(level) => (level, levels.levels[level].clone(), levels.levels[level + 1..].iter().all(Vec::is_empty)),
+                    None => return Ok(()),
+                }
+            };
+
+            let horizon = self.horizon.load(Ordering::SeqCst);
+            let sources = inputs.iter().rev()
+                .map(|table| table.range(Bound::Unbounded))
+                .collect::<Result<Vec<_>>>()?;
+            let entries = MergeIter::new(sources)
+                .filter_map(|entry| entry.map(|(key, entry)| entry.compact(horizon, bottom).map(|entry| (key, entry))).transpose());
+            let expected_len = inputs.iter().map(|table| table.len() as usize).sum();
+            let table = self.write_table(entries, expected_len)?;
+            self.install(level + 1, table, &inputs)?;
+        }
+    }
+}
+
+// compacts in the background every time a table was flushed
+struct Compactor {
+    sender: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Compactor {
+    fn start<K, E>(shared: Arc<Shared<K, E>>) -> Self where K: Serialize + DeserializeOwned + Clone + Ord + AsRef<[u8]> + Send + Sync + 'static, E: LsmEntry {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while receiver.recv().is_ok() {
+                if let Err(e) = shared.compact() {
+                    *shared.compaction_error.lock().unwrap() = Some(e);
+                }
+            }
+        });
+
+        Self { sender, handle }
+    }
+}
+
+// the memtable, its log and the sorted tables of an lsm tree. the memtable is flushed into a table of
+// level 0 once it has `tree_size` entries and a background thread merges full levels
+pub struct LsmStore<K, E> {
+    map: BTreeMap<K, E>,
+    // the memtable survives a restart through this log, it reuses the wal framing with one `LogRecord` per record
+    log: Wal,
+    shared: Arc<Shared<K, E>>,
+    compactor: Option<Compactor>,
+}
+
+impl<K, E> LsmStore<K, E> where K: Serialize + DeserializeOwned + Clone + Ord + AsRef<[u8]> + Send + Sync + 'static, E: LsmEntry {
+    // opens the tables in `path` and fills the memtable from its log. also returns whether the store is new or
+    // missed a change of the data file, then the caller has to rebuild it with `replace_all`
+    pub fn open(path: &str, tree_size: usize, bloom_filter_size: usize) -> Result<(Self, bool)> {
+        let path = PathBuf::from(path);
+        fs::create_dir_all(&path)?;
+        let log = Wal::new(Box::new(FileHandlerImpl::new(&path.join(LOG_FILE_NAME).to_string_lossy())?))?;
+
+        let manifest = read_manifest(&path)?;
+        let is_new = manifest.is_none();
+        let manifest = manifest.unwrap_or_default();
+        if !is_new && manifest.kind != E::KIND {
+            bail!("ss tables in {} hold {} and not {}", path.display(), manifest.kind, E::KIND);
+        }
+        let levels = manifest.levels.iter()
+            .map(|ids| ids.iter().map(|id| Ok(Arc::new(SsTable::open(&path, *id)?))).collect::<Result<Vec<_>>>())
+            .collect::<Result<Vec<_>>>()?;
+        remove_unused_files(&path, &manifest)?;
+
+        let mut store = Self {
+            map: BTreeMap::new(),
+            log,
+            shared: Arc::new(Shared {
+                path,
+                tree_size: tree_size.max(1),
+                bloom_filter_size,
+                levels: Mutex::new(Levels { levels, next_table_id: manifest.next_table_id }),
+                compaction: Mutex::new(()),
+                compaction_error: Mutex::new(None),
+                horizon: AtomicU64::new(u64::MAX),
+            }),
+            compactor: None,
+        };
+        let missed_write = store.replay_log()?;
+        if is_new {
+            // a new store misses every change until it is rebuilt, the manifest claims the directory for its kind
+            store.log_record(&LogRecord::Pending)?;
+            write_manifest(&store.shared.path, &Manifest { kind: E::KIND.to_string(), levels: Vec::new(), next_table_id: 0 })?;
+        }
+
+        Ok((store, is_new || missed_write))
+    }
+
+    // fills the memtable from the log, returns true if the data file might have changed after the last entries
+    fn replay_log(&mut self) -> Result<bool> {
+        let mut pending = false;
+        for record in self.log.records()? {
+            for write in record.writes {
+                match bincode::deserialize(&write.data)? {
+                    LogRecord::Pending => pending = true,
+                    LogRecord::Entries(entries) => {
+                        pending = false;
+                        self.map.extend(entries);
+                    }
+                }
+            }
+        }
+
+        Ok(pending)
+    }
+
+    // starts the background compaction, levels left full by the last run are merged right away
+    pub fn start_compaction(&mut self) {
+        self.compactor = Some(Compactor::start(self.shared.clone()));
+        self.notify_compactor();
+    }
+
+    // merges every full level right away instead of waiting for the background compaction
+    pub fn compact(&self) -> Result<()> {
+        self.shared.compact()
+    }
+
+    // versions a snapshot with an xmax of at least `horizon` can't see are dropped by compaction
+    pub fn set_horizon(&self, horizon: u64) {
+        self.shared.horizon.store(horizon, Ordering::SeqCst);
+    }
+
+    fn notify_compactor(&self) {
+        if let Some(compactor) = &self.compactor {
+            // the thread only stops when the store is dropped
+            let _ = compactor.sender.send(());
+        }
+    }
+
+    fn log_record(&mut self, record: &LogRecord<K, E>) -> Result<()> {
+        self.log.log(vec![WalWrite { offset: 0, data: bincode::serialize(record)? }])?;
+        Ok(())
+    }
+
+    // has to be called before the data file is changed, fails instead if the background compaction failed
+    pub fn log_pending(&mut self) -> Result<()> {
+        self.check_compaction()?;
+        self.log_record(&LogRecord::Pending)
+    }
+
+    pub fn check_compaction(&self) -> Result<()> {
+        match self.shared.compaction_error.lock().unwrap().take() {
+            Some(e) => Err(e.context("compaction of the ss tables failed")),
+            None => Ok(()),
+        }
+    }
+
+    // logs the entries and puts them into the memtable, all of them survive a crash or none
+    pub fn add(&mut self, entries: Vec<(K, E)>) -> Result<()> {
+        self.log_record(&LogRecord::Entries(entries.clone()))?;
+        self.map.extend(entries);
+        if self.map.len() >= self.shared.tree_size {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let entries = self.map.iter().map(|(key, entry)| Ok((key.clone(), entry.clone())));
+        let table = self.shared.write_table(entries, self.map.len())?;
+        self.shared.install(0, table, &[])?;
+        self.map.clear();
+        self.log.truncate()?;
+        self.notify_compactor();
+
+        Ok(())
+    }
+
+    // the newest entry of the key, in memory or in the newest table that has it
+    pub fn get(&self, key: &K) -> Result<Option<E>> {
+        if let Some(entry) = self.map.get(key) {
+            return Ok(Some(entry.clone()));
+        }
+
+        for table in self.shared.tables() {
+            if let Some(entry) = table.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn memtable(&self) -> &BTreeMap<K, E> {
+        &self.map
+    }
+
+    // every table from newest to oldest, to be passed to `merged`
+    pub fn tables(&self) -> Vec<Table<K, E>> {
+        self.shared.tables()
+    }
+
+    // the newest entry of every key from `from` on in key order, out of the memtable and `tables`
+    pub fn merged<'a>(&'a self, tables: &'a [Table<K, E>], from: Bound<&K>) -> Result<MergeIter<K, E, EntryIter<'a, K, E>>> {
+        let mut sources: Vec<EntryIter<'a, K, E>> = vec![Box::new(
+            self.map.range((from.cloned(), Bound::Unbounded))
+                .map(|(key, entry)| Ok((key.clone(), entry.clone())))
+        )];
+        for table in tables {
+            sources.push(Box::new(table.range(from)?));
+        }
+
+        Ok(MergeIter::new(sources))
+    }
+
+    // replaces the memtable and every table with `entries` in key order
+    pub fn replace_all(&mut self, entries: impl Iterator<Item = Result<(K, E)>>, expected_len: usize) -> Result<()> {
+        let tables = self.shared.tables();
+        self.shared.replace(entries, expected_len, &tables)?;
+        self.map.clear();
+        self.log.truncate()
+    }
+
+    // replaces the memtable and every table with one table of what `rewrite` returns for the newest entry
+    // of every key, None drops the key
+    pub fn rewrite(&mut self, mut rewrite: impl FnMut(&K, E) -> Result<Option<E>>) -> Result<()> {
+        let shared = self.shared.clone();
+        let _compaction = shared.compaction.lock().unwrap();
+
+        let tables = shared.tables();
+        let expected_len = self.map.len() + tables.iter().map(|table| table.len() as usize).sum::<usize>();
+        let entries = self.merged(&tables, Bound::Unbounded)?
+            .filter_map(|entry| entry.and_then(|(key, entry)| Ok(rewrite(&key, entry)?.map(|entry| (key, entry)))).transpose());
+        shared.replace(entries, expected_len, &tables)?;
+        self.map.clear();
+        self.log.truncate()
+    }
+}
+
+impl<K, E> Drop for LsmStore<K, E> {
+    fn drop(&mut self) {
+        if let Some(Compactor { sender, handle }) = self.compactor.take() {
+            // lets a running compaction finish, the thread stops once the channel is closed
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+}
+
+fn read_manifest(path: &Path) -> Result<Option<Manifest>> {
+    let data = match fs::read(path.join(MANIFEST_FILE_NAME)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if data.len() < 4 || crc32c::crc32c(&data[4..]).to_le_bytes() != data[..4] {
+        bail!("manifest of the ss tables in {} is corrupted", path.display());
+    }
+
+    Ok(Some(bincode::deserialize(&data[4..])?))
+}
+
+fn write_manifest(path: &Path, manifest: &Manifest) -> Result<()> {
+    let payload = bincode::serialize(manifest)?;
+    let mut data = crc32c::crc32c(&payload).to_le_bytes().to_vec();
+    data.extend_from_slice(&payload);
+
+    let tmp_path = path.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+    fs::write(&tmp_path, data)?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(&tmp_path, path.join(MANIFEST_FILE_NAME))?;
+    File::open(path)?.sync_all()?;
+
+    Ok(())
+}
+
+// removes tables a crash left behind and everything else that isn't part of the store
+fn remove_unused_files(path: &Path, manifest: &Manifest) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let file = entry?.path();
+        let used = match file.file_name().and_then(|name| name.to_str()) {
+            Some(MANIFEST_FILE_NAME) | Some(LOG_FILE_NAME) => true,
+            _ => ss_table_id(&file).is_some_and(|id| manifest.levels.iter().flatten().any(|used_id| *used_id == id)),
+        };
+        if !used && file.is_file() {
+            fs::remove_file(&file)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    // a value that is dropped by compaction once it is zero and nothing older can be below it
+    impl LsmEntry for u64 {
+        const KIND: &'static str = "test";
+
+        fn compact(self, _horizon: u64, bottom: bool) -> Option<Self> {
+            (self != 0 || !bottom).then_some(self)
+        }
+    }
+
+    fn open(path: &Path) -> Result<LsmStore<String, u64>> {
+        let (mut store, _) = LsmStore::open(path.to_str().unwrap(), 10, 100)?;
+        store.start_compaction();
+        Ok(store)
+    }
+
+    #[test]
+    fn open_reuses_tables_and_memtable() -> Result<()> {
+        let dir = tempdir()?;
+        let (store, is_new) = LsmStore::<String, u64>::open(dir.path().to_str().unwrap(), 10, 100)?;
+        assert!(is_new);
+        drop(store);
+
+        let mut store = open(dir.path())?;
+        for i in 0..15 {
+            store.add(vec![(format!("{:02}", i), i + 1)])?;
+        }
+        drop(store);
+
+        let (store, is_new) = LsmStore::<String, u64>::open(dir.path().to_str().unwrap(), 10, 100)?;
+
+        assert!(!is_new);
+        assert_eq!(store.memtable().len(), 5);
+        for i in 0..15 {
+            assert_eq!(store.get(&format!("{:02}", i))?, Some(i + 1));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_reports_missed_write() -> Result<()> {
+        let dir = tempdir()?;
+        let mut store = open(dir.path())?;
+        store.add(vec![("a".to_string(), 1)])?;
+        store.log_pending()?;
+        drop(store);
+
+        let (store, missed_write) = LsmStore::<String, u64>::open(dir.path().to_str().unwrap(), 10, 100)?;
+
+        assert!(missed_write);
+        assert_eq!(store.get(&"a".to_string())?, Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_merges_full_level_into_next_level() -> Result<()> {
+        let dir = tempdir()?;
+        let mut store = open(dir.path())?;
+        for i in 0..30 {
+            store.add(vec![(format!("{:02}", i), i + 1)])?;
+        }
+        // the newest entry of a key wins, a zero is dropped because no older table is left below the merge
+        store.add(vec![("05".to_string(), 0)])?;
+        for i in 30..40 {
+            store.add(vec![(format!("{:02}", i), i + 1)])?;
+        }
+
+        store.compact()?;
+
+        {
+            let levels = store.shared.levels.lock().unwrap();
+            assert!(levels.levels[0].is_empty());
+            assert_eq!(levels.levels[1].len(), 1);
+            // "00" to "38" without "05", "39" is still in memory
+            assert_eq!(levels.levels[1][0].len(), 38);
+        }
+        assert_eq!(store.get(&"05".to_string())?, None);
+        assert_eq!(store.get(&"39".to_string())?, Some(40));
+        // only the merged table is left once the replaced ones are dropped
+        drop(store);
+        let tables = fs::read_dir(dir.path())?
+            .filter(|entry| entry.as_ref().is_ok_and(|entry| ss_table_id(&entry.path()).is_some()))
+            .count();
+        assert_eq!(tables, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_replaces_everything_with_one_table() -> Result<()> {
+        let dir = tempdir()?;
+        let mut store = open(dir.path())?;
+        for i in 0..15 {
+            store.add(vec![(format!("{:02}", i), i)])?;
+        }
+
+        store.rewrite(|_, entry| Ok((entry % 2 == 0).then_some(entry * 10)))?;
+
+        assert!(store.memtable().is_empty());
+        assert_eq!(store.tables().len(), 1);
+        assert_eq!(store.get(&"04".to_string())?, Some(40));
+        assert_eq!(store.get(&"05".to_string())?, None);
+        let keys: Vec<String> = store.merged(&store.tables(), Bound::Included(&"10".to_string()))?
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        assert_eq!(keys, vec!["10", "12", "14"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_rejects_tables_of_another_kind() -> Result<()> {
+        let dir = tempdir()?;
+        write_manifest(dir.path(), &Manifest { kind: "other".to_string(), levels: Vec::new(), next_table_id: 0 })?;
+
+        assert!(LsmStore::<String, u64>::open(dir.path().to_str().unwrap(), 10, 100).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_keeps_reporting_new_store_until_it_is_rebuilt() -> Result<()> {
+        let dir = tempdir()?;
+        let (store, is_new) = LsmStore::<String, u64>::open(dir.path().to_str().unwrap(), 10, 100)?;
+        assert!(is_new);
+        drop(store);
+
+        let (mut store, rebuild) = LsmStore::<String, u64>::open(dir.path().to_str().unwrap(), 10, 100)?;
+        assert!(rebuild);
+        store.replace_all(std::iter::empty(), 0)?;
+        drop(store);
+
+        let (_, rebuild) = LsmStore::<String, u64>::open(dir.path().to_str().unwrap(), 10, 100)?;
+        assert!(!rebuild);
+
+        Ok(())
+    }
+}
@@ -1,325 +1,306 @@
-use std::collections::BTreeMap;
-use std::fs;
-use std::fs::OpenOptions;
 use std::hash::Hash;
-use std::io::Write;
 use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::ops::Bound;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
 
-use bloomfilter::BloomFilter;
 #[cfg(test)]
 use mockall::{mock, predicate::*};
-use storageengine::operations::{DbOperations, NONE_SENTINEL, OffsetSize, Row};
+use storageengine::backup::{BackupManifest, WalArchive};
+use storageengine::file_handler::FileHandler;
+use storageengine::file_header::FileHeader;
+use storageengine::operations::{DbOperations, NEVER_EXPIRES, OffsetSize, RowWrite};
+use storageengine::transaction::Snapshot;
 
-use crate::index::{Document, Index, IndexError};
+use crate::codec::{Codec, CodecKind};
+use crate::index::{Change, check_conflict, check_update_id, decode_unexpired, Document, expired_ids, Index, IndexError, is_before_end, is_empty_range, newest_versions, pin_version, read_unexpired, repoint, repoint_if_kept, resolve_writes, row_expires_at, row_version, StagedWrite, Transaction};
+use crate::lsm_store::{LsmEntry, LsmStore};
 
+// an lsm tree over the rows of the data file, its tables only keep where the rows are
 pub struct LsmTree<K, V> {
-    map: BTreeMap<K, LsmMapLeaf>,
+    store: LsmStore<K, LsmMapLeaf>,
     db_operations: Box<dyn DbOperations>,
-    transaction_id: u64,
-    bloom_filter: BloomFilter,
-    tree_size: usize,
-    ss_table_path: String,
+    codec: CodecKind,
     phantom: PhantomData<(K, V)>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+// where the newest version of a document is. deleted documents keep a leaf until vacuum drops them
+// because snapshots might still see them, and it hides the versions in older tables
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct LsmMapLeaf {
     offset_size: OffsetSize,
     is_deleted: bool,
 }
 
-impl<K, V> LsmTree<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::convert::AsRef<[u8]> + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
-    pub fn new(db_operations: Box<dyn DbOperations>, ss_table_path: String, tree_size: usize, bloom_filter_size: usize) -> Result<Self> {
-        let mut lsm_tree = Self::initialize_lsm_tree(db_operations, ss_table_path, tree_size, bloom_filter_size)?;
-        lsm_tree.remove_all_files_from_ss_table()?;
-        lsm_tree.process_db_rows()?;
+impl LsmEntry for LsmMapLeaf {
+    const KIND: &'static str = "offsets";
 
-        Ok(lsm_tree)
+    // the rows of deleted documents stay until vacuum, and so do their leaves
+    fn compact(self, _horizon: u64, _bottom: bool) -> Option<Self> {
+        Some(self)
     }
+}
 
-    fn initialize_lsm_tree(db_operations: Box<dyn DbOperations>, ss_table_path: String, tree_size: usize, bloom_filter_size: usize) -> Result<Self> {
-        Ok(Self {
-            map: BTreeMap::new(),
+impl<K, V> LsmTree<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::convert::AsRef<[u8]> + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync + 'static, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
+    // `ss_table_path` is the directory of the tables, they are only built from the rows if the directory
+    // is new or a crash left them behind the data file
+    pub fn new(db_operations: Box<dyn DbOperations>, ss_table_path: String, tree_size: usize, bloom_filter_size: usize, codec: CodecKind) -> Result<Self> {
+        let (store, rebuild) = LsmStore::open(&ss_table_path, tree_size, bloom_filter_size)?;
+        let mut lsm_tree = Self {
+            store,
             db_operations,
-            transaction_id: 0,
-            bloom_filter: BloomFilter::new(bloom_filter_size),
-            tree_size,
-            ss_table_path,
+            codec,
             phantom: PhantomData,
-        })
+        };
+        if rebuild {
+            lsm_tree.rebuild()?;
+        }
+        lsm_tree.store.start_compaction();
+
+        Ok(lsm_tree)
     }
 
-    fn remove_all_files_from_ss_table(&mut self) -> Result<()> {
-        self.remove_all_files_ss_table()
+    // merges every full level right away instead of waiting for the background compaction
+    pub fn compact(&self) -> Result<()> {
+        self.store.compact()
     }
 
-    fn process_db_rows(&mut self) -> Result<()> {
-        let mut offset = 0;
-        let rows = self.db_operations.read_all()?;
-        for row in rows {
-            self.handle_db_row(row, &mut offset)?;
-        }
-        self.transaction_id = self.map.len() as u64;
+    fn rebuild(&mut self) -> Result<()> {
+        let versions = newest_versions::<K, V>(self.db_operations.as_mut(), self.codec)?;
+        let expected_len = versions.len();
+        let leaves = versions.into_iter()
+            .map(|(id, (offset_size, is_deleted))| Ok((id, LsmMapLeaf { offset_size, is_deleted })));
 
-        Ok(())
+        self.store.replace_all(leaves, expected_len)
     }
 
-    fn handle_db_row(&mut self, row: Row, offset: &mut u64) -> Result<()> {
-        // deleted
-        if row.header.cmax != NONE_SENTINEL {
-            *offset += row.header.tuple_length;
-            return Ok(());
-        }
+    fn live(&self, id: &K) -> Result<Option<OffsetSize>> {
+        Ok(self.store.get(id)?
+            .filter(|lsm_map_leaf| !lsm_map_leaf.is_deleted)
+            .map(|lsm_map_leaf| lsm_map_leaf.offset_size))
+    }
 
-        let doc: Document<K, V> = bincode::deserialize(&row.data)?;
+    fn deleted(&self, id: &K) -> Result<Option<OffsetSize>> {
+        Ok(self.store.get(id)?
+            .filter(|lsm_map_leaf| lsm_map_leaf.is_deleted)
+            .map(|lsm_map_leaf| lsm_map_leaf.offset_size))
+    }
 
-        self.map.insert(doc.id.clone(), LsmMapLeaf {
-            offset_size: OffsetSize {
-                offset: *offset,
-                size: row.header.tuple_length,
-            },
-            is_deleted: false,
-        });
-        self.bloom_filter.insert(doc.id);
-        if self.map.len() == self.tree_size {
-            self.flush_tree_to_disk()?;
+    fn apply_writes(&mut self, writes: Vec<StagedWrite<K, V>>, snapshot: &Snapshot, expires_at: u64) -> Result<()> {
+        for write in &writes {
+            if let Some(lsm_map_leaf) = self.store.get(write.id())? {
+                check_conflict(self.db_operations.as_mut(), &lsm_map_leaf.offset_size, snapshot)?;
+            }
         }
 
-        *offset += row.header.tuple_length;
+        let changes = resolve_writes(writes, |id| Ok(self.live(id)?.is_some()))?;
+        if changes.is_empty() {
+            return Ok(());
+        }
 
-        Ok(())
+        let mut row_writes = Vec::with_capacity(changes.len());
+        for change in &changes {
+            row_writes.push(match change {
+                Change::Insert(document) => match self.deleted(&document.id)? {
+                    Some(deleted_offset_size) => RowWrite::Reinsert { deleted_offset_size, data: self.codec.encode(document)? },
+                    None => RowWrite::Insert { data: self.codec.encode(document)? },
+                },
+                Change::Update(document) => RowWrite::Update {
+                    old_offset_size: self.live(&document.id)?.ok_or(IndexError::NotFound)?,
+                    data: self.codec.encode(document)?,
+                },
+                Change::Delete(id) => RowWrite::Delete { offset_size: self.live(id)?.ok_or(IndexError::NotFound)? },
+            });
+        }
+        let transaction_id = self.db_operations.begin()?;
+        self.store.log_pending()?;
+        let offset_sizes = self.db_operations.write_batch(row_writes, transaction_id, expires_at)?;
+
+        let leaves = changes.into_iter().zip(offset_sizes)
+            .map(|(change, offset_size)| match change {
+                Change::Insert(document) | Change::Update(document) => (document.id, LsmMapLeaf { offset_size, is_deleted: false }),
+                Change::Delete(id) => (id, LsmMapLeaf { offset_size, is_deleted: true }),
+            })
+            .collect();
+
+        self.store.add(leaves)
     }
 
-    fn flush_tree_to_disk(&mut self) -> Result<()> {
-        let data = bincode::serialize(&self.map)?;
+    // a single write that expires, the snapshot is taken right before it so it never conflicts
+    fn write_expiring(&mut self, write: StagedWrite<K, V>, expires_at: u64) -> Result<()> {
+        let snapshot = self.db_operations.snapshot();
+        let result = self.apply_writes(vec![write], &snapshot, expires_at);
+        self.db_operations.release_snapshot(&snapshot);
 
-        fs::create_dir_all(&self.ss_table_path)?;
+        result
+    }
+}
 
-        let count = fs::read_dir(&self.ss_table_path)?
-            .map(|res| res.map(|e| e.path()))
-            .collect::<Result<Vec<_>, std::io::Error>>()?.len();
+impl<K, V> Index<K, V> for LsmTree<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::convert::AsRef<[u8]> + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync + 'static, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
+    fn insert(&mut self, document: Document<K, V>) -> Result<()> {
+        // check if item is already present
+        if self.live(&document.id)?.is_some() {
+            return Err(IndexError::AlreadyExists.into());
+        }
 
-        let file_name = format!("{}/ss_table_{}", self.ss_table_path, count);
-        let mut file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(file_name)?;
-        file.write_all(&data)?;
-        file.flush()?;
+        let data = self.codec.encode(&document)?;
+        let deleted_offset_size = self.deleted(&document.id)?;
+        let transaction_id = self.db_operations.begin()?;
+        self.store.log_pending()?;
+        let offset_size = match deleted_offset_size {
+            Some(deleted_offset_size) => self.db_operations.reinsert_with_offset(&deleted_offset_size, data, transaction_id)?,
+            None => self.db_operations.insert(data, transaction_id)?,
+        };
+        self.store.add(vec![(document.id, LsmMapLeaf { offset_size, is_deleted: false })])
+    }
 
-        self.map.clear();
+    fn search(&mut self, id: &K) -> Result<Document<K, V>> {
+        let offset_size = self.live(id)?.ok_or(IndexError::NotFound)?;
+        let row = self.db_operations.read_with_offset(&offset_size)?;
+        decode_unexpired(self.codec, row)?.ok_or_else(|| IndexError::NotFound.into())
+    }
 
-        Ok(())
+    fn delete(&mut self, id: &K) -> Result<()> {
+        let offset_size = self.live(id)?.ok_or(IndexError::NotFound)?;
+        let transaction_id = self.db_operations.begin()?;
+        self.store.log_pending()?;
+        self.db_operations.delete_with_offset(&offset_size, transaction_id)?;
+        self.store.add(vec![(id.clone(), LsmMapLeaf { offset_size, is_deleted: true })])
     }
 
-    fn remove_all_files_ss_table(&mut self) -> Result<()> {
-        // check directory exists
-        if !PathBuf::from(&self.ss_table_path).exists() {
-            return Ok(());
+    fn update(&mut self, id: &K, document: Document<K, V>) -> Result<()> {
+        let offset_size = self.live(id)?.ok_or(IndexError::NotFound)?;
+        let data = self.codec.encode(&document)?;
+        let transaction_id = self.db_operations.begin()?;
+        self.store.log_pending()?;
+        let new_offset_size = self.db_operations.update_with_offset(&offset_size, data, transaction_id)?;
+        self.store.add(vec![(document.id, LsmMapLeaf { offset_size: new_offset_size, is_deleted: false })])
+    }
+
+    fn scan(&mut self, range: (Bound<K>, Bound<K>), limit: Option<usize>) -> Result<Vec<Document<K, V>>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
         }
 
-        fs::read_dir(&self.ss_table_path)?
-            .map(|res| res.map(|e| e.path()))
-            .collect::<Result<Vec<_>, std::io::Error>>()?
-            .iter()
-            .for_each(|path| {
-                fs::remove_file(path).expect("Unable to remove file");
-            });
+        let tables = self.store.tables();
+        let offset_sizes = self.store.merged(&tables, range.0.as_ref())?
+            .take_while(|entry| !matches!(entry, Ok((id, _)) if !is_before_end(id, &range.1)))
+            .filter(|entry| !matches!(entry, Ok((_, lsm_map_leaf)) if lsm_map_leaf.is_deleted))
+            .map(|entry| entry.map(|(_, lsm_map_leaf)| lsm_map_leaf.offset_size));
 
-        Ok(())
+        read_unexpired(self.db_operations.as_mut(), self.codec, offset_sizes, limit)
     }
 
-    fn search_in_files(&mut self, id: &K) -> Result<Document<K, V>> {
-        let files = fs::read_dir(&self.ss_table_path)?
-            .map(|res| res.map(|e| e.path()))
-            .collect::<Result<Vec<PathBuf>, std::io::Error>>()?;
-
-        for file in &files {
-            let data = fs::read(file)?;
-            let map: BTreeMap<K, LsmMapLeaf> = bincode::deserialize(&data)?;
-            match map.get(id) {
-                Some(lsm_map_leaf) => {
-                    let row = self.db_operations.read_with_offset(&lsm_map_leaf.offset_size)?;
-                    let doc: Document<K, V> = bincode::deserialize(&row.data)?;
-                    return Ok(doc);
-                }
-                None => continue,
+    fn search_at(&mut self, id: &K, snapshot: &Snapshot) -> Result<Document<K, V>> {
+        // older versions are reached through the chain of the newest one
+        if let Some(lsm_map_leaf) = self.store.get(id)? {
+            if let Some(row) = self.db_operations.read_visible(&lsm_map_leaf.offset_size, snapshot)? {
+                return decode_unexpired(self.codec, row)?.ok_or_else(|| IndexError::NotFound.into());
             }
         }
 
         Err(IndexError::NotFound.into())
     }
 
-    fn mark_ss_table_as_deleted(&mut self, id: &K) -> Result<()> {
-        let files = self.read_files_from_ss_table()?;
-
-        for file in &files {
-            if let Ok(()) = self.process_file(file, id) {
-                return Ok(());
-            }
-        }
+    fn snapshot(&mut self) -> Snapshot {
+        self.db_operations.snapshot()
+    }
 
-        Err(IndexError::NotFound.into())
+    fn release_snapshot(&mut self, snapshot: Snapshot) {
+        self.db_operations.release_snapshot(&snapshot)
     }
 
-    fn read_files_from_ss_table(&self) -> Result<Vec<PathBuf>, std::io::Error> {
-        fs::read_dir(&self.ss_table_path)?
-            .map(|res| res.map(|e| e.path()))
-            .collect::<Result<Vec<PathBuf>, std::io::Error>>()
+    fn commit(&mut self, transaction: Transaction<K, V>) -> Result<()> {
+        let (snapshot, writes) = transaction.into_parts();
+        let result = self.apply_writes(writes, &snapshot, NEVER_EXPIRES);
+        self.db_operations.release_snapshot(&snapshot);
+
+        result
     }
 
-    fn process_file(&mut self, file: &PathBuf, id: &K) -> Result<()> {
-        let file_name = match file.file_name() {
-            Some(name) => name.to_string_lossy().into_owned(),
-            None => return Err(IndexError::NotFound.into()),
-        };
+    // merges the memtable and every table into one table that points to the moved rows
+    fn vacuum(&mut self) -> Result<()> {
+        self.store.log_pending()?;
+        let mut remapping = self.db_operations.vacuum()?;
 
-        let data = fs::read(file)?;
-        let mut map: BTreeMap<K, LsmMapLeaf> = bincode::deserialize(&data)?;
-        self.handle_map_leaf(&mut map, id, &file_name)
-    }
-
-    fn handle_map_leaf(&mut self, map: &mut BTreeMap<K, LsmMapLeaf>, id: &K, file_name: &str) -> Result<()> {
-        match map.get_mut(id) {
-            Some(lsm_map_leaf) => {
-                if lsm_map_leaf.is_deleted {
-                    return Err(IndexError::NotFound.into());
-                }
-
-                self.db_operations.delete_with_offset(&lsm_map_leaf.offset_size, self.transaction_id)?;
-                self.transaction_id += 1;
-                self.bloom_filter.remove(id);
-
-                lsm_map_leaf.is_deleted = true;
-                // flush to disk
-                let lsm_tree_data = bincode::serialize(&map)?;
-                let mut f = OpenOptions::new()
-                    .write(true)
-                    .read(true)
-                    .append(true)
-                    .create(true)
-                    .open(file_name)?;
-                f.write_all(&lsm_tree_data)?;
-                f.flush()?;
-
-                Ok(())
+        self.store.rewrite(|_, mut lsm_map_leaf| {
+            if !lsm_map_leaf.is_deleted {
+                repoint(&mut lsm_map_leaf.offset_size, &mut remapping)?;
+                return Ok(Some(lsm_map_leaf));
             }
-            None => Err(IndexError::NotFound.into()),
-        }
+            Ok(repoint_if_kept(&mut lsm_map_leaf.offset_size, &mut remapping).then_some(lsm_map_leaf))
+        })
     }
 
-    fn update_ss_table(&mut self, id: &K, document: Document<K, V>) -> Result<()> {
-        match self.mark_ss_table_as_deleted(id) {
-            Ok(_) => {
-                self.insert(document)?;
-                Ok(())
-            }
-            Err(err) => {
-                Err(err)
-            }
-        }
+    fn sync(&mut self) -> Result<()> {
+        self.db_operations.sync()
     }
-}
 
-impl<K, V> Index<K, V> for LsmTree<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::convert::AsRef<[u8]> + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
-    fn insert(&mut self, document: Document<K, V>) -> Result<()> {
-        // check if item is already present
-        if self.search(&document.id).is_ok() {
-            return Err(IndexError::AlreadyExists.into());
-        }
+    fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest> {
+        self.db_operations.backup(target, archive)
+    }
 
-        let data = bincode::serialize(&document)?;
-        let offset_size = self.db_operations.insert(data, self.transaction_id)?;
-        self.map.insert(document.id.clone(), LsmMapLeaf {
-            offset_size,
-            is_deleted: false,
-        });
-        if self.map.len() == self.tree_size {
-            self.flush_tree_to_disk()?;
-        }
-        self.bloom_filter.insert(document.id);
-        self.transaction_id += 1;
+    fn header(&self) -> FileHeader {
+        self.db_operations.header()
+    }
 
-        Ok(())
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.db_operations.set_header(header)
     }
 
-    fn search(&mut self, id: &K) -> Result<Document<K, V>> {
-        // if item is not in bloom filter, it is not in the db
-        if !self.bloom_filter.check(id) {
-            return Err(IndexError::NotFound.into());
-        }
+    fn insert_expiring(&mut self, document: Document<K, V>, expires_at: u64) -> Result<()> {
+        self.write_expiring(StagedWrite::Insert(document), expires_at)
+    }
 
-        match self.map.get(id) {
-            // item is in memory
-            Some(lsm_map_leaf) => {
-                let row = self.db_operations.read_with_offset(&lsm_map_leaf.offset_size)?;
-                let doc: Document<K, V> = bincode::deserialize(&row.data)?;
-                Ok(doc)
-            }
-            None => {
-                // item is not in memory, search in all files
-                self.search_in_files(id)
-            }
-        }
+    fn update_expiring(&mut self, id: &K, document: Document<K, V>, expires_at: u64) -> Result<()> {
+        check_update_id(id, &document)?;
+        self.write_expiring(StagedWrite::Update(document), expires_at)
     }
 
-    fn delete(&mut self, id: &K) -> Result<()> {
-        if !self.bloom_filter.check(id) {
-            return Err(IndexError::NotFound.into());
-        }
+    fn can_expire(&self) -> bool {
+        true
+    }
 
-        match self.map.get(id) {
-            Some(lsm_map_leaf) => {
-                self.db_operations.delete_with_offset(&lsm_map_leaf.offset_size, self.transaction_id)?;
-                self.map.remove(id);
-                self.bloom_filter.remove(id);
-                self.transaction_id += 1;
-                Ok(())
-            }
-            None => {
-                self.mark_ss_table_as_deleted(id)
-            }
-        }
+    fn expired(&mut self, now: u64, limit: usize) -> Result<Vec<K>> {
+        expired_ids::<K, V>(self.db_operations.as_mut(), self.codec, now, limit)
     }
 
-    fn update(&mut self, id: &K, document: Document<K, V>) -> Result<()> {
-        if !self.bloom_filter.check(id) {
-            return Err(IndexError::NotFound.into());
-        }
+    fn search_including_expired(&mut self, id: &K) -> Result<Document<K, V>> {
+        let offset_size = self.live(id)?.ok_or(IndexError::NotFound)?;
+        let row = self.db_operations.read_with_offset(&offset_size)?;
+        self.codec.decode(&row.data)
+    }
 
-        match self.map.get(id) {
-            Some(lsm_map_leaf) => {
-                let data = bincode::serialize(&document)?;
-                let new_offset_size = self.db_operations.update_with_offset(&lsm_map_leaf.offset_size, data, self.transaction_id)?;
-                self.map.insert(document.id, LsmMapLeaf {
-                    offset_size: new_offset_size,
-                    is_deleted: false,
-                });
-                self.transaction_id += 1;
-                Ok(())
-            }
-            None => {
-                self.update_ss_table(id, document)
-            }
-        }
+    fn version(&mut self, id: &K) -> Result<u64> {
+        let offset_size = self.live(id)?.ok_or(IndexError::NotFound)?;
+        row_version(self.db_operations.as_mut(), &offset_size)
+    }
+
+    fn pin_version(&mut self, version: Option<u64>) -> Result<()> {
+        pin_version(self.db_operations.as_mut(), version)
+    }
+
+    fn expires_at(&mut self, id: &K) -> Result<u64> {
+        let offset_size = self.live(id)?.ok_or(IndexError::NotFound)?;
+        row_expires_at(self.db_operations.as_mut(), &offset_size)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
     use anyhow::Result;
 
     use mockall::predicate;
-    use storageengine::operations::{Header, Row};
+    use storageengine::operations::{Header, NONE_SENTINEL, Row, RowBatch};
     #[cfg(test)]
-    use tempfile::tempdir;
+    use tempfile::{tempdir, TempDir};
+
+    use crate::ss_table::ss_table_id;
 
     use super::*;
 
@@ -328,9 +309,21 @@ mod tests {
         impl DbOperations for DbOperationsImpl {
             fn insert(&mut self, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
             fn read_with_offset(&mut self, offset_size: &OffsetSize) -> Result<Row>;
-            fn read_all(&mut self) -> Result<Vec<Row>>;
+            fn read_page(&mut self, page_id: u64) -> Result<RowBatch>;
             fn update_with_offset(&mut self, old_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
             fn delete_with_offset(&mut self, offset_size: &OffsetSize, transaction_id: u64) -> Result<()>;
+            fn reinsert_with_offset(&mut self, deleted_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
+            fn read_visible(&mut self, offset_size: &OffsetSize, snapshot: &Snapshot) -> Result<Option<Row>>;
+            fn write_batch(&mut self, writes: Vec<RowWrite>, transaction_id: u64, expires_at: u64) -> Result<Vec<OffsetSize>>;
+            fn begin(&mut self) -> Result<u64>;
+            fn pin_transaction_id(&mut self, transaction_id: Option<u64>) -> Result<()>;
+            fn snapshot(&mut self) -> Snapshot;
+            fn release_snapshot(&mut self, snapshot: &Snapshot);
+            fn vacuum(&mut self) -> Result<HashMap<u64, OffsetSize>>;
+            fn sync(&mut self) -> Result<()>;
+            fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest>;
+            fn header(&self) -> FileHeader;
+            fn set_header(&mut self, header: FileHeader) -> Result<()>;
         }
     }
 
@@ -340,7 +333,8 @@ mod tests {
         let data = bincode::serialize(&document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -354,21 +348,20 @@ mod tests {
                     cmax: NONE_SENTINEL,
                     xmax: NONE_SENTINEL,
                     tuple_length: 3,
-                    table_oid: 0,
+                    expires_at: 0,
                     ctid: 0,
                     cmin: 0,
                 },
                 data: data.clone(),
             }));
 
-        let mut lsm_tree = setup_lsm_tree(mock)?;
+        let (mut lsm_tree, _dir) = setup_lsm_tree(mock)?;
 
         let res = lsm_tree.insert(document.clone());
 
         assert!(res.is_ok());
-        assert!(!lsm_tree.map.get(&document.id).unwrap().is_deleted);
-        assert!(lsm_tree.bloom_filter.check(&document.id));
-        assert!(lsm_tree.map.get(&document.id).unwrap().offset_size.offset == 0);
+        assert!(!lsm_tree.store.memtable().get(&document.id).unwrap().is_deleted);
+        assert!(lsm_tree.store.memtable().get(&document.id).unwrap().offset_size.offset == 0);
 
         let doc = lsm_tree.search(&document.id)?;
         assert_eq!(doc, document);
@@ -382,7 +375,8 @@ mod tests {
         let data = bincode::serialize(&document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_read_with_offset()
             .with(predicate::eq(&OffsetSize { offset: 50 * 3, size: 51 * 3 }))
             .times(2)
@@ -392,7 +386,7 @@ mod tests {
                     cmax: NONE_SENTINEL,
                     xmax: NONE_SENTINEL,
                     tuple_length: 3,
-                    table_oid: 0,
+                    expires_at: 0,
                     ctid: 0,
                     cmin: 0,
                 },
@@ -401,14 +395,14 @@ mod tests {
 
         mock.expect_insert()
             .with(predicate::always(), predicate::always())
-            .times(101)
+            .times(100)
             .returning(move |_, _| Ok(OffsetSize { offset: 50 * 3, size: (50 + 1) * 3 }));
-        mock.expect_delete_with_offset()
-            .with(predicate::eq(&OffsetSize { offset: 50 * 3, size: (50 + 1) * 3 }), predicate::always())
+        mock.expect_update_with_offset()
+            .with(predicate::eq(&OffsetSize { offset: 50 * 3, size: (50 + 1) * 3 }), predicate::always(), predicate::always())
             .times(1)
-            .returning(move |_, _| Ok(()));
+            .returning(move |_, _, _| Ok(OffsetSize { offset: 50 * 3, size: (50 + 1) * 3 }));
 
-        let mut lsm_tree = setup_lsm_tree(mock)?;
+        let (mut lsm_tree, _dir) = setup_lsm_tree(mock)?;
 
         for i in 0..100 {
             let document = Document { id: i.to_string(), value: vec![1, 2, 3] };
@@ -416,6 +410,8 @@ mod tests {
             assert!(res.is_ok());
         }
 
+        assert!(lsm_tree.store.memtable().is_empty());
+        assert!(!lsm_tree.store.tables().is_empty());
         let doc = lsm_tree.search(&"50".to_string())?;
         assert_eq!(doc, document);
 
@@ -433,7 +429,8 @@ mod tests {
         let data = bincode::serialize(&document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -447,14 +444,14 @@ mod tests {
                     cmax: NONE_SENTINEL,
                     xmax: NONE_SENTINEL,
                     tuple_length: 3,
-                    table_oid: 0,
+                    expires_at: 0,
                     ctid: 0,
                     cmin: 0,
                 },
                 data: data.clone(),
             }));
 
-        let mut lsm_tree = setup_lsm_tree(mock)?;
+        let (mut lsm_tree, _dir) = setup_lsm_tree(mock)?;
 
         lsm_tree.insert(document.clone())?;
 
@@ -468,9 +465,10 @@ mod tests {
     #[test]
     fn search_returns_error_if_not_found() -> Result<()> {
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
 
-        let mut lsm_tree: LsmTree<String, i32> = setup_lsm_tree(mock)?;
+        let (mut lsm_tree, _dir): (LsmTree<String, i32>, _) = setup_lsm_tree(mock)?;
 
         let result = lsm_tree.search(&"1".to_string());
 
@@ -485,7 +483,8 @@ mod tests {
         let data = bincode::serialize(&document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -495,11 +494,12 @@ mod tests {
             .times(1)
             .returning(move |_, _| Ok(()));
 
-        let mut lsm_tree = setup_lsm_tree(mock)?;
+        let (mut lsm_tree, _dir) = setup_lsm_tree(mock)?;
         lsm_tree.insert(document.clone())?;
         lsm_tree.delete(&document.id)?;
 
-        assert!(lsm_tree.map.get(&document.id).is_none());
+        assert!(lsm_tree.store.memtable().get(&document.id).unwrap().is_deleted);
+        assert!(lsm_tree.search(&document.id).is_err());
 
         Ok(())
     }
@@ -507,9 +507,10 @@ mod tests {
     #[test]
     fn delete_returns_error_if_not_found() -> Result<()> {
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
 
-        let mut lsm_tree: LsmTree<String, i32> = setup_lsm_tree(mock)?;
+        let (mut lsm_tree, _dir): (LsmTree<String, i32>, _) = setup_lsm_tree(mock)?;
 
         let result = lsm_tree.delete(&"1".to_string());
 
@@ -528,7 +529,8 @@ mod tests {
         let updated_data_clone = bincode::serialize(&updated_document)?;
 
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
         mock.expect_insert()
             .with(predicate::eq(data.clone()), predicate::eq(0_u64))
             .times(1)
@@ -542,7 +544,7 @@ mod tests {
                     cmax: NONE_SENTINEL,
                     xmax: NONE_SENTINEL,
                     tuple_length: 3,
-                    table_oid: 0,
+                    expires_at: 0,
                     ctid: 0,
                     cmin: 0,
                 },
@@ -553,13 +555,13 @@ mod tests {
             .times(1)
             .returning(move |_, _, _| Ok(OffsetSize { offset: 3, size: 3 }));
 
-        let mut lsm_tree = setup_lsm_tree(mock)?;
+        let (mut lsm_tree, _dir) = setup_lsm_tree(mock)?;
         lsm_tree.insert(document.clone()).unwrap();
 
         lsm_tree.update(&updated_document.id, updated_document.clone())?;
 
-        assert!(lsm_tree.map.get(&updated_document.id).is_some());
-        assert!(lsm_tree.map.get(&updated_document.id).unwrap().offset_size.offset == 3);
+        assert!(lsm_tree.store.memtable().contains_key(&updated_document.id));
+        assert!(lsm_tree.store.memtable().get(&updated_document.id).unwrap().offset_size.offset == 3);
 
         let doc = lsm_tree.search(&updated_document.id)?;
         assert_eq!(doc, updated_document);
@@ -570,9 +572,10 @@ mod tests {
     #[test]
     fn update_inserts_new_document_if_not_found() -> Result<()> {
         let mut mock = MockDbOperationsImpl::new();
-        mock.expect_read_all().times(1).returning(move || Ok(vec![]));
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
 
-        let mut lsm_tree = setup_lsm_tree(mock)?;
+        let (mut lsm_tree, _dir) = setup_lsm_tree(mock)?;
         let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
 
         let res = lsm_tree.update(&document.id, document.clone());
@@ -581,12 +584,323 @@ mod tests {
         Ok(())
     }
 
-    fn setup_lsm_tree<K, V>(mock_db_operations_impl: MockDbOperationsImpl) -> Result<LsmTree<K, V>>
-        where K: Serialize + DeserializeOwned + Hash + Eq + std::convert::AsRef<[u8]> + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync
+    #[test]
+    fn vacuum_repoints_memory_and_ss_tables() -> Result<()> {
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        let mut offset = 0;
+        mock.expect_insert()
+            .times(15)
+            .returning(move |_, _| {
+                offset += 1;
+                Ok(OffsetSize { offset, size: 3 })
+            });
+        mock.expect_vacuum()
+            .times(1)
+            .returning(move || Ok((1..=15).map(|offset| (offset, OffsetSize { offset: offset + 100, size: 3 })).collect()));
+
+        let dir = tempdir()?;
+        let mut lsm_tree: LsmTree<String, Vec<u8>> = LsmTree::new(Box::new(mock), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+        for i in 0..15 {
+            lsm_tree.insert(Document { id: format!("{:02}", i), value: vec![1, 2, 3] })?;
+        }
+
+        lsm_tree.vacuum()?;
+
+        assert!(lsm_tree.store.memtable().is_empty());
+        assert_eq!(lsm_tree.store.tables().len(), 1);
+        assert_eq!(lsm_tree.live(&"12".to_string())?, Some(OffsetSize { offset: 113, size: 3 }));
+        assert_eq!(lsm_tree.live(&"03".to_string())?, Some(OffsetSize { offset: 104, size: 3 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn search_at_finds_deleted_document_in_older_snapshot() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+        let data = bincode::serialize(&document)?;
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Ok(OffsetSize { offset: 0, size: 3 }));
+        mock.expect_snapshot()
+            .times(1)
+            .returning(|| Snapshot { xmax: 1 });
+        mock.expect_delete_with_offset()
+            .with(predicate::eq(&OffsetSize { offset: 0, size: 3 }), predicate::eq(1_u64))
+            .times(1)
+            .returning(move |_, _| Ok(()));
+        mock.expect_read_visible()
+            .with(predicate::eq(&OffsetSize { offset: 0, size: 3 }), predicate::eq(&Snapshot { xmax: 1 }))
+            .times(1)
+            .returning(move |_, _| Ok(Some(Row {
+                header: Header {
+                    xmin: 0,
+                    cmax: 1,
+                    xmax: 1,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                },
+                data: data.clone(),
+            })));
+
+        let (mut lsm_tree, _dir) = setup_lsm_tree(mock)?;
+        lsm_tree.insert(document.clone())?;
+        let snapshot = lsm_tree.snapshot();
+        lsm_tree.delete(&document.id)?;
+
+        assert!(lsm_tree.search(&document.id).is_err());
+        assert_eq!(lsm_tree.search_at(&document.id, &snapshot)?, document);
+
+        Ok(())
+    }
+
+    #[test]
+    fn commit_shadows_documents_of_ss_tables() -> Result<()> {
+        let updated_document = Document { id: "05".to_string(), value: vec![4, 5, 6] };
+        let expected_writes = vec![
+            RowWrite::Update { old_offset_size: OffsetSize { offset: 5, size: 3 }, data: bincode::serialize(&updated_document)? },
+            RowWrite::Delete { offset_size: OffsetSize { offset: 7, size: 3 } },
+        ];
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        let mut offset = 0;
+        mock.expect_insert()
+            .times(10)
+            .returning(move |_, _| {
+                offset += 1;
+                Ok(OffsetSize { offset: offset - 1, size: 3 })
+            });
+        mock.expect_snapshot()
+            .times(1)
+            .returning(|| Snapshot { xmax: 10 });
+        mock.expect_read_with_offset()
+            .times(2)
+            .returning(move |_| Ok(Row {
+                header: Header {
+                    xmin: 0,
+                    cmax: NONE_SENTINEL,
+                    xmax: NONE_SENTINEL,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                },
+                data: vec![],
+            }));
+        mock.expect_write_batch()
+            .with(predicate::eq(expected_writes), predicate::eq(10_u64), predicate::eq(NEVER_EXPIRES))
+            .times(1)
+            .returning(|_, _, _| Ok(vec![OffsetSize { offset: 10, size: 3 }, OffsetSize { offset: 7, size: 3 }]));
+        mock.expect_release_snapshot().times(1).returning(|_| ());
+
+        let dir = tempdir()?;
+        let mut lsm_tree: LsmTree<String, Vec<u8>> = LsmTree::new(Box::new(mock), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+        for i in 0..10 {
+            lsm_tree.insert(Document { id: format!("{:02}", i), value: vec![1, 2, 3] })?;
+        }
+        let mut transaction = lsm_tree.begin();
+        transaction.update(updated_document);
+        transaction.delete("07".to_string());
+        lsm_tree.commit(transaction)?;
+
+        assert_eq!(lsm_tree.store.memtable().get("05").unwrap().offset_size, OffsetSize { offset: 10, size: 3 });
+        assert!(lsm_tree.store.memtable().get("07").unwrap().is_deleted);
+        // the table keeps the old leaves, the newer ones in memory hide them
+        let ss_table = &lsm_tree.store.tables()[0];
+        assert_eq!(ss_table.get(&"05".to_string())?.unwrap().offset_size, OffsetSize { offset: 5, size: 3 });
+        assert!(!ss_table.get(&"07".to_string())?.unwrap().is_deleted);
+        assert!(lsm_tree.search(&"07".to_string()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_merges_memory_and_ss_tables() -> Result<()> {
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        let mut offset = 0;
+        mock.expect_insert()
+            .times(12)
+            .returning(move |_, _| {
+                offset += 1;
+                Ok(OffsetSize { offset: offset - 1, size: 3 })
+            });
+        mock.expect_delete_with_offset()
+            .with(predicate::eq(&OffsetSize { offset: 5, size: 3 }), predicate::always())
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock.expect_read_with_offset()
+            .returning(move |offset_size| Ok(Row {
+                header: Header {
+                    xmin: 0,
+                    cmax: NONE_SENTINEL,
+                    xmax: NONE_SENTINEL,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                },
+                data: bincode::serialize(&Document { id: format!("{:02}", offset_size.offset), value: vec![1_u8] }).unwrap(),
+            }));
+
+        let dir = tempdir()?;
+        let mut lsm_tree: LsmTree<String, Vec<u8>> = LsmTree::new(Box::new(mock), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+        for i in 0..12 {
+            lsm_tree.insert(Document { id: format!("{:02}", i), value: vec![1] })?;
+        }
+        lsm_tree.delete(&"05".to_string())?;
+
+        let documents = lsm_tree.scan((Bound::Included("04".to_string()), Bound::Unbounded), Some(6))?;
+
+        let ids: Vec<String> = documents.into_iter().map(|document| document.id).collect();
+        assert_eq!(ids, vec!["04", "06", "07", "08", "09", "10"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_reopens_tables_and_memtable_without_reading_rows() -> Result<()> {
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        let mut offset = 0;
+        mock.expect_insert()
+            .times(15)
+            .returning(move |_, _| {
+                offset += 1;
+                Ok(OffsetSize { offset, size: 3 })
+            });
+
+        let dir = tempdir()?;
+        let mut lsm_tree: LsmTree<String, Vec<u8>> = LsmTree::new(Box::new(mock), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+        for i in 0..15 {
+            lsm_tree.insert(Document { id: format!("{:02}", i), value: vec![1, 2, 3] })?;
+        }
+        drop(lsm_tree);
+
+        let mut mock = MockDbOperationsImpl::new();
+        mock.expect_read_page().times(0);
+        let lsm_tree: LsmTree<String, Vec<u8>> = LsmTree::new(Box::new(mock), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+
+        assert_eq!(lsm_tree.store.memtable().len(), 5);
+        for i in 0..15 {
+            assert_eq!(lsm_tree.live(&format!("{:02}", i))?, Some(OffsetSize { offset: i + 1, size: 3 }));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_rebuilds_tree_that_missed_a_write() -> Result<()> {
+        let document = Document { id: "1".to_string(), value: vec![1, 2, 3] };
+        let data = bincode::serialize(&document)?;
+
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        // the row is written, but the leaf never reaches the log
+        mock.expect_insert()
+            .times(1)
+            .returning(move |_, _| Err(anyhow::anyhow!("crash")));
+
+        let dir = tempdir()?;
+        let mut lsm_tree: LsmTree<String, Vec<u8>> = LsmTree::new(Box::new(mock), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+        assert!(lsm_tree.insert(document.clone()).is_err());
+        drop(lsm_tree);
+
+        let mut mock = MockDbOperationsImpl::new();
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch {
+            rows: vec![(OffsetSize { offset: 0, size: 3 }, Row {
+                header: Header {
+                    xmin: 0,
+                    cmax: NONE_SENTINEL,
+                    xmax: NONE_SENTINEL,
+                    tuple_length: 3,
+                    expires_at: 0,
+                    ctid: NONE_SENTINEL,
+                    cmin: 0,
+                },
+                data: data.clone(),
+            })],
+            next_page_id: None,
+        }));
+        let lsm_tree: LsmTree<String, Vec<u8>> = LsmTree::new(Box::new(mock), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+
+        assert_eq!(lsm_tree.live(&document.id)?, Some(OffsetSize { offset: 0, size: 3 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_merges_full_level_into_next_level() -> Result<()> {
+        let mut mock = MockDbOperationsImpl::new();
+        expect_begin(&mut mock);
+        mock.expect_read_page().times(1).returning(move |_| Ok(RowBatch::default()));
+        let mut offset = 0;
+        mock.expect_insert()
+            .times(40)
+            .returning(move |_, _| {
+                offset += 1;
+                Ok(OffsetSize { offset: offset - 1, size: 3 })
+            });
+        mock.expect_delete_with_offset()
+            .with(predicate::eq(&OffsetSize { offset: 5, size: 3 }), predicate::always())
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let (mut lsm_tree, dir) = setup_lsm_tree::<String, Vec<u8>>(mock)?;
+        for i in 0..30 {
+            lsm_tree.insert(Document { id: format!("{:02}", i), value: vec![1] })?;
+        }
+        // the deleted leaf of the newest table has to hide the live one of the oldest table
+        lsm_tree.delete(&"05".to_string())?;
+        for i in 30..40 {
+            lsm_tree.insert(Document { id: format!("{:02}", i), value: vec![1] })?;
+        }
+
+        lsm_tree.compact()?;
+
+        let tables = lsm_tree.store.tables();
+        assert_eq!(tables.len(), 1);
+        // "00" to "38" with "05" only once, "39" is still in memory
+        assert_eq!(tables[0].len(), 39);
+        drop(tables);
+        assert!(lsm_tree.store.get(&"05".to_string())?.unwrap().is_deleted);
+        assert_eq!(lsm_tree.live(&"39".to_string())?, Some(OffsetSize { offset: 39, size: 3 }));
+        // only the merged table is left once the replaced ones are dropped
+        drop(lsm_tree);
+        let tables = fs::read_dir(dir.path())?
+            .filter(|entry| entry.as_ref().is_ok_and(|entry| ss_table_id(&entry.path()).is_some()))
+            .count();
+        assert_eq!(tables, 1);
+
+        Ok(())
+    }
+
+    fn setup_lsm_tree<K, V>(mock_db_operations_impl: MockDbOperationsImpl) -> Result<(LsmTree<K, V>, TempDir)>
+        where K: Serialize + DeserializeOwned + Hash + Eq + std::convert::AsRef<[u8]> + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync + 'static, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync
     {
         let dir = tempdir()?;
-        let path = dir.path().to_path_buf();
         let db_operations = Box::new(mock_db_operations_impl);
-        LsmTree::new(db_operations, path.to_str().unwrap().to_string(), 10, 100)
+        let lsm_tree = LsmTree::new(db_operations, dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+        Ok((lsm_tree, dir))
+    }
+
+    fn expect_begin(mock: &mut MockDbOperationsImpl) {
+        let mut transaction_id = 0;
+        mock.expect_begin().returning(move || {
+            transaction_id += 1;
+            Ok(transaction_id - 1)
+        });
     }
 }
@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::Bound;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+#[cfg(test)]
+use mockall::mock;
+use storageengine::backup::{BackupManifest, WalArchive};
+use storageengine::file_handler::FileHandler;
+use storageengine::file_header::FileHeader;
+use storageengine::operations::DbOperations;
+use storageengine::transaction::Snapshot;
+
+use crate::codec::{Codec, CodecKind};
+use crate::index::{Change, Document, Index, IndexError, is_before_end, is_empty_range, pin_version, resolve_writes, StagedWrite, Transaction};
+use crate::lsm_store::{LsmEntry, LsmStore};
+
+// an lsm tree that keeps the documents in its memtable and tables, the data file is not used.
+// `db_operations` only hands out the transaction ids and snapshots
+pub struct LsmValueTree<K, V> {
+    store: LsmStore<K, Versions>,
+    db_operations: Box<dyn DbOperations>,
+    // xmax of every snapshot handed out and not released yet, and how often it was taken
+    snapshots: BTreeMap<u64, usize>,
+    codec: CodecKind,
+    phantom: PhantomData<(K, V)>,
+}
+
+// a version of a document written by transaction `xmin`, without a value if the document was deleted
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct Version {
+    xmin: u64,
+    value: Option<Vec<u8>>,
+}
+
+// the versions of a document from newest to oldest, older ones are kept as long as a snapshot might see them
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct Versions(Vec<Version>);
+
+impl Versions {
+    fn newest(&self) -> Option<&Version> {
+        self.0.first()
+    }
+
+    fn live(&self) -> Option<&Vec<u8>> {
+        self.newest().and_then(|version| version.value.as_ref())
+    }
+
+    fn visible(&self, snapshot: &Snapshot) -> Option<&Version> {
+        self.0.iter().find(|version| version.xmin < snapshot.xmax)
+    }
+
+    fn push(&mut self, version: Version, horizon: u64) {
+        self.0.insert(0, version);
+        self.prune(horizon);
+    }
+
+    // every snapshot in use sees the newest version older than `horizon` or a newer one, so the versions
+    // before that one can go
+    fn prune(&mut self, horizon: u64) {
+        if let Some(position) = self.0.iter().position(|version| version.xmin < horizon) {
+            self.0.truncate(position + 1);
+        }
+    }
+}
+
+impl LsmEntry for Versions {
+    const KIND: &'static str = "values";
+
+    fn compact(mut self, horizon: u64, bottom: bool) -> Option<Self> {
+        self.prune(horizon);
+        // a delete no snapshot can see past, and no older table is left that it has to hide versions in
+        let dead = bottom && self.0.len() == 1 && self.0[0].value.is_none() && self.0[0].xmin < horizon;
+        (!dead).then_some(self)
+    }
+}
+
+impl<K, V> LsmValueTree<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::convert::AsRef<[u8]> + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync + 'static, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
+    // `ss_table_path` is the directory of the tables and the log of the memtable, which hold all documents
+    pub fn new(db_operations: Box<dyn DbOperations>, ss_table_path: String, tree_size: usize, bloom_filter_size: usize, codec: CodecKind) -> Result<Self> {
+        // nothing is written before the log, so there is no change the store could have missed
+        let (mut store, _) = LsmStore::open(&ss_table_path, tree_size, bloom_filter_size)?;
+        store.start_compaction();
+
+        Ok(Self {
+            store,
+            db_operations,
+            snapshots: BTreeMap::new(),
+            codec,
+            phantom: PhantomData,
+        })
+    }
+
+    // merges every full level right away instead of waiting for the background compaction
+    pub fn compact(&self) -> Result<()> {
+        self.store.compact()
+    }
+
+    // xmax of the oldest snapshot in use
+    fn horizon(&self) -> u64 {
+        self.snapshots.keys().next().copied().unwrap_or(u64::MAX)
+    }
+
+    fn live(&self, id: &K) -> Result<Option<Vec<u8>>> {
+        Ok(self.store.get(id)?.and_then(|versions| versions.live().cloned()))
+    }
+
+    fn document(&self, id: K, value: &[u8]) -> Result<Document<K, V>> {
+        Ok(Document { id, value: self.codec.decode(value)? })
+    }
+
+    // adds a version of every document in one transaction, a version without value deletes the document
+    fn write(&mut self, changes: Vec<(K, Option<Vec<u8>>)>) -> Result<()> {
+        self.store.check_compaction()?;
+        let transaction_id = self.db_operations.begin()?;
+        let horizon = self.horizon();
+
+        let mut entries = Vec::with_capacity(changes.len());
+        for (id, value) in changes {
+            let mut versions = self.store.get(&id)?.unwrap_or_default();
+            versions.push(Version { xmin: transaction_id, value }, horizon);
+            entries.push((id, versions));
+        }
+
+        self.store.add(entries)
+    }
+
+    fn apply_writes(&mut self, writes: Vec<StagedWrite<K, V>>, snapshot: &Snapshot) -> Result<()> {
+        // every version written after the snapshot was taken has an id of at least `snapshot.xmax`
+        for write in &writes {
+            if let Some(version) = self.store.get(write.id())?.as_ref().and_then(Versions::newest) {
+                if version.xmin >= snapshot.xmax {
+                    return Err(IndexError::Conflict.into());
+                }
+            }
+        }
+
+        let changes = resolve_writes(writes, |id| Ok(self.live(id)?.is_some()))?;
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let changes = changes.into_iter()
+            .map(|change| Ok(match change {
+                Change::Insert(document) | Change::Update(document) => (document.id, Some(self.codec.encode(&document.value)?)),
+                Change::Delete(id) => (id, None),
+            }))
+            .collect::<Result<_>>()?;
+        self.write(changes)
+    }
+}
+
+impl<K, V> Index<K, V> for LsmValueTree<K, V> where K: Serialize + DeserializeOwned + Hash + Eq + std::convert::AsRef<[u8]> + Clone + std::cmp::Ord + std::marker::Send + std::marker::Sync + 'static, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
+    fn insert(&mut self, document: Document<K, V>) -> Result<()> {
+        if self.live(&document.id)?.is_some() {
+            return Err(IndexError::AlreadyExists.into());
+        }
+
+        let value = self.codec.encode(&document.value)?;
+        self.write(vec![(document.id, Some(value))])
+    }
+
+    fn search(&mut self, id: &K) -> Result<Document<K, V>> {
+        let value = self.live(id)?.ok_or(IndexError::NotFound)?;
+        self.document(id.clone(), &value)
+    }
+
+    fn version(&mut self, id: &K) -> Result<u64> {
+        let versions = self.store.get(id)?.ok_or(IndexError::NotFound)?;
+        match versions.newest() {
+            Some(version) if version.value.is_some() => Ok(version.xmin + 1),
+            _ => Err(IndexError::NotFound.into()),
+        }
+    }
+
+    fn pin_version(&mut self, version: Option<u64>) -> Result<()> {
+        pin_version(self.db_operations.as_mut(), version)
+    }
+
+    fn delete(&mut self, id: &K) -> Result<()> {
+        if self.live(id)?.is_none() {
+            return Err(IndexError::NotFound.into());
+        }
+
+        self.write(vec![(id.clone(), None)])
+    }
+
+    fn update(&mut self, id: &K, document: Document<K, V>) -> Result<()> {
+        if self.live(id)?.is_none() {
+            return Err(IndexError::NotFound.into());
+        }
+
+        let value = self.codec.encode(&document.value)?;
+        self.write(vec![(document.id, Some(value))])
+    }
+
+    fn scan(&mut self, range: (Bound<K>, Bound<K>), limit: Option<usize>) -> Result<Vec<Document<K, V>>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+
+        let limit = limit.unwrap_or(usize::MAX);
+        let tables = self.store.tables();
+        let mut documents = Vec::new();
+        for entry in self.store.merged(&tables, range.0.as_ref())? {
+            let (id, versions) = entry?;
+            if !is_before_end(&id, &range.1) || documents.len() >= limit {
+                break;
+            }
+            if let Some(value) = versions.live() {
+                documents.push(self.document(id, value)?);
+            }
+        }
+
+        Ok(documents)
+    }
+
+    fn search_at(&mut self, id: &K, snapshot: &Snapshot) -> Result<Document<K, V>> {
+        let versions = self.store.get(id)?.ok_or(IndexError::NotFound)?;
+        match versions.visible(snapshot).and_then(|version| version.value.as_ref()) {
+            Some(value) => self.document(id.clone(), value),
+            None => Err(IndexError::NotFound.into()),
+        }
+    }
+
+    fn snapshot(&mut self) -> Snapshot {
+        let snapshot = self.db_operations.snapshot();
+        *self.snapshots.entry(snapshot.xmax).or_default() += 1;
+        self.store.set_horizon(self.horizon());
+
+        snapshot
+    }
+
+    fn release_snapshot(&mut self, snapshot: Snapshot) {
+        if let Some(count) = self.snapshots.get_mut(&snapshot.xmax) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&snapshot.xmax);
+            }
+        }
+        self.store.set_horizon(self.horizon());
+        self.db_operations.release_snapshot(&snapshot)
+    }
+
+    fn commit(&mut self, transaction: Transaction<K, V>) -> Result<()> {
+        let (snapshot, writes) = transaction.into_parts();
+        let result = self.apply_writes(writes, &snapshot);
+        self.release_snapshot(snapshot);
+
+        result
+    }
+
+    // there are no rows to compact, but old versions and deletes no snapshot can see anymore are dropped
+    fn vacuum(&mut self) -> Result<()> {
+        let horizon = self.horizon();
+        self.store.rewrite(|_, versions| Ok(versions.compact(horizon, true)))
+    }
+
+    // the documents are in the store, its log is synced on every write. only the transaction ids are in the data file
+    fn sync(&mut self) -> Result<()> {
+        self.db_operations.sync()
+    }
+
+    fn backup(&mut self, _target: &mut dyn FileHandler, _archive: Option<WalArchive>) -> Result<BackupManifest> {
+        bail!("the LSMValueTree index engine keeps the documents in its tables, they are not in a backup of the storage file")
+    }
+
+    fn header(&self) -> FileHeader {
+        self.db_operations.header()
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.db_operations.set_header(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use anyhow::Result;
+    use storageengine::operations::{OffsetSize, Row, RowBatch, RowWrite};
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+
+    mock! {
+        DbOperationsImpl {}
+        impl DbOperations for DbOperationsImpl {
+            fn insert(&mut self, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
+            fn read_with_offset(&mut self, offset_size: &OffsetSize) -> Result<Row>;
+            fn read_page(&mut self, page_id: u64) -> Result<RowBatch>;
+            fn update_with_offset(&mut self, old_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
+            fn delete_with_offset(&mut self, offset_size: &OffsetSize, transaction_id: u64) -> Result<()>;
+            fn reinsert_with_offset(&mut self, deleted_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
+            fn read_visible(&mut self, offset_size: &OffsetSize, snapshot: &Snapshot) -> Result<Option<Row>>;
+            fn write_batch(&mut self, writes: Vec<RowWrite>, transaction_id: u64, expires_at: u64) -> Result<Vec<OffsetSize>>;
+            fn begin(&mut self) -> Result<u64>;
+            fn pin_transaction_id(&mut self, transaction_id: Option<u64>) -> Result<()>;
+            fn snapshot(&mut self) -> Snapshot;
+            fn release_snapshot(&mut self, snapshot: &Snapshot);
+            fn vacuum(&mut self) -> Result<HashMap<u64, OffsetSize>>;
+            fn sync(&mut self) -> Result<()>;
+            fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest>;
+            fn header(&self) -> FileHeader;
+            fn set_header(&mut self, header: FileHeader) -> Result<()>;
+        }
+    }
+
+    // only transactions and snapshots are expected, the data file must not be touched
+    fn transactions_only() -> MockDbOperationsImpl {
+        let next_transaction_id = Arc::new(AtomicU64::new(0));
+        let mut mock = MockDbOperationsImpl::new();
+        let begin_transaction_id = next_transaction_id.clone();
+        mock.expect_begin().returning(move || Ok(begin_transaction_id.fetch_add(1, Ordering::SeqCst)));
+        mock.expect_snapshot().returning(move || Snapshot { xmax: next_transaction_id.load(Ordering::SeqCst) });
+        mock.expect_release_snapshot().returning(|_| ());
+        mock
+    }
+
+    fn setup_lsm_value_tree() -> Result<(LsmValueTree<String, Vec<u8>>, TempDir)> {
+        let dir = tempdir()?;
+        let lsm_value_tree = LsmValueTree::new(Box::new(transactions_only()), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+        Ok((lsm_value_tree, dir))
+    }
+
+    #[test]
+    fn insert_keeps_documents_in_tables() -> Result<()> {
+        let (mut lsm_value_tree, _dir) = setup_lsm_value_tree()?;
+
+        for i in 0..15 {
+            lsm_value_tree.insert(Document { id: format!("{:02}", i), value: vec![i] })?;
+        }
+
+        assert_eq!(lsm_value_tree.store.tables().len(), 1);
+        assert_eq!(lsm_value_tree.search(&"03".to_string())?, Document { id: "03".to_string(), value: vec![3] });
+        assert_eq!(lsm_value_tree.search(&"12".to_string())?, Document { id: "12".to_string(), value: vec![12] });
+        assert!(lsm_value_tree.insert(Document { id: "03".to_string(), value: vec![0] }).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_encodes_values_with_codec() -> Result<()> {
+        let dir = tempdir()?;
+        let mut lsm_value_tree: LsmValueTree<String, Vec<u8>> = LsmValueTree::new(Box::new(transactions_only()), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Json)?;
+
+        lsm_value_tree.insert(Document { id: "1".to_string(), value: vec![1, 2] })?;
+
+        let versions = lsm_value_tree.store.get(&"1".to_string())?.unwrap();
+        assert_eq!(versions.live(), Some(&b"[1,2]".to_vec()));
+        assert_eq!(lsm_value_tree.search(&"1".to_string())?.value, vec![1, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_reopens_documents() -> Result<()> {
+        let dir = tempdir()?;
+        let mut lsm_value_tree: LsmValueTree<String, Vec<u8>> = LsmValueTree::new(Box::new(transactions_only()), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+        for i in 0..15 {
+            lsm_value_tree.insert(Document { id: format!("{:02}", i), value: vec![i] })?;
+        }
+        lsm_value_tree.delete(&"13".to_string())?;
+        drop(lsm_value_tree);
+
+        let mut lsm_value_tree: LsmValueTree<String, Vec<u8>> = LsmValueTree::new(Box::new(transactions_only()), dir.path().to_str().unwrap().to_string(), 10, 100, CodecKind::Bincode)?;
+
+        assert_eq!(lsm_value_tree.search(&"04".to_string())?.value, vec![4]);
+        assert_eq!(lsm_value_tree.search(&"14".to_string())?.value, vec![14]);
+        assert!(lsm_value_tree.search(&"13".to_string()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn search_at_reads_version_of_snapshot() -> Result<()> {
+        let (mut lsm_value_tree, _dir) = setup_lsm_value_tree()?;
+        lsm_value_tree.insert(Document { id: "1".to_string(), value: vec![1] })?;
+        let snapshot = lsm_value_tree.snapshot();
+        lsm_value_tree.update(&"1".to_string(), Document { id: "1".to_string(), value: vec![2] })?;
+        lsm_value_tree.delete(&"1".to_string())?;
+
+        assert!(lsm_value_tree.search(&"1".to_string()).is_err());
+        assert_eq!(lsm_value_tree.search_at(&"1".to_string(), &snapshot)?.value, vec![1]);
+
+        // without the snapshot only the delete is left, and vacuum drops it
+        lsm_value_tree.release_snapshot(snapshot);
+        lsm_value_tree.vacuum()?;
+        assert_eq!(lsm_value_tree.store.get(&"1".to_string())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_drops_versions_no_snapshot_can_see() -> Result<()> {
+        let (mut lsm_value_tree, _dir) = setup_lsm_value_tree()?;
+        lsm_value_tree.insert(Document { id: "1".to_string(), value: vec![1] })?;
+        for value in 2..5 {
+            lsm_value_tree.update(&"1".to_string(), Document { id: "1".to_string(), value: vec![value] })?;
+        }
+
+        assert_eq!(lsm_value_tree.store.get(&"1".to_string())?.unwrap().0.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_drops_deleted_documents() -> Result<()> {
+        let (mut lsm_value_tree, _dir) = setup_lsm_value_tree()?;
+        for i in 0..30 {
+            lsm_value_tree.insert(Document { id: format!("{:02}", i), value: vec![i] })?;
+        }
+        lsm_value_tree.delete(&"05".to_string())?;
+        for i in 30..39 {
+            lsm_value_tree.insert(Document { id: format!("{:02}", i), value: vec![i] })?;
+        }
+
+        lsm_value_tree.compact()?;
+
+        let tables = lsm_value_tree.store.tables();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].len(), 38);
+        assert_eq!(lsm_value_tree.store.get(&"05".to_string())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn commit_fails_if_key_was_written_after_snapshot() -> Result<()> {
+        let (mut lsm_value_tree, _dir) = setup_lsm_value_tree()?;
+        lsm_value_tree.insert(Document { id: "1".to_string(), value: vec![1] })?;
+
+        let mut transaction = lsm_value_tree.begin();
+        transaction.update(Document { id: "1".to_string(), value: vec![2] });
+        lsm_value_tree.update(&"1".to_string(), Document { id: "1".to_string(), value: vec![3] })?;
+        let result = lsm_value_tree.commit(transaction);
+
+        assert!(matches!(result.unwrap_err().downcast_ref::<IndexError>(), Some(IndexError::Conflict)));
+        assert_eq!(lsm_value_tree.search(&"1".to_string())?.value, vec![3]);
+        assert!(lsm_value_tree.snapshots.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_skips_deleted_documents() -> Result<()> {
+        let (mut lsm_value_tree, _dir) = setup_lsm_value_tree()?;
+        for i in 0..12 {
+            lsm_value_tree.insert(Document { id: format!("{:02}", i), value: vec![i] })?;
+        }
+        lsm_value_tree.delete(&"05".to_string())?;
+
+        let documents = lsm_value_tree.scan((Bound::Included("04".to_string()), Bound::Excluded("09".to_string())), None)?;
+
+        let ids: Vec<String> = documents.into_iter().map(|document| document.id).collect();
+        assert_eq!(ids, vec!["04", "06", "07", "08"]);
+
+        Ok(())
+    }
+}
@@ -1,95 +1,252 @@
 use std::hash::Hash;
-use crate::index::{Document, Index, IndexError};
+use std::ops::{Bound, RangeBounds};
+
+use crate::codec::{Codec, CodecKind};
+use crate::index::{Change, check_conflict, check_update_id, Document, expired_ids, Index, IndexError, pin_version, resolve_writes, row_expires_at, row_version, StagedWrite, Transaction};
 use anyhow::Result;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use storageengine::backup::{BackupManifest, WalArchive};
+use storageengine::file_handler::FileHandler;
+use storageengine::file_header::FileHeader;
+use storageengine::operations::{NEVER_EXPIRES, NONE_SENTINEL, OffsetSize, RowIterator, RowWrite, unix_millis};
+use storageengine::transaction::Snapshot;
+
+// a live document, where it is stored and whether it expired
+type Found<K, V> = (OffsetSize, Document<K, V>, bool);
 
 pub struct NoIndex {
     db_operations: Box<dyn storageengine::operations::DbOperations>,
-    transaction_id: u64,
+    codec: CodecKind,
 }
 
 impl NoIndex {
-    pub fn new(db_operations: Box<dyn storageengine::operations::DbOperations>) -> Self {
+    pub fn new(db_operations: Box<dyn storageengine::operations::DbOperations>, codec: CodecKind) -> Self {
         Self {
             db_operations,
-            transaction_id: 0,
+            codec,
+        }
+    }
+
+    // scans the table for the live version of the document with the given id and whether it expired
+    fn find<K, V>(&mut self, id: &K) -> Result<Option<Found<K, V>>> where K: DeserializeOwned + Eq, V: DeserializeOwned {
+        let now = unix_millis();
+        for row in RowIterator::new(self.db_operations.as_mut()) {
+            let (offset_size, row) = row?;
+            // deleted or replaced by a newer version
+            if row.header.xmax != NONE_SENTINEL {
+                continue;
+            }
+
+            let doc: Document<K, V> = self.codec.decode(&row.data)?;
+            if doc.id == *id {
+                return Ok(Some((offset_size, doc, row.header.is_expired(now))));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn live_offset_size<K, V>(&mut self, id: &K) -> Result<OffsetSize> where K: DeserializeOwned + Eq, V: DeserializeOwned {
+        match self.find::<K, V>(id)? {
+            Some((offset_size, _, _)) => Ok(offset_size),
+            None => Err(IndexError::NotFound.into()),
         }
     }
+
+    // without an index deleted documents are not tracked, so only writes to live documents conflict
+    fn apply_writes<K, V>(&mut self, writes: Vec<StagedWrite<K, V>>, snapshot: &Snapshot, expires_at: u64) -> Result<()> where K: Serialize + DeserializeOwned + Eq, V: Serialize + DeserializeOwned {
+        for write in &writes {
+            if let Some((offset_size, _, _)) = self.find::<K, V>(write.id())? {
+                check_conflict(self.db_operations.as_mut(), &offset_size, snapshot)?;
+            }
+        }
+
+        let changes = resolve_writes(writes, |id| Ok(self.find::<K, V>(id)?.is_some()))?;
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let mut row_writes = Vec::new();
+        for change in &changes {
+            row_writes.push(match change {
+                Change::Insert(document) => RowWrite::Insert { data: self.codec.encode(document)? },
+                Change::Update(document) => RowWrite::Update { old_offset_size: self.live_offset_size::<K, V>(&document.id)?, data: self.codec.encode(document)? },
+                Change::Delete(id) => RowWrite::Delete { offset_size: self.live_offset_size::<K, V>(id)? },
+            });
+        }
+        let transaction_id = self.db_operations.begin()?;
+        self.db_operations.write_batch(row_writes, transaction_id, expires_at)?;
+
+        Ok(())
+    }
+
+    // a single write that expires, the snapshot is taken right before it so it never conflicts
+    fn write_expiring<K, V>(&mut self, write: StagedWrite<K, V>, expires_at: u64) -> Result<()> where K: Serialize + DeserializeOwned + Eq, V: Serialize + DeserializeOwned {
+        let snapshot = self.db_operations.snapshot();
+        let result = self.apply_writes(vec![write], &snapshot, expires_at);
+        self.db_operations.release_snapshot(&snapshot);
+
+        result
+    }
 }
 
-impl<K, V> Index<K, V> for NoIndex where K: Serialize + DeserializeOwned + Hash + Eq + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
+impl<K, V> Index<K, V> for NoIndex where K: Serialize + DeserializeOwned + Hash + Eq + std::cmp::Ord + std::marker::Send + std::marker::Sync, V: Serialize + DeserializeOwned + std::marker::Send + std::marker::Sync {
     fn insert(&mut self, document: Document<K, V>) -> Result<()> {
-        if <NoIndex as Index<K, V>>::search(self, &document.id).is_ok() {
+        // an expired document is hidden from `search`, but it is still there
+        if self.find::<K, V>(&document.id)?.is_some() {
             return Err(IndexError::AlreadyExists.into());
         }
 
-        let data = bincode::serialize(&document)?;
-        self.db_operations.insert(data, self.transaction_id)?;
-        self.transaction_id += 1;
+        let data = self.codec.encode(&document)?;
+        let transaction_id = self.db_operations.begin()?;
+        self.db_operations.insert(data, transaction_id)?;
         Ok(())
     }
 
     fn search(&mut self, id: &K) -> Result<Document<K, V>> {
-        let rows = self.db_operations.read_all()?;
-        for row in rows {
-            let doc: Document<K, V> = bincode::deserialize(&row.data)?;
-            if doc.id == *id {
-                return Ok(doc);
-            }
+        match self.find(id)? {
+            Some((_, doc, false)) => Ok(doc),
+            _ => Err(IndexError::NotFound.into()),
         }
-
-        Err(IndexError::NotFound.into())
     }
 
     fn delete(&mut self, id: &K) -> Result<()> {
-        let rows = self.db_operations.read_all()?;
-        let mut offset_size = storageengine::operations::OffsetSize {
-            offset: 0,
-            size: 0,
+        let offset_size = match self.find::<K, V>(id)? {
+            Some((offset_size, _, _)) => offset_size,
+            None => return Err(IndexError::NotFound.into()),
         };
 
-        for row in rows {
-            offset_size.size = row.header.tuple_length;
-            let doc: Document<K, V> = bincode::deserialize(&row.data)?;
-            if doc.id == *id {
-                match self.db_operations.delete_with_offset(&offset_size, self.transaction_id) {
-                    Ok(_) => {
-                        self.transaction_id += 1;
-                        return Ok(());
-                    },
-                    Err(e) => return Err(e),
-                }
+        let transaction_id = self.db_operations.begin()?;
+        self.db_operations.delete_with_offset(&offset_size, transaction_id)?;
+        Ok(())
+    }
+
+    fn update(&mut self, id: &K, document: Document<K, V>) -> Result<()> {
+        let offset_size = match self.find::<K, V>(id)? {
+            Some((offset_size, _, _)) => offset_size,
+            None => return Err(IndexError::NotFound.into()),
+        };
+
+        let data = self.codec.encode(&document)?;
+        let transaction_id = self.db_operations.begin()?;
+        self.db_operations.update_with_offset(&offset_size, data, transaction_id)?;
+        Ok(())
+    }
+
+    fn scan(&mut self, range: (Bound<K>, Bound<K>), limit: Option<usize>) -> Result<Vec<Document<K, V>>> {
+        let now = unix_millis();
+        let mut documents = Vec::new();
+        for row in RowIterator::new(self.db_operations.as_mut()) {
+            let (_, row) = row?;
+            // deleted or replaced by a newer version
+            if row.header.xmax != NONE_SENTINEL || row.header.is_expired(now) {
+                continue;
+            }
+
+            let doc: Document<K, V> = self.codec.decode(&row.data)?;
+            if range.contains(&doc.id) {
+                documents.push(doc);
             }
-            offset_size.offset += row.header.tuple_length;
         }
 
-        Err(IndexError::NotFound.into())
+        documents.sort_by(|a, b| a.id.cmp(&b.id));
+        documents.truncate(limit.unwrap_or(usize::MAX));
+        Ok(documents)
     }
 
-    fn update(&mut self, id: &K, document: Document<K, V>) -> Result<()> {
-        let rows = self.db_operations.read_all()?;
-        let data = bincode::serialize(&document)?;
-        let mut offset_size = storageengine::operations::OffsetSize {
-            offset: 0,
-            size: 0,
-        };
+    fn search_at(&mut self, id: &K, snapshot: &Snapshot) -> Result<Document<K, V>> {
+        for row in RowIterator::new(self.db_operations.as_mut()) {
+            let (_, row) = row?;
+            if !snapshot.is_visible(&row.header) {
+                continue;
+            }
 
-        for row in rows {
-            offset_size.size = row.header.tuple_length;
-            let doc: Document<K, V> = bincode::deserialize(&row.data)?;
-            if doc.id == *id {
-                match self.db_operations.update_with_offset(&offset_size, data, self.transaction_id) {
-                    Ok(_) => {
-                        self.transaction_id += 1;
-                        return Ok(());
-                    },
-                    Err(e) => return Err(e),
-                }
+            let doc: Document<K, V> = self.codec.decode(&row.data)?;
+            if doc.id == *id && !row.header.is_expired(unix_millis()) {
+                return Ok(doc);
             }
-            offset_size.offset += row.header.tuple_length;
         }
 
         Err(IndexError::NotFound.into())
     }
+
+    fn snapshot(&mut self) -> Snapshot {
+        self.db_operations.snapshot()
+    }
+
+    fn release_snapshot(&mut self, snapshot: Snapshot) {
+        self.db_operations.release_snapshot(&snapshot)
+    }
+
+    fn commit(&mut self, transaction: Transaction<K, V>) -> Result<()> {
+        let (snapshot, writes) = transaction.into_parts();
+        let result = self.apply_writes(writes, &snapshot, NEVER_EXPIRES);
+        self.db_operations.release_snapshot(&snapshot);
+
+        result
+    }
+
+    fn vacuum(&mut self) -> Result<()> {
+        self.db_operations.vacuum()?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.db_operations.sync()
+    }
+
+    fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest> {
+        self.db_operations.backup(target, archive)
+    }
+
+    fn header(&self) -> FileHeader {
+        self.db_operations.header()
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.db_operations.set_header(header)
+    }
+
+    fn insert_expiring(&mut self, document: Document<K, V>, expires_at: u64) -> Result<()> {
+        self.write_expiring(StagedWrite::Insert(document), expires_at)
+    }
+
+    fn update_expiring(&mut self, id: &K, document: Document<K, V>, expires_at: u64) -> Result<()> {
+        check_update_id(id, &document)?;
+        self.write_expiring(StagedWrite::Update(document), expires_at)
+    }
+
+    fn can_expire(&self) -> bool {
+        true
+    }
+
+    fn expired(&mut self, now: u64, limit: usize) -> Result<Vec<K>> {
+        expired_ids::<K, V>(self.db_operations.as_mut(), self.codec, now, limit)
+    }
+
+    fn search_including_expired(&mut self, id: &K) -> Result<Document<K, V>> {
+        match self.find(id)? {
+            Some((_, doc, _)) => Ok(doc),
+            None => Err(IndexError::NotFound.into()),
+        }
+    }
+
+    fn version(&mut self, id: &K) -> Result<u64> {
+        match self.find::<K, V>(id)? {
+            Some((offset_size, _, false)) => row_version(self.db_operations.as_mut(), &offset_size),
+            _ => Err(IndexError::NotFound.into()),
+        }
+    }
+
+    fn pin_version(&mut self, version: Option<u64>) -> Result<()> {
+        pin_version(self.db_operations.as_mut(), version)
+    }
+
+    fn expires_at(&mut self, id: &K) -> Result<u64> {
+        match self.find::<K, V>(id)? {
+            Some((offset_size, _, _)) => row_expires_at(self.db_operations.as_mut(), &offset_size),
+            None => Err(IndexError::NotFound.into()),
+        }
+    }
 }
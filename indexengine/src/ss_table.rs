@@ -0,0 +1,514 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use bloomfilter::BloomFilter;
+
+// a block is closed once its entries take at least that many bytes
+const BLOCK_SIZE: usize = 4096;
+// bits of the bloom filter per entry, so big tables don't end up with a full filter
+const BLOOM_BITS_PER_ENTRY: usize = 10;
+// crc32c in front of every block and section
+const CHECKSUM_SIZE: u64 = 4;
+const FOOTER_SIZE: u64 = 7 * 8;
+// the last bytes of a completely written table
+const MAGIC: u64 = 0x5353_5461_626c_6533;
+// tables written before the bloom filter had its own format, their filters can't be checked anymore
+const OLD_MAGICS: [u64; 2] = [0x5353_5461_626c_6531, 0x5353_5461_626c_6532];
+
+pub fn ss_table_file_name(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", id))
+}
+
+// returns the id of a file named by `ss_table_file_name`
+pub fn ss_table_id(path: &Path) -> Option<u64> {
+    if path.extension()? != "sst" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+// where a block starts and the first key in it, the index has one for every block
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockHandle<K> {
+    first_key: K,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Footer {
+    index_offset: u64,
+    index_size: u64,
+    bloom_offset: u64,
+    bloom_size: u64,
+    // the filter keeps its own sizes, this is only the total for tools that read the footer
+    bloom_bits: u64,
+    len: u64,
+    magic: u64,
+}
+
+// an immutable file of sorted entries: the blocks, the block index, the bloom filter of all keys and the footer.
+// only the index and the bloom filter are kept in memory, the bloom filter is read on the first lookup and blocks
+// are read when they are needed
+pub struct SsTable<K, V> {
+    id: u64,
+    path: PathBuf,
+    index: Vec<BlockHandle<K>>,
+    // offset and size of the bloom filter, None for tables of an older format
+    bloom_section: Option<(u64, u64)>,
+    bloom_filter: OnceLock<BloomFilter>,
+    len: u64,
+    // set once the table is replaced, the file is removed when the last reader is done with it
+    obsolete: AtomicBool,
+    phantom: PhantomData<fn() -> V>,
+}
+
+impl<K, V> SsTable<K, V> where K: Serialize + DeserializeOwned + Ord + AsRef<[u8]>, V: Serialize + DeserializeOwned {
+    pub fn open(dir: &Path, id: u64) -> Result<Self> {
+        let path = ss_table_file_name(dir, id);
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_SIZE {
+            bail!("ss table {} is too short", path.display());
+        }
+
+        let mut footer = vec![0; FOOTER_SIZE as usize];
+        file.seek(SeekFrom::Start(size - FOOTER_SIZE))?;
+        file.read_exact(&mut footer)?;
+        let footer: Footer = bincode::deserialize(&footer)?;
+        if footer.magic != MAGIC && !OLD_MAGICS.contains(&footer.magic) {
+            bail!("ss table {} has no footer", path.display());
+        }
+
+        let index = bincode::deserialize(&read_section(&mut file, &path, footer.index_offset, footer.index_size)?)?;
+
+        Ok(Self {
+            id,
+            path,
+            index,
+            bloom_section: (footer.magic == MAGIC).then_some((footer.bloom_offset, footer.bloom_size)),
+            bloom_filter: OnceLock::new(),
+            len: footer.len,
+            obsolete: AtomicBool::new(false),
+            phantom: PhantomData,
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        if self.bloom_filter()?.is_some_and(|bloom_filter| !bloom_filter.check(key)) {
+            return Ok(None);
+        }
+
+        // the only block that can hold the key is the last one starting at or before it
+        let block = match self.index.partition_point(|handle| handle.first_key <= *key) {
+            0 => return Ok(None),
+            block => block - 1,
+        };
+        let entries = self.read_block(block)?;
+
+        Ok(entries.binary_search_by(|(entry_key, _)| entry_key.cmp(key))
+            .ok()
+            .map(|position| entries.into_iter().nth(position).unwrap().1))
+    }
+
+    fn bloom_filter(&self) -> Result<Option<&BloomFilter>> {
+        let Some((offset, size)) = self.bloom_section else {
+            return Ok(None);
+        };
+        if let Some(bloom_filter) = self.bloom_filter.get() {
+            return Ok(Some(bloom_filter));
+        }
+
+        let mut file = File::open(&self.path)?;
+        let bloom_filter = BloomFilter::from_bytes(&read_section(&mut file, &self.path, offset, size)?)?;
+        Ok(Some(self.bloom_filter.get_or_init(|| bloom_filter)))
+    }
+
+    // iterates over the entries in key order, starting at `from`
+    pub fn range(&self, from: Bound<&K>) -> Result<SsTableIter<'_, K, V>> {
+        let block = match from {
+            Bound::Unbounded => 0,
+            Bound::Included(key) | Bound::Excluded(key) => self.index.partition_point(|handle| handle.first_key <= *key).saturating_sub(1),
+        };
+        let mut entries = match self.index.is_empty() {
+            true => Vec::new(),
+            false => self.read_block(block)?,
+        };
+        let skip = entries.iter()
+            .take_while(|(key, _)| match from {
+                Bound::Unbounded => false,
+                Bound::Included(from) => key < from,
+                Bound::Excluded(from) => key <= from,
+            })
+            .count();
+        entries.drain(..skip);
+
+        Ok(SsTableIter {
+            table: self,
+            next_block: block + 1,
+            entries: entries.into_iter(),
+        })
+    }
+
+    // the file is removed once the table is dropped
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<(K, V)>> {
+        let handle = &self.index[block];
+        let mut file = File::open(&self.path)?;
+        Ok(bincode::deserialize(&read_section(&mut file, &self.path, handle.offset, handle.size)?)?)
+    }
+}
+
+impl<K, V> Drop for SsTable<K, V> {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            // a leftover file is removed the next time the tree is opened
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+pub struct SsTableIter<'a, K, V> {
+    table: &'a SsTable<K, V>,
+    next_block: usize,
+    entries: std::vec::IntoIter<(K, V)>,
+}
+
+impl<K, V> Iterator for SsTableIter<'_, K, V> where K: Serialize + DeserializeOwned + Ord + AsRef<[u8]>, V: Serialize + DeserializeOwned {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.next_block >= self.table.index.len() {
+                return None;
+            }
+
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => {
+                    self.next_block += 1;
+                    self.entries = entries.into_iter();
+                }
+                Err(e) => {
+                    self.next_block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// writes a table from entries added in key order, nothing is visible before `finish` synced the file
+pub struct SsTableWriter<K, V> {
+    dir: PathBuf,
+    id: u64,
+    file: BufWriter<File>,
+    offset: u64,
+    block: Vec<(K, V)>,
+    block_size: usize,
+    index: Vec<BlockHandle<K>>,
+    bloom_filter: BloomFilter,
+    len: u64,
+}
+
+impl<K, V> SsTableWriter<K, V> where K: Serialize + DeserializeOwned + Ord + AsRef<[u8]> + Clone, V: Serialize + DeserializeOwned {
+    // `expected_len` is an upper bound of the entries, the bloom filter is sized for it
+    pub fn new(dir: &Path, id: u64, expected_len: usize, bloom_filter_size: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(ss_table_file_name(dir, id))?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            id,
+            file: BufWriter::new(file),
+            offset: 0,
+            block: Vec::new(),
+            block_size: 0,
+            index: Vec::new(),
+            bloom_filter: BloomFilter::new(bloom_filter_size.max(expected_len * BLOOM_BITS_PER_ENTRY).max(1)),
+            len: 0,
+        })
+    }
+
+    pub fn add(&mut self, key: K, value: V) -> Result<()> {
+        self.bloom_filter.insert(&key);
+        self.block_size += (bincode::serialized_size(&key)? + bincode::serialized_size(&value)?) as usize;
+        self.block.push((key, value));
+        self.len += 1;
+        if self.block_size >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<SsTable<K, V>> {
+        self.write_block()?;
+
+        let index = bincode::serialize(&self.index)?;
+        let index_offset = self.offset;
+        let index_size = self.write_section(&index)?;
+        let bloom = self.bloom_filter.to_bytes();
+        let bloom_offset = self.offset;
+        let bloom_size = self.write_section(&bloom)?;
+        let footer = Footer {
+            index_offset,
+            index_size,
+            bloom_offset,
+            bloom_size,
+            bloom_bits: self.bloom_filter.size() as u64,
+            len: self.len,
+            magic: MAGIC,
+        };
+        self.file.write_all(&bincode::serialize(&footer)?)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+
+        Ok(SsTable {
+            id: self.id,
+            path: ss_table_file_name(&self.dir, self.id),
+            index: self.index,
+            bloom_section: Some((bloom_offset, bloom_size)),
+            bloom_filter: OnceLock::from(self.bloom_filter),
+            len: self.len,
+            obsolete: AtomicBool::new(false),
+            phantom: PhantomData,
+        })
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        let first_key = match self.block.first() {
+            Some((first_key, _)) => first_key.clone(),
+            None => return Ok(()),
+        };
+
+        let data = bincode::serialize(&self.block)?;
+        let offset = self.offset;
+        let size = self.write_section(&data)?;
+        self.index.push(BlockHandle { first_key, offset, size });
+        self.block.clear();
+        self.block_size = 0;
+
+        Ok(())
+    }
+
+    fn write_section(&mut self, data: &[u8]) -> Result<u64> {
+        self.file.write_all(&crc32c::crc32c(data).to_le_bytes())?;
+        self.file.write_all(data)?;
+        let size = CHECKSUM_SIZE + data.len() as u64;
+        self.offset += size;
+
+        Ok(size)
+    }
+}
+
+fn read_section(file: &mut File, path: &Path, offset: u64, size: u64) -> Result<Vec<u8>> {
+    if size < CHECKSUM_SIZE {
+        bail!("ss table {} is corrupted at {}", path.display(), offset);
+    }
+
+    let mut buf = vec![0; size as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    let data = buf.split_off(CHECKSUM_SIZE as usize);
+    if crc32c::crc32c(&data).to_le_bytes() != buf[..] {
+        bail!("ss table {} is corrupted at {}", path.display(), offset);
+    }
+
+    Ok(data)
+}
+
+// merges sorted sources into one sorted iterator, the sources are ordered from newest to oldest
+// and only the newest entry of a key is kept
+pub struct MergeIter<K, V, I> where I: Iterator<Item = Result<(K, V)>> {
+    sources: Vec<Peekable<I>>,
+}
+
+impl<K, V, I> MergeIter<K, V, I> where I: Iterator<Item = Result<(K, V)>> {
+    pub fn new(sources: Vec<I>) -> Self {
+        Self { sources: sources.into_iter().map(Iterator::peekable).collect() }
+    }
+}
+
+impl<K, V, I> Iterator for MergeIter<K, V, I> where K: Ord + Clone, I: Iterator<Item = Result<(K, V)>> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut newest: Option<(usize, K)> = None;
+        for (position, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Err(_)) => return source.next(),
+                Some(Ok((key, _))) if newest.as_ref().is_none_or(|(_, newest_key)| key < newest_key) => {
+                    newest = Some((position, key.clone()));
+                }
+                _ => {}
+            }
+        }
+
+        let (position, key) = newest?;
+        let entry = self.sources[position].next();
+        for source in &mut self.sources[position + 1..] {
+            while matches!(source.peek(), Some(Ok((older_key, _))) if *older_key == key) {
+                source.next();
+            }
+        }
+
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn write_table(dir: &Path, id: u64, entries: impl IntoIterator<Item = (String, u64)>) -> Result<SsTable<String, u64>> {
+        let mut writer = SsTableWriter::new(dir, id, 100, 100)?;
+        for (key, value) in entries {
+            writer.add(key, value)?;
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn get_finds_entries_in_every_block() -> Result<()> {
+        let dir = tempdir()?;
+        write_table(dir.path(), 1, (0..1000).map(|i| (format!("key{:04}", i), i)))?;
+
+        let table: SsTable<String, u64> = SsTable::open(dir.path(), 1)?;
+
+        assert!(table.index.len() > 1);
+        assert_eq!(table.len(), 1000);
+        assert_eq!(table.get(&"key0000".to_string())?, Some(0));
+        assert_eq!(table.get(&"key0500".to_string())?, Some(500));
+        assert_eq!(table.get(&"key0999".to_string())?, Some(999));
+        assert_eq!(table.get(&"key1000".to_string())?, None);
+        assert_eq!(table.get(&"a".to_string())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn range_starts_at_bound() -> Result<()> {
+        let dir = tempdir()?;
+        let table = write_table(dir.path(), 1, (0..1000).map(|i| (format!("key{:04}", i), i)))?;
+
+        let included: Vec<u64> = table.range(Bound::Included(&"key0500".to_string()))?.map(|entry| entry.map(|(_, value)| value)).collect::<Result<_>>()?;
+        let excluded = table.range(Bound::Excluded(&"key0500".to_string()))?.next().unwrap()?;
+
+        assert_eq!(included, (500..1000).collect::<Vec<_>>());
+        assert_eq!(excluded.1, 501);
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_detects_corrupted_table() -> Result<()> {
+        let dir = tempdir()?;
+        write_table(dir.path(), 1, (0..10).map(|i| (format!("key{:04}", i), i)))?;
+
+        let path = ss_table_file_name(dir.path(), 1);
+        let mut data = fs::read(&path)?;
+        let footer: Footer = bincode::deserialize(&data[data.len() - FOOTER_SIZE as usize..])?;
+        data[(footer.index_offset + CHECKSUM_SIZE) as usize] ^= 0xff;
+        fs::write(&path, data)?;
+
+        assert!(SsTable::<String, u64>::open(dir.path(), 1).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_detects_corrupted_bloom_filter() -> Result<()> {
+        let dir = tempdir()?;
+        write_table(dir.path(), 1, (0..10).map(|i| (format!("key{:04}", i), i)))?;
+
+        let path = ss_table_file_name(dir.path(), 1);
+        let mut data = fs::read(&path)?;
+        let footer_start = data.len() - FOOTER_SIZE as usize;
+        data[footer_start - 1] ^= 0xff;
+        fs::write(&path, data)?;
+
+        let table: SsTable<String, u64> = SsTable::open(dir.path(), 1)?;
+
+        assert!(table.get(&"key0005".to_string()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_skips_bloom_filter_of_old_tables() -> Result<()> {
+        let dir = tempdir()?;
+        write_table(dir.path(), 1, (0..10).map(|i| (format!("key{:04}", i), i)))?;
+
+        let path = ss_table_file_name(dir.path(), 1);
+        let mut data = fs::read(&path)?;
+        let magic_start = data.len() - 8;
+        data[magic_start..].copy_from_slice(&OLD_MAGICS[0].to_le_bytes());
+        fs::write(&path, data)?;
+
+        let table: SsTable<String, u64> = SsTable::open(dir.path(), 1)?;
+
+        assert!(table.bloom_filter()?.is_none());
+        assert_eq!(table.get(&"key0005".to_string())?, Some(5));
+        assert_eq!(table.get(&"key0010".to_string())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn obsolete_table_removes_its_file() -> Result<()> {
+        let dir = tempdir()?;
+        let table = write_table(dir.path(), 1, [("key".to_string(), 1)])?;
+
+        table.mark_obsolete();
+        drop(table);
+
+        assert!(!ss_table_file_name(dir.path(), 1).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_keeps_newest_entry_of_a_key() -> Result<()> {
+        let newer = vec![Ok(("b".to_string(), 2)), Ok(("d".to_string(), 2))];
+        let older = vec![Ok(("a".to_string(), 1)), Ok(("b".to_string(), 1)), Ok(("c".to_string(), 1))];
+
+        let merged: Vec<(String, u64)> = MergeIter::new(vec![newer.into_iter(), older.into_iter()]).collect::<Result<_>>()?;
+
+        assert_eq!(merged, vec![("a".to_string(), 1), ("b".to_string(), 2), ("c".to_string(), 1), ("d".to_string(), 2)]);
+
+        Ok(())
+    }
+}
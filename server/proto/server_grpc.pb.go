@@ -19,10 +19,15 @@ import (
 const _ = grpc.SupportPackageIsVersion7
 
 const (
-	KeyValueService_Get_FullMethodName    = "/server.KeyValueService/Get"
-	KeyValueService_Create_FullMethodName = "/server.KeyValueService/Create"
-	KeyValueService_Update_FullMethodName = "/server.KeyValueService/Update"
-	KeyValueService_Delete_FullMethodName = "/server.KeyValueService/Delete"
+	KeyValueService_Get_FullMethodName          = "/server.KeyValueService/Get"
+	KeyValueService_Create_FullMethodName       = "/server.KeyValueService/Create"
+	KeyValueService_Update_FullMethodName       = "/server.KeyValueService/Update"
+	KeyValueService_Delete_FullMethodName       = "/server.KeyValueService/Delete"
+	KeyValueService_Vacuum_FullMethodName       = "/server.KeyValueService/Vacuum"
+	KeyValueService_Backup_FullMethodName       = "/server.KeyValueService/Backup"
+	KeyValueService_Scan_FullMethodName         = "/server.KeyValueService/Scan"
+	KeyValueService_QueryByField_FullMethodName = "/server.KeyValueService/QueryByField"
+	KeyValueService_Transaction_FullMethodName  = "/server.KeyValueService/Transaction"
 )
 
 // KeyValueServiceClient is the client API for KeyValueService service.
@@ -33,6 +38,11 @@ type KeyValueServiceClient interface {
 	Create(ctx context.Context, in *CreateRequest, opts ...grpc.CallOption) (*CreateResponse, error)
 	Update(ctx context.Context, in *UpdateRequest, opts ...grpc.CallOption) (*UpdateResponse, error)
 	Delete(ctx context.Context, in *DeleteRequest, opts ...grpc.CallOption) (*DeleteResponse, error)
+	Vacuum(ctx context.Context, in *VacuumRequest, opts ...grpc.CallOption) (*VacuumResponse, error)
+	Backup(ctx context.Context, in *BackupRequest, opts ...grpc.CallOption) (*BackupResponse, error)
+	Scan(ctx context.Context, in *ScanRequest, opts ...grpc.CallOption) (KeyValueService_ScanClient, error)
+	QueryByField(ctx context.Context, in *QueryByFieldRequest, opts ...grpc.CallOption) (KeyValueService_QueryByFieldClient, error)
+	Transaction(ctx context.Context, in *TransactionRequest, opts ...grpc.CallOption) (*TransactionResponse, error)
 }
 
 type keyValueServiceClient struct {
@@ -79,6 +89,97 @@ func (c *keyValueServiceClient) Delete(ctx context.Context, in *DeleteRequest, o
 	return out, nil
 }
 
+func (c *keyValueServiceClient) Vacuum(ctx context.Context, in *VacuumRequest, opts ...grpc.CallOption) (*VacuumResponse, error) {
+	out := new(VacuumResponse)
+	err := c.cc.Invoke(ctx, KeyValueService_Vacuum_FullMethodName, in, out, opts...)
+	if err != nil {
+		return nil, err
+	}
+	return out, nil
+}
+
+func (c *keyValueServiceClient) Backup(ctx context.Context, in *BackupRequest, opts ...grpc.CallOption) (*BackupResponse, error) {
+	out := new(BackupResponse)
+	err := c.cc.Invoke(ctx, KeyValueService_Backup_FullMethodName, in, out, opts...)
+	if err != nil {
+		return nil, err
+	}
+	return out, nil
+}
+
+func (c *keyValueServiceClient) Scan(ctx context.Context, in *ScanRequest, opts ...grpc.CallOption) (KeyValueService_ScanClient, error) {
+	stream, err := c.cc.NewStream(ctx, &KeyValueService_ServiceDesc.Streams[0], KeyValueService_Scan_FullMethodName, opts...)
+	if err != nil {
+		return nil, err
+	}
+	x := &keyValueServiceScanClient{stream}
+	if err := x.ClientStream.SendMsg(in); err != nil {
+		return nil, err
+	}
+	if err := x.ClientStream.CloseSend(); err != nil {
+		return nil, err
+	}
+	return x, nil
+}
+
+type KeyValueService_ScanClient interface {
+	Recv() (*ScanResponse, error)
+	grpc.ClientStream
+}
+
+type keyValueServiceScanClient struct {
+	grpc.ClientStream
+}
+
+func (x *keyValueServiceScanClient) Recv() (*ScanResponse, error) {
+	m := new(ScanResponse)
+	if err := x.ClientStream.RecvMsg(m); err != nil {
+		return nil, err
+	}
+	return m, nil
+}
+
+func (c *keyValueServiceClient) QueryByField(ctx context.Context, in *QueryByFieldRequest, opts ...grpc.CallOption) (KeyValueService_QueryByFieldClient, error) {
+	stream, err := c.cc.NewStream(ctx, &KeyValueService_ServiceDesc.Streams[1], KeyValueService_QueryByField_FullMethodName, opts...)
+	if err != nil {
+		return nil, err
+	}
+	x := &keyValueServiceQueryByFieldClient{stream}
+	if err := x.ClientStream.SendMsg(in); err != nil {
+		return nil, err
+	}
+	if err := x.ClientStream.CloseSend(); err != nil {
+		return nil, err
+	}
+	return x, nil
+}
+
+type KeyValueService_QueryByFieldClient interface {
+	Recv() (*QueryByFieldResponse, error)
+	grpc.ClientStream
+}
+
+type keyValueServiceQueryByFieldClient struct {
+	grpc.ClientStream
+}
+
+func (x *keyValueServiceQueryByFieldClient) Recv() (*QueryByFieldResponse, error) {
+	m := new(QueryByFieldResponse)
+	if err := x.ClientStream.RecvMsg(m); err != nil {
+		return nil, err
+	}
+	return m, nil
+}
+
+func (c *keyValueServiceClient) Transaction(ctx context.Context, in *TransactionRequest, opts ...grpc.CallOption) (*TransactionResponse, error) {
+	out := new(TransactionResponse)
+	err := c.cc.Invoke(ctx, KeyValueService_Transaction_FullMethodName, in, out, opts...)
+	if err != nil {
+		return nil, err
+	}
+	return out, nil
+}
+
 // KeyValueServiceServer is the server API for KeyValueService service.
 // All implementations must embed UnimplementedKeyValueServiceServer
 // for forward compatibility
@@ -87,6 +188,11 @@ type KeyValueServiceServer interface {
 	Create(context.Context, *CreateRequest) (*CreateResponse, error)
 	Update(context.Context, *UpdateRequest) (*UpdateResponse, error)
 	Delete(context.Context, *DeleteRequest) (*DeleteResponse, error)
+	Vacuum(context.Context, *VacuumRequest) (*VacuumResponse, error)
+	Backup(context.Context, *BackupRequest) (*BackupResponse, error)
+	Scan(*ScanRequest, KeyValueService_ScanServer) error
+	QueryByField(*QueryByFieldRequest, KeyValueService_QueryByFieldServer) error
+	Transaction(context.Context, *TransactionRequest) (*TransactionResponse, error)
 	mustEmbedUnimplementedKeyValueServiceServer()
 }
 
@@ -106,6 +212,21 @@ func (UnimplementedKeyValueServiceServer) Update(context.Context, *UpdateRequest
 func (UnimplementedKeyValueServiceServer) Delete(context.Context, *DeleteRequest) (*DeleteResponse, error) {
 	return nil, status.Errorf(codes.Unimplemented, "method Delete not implemented")
 }
+func (UnimplementedKeyValueServiceServer) Vacuum(context.Context, *VacuumRequest) (*VacuumResponse, error) {
+	return nil, status.Errorf(codes.Unimplemented, "method Vacuum not implemented")
+}
+func (UnimplementedKeyValueServiceServer) Backup(context.Context, *BackupRequest) (*BackupResponse, error) {
+	return nil, status.Errorf(codes.Unimplemented, "method Backup not implemented")
+}
+func (UnimplementedKeyValueServiceServer) Scan(*ScanRequest, KeyValueService_ScanServer) error {
+	return status.Errorf(codes.Unimplemented, "method Scan not implemented")
+}
+func (UnimplementedKeyValueServiceServer) QueryByField(*QueryByFieldRequest, KeyValueService_QueryByFieldServer) error {
+	return status.Errorf(codes.Unimplemented, "method QueryByField not implemented")
+}
+func (UnimplementedKeyValueServiceServer) Transaction(context.Context, *TransactionRequest) (*TransactionResponse, error) {
+	return nil, status.Errorf(codes.Unimplemented, "method Transaction not implemented")
+}
 func (UnimplementedKeyValueServiceServer) mustEmbedUnimplementedKeyValueServiceServer() {}
 
 // UnsafeKeyValueServiceServer may be embedded to opt out of forward compatibility for this service.
@@ -191,6 +312,102 @@ func _KeyValueService_Delete_Handler(srv interface{}, ctx context.Context, dec f
 	return interceptor(ctx, in, info, handler)
 }
 
+func _KeyValueService_Vacuum_Handler(srv interface{}, ctx context.Context, dec func(interface{}) error, interceptor grpc.UnaryServerInterceptor) (interface{}, error) {
+	in := new(VacuumRequest)
+	if err := dec(in); err != nil {
+		return nil, err
+	}
+	if interceptor == nil {
+		return srv.(KeyValueServiceServer).Vacuum(ctx, in)
+	}
+	info := &grpc.UnaryServerInfo{
+		Server:     srv,
+		FullMethod: KeyValueService_Vacuum_FullMethodName,
+	}
+	handler := func(ctx context.Context, req interface{}) (interface{}, error) {
+		return srv.(KeyValueServiceServer).Vacuum(ctx, req.(*VacuumRequest))
+	}
+	return interceptor(ctx, in, info, handler)
+}
+
+func _KeyValueService_Backup_Handler(srv interface{}, ctx context.Context, dec func(interface{}) error, interceptor grpc.UnaryServerInterceptor) (interface{}, error) {
+	in := new(BackupRequest)
+	if err := dec(in); err != nil {
+		return nil, err
+	}
+	if interceptor == nil {
+		return srv.(KeyValueServiceServer).Backup(ctx, in)
+	}
+	info := &grpc.UnaryServerInfo{
+		Server:     srv,
+		FullMethod: KeyValueService_Backup_FullMethodName,
+	}
+	handler := func(ctx context.Context, req interface{}) (interface{}, error) {
+		return srv.(KeyValueServiceServer).Backup(ctx, req.(*BackupRequest))
+	}
+	return interceptor(ctx, in, info, handler)
+}
+
+func _KeyValueService_Scan_Handler(srv interface{}, stream grpc.ServerStream) error {
+	m := new(ScanRequest)
+	if err := stream.RecvMsg(m); err != nil {
+		return err
+	}
+	return srv.(KeyValueServiceServer).Scan(m, &keyValueServiceScanServer{stream})
+}
+
+type KeyValueService_ScanServer interface {
+	Send(*ScanResponse) error
+	grpc.ServerStream
+}
+
+type keyValueServiceScanServer struct {
+	grpc.ServerStream
+}
+
+func (x *keyValueServiceScanServer) Send(m *ScanResponse) error {
+	return x.ServerStream.SendMsg(m)
+}
+
+func _KeyValueService_QueryByField_Handler(srv interface{}, stream grpc.ServerStream) error {
+	m := new(QueryByFieldRequest)
+	if err := stream.RecvMsg(m); err != nil {
+		return err
+	}
+	return srv.(KeyValueServiceServer).QueryByField(m, &keyValueServiceQueryByFieldServer{stream})
+}
+
+type KeyValueService_QueryByFieldServer interface {
+	Send(*QueryByFieldResponse) error
+	grpc.ServerStream
+}
+
+type keyValueServiceQueryByFieldServer struct {
+	grpc.ServerStream
+}
+
+func (x *keyValueServiceQueryByFieldServer) Send(m *QueryByFieldResponse) error {
+	return x.ServerStream.SendMsg(m)
+}
+
+func _KeyValueService_Transaction_Handler(srv interface{}, ctx context.Context, dec func(interface{}) error, interceptor grpc.UnaryServerInterceptor) (interface{}, error) {
+	in := new(TransactionRequest)
+	if err := dec(in); err != nil {
+		return nil, err
+	}
+	if interceptor == nil {
+		return srv.(KeyValueServiceServer).Transaction(ctx, in)
+	}
+	info := &grpc.UnaryServerInfo{
+		Server:     srv,
+		FullMethod: KeyValueService_Transaction_FullMethodName,
+	}
+	handler := func(ctx context.Context, req interface{}) (interface{}, error) {
+		return srv.(KeyValueServiceServer).Transaction(ctx, req.(*TransactionRequest))
+	}
+	return interceptor(ctx, in, info, handler)
+}
+
 // KeyValueService_ServiceDesc is the grpc.ServiceDesc for KeyValueService service.
 // It's only intended for direct use with grpc.RegisterService,
 // and not to be introspected or modified (even as a copy)
@@ -214,7 +431,30 @@ var KeyValueService_ServiceDesc = grpc.ServiceDesc{
 			MethodName: "Delete",
 			Handler:    _KeyValueService_Delete_Handler,
 		},
+		{
+			MethodName: "Vacuum",
+			Handler:    _KeyValueService_Vacuum_Handler,
+		},
+		{
+			MethodName: "Backup",
+			Handler:    _KeyValueService_Backup_Handler,
+		},
+		{
+			MethodName: "Transaction",
+			Handler:    _KeyValueService_Transaction_Handler,
+		},
+	},
+	Streams: []grpc.StreamDesc{
+		{
+			StreamName:    "Scan",
+			Handler:       _KeyValueService_Scan_Handler,
+			ServerStreams: true,
+		},
+		{
+			StreamName:    "QueryByField",
+			Handler:       _KeyValueService_QueryByField_Handler,
+			ServerStreams: true,
+		},
 	},
-	Streams:  []grpc.StreamDesc{},
 	Metadata: "server.proto",
 }
@@ -4,7 +4,7 @@ use prost_types::Value;
 use prost_types::value::Kind;
 use anyhow::Result;
 
-use key_value_store::{GetRequest, CreateRequest, UpdateRequest, DeleteRequest};
+use key_value_store::{BackupRequest, GetRequest, CreateRequest, UpdateRequest, DeleteRequest, QueryByFieldRequest, ScanRequest, VacuumRequest};
 use key_value_store::key_value_service_client::KeyValueServiceClient;
 
 pub mod key_value_store {
@@ -13,10 +13,54 @@ pub mod key_value_store {
 
 #[derive(Subcommand, PartialEq, Debug)]
 enum Action {
-    Add { key: String, value: String },
-    Update { key: String, value: String },
-    Get { key: String },
-    Delete { key: String },
+    // the key expires `--ttl-ms` milliseconds after the write, never if 0
+    Add {
+        key: String,
+        value: String,
+        #[arg(long, default_value_t = 0)]
+        ttl_ms: u64,
+        #[arg(long)]
+        upsert: bool,
+    },
+    // `--expected-version` takes the version `get` printed, it isn't checked if 0
+    Update {
+        key: String,
+        value: String,
+        #[arg(long, default_value_t = 0)]
+        ttl_ms: u64,
+        #[arg(long, default_value_t = 0)]
+        expected_version: u64,
+    },
+    Get {
+        key: String,
+    },
+    Delete {
+        key: String,
+        #[arg(long, default_value_t = 0)]
+        expected_version: u64,
+    },
+    Vacuum,
+    // the file is written on the server, the router has every node write one
+    Backup {
+        backup_file: String,
+    },
+    Scan {
+        #[arg(long)]
+        from: Option<String>,
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long, default_value = "")]
+        prefix: String,
+        #[arg(long, default_value_t = 0)]
+        limit: u32,
+    },
+    // the server has to be started with `--secondary-index <field path>`
+    QueryByField {
+        field_path: String,
+        value: String,
+        #[arg(long, default_value_t = 0)]
+        limit: u32,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -36,7 +80,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut client = KeyValueServiceClient::connect(server_url).await?;
 
     match args.action {
-        Action::Add { key, value } => {
+        Action::Add { key, value, ttl_ms, upsert } => {
             let key = Value {
                 kind: Some(Kind::StringValue(key)),
             };
@@ -49,6 +93,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     key: Some(key),
                     value: Some(value),
                 }),
+                ttl_ms,
+                upsert,
+                leader_version: 0,
             });
 
             let response = client.create(request).await?;
@@ -67,16 +114,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             let response = client.get(request).await?;
             println!("RESPONSE={:?}", response);
-            let key_value = response.into_inner().key_value.unwrap();
-            print_key_value(key_value);
+            let response = response.into_inner();
+            print_key_value(response.key_value.unwrap());
+            println!("VERSION={}", response.version);
         }
-        Action::Delete { key } => {
+        Action::Delete { key, expected_version } => {
             let key = Value {
                 kind: Some(Kind::StringValue(key)),
             };
 
             let request = tonic::Request::new(DeleteRequest {
                 key: key.into(),
+                expected_version,
             });
 
             let response = client.delete(request).await?;
@@ -84,7 +133,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let key_value = response.into_inner().key_value.unwrap();
             print_key_value(key_value);
         }
-        Action::Update { key, value } => {
+        Action::Update { key, value, ttl_ms, expected_version } => {
             let key = Value {
                 kind: Some(Kind::StringValue(key)),
             };
@@ -97,6 +146,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     key: Some(key),
                     value: Some(value),
                 }),
+                ttl_ms,
+                expected_version,
+                leader_version: 0,
             });
 
             let response = client.update(request).await?;
@@ -104,6 +156,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let key_value = response.into_inner().key_value.unwrap();
             print_key_value(key_value);
         }
+        Action::Vacuum => {
+            let response = client.vacuum(tonic::Request::new(VacuumRequest {})).await?;
+            println!("RESPONSE={:?}", response);
+        }
+        Action::Backup { backup_file } => {
+            let response = client.backup(tonic::Request::new(BackupRequest { backup_file })).await?;
+            println!("RESPONSE={:?}", response);
+        }
+        Action::Scan { from, to, prefix, limit } => {
+            let request = tonic::Request::new(ScanRequest {
+                from: from.map(|from| Value { kind: Some(Kind::StringValue(from)) }),
+                to: to.map(|to| Value { kind: Some(Kind::StringValue(to)) }),
+                prefix,
+                limit,
+            });
+
+            let mut stream = client.scan(request).await?.into_inner();
+            while let Some(response) = stream.message().await? {
+                print_key_value(response.key_value.unwrap());
+            }
+        }
+        Action::QueryByField { field_path, value, limit } => {
+            let request = tonic::Request::new(QueryByFieldRequest {
+                field_path,
+                value: Some(Value { kind: Some(Kind::StringValue(value)) }),
+                limit,
+            });
+
+            let mut stream = client.query_by_field(request).await?.into_inner();
+            while let Some(response) = stream.message().await? {
+                print_key_value(response.key_value.unwrap());
+            }
+        }
     }
 
     Ok(())
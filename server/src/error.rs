@@ -3,13 +3,17 @@ use prost::DecodeError;
 
 use tonic::Status;
 use indexengine::index::IndexError;
+use storageengine::operations::StorageError;
 
 use indexengine::index::IndexError::AlreadyExists;
+use indexengine::index::IndexError::Conflict;
 use indexengine::index::IndexError::NotFound;
+use indexengine::index::IndexError::VersionMismatch;
 
 #[derive(Debug)]
 pub enum ServerError {
     IndexError(IndexError),
+    StorageError(StorageError),
     InternalError(anyhow::Error),
     InvalidArgument(String),
     Internal(String),
@@ -20,6 +24,7 @@ impl Display for ServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ServerError::IndexError(e) => write!(f, "IndexError: {:?}", e),
+            ServerError::StorageError(e) => write!(f, "StorageError: {}", e),
             ServerError::InternalError(e) => write!(f, "InternalError: {}", e),
             ServerError::InvalidArgument(e) => write!(f, "InvalidArgument: {}", e),
             ServerError::Internal(e) => write!(f, "Internal: {}", e),
@@ -34,7 +39,10 @@ impl From<ServerError> for Status {
             ServerError::IndexError(e) => match e {
                 AlreadyExists => Status::already_exists("document already exists"),
                 NotFound => Status::not_found("document not found"),
+                Conflict => Status::aborted("document was changed by another transaction"),
+                VersionMismatch => Status::failed_precondition("document has a different version"),
             },
+            ServerError::StorageError(e) => Status::data_loss(e.to_string()),
             ServerError::InternalError(e) => Status::internal(e.to_string()),
             ServerError::InvalidArgument(e) => Status::invalid_argument(e.to_string()),
             ServerError::Internal(e) => Status::internal(e.to_string()),
@@ -53,6 +61,7 @@ impl From<anyhow::Error> for ServerError {
     fn from(e: anyhow::Error) -> Self {
         e.downcast::<IndexError>()
             .map(ServerError::IndexError)
+            .or_else(|e| e.downcast::<StorageError>().map(ServerError::StorageError))
             .unwrap_or_else(ServerError::InternalError)
     }
 }
@@ -0,0 +1,137 @@
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+use anyhow::Result;
+
+use indexengine::bplus_tree::{check_entry_size, BPlusTree};
+use indexengine::index::Index;
+use storageengine::file_handler::FileHandler;
+use storageengine::wal::Wal;
+
+pub fn expiry_index_file_name(storage_file_name: &str) -> String {
+    format!("{}.expiry", storage_file_name)
+}
+
+// when a key expires and its encoded key
+type Entry = (u64, Vec<u8>);
+
+// the keys that were written with a ttl, ordered by when they expire, so the reaper only reads the keys that are
+// due. an entry is added before its key is written, so the tree is kept across restarts and never misses a key. a key
+// written again keeps its old entry until that is due, the reaper drops entries of keys that don't expire then anymore
+pub struct ExpiryIndex {
+    tree: BPlusTree<Entry, ()>,
+}
+
+impl ExpiryIndex {
+    // the tree is only built from the documents of `index_engine` if it is new, which reads all of them once
+    pub fn new(file_handler: Box<dyn FileHandler>, wal: Wal, index_engine: &mut dyn Index<Vec<u8>, Vec<u8>>) -> Result<Self> {
+        let mut expiry_index = Self {
+            tree: BPlusTree::new(file_handler, wal)?,
+        };
+        if expiry_index.tree.is_dirty() {
+            expiry_index.rebuild(index_engine)?;
+        }
+
+        Ok(expiry_index)
+    }
+
+    // builds the tree again from the documents of `index_engine` that expire, like after the keys were migrated
+    pub fn rebuild(&mut self, index_engine: &mut dyn Index<Vec<u8>, Vec<u8>>) -> Result<()> {
+        let mut entries = BTreeSet::new();
+        // every document that expires at some point has expired by the end of time
+        for key in index_engine.expired(u64::MAX, usize::MAX)? {
+            entries.insert((index_engine.expires_at(&key)?, key));
+        }
+        self.tree.rebuild(entries.into_iter().map(|entry| (entry, ())))
+    }
+
+    // fails if the key is too big to be indexed, checked before the document is written
+    pub fn check(&self, key: &[u8]) -> Result<()> {
+        check_entry_size(&(u64::MAX, key.to_vec()), &())?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, expires_at: u64, key: Vec<u8>) -> Result<()> {
+        self.tree.insert((expires_at, key), ())?;
+        Ok(())
+    }
+
+    // up to `limit` entries that are due at `now`, the ones that are due first come first
+    pub fn due(&mut self, now: u64, limit: usize) -> Result<Vec<Entry>> {
+        // the smallest entry that is due after `now`
+        let end = match now.checked_add(1) {
+            Some(after) => Bound::Excluded((after, Vec::new())),
+            None => Bound::Unbounded,
+        };
+        let mut entries = Vec::new();
+        for entry in self.tree.range((Bound::Unbounded, end))?.take(limit) {
+            entries.push(entry?.0);
+        }
+        Ok(entries)
+    }
+
+    pub fn remove(&mut self, entries: Vec<Entry>) -> Result<()> {
+        self.tree.apply(entries.into_iter().map(|entry| (entry, None)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexengine::index::Document;
+    use storageengine::file_handler::FileHandlerImpl;
+    use storageengine::wal::wal_file_name;
+
+    use super::*;
+    use crate::test_support::{memory_file_handler, memory_index_engine, memory_wal};
+
+    #[test]
+    fn test_new_index_is_built_from_expiring_documents() -> Result<()> {
+        let mut index_engine = memory_index_engine()?;
+        index_engine.insert_expiring(Document { id: b"a".to_vec(), value: Vec::new() }, 1)?;
+        index_engine.insert(Document { id: b"b".to_vec(), value: Vec::new() })?;
+        index_engine.insert_expiring(Document { id: b"c".to_vec(), value: Vec::new() }, u64::MAX - 1)?;
+
+        let mut expiry_index = ExpiryIndex::new(memory_file_handler(), memory_wal()?, index_engine.as_mut())?;
+
+        assert_eq!(expiry_index.due(u64::MAX, usize::MAX)?, vec![(1, b"a".to_vec()), (u64::MAX - 1, b"c".to_vec())]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_existing_index_is_kept_without_reading_the_documents() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db.expiry").to_str().unwrap().to_string();
+        let open = || -> Result<(Box<dyn FileHandler>, Wal)> {
+            Ok((Box::new(FileHandlerImpl::new(&file_name)?), Wal::new(Box::new(FileHandlerImpl::new(&wal_file_name(&file_name))?))?))
+        };
+        let (file_handler, wal) = open()?;
+        ExpiryIndex::new(file_handler, wal, memory_index_engine()?.as_mut())?.insert(10, b"a".to_vec())?;
+
+        // built from the documents again it would be empty
+        let (file_handler, wal) = open()?;
+        let mut expiry_index = ExpiryIndex::new(file_handler, wal, memory_index_engine()?.as_mut())?;
+
+        assert_eq!(expiry_index.due(10, usize::MAX)?, vec![(10, b"a".to_vec())]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_due_reads_only_the_entries_that_are_due() -> Result<()> {
+        let mut expiry_index = ExpiryIndex::new(memory_file_handler(), memory_wal()?, memory_index_engine()?.as_mut())?;
+        for (expires_at, key) in [(30, "c"), (10, "a"), (20, "b"), (20, "a")] {
+            expiry_index.insert(expires_at, key.as_bytes().to_vec())?;
+        }
+
+        assert!(expiry_index.due(9, usize::MAX)?.is_empty());
+        assert_eq!(expiry_index.due(20, usize::MAX)?, vec![(10, b"a".to_vec()), (20, b"a".to_vec()), (20, b"b".to_vec())]);
+        assert_eq!(expiry_index.due(20, 2)?, vec![(10, b"a".to_vec()), (20, b"a".to_vec())]);
+
+        expiry_index.remove(vec![(10, b"a".to_vec()), (20, b"a".to_vec())])?;
+        assert_eq!(expiry_index.due(u64::MAX, usize::MAX)?, vec![(20, b"b".to_vec()), (30, b"c".to_vec())]);
+        assert!(expiry_index.check(&[0; 8192]).is_err());
+
+        Ok(())
+    }
+}
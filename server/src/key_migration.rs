@@ -0,0 +1,97 @@
+use anyhow::Result;
+use prost::Message;
+use prost_types::Value;
+
+use indexengine::index::{Document, Index, IndexError};
+use server::key_order;
+use storageengine::operations::NEVER_EXPIRES;
+
+// recorded in the header of the data file once every key is encoded by `key_order::encode`
+pub const ORDERED_KEYS: u8 = 1;
+// keys migrated from the protobuf encoding at once
+const MIGRATION_BATCH_SIZE: usize = 100;
+
+// true until `finish_migration` recorded that the keys are migrated. it stays true after an interrupted migration,
+// so the next start migrates the keys that are left and builds the indexes over the keys again
+pub fn needs_migration(index_engine: &dyn Index<Vec<u8>, Vec<u8>>) -> bool {
+    index_engine.header().key_encoding != Some(ORDERED_KEYS)
+}
+
+// rewrites the keys that are still protobuf encoded, returns how many. expired documents are left to be reaped
+pub fn migrate_legacy_keys(index_engine: &mut dyn Index<Vec<u8>, Vec<u8>>) -> Result<usize> {
+    let mut migrated = 0;
+    loop {
+        let mut documents = Vec::new();
+        for range in key_order::legacy_ranges() {
+            documents.extend(index_engine.scan(range, Some(MIGRATION_BATCH_SIZE))?);
+        }
+        if documents.is_empty() {
+            return Ok(migrated);
+        }
+
+        for document in documents {
+            let id = key_order::encode(&Value::decode(document.id.as_slice())?);
+            let expires_at = index_engine.expires_at(&document.id)?;
+            // the new key is written first, a crash in between leaves both and the next start deletes the old one
+            let migrated_document = Document { id: id.clone(), value: document.value };
+            match index_engine.search_including_expired(&id) {
+                Ok(_) => {}
+                Err(e) if matches!(e.downcast_ref::<IndexError>(), Some(IndexError::NotFound)) => match expires_at {
+                    NEVER_EXPIRES => index_engine.insert(migrated_document)?,
+                    expires_at => index_engine.insert_expiring(migrated_document, expires_at)?,
+                },
+                Err(e) => return Err(e),
+            }
+            index_engine.delete(&document.id)?;
+            migrated += 1;
+        }
+    }
+}
+
+// records in the header of the data file that the keys are migrated, call once the indexes over the keys are built
+// again
+pub fn finish_migration(index_engine: &mut dyn Index<Vec<u8>, Vec<u8>>) -> Result<()> {
+    // the migrated keys have to be durable before the header says so
+    index_engine.sync()?;
+    let mut header = index_engine.header();
+    header.key_encoding = Some(ORDERED_KEYS);
+    index_engine.set_header(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{memory_index_engine, string};
+
+    use super::*;
+
+    fn document(id: Vec<u8>) -> Document<Vec<u8>, Vec<u8>> {
+        Document { id, value: b"value".to_vec() }
+    }
+
+    #[test]
+    fn migrate_keys_finishes_an_interrupted_migration_once() -> Result<()> {
+        let mut index_engine = memory_index_engine()?;
+        for key in ["a", "b", "c"] {
+            index_engine.insert(document(string(key).encode_to_vec()))?;
+        }
+        // a crash after the new key of "a" was written and before its old key was deleted
+        index_engine.insert(document(key_order::encode(&string("a"))))?;
+
+        assert!(needs_migration(index_engine.as_ref()));
+        assert_eq!(migrate_legacy_keys(index_engine.as_mut())?, 3);
+
+        for key in ["a", "b", "c"] {
+            assert_eq!(index_engine.search(&key_order::encode(&string(key)))?.value, b"value");
+            assert!(index_engine.search(&string(key).encode_to_vec()).is_err());
+        }
+        // a crash before it was recorded runs it again
+        assert!(needs_migration(index_engine.as_ref()));
+        assert_eq!(migrate_legacy_keys(index_engine.as_mut())?, 0);
+
+        finish_migration(index_engine.as_mut())?;
+        assert_eq!(index_engine.header().key_encoding, Some(ORDERED_KEYS));
+        assert!(!needs_migration(index_engine.as_ref()));
+
+        Ok(())
+    }
+}
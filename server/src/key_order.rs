@@ -0,0 +1,128 @@
+use std::ops::Bound;
+
+use prost::{DecodeError, Message};
+use prost_types::{ListValue, Struct, Value};
+use prost_types::value::Kind;
+
+// keys are stored so their bytes sort like the keys, then the index engines can scan a range of them: a byte
+// for the kind of the key and the key itself. the kinds sort null, bools, numbers, strings, lists and structs,
+// lists and structs are protobuf encoded and only sort by their encoding
+const NULL: u8 = 0;
+const BOOL: u8 = 1;
+const NUMBER: u8 = 2;
+const STRING: u8 = 3;
+const LIST: u8 = 4;
+const STRUCT: u8 = 5;
+
+pub fn encode(key: &Value) -> Vec<u8> {
+    match &key.kind {
+        None | Some(Kind::NullValue(_)) => vec![NULL],
+        Some(Kind::BoolValue(b)) => vec![BOOL, *b as u8],
+        Some(Kind::NumberValue(n)) => {
+            // flips the sign bit of positive numbers and all bits of negative ones, like `f64::total_cmp`
+            let bits = n.to_bits();
+            let bits = if bits >> 63 == 1 { !bits } else { bits | 1 << 63 };
+            [&[NUMBER][..], &bits.to_be_bytes()].concat()
+        }
+        Some(Kind::StringValue(s)) => [&[STRING], s.as_bytes()].concat(),
+        Some(Kind::ListValue(list)) => [vec![LIST], list.encode_to_vec()].concat(),
+        Some(Kind::StructValue(fields)) => [vec![STRUCT], fields.encode_to_vec()].concat(),
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Value, DecodeError> {
+    if is_legacy(bytes) {
+        return Value::decode(bytes);
+    }
+
+    let kind = match (bytes[0], &bytes[1..]) {
+        (NULL, []) => Kind::NullValue(0),
+        (BOOL, [b @ (0 | 1)]) => Kind::BoolValue(*b == 1),
+        (NUMBER, number) => {
+            let bits = u64::from_be_bytes(number.try_into().map_err(|_| DecodeError::new("invalid number key"))?);
+            let bits = if bits >> 63 == 1 { bits & !(1 << 63) } else { !bits };
+            Kind::NumberValue(f64::from_bits(bits))
+        }
+        (STRING, s) => Kind::StringValue(String::from_utf8(s.to_vec()).map_err(|_| DecodeError::new("invalid string key"))?),
+        (LIST, list) => Kind::ListValue(ListValue::decode(list)?),
+        (STRUCT, fields) => Kind::StructValue(Struct::decode(fields)?),
+        _ => return Err(DecodeError::new("invalid key")),
+    };
+    Ok(Value { kind: Some(kind) })
+}
+
+// keys written before were protobuf encoded, they are empty or start with a protobuf tag but never with a kind
+pub fn is_legacy(bytes: &[u8]) -> bool {
+    bytes.first().is_none_or(|kind| *kind > STRUCT)
+}
+
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// the keys that are still protobuf encoded are in these ranges, see `is_legacy`
+pub fn legacy_ranges() -> [KeyRange; 2] {
+    [(Bound::Unbounded, Bound::Excluded(vec![NULL])), (Bound::Included(vec![STRUCT + 1]), Bound::Unbounded)]
+}
+
+// the encoded keys of string keys starting with `prefix` start with this
+pub fn encode_prefix(prefix: &str) -> Vec<u8> {
+    [&[STRING], prefix.as_bytes()].concat()
+}
+
+// where a scan from `from` over the keys starting with the encoded `prefix` starts
+pub fn range_start(from: Option<&Value>, prefix: Option<&[u8]>) -> Bound<Vec<u8>> {
+    match (from.map(encode), prefix) {
+        (Some(from), Some(prefix)) => Bound::Included(from.max(prefix.to_vec())),
+        (Some(from), None) => Bound::Included(from),
+        (None, Some(prefix)) => Bound::Included(prefix.to_vec()),
+        (None, None) => Bound::Unbounded,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value { kind: Some(Kind::StringValue(s.to_string())) }
+    }
+
+    fn number(n: f64) -> Value {
+        Value { kind: Some(Kind::NumberValue(n)) }
+    }
+
+    #[test]
+    fn test_encoded_keys_sort_like_keys() {
+        let keys = [
+            Value { kind: Some(Kind::NullValue(0)) },
+            Value { kind: Some(Kind::BoolValue(false)) },
+            Value { kind: Some(Kind::BoolValue(true)) },
+            number(f64::NEG_INFINITY),
+            number(-2.5),
+            number(-0.0),
+            number(0.0),
+            number(10.0),
+            string(""),
+            string("ab"),
+            string("b"),
+            Value { kind: Some(Kind::ListValue(ListValue { values: vec![string("a")] })) },
+            Value { kind: Some(Kind::StructValue(Struct::default())) },
+        ];
+
+        for pair in keys.windows(2) {
+            assert!(encode(&pair[0]) < encode(&pair[1]), "{:?}", pair);
+        }
+        for key in keys {
+            assert_eq!(decode(&encode(&key)).unwrap(), key);
+            assert!(!is_legacy(&encode(&key)));
+        }
+    }
+
+    #[test]
+    fn test_decode_reads_legacy_keys() {
+        for key in [string("a"), number(1.0), Value { kind: Some(Kind::BoolValue(true)) }, Value { kind: None }] {
+            assert!(is_legacy(&key.encode_to_vec()));
+            assert_eq!(decode(&key.encode_to_vec()).unwrap(), key);
+        }
+        assert!(decode(&[NUMBER, 1]).is_err());
+    }
+}
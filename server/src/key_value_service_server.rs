@@ -1,4 +1,8 @@
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 use log::{error, info};
 use prost::bytes::Bytes;
@@ -6,39 +10,84 @@ use prost::Message;
 use prost_types::Value;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::mpsc::channel;
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
 use configmanager::ConfigManager;
-use indexengine::index::{Document, Index};
-use key_value_store::{CreateRequest, CreateResponse, DeleteRequest, DeleteResponse, GetRequest, GetResponse, KeyValue, UpdateRequest, UpdateResponse};
+use indexengine::index::{Document, Index, IndexError, Transaction};
+use key_value_store::{BackupRequest, BackupResponse, CreateRequest, CreateResponse, DeleteRequest, DeleteResponse, GetRequest, GetResponse, KeyValue, QueryByFieldRequest, QueryByFieldResponse, ScanRequest, ScanResponse, TransactionOperation, TransactionRequest, TransactionResponse, UpdateRequest, UpdateResponse, VacuumRequest, VacuumResponse};
+use key_value_store::transaction_operation::Operation;
 use key_value_store::key_value_service_client::KeyValueServiceClient;
 use key_value_store::key_value_service_server::KeyValueService;
+use storageengine::backup::WalArchive;
+use storageengine::encrypted_file_handler::{self, Encryption, EncryptionKey};
+use storageengine::operations::{NEVER_EXPIRES, unix_millis};
 
 use crate::error::ServerError;
+use crate::expiry_index::ExpiryIndex;
+use server::key_order;
+use crate::secondary_index::SecondaryIndex;
 
 pub mod key_value_store {
     tonic::include_proto!("server");
 }
 
+pub type ScanStream = ReceiverStream<Result<ScanResponse, Status>>;
+pub type QueryByFieldStream = ReceiverStream<Result<QueryByFieldResponse, Status>>;
+
 pub struct KeyValueStoreImpl {
-    index_engine: Mutex<Box<dyn Index<Vec<u8>, Vec<u8>>>>,
+    index_engine: SharedIndex,
     tx: Sender<Replication>,
+    // set with group commit, writes wait here until the batch they are part of is synced
+    group_commit: Option<Sender<SyncWaiter>>,
+    // locked before the secondary indexes, None if the reaper reads the expired keys from the index engine
+    expiry_index: Mutex<Option<ExpiryIndex>>,
+    // locked before the index engine, writes hold both so the secondary indexes never miss a change
+    secondary_indexes: Arc<Mutex<Vec<SecondaryIndex>>>,
+    config_manager: Arc<Mutex<Box<dyn ConfigManager>>>,
+    // None with memory storage, there is no data file to back up
+    backup: Option<BackupConfig>,
+    // writes with a ttl are rejected if the index engine can't expire keys
+    can_expire: bool,
+}
+
+// backups are encrypted like the data file and start archiving its wal in `wal_archive_dir`
+struct BackupConfig {
+    wal_archive_dir: String,
+    encryption: Option<Encryption>,
+    key: Option<EncryptionKey>,
 }
 
+type SharedIndex = Arc<Mutex<Box<dyn Index<Vec<u8>, Vec<u8>>>>>;
+type SyncWaiter = oneshot::Sender<Result<(), String>>;
+
 #[derive(Clone, Debug)]
 enum Action {
     Add,
+    // an add that overwrites the key if it exists
+    Upsert,
     Update,
     Delete,
+    Transaction(Vec<TransactionOperation>),
 }
 
 #[derive(Clone, Debug)]
 struct Replication {
     action: Action,
     key_value: KeyValue,
+    // when a created or updated key expires, the followers get the ttl that is left when it is sent
+    expires_at: u64,
+    // the version the leader wrote the keys with, the followers write them with the same one. 0 for a delete
+    version: u64,
 }
 
+// documents a scan or a query by field reads from the index engine at once
+const SCAN_PAGE_SIZE: usize = 100;
+
+// keys the ttl reaper deletes per round
+const TTL_REAP_LIMIT: usize = 1000;
+
 impl KeyValueStoreImpl {
     pub async fn new(index_engine: Box<dyn Index<Vec<u8>, Vec<u8>>>, config_manager: Box<dyn ConfigManager>) -> Self {
         let (tx, rx) = channel::<Replication>(1000);
@@ -46,9 +95,193 @@ impl KeyValueStoreImpl {
         start_replicator(rx, config_manager.clone()).await;
 
         Self {
-            index_engine: Mutex::new(index_engine),
+            can_expire: index_engine.can_expire(),
+            index_engine: Arc::new(Mutex::new(index_engine)),
             tx,
+            group_commit: None,
+            expiry_index: Mutex::new(None),
+            secondary_indexes: Arc::new(Mutex::new(Vec::new())),
+            config_manager,
+            backup: None,
+        }
+    }
+
+    // keeps the secondary indexes up to date on every write and answers `QueryByField` with them
+    pub fn with_secondary_indexes(mut self, secondary_indexes: Vec<SecondaryIndex>) -> Self {
+        self.secondary_indexes = Arc::new(Mutex::new(secondary_indexes));
+        self
+    }
+
+    // keeps the keys written with a ttl in `expiry_index`, the reaper only reads the keys that are due from it. without
+    // one expired keys are hidden but never reaped
+    pub fn with_expiry_index(mut self, expiry_index: ExpiryIndex) -> Self {
+        self.expiry_index = Mutex::new(Some(expiry_index));
+        self
+    }
+
+    // answers `Backup` with copies of the data file `storage_file_name`
+    pub fn with_backup(mut self, storage_file_name: &str, encryption: Option<Encryption>, key: Option<EncryptionKey>) -> Self {
+        self.backup = Some(BackupConfig {
+            wal_archive_dir: storageengine::backup::wal_archive_dir_name(storage_file_name),
+            encryption,
+            key,
+        });
+        self
+    }
+
+    // acknowledges writes only once they are synced, the writes that arrive within `window` of each other
+    // are synced together. the index engine has to be opened with `Durability::GroupCommit`
+    pub fn with_group_commit(mut self, window: Duration) -> Self {
+        self.group_commit = Some(start_group_commit(self.index_engine.clone(), window));
+        self
+    }
+
+    // returns once the writes done so far are durable, right away without group commit
+    async fn wait_for_sync(&self) -> Result<(), ServerError> {
+        let Some(group_commit) = &self.group_commit else {
+            return Ok(());
+        };
+
+        let (tx, rx) = oneshot::channel();
+        group_commit.send(tx).await.map_err(|_| ServerError::Internal("group commit stopped".to_string()))?;
+        rx.await.map_err(|_| ServerError::Internal("group commit stopped".to_string()))?
+            .map_err(ServerError::Internal)
+    }
+
+    // runs `write` on the index engine and moves the keys it changed in the secondary indexes. `writes` holds
+    // every key the write touches together with the value it stores, None for a delete
+    async fn write<T>(&self, writes: Vec<(Vec<u8>, Option<Value>)>, write: impl FnOnce(&mut dyn Index<Vec<u8>, Vec<u8>>) -> Result<T, ServerError>) -> Result<T, ServerError> {
+        let mut secondary_indexes = self.secondary_indexes.lock().await;
+        let mut index_engine = self.index_engine.lock().await;
+        if secondary_indexes.is_empty() {
+            return write(index_engine.as_mut());
+        }
+
+        for secondary_index in secondary_indexes.iter() {
+            for (key, value) in &writes {
+                if let Some(value) = value {
+                    secondary_index.check(key, value).map_err(|e| ServerError::InvalidArgument(e.to_string()))?;
+                }
+            }
+        }
+        let keys: BTreeSet<Vec<u8>> = writes.into_iter().map(|(key, _)| key).collect();
+        let old_values = read_values(index_engine.as_mut(), &keys)?;
+        for secondary_index in secondary_indexes.iter_mut() {
+            secondary_index.mark_dirty().map_err(ServerError::from)?;
+        }
+        // the documents are read back even if the write failed, so the indexes follow whatever it left behind
+        let result = write(index_engine.as_mut());
+        let new_values = read_values(index_engine.as_mut(), &keys)?;
+
+        let changes: Vec<_> = keys.into_iter().zip(old_values).zip(new_values)
+            .map(|((key, old_value), new_value)| (key, old_value, new_value))
+            .collect();
+        for secondary_index in secondary_indexes.iter_mut() {
+            secondary_index.apply(&changes).map_err(ServerError::from)?;
+        }
+        result
+    }
+
+    // like `write`, but a key written with a ttl is added to the expiry index
+    async fn write_expiring<T>(&self, key: &[u8], expires_at: u64, writes: Vec<(Vec<u8>, Option<Value>)>, write: impl FnOnce(&mut dyn Index<Vec<u8>, Vec<u8>>) -> Result<T, ServerError>) -> Result<T, ServerError> {
+        let mut expiry_index = self.expiry_index.lock().await;
+        let Some(expiry_index) = expiry_index.as_mut().filter(|_| expires_at != NEVER_EXPIRES) else {
+            return self.write(writes, write).await;
+        };
+
+        expiry_index.check(key).map_err(|e| ServerError::InvalidArgument(e.to_string()))?;
+        // the entry is added first, so a crash can't lose it. if the write fails the reaper skips the key, it doesn't
+        // expire by then
+        expiry_index.insert(expires_at, key.to_vec()).map_err(ServerError::from)?;
+        self.write(writes, write).await
+    }
+
+    // deletes up to `limit` keys whose ttl ran out like `Delete` does, so the deletes reach the followers too.
+    // only the leader reaps, the followers hide an expired key until the delete of the leader arrives
+    pub async fn reap_expired(&self, limit: usize) -> Result<usize, ServerError> {
+        if !self.config_manager.lock().await.is_leader() {
+            return Ok(0);
+        }
+
+        let mut expiry_index = self.expiry_index.lock().await;
+        let Some(expiry_index) = expiry_index.as_mut() else {
+            return Ok(0);
+        };
+        let due = expiry_index.due(unix_millis(), limit).map_err(ServerError::from)?;
+        let mut reaped = Vec::new();
+        for (_, key_bytes) in due.clone() {
+            let is_deleted = self.write(vec![(key_bytes.clone(), None)], |index_engine| {
+                // written again or deleted since it was listed
+                if index_engine.search(&key_bytes).is_ok() {
+                    return Ok(false);
+                }
+                match index_engine.delete(&key_bytes) {
+                    Ok(()) => Ok(true),
+                    Err(e) if matches!(e.downcast_ref::<IndexError>(), Some(IndexError::NotFound)) => Ok(false),
+                    Err(e) => Err(e.into()),
+                }
+            }).await?;
+            if is_deleted {
+                reaped.push(key_order::decode(&key_bytes)?);
+            }
+        }
+        // the keys that were not deleted don't expire by then anymore, they have a later entry if they expire at all
+        expiry_index.remove(due).map_err(ServerError::from)?;
+        if reaped.is_empty() {
+            return Ok(0);
+        }
+        self.wait_for_sync().await?;
+
+        let count = reaped.len();
+        for key in reaped {
+            self.send_replication(Replication {
+                action: Action::Delete,
+                key_value: KeyValue {
+                    key: Some(key),
+                    value: None,
+                },
+                expires_at: NEVER_EXPIRES,
+                version: 0,
+            }).await;
+        }
+        Ok(count)
+    }
+
+    // reaps the expired keys every `interval`, there are none if the index engine can't expire keys
+    pub fn start_ttl_reaper(self: Arc<Self>, interval: Duration) {
+        if !self.can_expire {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match self.reap_expired(TTL_REAP_LIMIT).await {
+                    Ok(0) => {}
+                    Ok(reaped) => info!("Reaped {} expired keys", reaped),
+                    Err(e) => error!("Failed to reap expired keys: {}", e),
+                }
+            }
+        });
+    }
+
+    // like `expires_at`, but fails if the index engine can't expire keys
+    fn expires_at(&self, ttl_ms: u64) -> Result<u64, ServerError> {
+        if ttl_ms > 0 && !self.can_expire {
+            return Err(ServerError::InvalidArgument("the index engine can't expire keys, ttl_ms has to be 0".to_string()));
+        }
+        Ok(expires_at(ttl_ms))
+    }
+
+    // the version a follower writes a replicated write with, None for a write of a client
+    async fn leader_version(&self, leader_version: u64) -> Result<Option<u64>, ServerError> {
+        if leader_version == 0 {
+            return Ok(None);
         }
+        if self.config_manager.lock().await.is_leader() {
+            return Err(ServerError::InvalidArgument("leader_version is only set when the leader replicates a write".to_string()));
+        }
+        Ok(Some(leader_version))
     }
 
     async fn send_replication(&self, replication: Replication) {
@@ -68,13 +301,13 @@ impl KeyValueService for KeyValueStoreImpl {
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
         let key_val = request.into_inner().key
             .ok_or_else(|| ServerError::InvalidArgument("key must be set".to_string()))?;
-        let key_bytes = key_val.encode_to_vec();
+        let key_bytes = key_order::encode(&key_val);
 
         let mut index_engine = self.index_engine.lock().await;
         let document = index_engine.search(&key_bytes).map_err(ServerError::from)?;
+        let version = index_engine.version(&key_bytes).map_err(ServerError::from)?;
 
-        let bytes = Bytes::from(document.id);
-        let key = Value::decode(bytes).map_err(ServerError::from)?;
+        let key = key_order::decode(&document.id).map_err(ServerError::from)?;
         let bytes = Bytes::from(document.value);
         let value = Value::decode(bytes).map_err(ServerError::from)?;
 
@@ -82,32 +315,52 @@ impl KeyValueService for KeyValueStoreImpl {
             key_value: Some(KeyValue {
                 key: key.into(),
                 value: value.into(),
-            })
+            }),
+            version,
         };
 
         Ok(Response::new(reply))
     }
 
     async fn create(&self, request: Request<CreateRequest>) -> Result<Response<CreateResponse>, Status> {
-        let key_value = request.into_inner().key_value
+        let request = request.into_inner();
+        let expires_at = self.expires_at(request.ttl_ms)?;
+        let upsert = request.upsert;
+        let leader_version = self.leader_version(request.leader_version).await?;
+        let key_value = request.key_value
             .ok_or_else(|| ServerError::InvalidArgument("key_value must be set".to_string()))?;
 
-        let replication = Replication {
-            action: Action::Add,
+        let mut replication = Replication {
+            action: if upsert { Action::Upsert } else { Action::Add },
             key_value: key_value.clone(),
+            expires_at,
+            version: 0,
         };
 
         let key_val = key_value.key.ok_or_else(|| ServerError::InvalidArgument("key must be set".to_string()))?;
-        let key_bytes = key_val.encode_to_vec();
+        let key_bytes = key_order::encode(&key_val);
 
         let value_val = key_value.value.ok_or_else(|| ServerError::InvalidArgument("value must be set".to_string()))?;
         let value_bytes = value_val.encode_to_vec();
 
-        let mut index_engine = self.index_engine.lock().await;
-        index_engine.insert(Document {
-            id: key_bytes,
-            value: value_bytes,
-        }).map_err(ServerError::from)?;
+        replication.version = self.write_expiring(&key_bytes, expires_at, vec![(key_bytes.clone(), Some(value_val.clone()))], |index_engine| {
+            let document = Document {
+                id: key_bytes.clone(),
+                value: value_bytes,
+            };
+            with_version(index_engine, leader_version, |index_engine| {
+                // an expired key is still there until it is reaped
+                let exists = upsert && index_engine.search_including_expired(&key_bytes).is_ok();
+                match (exists, expires_at) {
+                    (false, NEVER_EXPIRES) => index_engine.insert(document),
+                    (false, expires_at) => index_engine.insert_expiring(document, expires_at),
+                    (true, NEVER_EXPIRES) => index_engine.update(&key_bytes, document),
+                    (true, expires_at) => index_engine.update_expiring(&key_bytes, document, expires_at),
+                }.map_err(ServerError::from)
+            })?;
+            written_version(index_engine, &key_bytes)
+        }).await?;
+        self.wait_for_sync().await?;
 
         self.send_replication(replication).await;
 
@@ -122,25 +375,42 @@ impl KeyValueService for KeyValueStoreImpl {
     }
 
     async fn update(&self, request: Request<UpdateRequest>) -> Result<Response<UpdateResponse>, Status> {
-        let key_value = request.into_inner().key_value
+        let request = request.into_inner();
+        let expires_at = self.expires_at(request.ttl_ms)?;
+        let expected_version = request.expected_version;
+        let leader_version = self.leader_version(request.leader_version).await?;
+        let key_value = request.key_value
             .ok_or_else(|| ServerError::InvalidArgument("key_value must be set".to_string()))?;
 
-        let replication = Replication {
+        // the version was checked here already, the followers get a plain update with the version it is written with
+        let mut replication = Replication {
             action: Action::Update,
             key_value: key_value.clone(),
+            expires_at,
+            version: 0,
         };
 
         let key_val = key_value.key.ok_or_else(|| ServerError::InvalidArgument("key must be set".to_string()))?;
-        let key_bytes = key_val.encode_to_vec();
+        let key_bytes = key_order::encode(&key_val);
 
         let value_val = key_value.value.ok_or_else(|| ServerError::InvalidArgument("value must be set".to_string()))?;
         let value_bytes = value_val.encode_to_vec();
 
-        let mut index_engine = self.index_engine.lock().await;
-        index_engine.update(&key_bytes.clone(), Document {
-            id: key_bytes,
-            value: value_bytes,
-        }).map_err(ServerError::from)?;
+        replication.version = self.write_expiring(&key_bytes, expires_at, vec![(key_bytes.clone(), Some(value_val.clone()))], |index_engine| {
+            let document = Document {
+                id: key_bytes.clone(),
+                value: value_bytes,
+            };
+            with_version(index_engine, leader_version, |index_engine| {
+                match (expected_version, expires_at) {
+                    (0, NEVER_EXPIRES) => index_engine.update(&key_bytes, document),
+                    (0, expires_at) => index_engine.update_expiring(&key_bytes, document, expires_at),
+                    (expected_version, expires_at) => index_engine.compare_and_swap(&key_bytes, expected_version, Some(document), expires_at),
+                }.map_err(ServerError::from)
+            })?;
+            written_version(index_engine, &key_bytes)
+        }).await?;
+        self.wait_for_sync().await?;
 
         self.send_replication(replication).await;
 
@@ -155,9 +425,11 @@ impl KeyValueService for KeyValueStoreImpl {
     }
 
     async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
-        let key = request.into_inner().key
+        let request = request.into_inner();
+        let expected_version = request.expected_version;
+        let key = request.key
             .ok_or_else(|| ServerError::InvalidArgument("key_value must be set".to_string()))?;
-        let key_bytes = key.encode_to_vec();
+        let key_bytes = key_order::encode(&key);
 
         let replication = Replication {
             action: Action::Delete,
@@ -165,10 +437,17 @@ impl KeyValueService for KeyValueStoreImpl {
                 key: Some(key.clone()),
                 value: None,
             },
+            expires_at: NEVER_EXPIRES,
+            version: 0,
         };
 
-        let mut index_engine = self.index_engine.lock().await;
-        index_engine.delete(&key_bytes).map_err(ServerError::from)?;
+        self.write(vec![(key_bytes.clone(), None)], |index_engine| {
+            match expected_version {
+                0 => index_engine.delete(&key_bytes),
+                expected_version => index_engine.compare_and_swap(&key_bytes, expected_version, None, NEVER_EXPIRES),
+            }.map_err(ServerError::from)
+        }).await?;
+        self.wait_for_sync().await?;
 
         self.send_replication(replication).await;
 
@@ -181,8 +460,307 @@ impl KeyValueService for KeyValueStoreImpl {
 
         Ok(Response::new(reply))
     }
+
+    // vacuum only compacts the local data file, the router sends it to every node
+    async fn vacuum(&self, _request: Request<VacuumRequest>) -> Result<Response<VacuumResponse>, Status> {
+        let mut index_engine = self.index_engine.lock().await;
+        index_engine.vacuum().map_err(ServerError::from)?;
+
+        Ok(Response::new(VacuumResponse {}))
+    }
+
+    // copies the data file in between two writes while the server keeps serving, the dirty pages are written
+    // first. the wal is archived from then on, also after a restart
+    async fn backup(&self, request: Request<BackupRequest>) -> Result<Response<BackupResponse>, Status> {
+        let backup_file = request.into_inner().backup_file;
+        let backup = self.backup.as_ref()
+            .ok_or_else(|| ServerError::InvalidArgument("memory storage can't be backed up".to_string()))?;
+        if backup_file.is_empty() {
+            return Err(ServerError::InvalidArgument("backup_file must be set".to_string()).into());
+        }
+        if Path::new(&backup_file).exists() {
+            return Err(ServerError::InvalidArgument(format!("{} already exists", backup_file)).into());
+        }
+
+        let mut target = encrypted_file_handler::open_file_handler(&backup_file, backup.encryption, backup.key.as_ref()).map_err(ServerError::from)?;
+        let archive = WalArchive::create(&backup.wal_archive_dir, backup.key.clone()).map_err(ServerError::from)?;
+        let manifest = self.index_engine.lock().await.backup(target.as_mut(), Some(archive)).map_err(ServerError::from)?;
+        manifest.save(&backup_file).map_err(ServerError::from)?;
+        info!("backed up to {} before transaction {}", backup_file, manifest.transaction_id);
+
+        Ok(Response::new(BackupResponse {}))
+    }
+
+    type ScanStream = ScanStream;
+
+    // the keys are stored in key order, so the range is read from the index engine page by page while it is sent
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<Self::ScanStream>, Status> {
+        let request = request.into_inner();
+        let prefix = match request.prefix.as_str() {
+            "" => None,
+            prefix => Some(key_order::encode_prefix(prefix)),
+        };
+        let range = (
+            key_order::range_start(request.from.as_ref(), prefix.as_deref()),
+            request.to.as_ref().map_or(Bound::Unbounded, |to| Bound::Excluded(key_order::encode(to))),
+        );
+        let limit = match request.limit {
+            0 => usize::MAX,
+            limit => limit as usize,
+        };
+
+        let (tx, rx) = channel(SCAN_PAGE_SIZE);
+        let index_engine = self.index_engine.clone();
+        tokio::spawn(async move {
+            if let Err(e) = scan_pages(index_engine, range, prefix, limit, &tx).await {
+                let _ = tx.send(Err(e.into())).await;
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn transaction(&self, request: Request<TransactionRequest>) -> Result<Response<TransactionResponse>, Status> {
+        let request = request.into_inner();
+        let operations = request.operations;
+        if operations.is_empty() {
+            return Err(ServerError::InvalidArgument("operations must not be empty".to_string()).into());
+        }
+        let leader_version = self.leader_version(request.leader_version).await?;
+
+        let mut replication = Replication {
+            action: Action::Transaction(operations.clone()),
+            key_value: KeyValue::default(),
+            expires_at: NEVER_EXPIRES,
+            version: 0,
+        };
+
+        let writes: Vec<_> = operations.iter()
+            .filter_map(|operation| match &operation.operation {
+                Some(Operation::Create(key_value)) | Some(Operation::Update(key_value)) => key_value.key.as_ref()
+                    .map(|key| (key_order::encode(key), key_value.value.clone())),
+                Some(Operation::Delete(key)) => Some((key_order::encode(key), None)),
+                None => None,
+            })
+            .collect();
+        // all keys are written with the version of the transaction, a transaction of deletes only leaves none behind
+        let written_key = writes.iter().find(|(_, value)| value.is_some()).map(|(key, _)| key.clone());
+        replication.version = self.write(writes, |index_engine| {
+            with_version(index_engine, leader_version, |index_engine| {
+                let mut transaction = index_engine.begin();
+                if let Err(e) = stage_operations(&mut transaction, operations) {
+                    index_engine.rollback(transaction);
+                    return Err(e);
+                }
+                index_engine.commit(transaction).map_err(ServerError::from)
+            })?;
+            match written_key {
+                Some(key) => written_version(index_engine, &key),
+                None => Ok(0),
+            }
+        }).await?;
+        self.wait_for_sync().await?;
+
+        self.send_replication(replication).await;
+
+        Ok(Response::new(TransactionResponse {}))
+    }
+
+    type QueryByFieldStream = QueryByFieldStream;
+
+    // looks the keys up in the secondary index of the field, the documents are ordered by key like a scan
+    async fn query_by_field(&self, request: Request<QueryByFieldRequest>) -> Result<Response<Self::QueryByFieldStream>, Status> {
+        let request = request.into_inner();
+        let value = request.value
+            .ok_or_else(|| ServerError::InvalidArgument("value must be set".to_string()))?;
+        let field = self.secondary_indexes.lock().await.iter()
+            .position(|secondary_index| secondary_index.field_path() == request.field_path)
+            .ok_or_else(|| ServerError::InvalidArgument(format!("no secondary index on field {:?}", request.field_path)))?;
+        let limit = match request.limit {
+            0 => usize::MAX,
+            limit => limit as usize,
+        };
+
+        let (tx, rx) = channel(SCAN_PAGE_SIZE);
+        let secondary_indexes = self.secondary_indexes.clone();
+        let index_engine = self.index_engine.clone();
+        tokio::spawn(async move {
+            if let Err(e) = query_pages(secondary_indexes, index_engine, field, value, limit, &tx).await {
+                let _ = tx.send(Err(e.into())).await;
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
 }
 
+fn stage_operations(transaction: &mut Transaction<Vec<u8>, Vec<u8>>, operations: Vec<TransactionOperation>) -> Result<(), ServerError> {
+    for operation in operations {
+        match operation.operation.ok_or_else(|| ServerError::InvalidArgument("operation must be set".to_string()))? {
+            Operation::Create(key_value) => transaction.insert(to_document(key_value)?),
+            Operation::Update(key_value) => transaction.update(to_document(key_value)?),
+            Operation::Delete(key) => transaction.delete(key_order::encode(&key)),
+        }
+    }
+
+    Ok(())
+}
+
+// sends up to `limit` documents of `range` whose key starts with `prefix`. the index engine is only locked for a
+// page at a time, so a scan sees the writes that happen while it runs after the key it got to
+async fn scan_pages(index_engine: SharedIndex, mut range: (Bound<Vec<u8>>, Bound<Vec<u8>>), prefix: Option<Vec<u8>>, mut limit: usize, tx: &Sender<Result<ScanResponse, Status>>) -> Result<(), ServerError> {
+    while limit > 0 {
+        let page_size = limit.min(SCAN_PAGE_SIZE);
+        let page = index_engine.lock().await.scan(range.clone(), Some(page_size))?;
+        let is_last_page = page.len() < page_size;
+        for document in page {
+            if prefix.as_ref().is_some_and(|prefix| !document.id.starts_with(prefix)) {
+                return Ok(());
+            }
+            range.0 = Bound::Excluded(document.id.clone());
+            // the client is gone
+            if tx.send(Ok(ScanResponse { key_value: Some(to_key_value(document)?) })).await.is_err() {
+                return Ok(());
+            }
+            limit -= 1;
+        }
+        if is_last_page {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// sends up to `limit` documents whose field is `value`, a page of keys of the secondary index at a time like
+// `scan_pages`. the documents of a page are read before the secondary indexes are unlocked, so they match the keys
+async fn query_pages(secondary_indexes: Arc<Mutex<Vec<SecondaryIndex>>>, index_engine: SharedIndex, field: usize, value: Value, mut limit: usize, tx: &Sender<Result<QueryByFieldResponse, Status>>) -> Result<(), ServerError> {
+    let mut after = None;
+    while limit > 0 {
+        let mut key_values = Vec::new();
+        let is_last_page = {
+            let mut secondary_indexes = secondary_indexes.lock().await;
+            let keys = secondary_indexes[field].keys(&value, after.as_deref(), SCAN_PAGE_SIZE)?;
+            let mut index_engine = index_engine.lock().await;
+            for key in &keys {
+                // expired, the index keeps it until the key is deleted
+                match index_engine.search(key) {
+                    Ok(document) if key_values.len() < limit => key_values.push(to_key_value(document)?),
+                    Ok(_) => break,
+                    Err(e) if matches!(e.downcast_ref::<IndexError>(), Some(IndexError::NotFound)) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            after = keys.last().cloned();
+            keys.len() < SCAN_PAGE_SIZE
+        };
+        for key_value in key_values {
+            // the client is gone
+            if tx.send(Ok(QueryByFieldResponse { key_value: Some(key_value) })).await.is_err() {
+                return Ok(());
+            }
+            limit -= 1;
+        }
+        if is_last_page {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// the values stored under `keys`, None for keys without a document. expired documents are still in the
+// secondary indexes, so they are read too
+fn read_values(index_engine: &mut dyn Index<Vec<u8>, Vec<u8>>, keys: &BTreeSet<Vec<u8>>) -> Result<Vec<Option<Value>>, ServerError> {
+    let mut values = Vec::with_capacity(keys.len());
+    for key in keys {
+        match index_engine.search_including_expired(key) {
+            Ok(document) => values.push(Some(Value::decode(Bytes::from(document.value))?)),
+            Err(e) if matches!(e.downcast_ref::<IndexError>(), Some(IndexError::NotFound)) => values.push(None),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(values)
+}
+
+// when a key written with `ttl_ms` expires
+fn expires_at(ttl_ms: u64) -> u64 {
+    match ttl_ms {
+        0 => NEVER_EXPIRES,
+        ttl_ms => unix_millis().saturating_add(ttl_ms),
+    }
+}
+
+// the ttl left until `expires_at`, a key that expired in the meantime gets the shortest ttl instead of none
+fn remaining_ttl_ms(expires_at: u64) -> u64 {
+    match expires_at {
+        NEVER_EXPIRES => 0,
+        expires_at => expires_at.saturating_sub(unix_millis()).max(1),
+    }
+}
+
+fn to_key_value(document: Document<Vec<u8>, Vec<u8>>) -> Result<KeyValue, ServerError> {
+    Ok(KeyValue {
+        key: Some(key_order::decode(&document.id)?),
+        value: Some(Value::decode(Bytes::from(document.value))?),
+    })
+}
+
+fn to_document(key_value: KeyValue) -> Result<Document<Vec<u8>, Vec<u8>>, ServerError> {
+    let key_val = key_value.key.ok_or_else(|| ServerError::InvalidArgument("key must be set".to_string()))?;
+    let value_val = key_value.value.ok_or_else(|| ServerError::InvalidArgument("value must be set".to_string()))?;
+
+    Ok(Document {
+        id: key_order::encode(&key_val),
+        value: value_val.encode_to_vec(),
+    })
+}
+
+
+// waits `window` after the first write of a batch so concurrent writes can join it, then syncs the batch with
+// one fsync and acknowledges all of its writes with the result
+fn start_group_commit(index_engine: SharedIndex, window: Duration) -> Sender<SyncWaiter> {
+    let (tx, mut rx) = channel::<SyncWaiter>(1000);
+    tokio::spawn(async move {
+        while let Some(waiter) = rx.recv().await {
+            tokio::time::sleep(window).await;
+            let mut waiters = vec![waiter];
+            while let Ok(waiter) = rx.try_recv() {
+                waiters.push(waiter);
+            }
+
+            let result = index_engine.lock().await.sync().map_err(|e| e.to_string());
+            if let Err(e) = &result {
+                error!("Failed to sync {} writes: {}", waiters.len(), e);
+            }
+            for waiter in waiters {
+                // the request may have timed out in the meantime
+                let _ = waiter.send(result.clone());
+            }
+        }
+    });
+
+    tx
+}
+
+// runs `write` with the version of the leader if it is set, see `Index::pin_version`
+fn with_version<T>(index_engine: &mut dyn Index<Vec<u8>, Vec<u8>>, leader_version: Option<u64>, write: impl FnOnce(&mut dyn Index<Vec<u8>, Vec<u8>>) -> Result<T, ServerError>) -> Result<T, ServerError> {
+    if leader_version.is_none() {
+        return write(index_engine);
+    }
+
+    index_engine.pin_version(leader_version).map_err(ServerError::from)?;
+    let result = write(index_engine);
+    index_engine.pin_version(None).map_err(ServerError::from)?;
+    result
+}
+
+// the version the key was just written with, 0 if it expired already and doesn't need one
+fn written_version(index_engine: &mut dyn Index<Vec<u8>, Vec<u8>>, key: &Vec<u8>) -> Result<u64, ServerError> {
+    match index_engine.version(key) {
+        Ok(version) => Ok(version),
+        Err(e) if matches!(e.downcast_ref::<IndexError>(), Some(IndexError::NotFound)) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
 
 async fn start_replicator(mut rx: Receiver<Replication>, config_manager: Arc<Mutex<Box<dyn ConfigManager>>>) {
     let config_manager = config_manager.clone();
@@ -225,15 +803,20 @@ async fn replicate_to_follower(follower_address: String, message: Replication) {
 
 async fn handle_message(client: &mut KeyValueServiceClient<tonic::transport::Channel>, message: Replication) {
     match message.action {
-        Action::Add => replicate_create(client, message.key_value).await,
-        Action::Update => replicate_update(client, message.key_value).await,
+        Action::Add => replicate_create(client, message.key_value, remaining_ttl_ms(message.expires_at), false, message.version).await,
+        Action::Upsert => replicate_create(client, message.key_value, remaining_ttl_ms(message.expires_at), true, message.version).await,
+        Action::Update => replicate_update(client, message.key_value, remaining_ttl_ms(message.expires_at), message.version).await,
         Action::Delete => replicate_delete(client, message.key_value.key).await,
+        Action::Transaction(operations) => replicate_transaction(client, operations, message.version).await,
     }
 }
 
-async fn replicate_create(client: &mut KeyValueServiceClient<tonic::transport::Channel>, key_value: KeyValue) {
+async fn replicate_create(client: &mut KeyValueServiceClient<tonic::transport::Channel>, key_value: KeyValue, ttl_ms: u64, upsert: bool, leader_version: u64) {
     let request = Request::new(CreateRequest {
         key_value: Some(key_value),
+        ttl_ms,
+        upsert,
+        leader_version,
     });
     match client.create(request).await {
         Ok(_) => {
@@ -245,9 +828,12 @@ async fn replicate_create(client: &mut KeyValueServiceClient<tonic::transport::C
     }
 }
 
-async fn replicate_update(client: &mut KeyValueServiceClient<tonic::transport::Channel>, key_value: KeyValue) {
+async fn replicate_update(client: &mut KeyValueServiceClient<tonic::transport::Channel>, key_value: KeyValue, ttl_ms: u64, leader_version: u64) {
     let request = Request::new(UpdateRequest {
         key_value: Some(key_value),
+        ttl_ms,
+        expected_version: 0,
+        leader_version,
     });
     match client.update(request).await {
         Ok(_) => {}
@@ -260,6 +846,7 @@ async fn replicate_update(client: &mut KeyValueServiceClient<tonic::transport::C
 async fn replicate_delete(client: &mut KeyValueServiceClient<tonic::transport::Channel>, key: Option<Value>) {
     let request = Request::new(DeleteRequest {
         key,
+        expected_version: 0,
     });
     match client.delete(request).await {
         Ok(_) => {}
@@ -269,6 +856,19 @@ async fn replicate_delete(client: &mut KeyValueServiceClient<tonic::transport::C
     }
 }
 
+async fn replicate_transaction(client: &mut KeyValueServiceClient<tonic::transport::Channel>, operations: Vec<TransactionOperation>, leader_version: u64) {
+    let request = Request::new(TransactionRequest {
+        operations,
+        leader_version,
+    });
+    match client.transaction(request).await {
+        Ok(_) => {}
+        Err(e) => {
+            error!("Failed to replicate transaction to follower: {:?}", e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
@@ -276,8 +876,13 @@ mod tests {
     use mockall::mock;
     use mockall::predicate;
     use prost_types::value::Kind;
+    use storageengine::backup::BackupManifest;
+    use storageengine::file_handler::FileHandler;
+    use storageengine::file_header::FileHeader;
+    use storageengine::transaction::Snapshot;
 
     use super::*;
+    use crate::test_support::{memory_file_handler, memory_index_engine, memory_wal, open_index_engine, string};
 
     mock! {
         IndexImpl{}
@@ -286,6 +891,23 @@ mod tests {
             fn update(&mut self, key: &Vec<u8>, document: Document<Vec<u8>, Vec<u8>>) -> Result<()>;
             fn delete(&mut self, key: &Vec<u8>) -> Result<()>;
             fn search(&mut self, key: &Vec<u8>) -> Result<Document<Vec<u8>, Vec<u8>>>;
+            fn search_at(&mut self, key: &Vec<u8>, snapshot: &Snapshot) -> Result<Document<Vec<u8>, Vec<u8>>>;
+            fn snapshot(&mut self) -> Snapshot;
+            fn release_snapshot(&mut self, snapshot: Snapshot);
+            fn commit(&mut self, transaction: Transaction<Vec<u8>, Vec<u8>>) -> Result<()>;
+            fn vacuum(&mut self) -> Result<()>;
+            fn sync(&mut self) -> Result<()>;
+            fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest>;
+            fn header(&self) -> FileHeader;
+            fn set_header(&mut self, header: FileHeader) -> Result<()>;
+            fn scan(&mut self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>), limit: Option<usize>) -> Result<Vec<Document<Vec<u8>, Vec<u8>>>>;
+            fn insert_expiring(&mut self, document: Document<Vec<u8>, Vec<u8>>, expires_at: u64) -> Result<()>;
+            fn update_expiring(&mut self, key: &Vec<u8>, document: Document<Vec<u8>, Vec<u8>>, expires_at: u64) -> Result<()>;
+            fn expired(&mut self, now: u64, limit: usize) -> Result<Vec<Vec<u8>>>;
+            fn search_including_expired(&mut self, key: &Vec<u8>) -> Result<Document<Vec<u8>, Vec<u8>>>;
+            fn version(&mut self, key: &Vec<u8>) -> Result<u64>;
+            fn pin_version(&mut self, version: Option<u64>) -> Result<()>;
+            fn compare_and_swap(&mut self, key: &Vec<u8>, expected_version: u64, document: Option<Document<Vec<u8>, Vec<u8>>>, expires_at: u64) -> Result<()>;
         }
     }
 
@@ -299,12 +921,16 @@ mod tests {
         }
     }
 
+    fn user(email: &str) -> Value {
+        Value { kind: Some(Kind::StructValue(prost_types::Struct { fields: [("email".to_string(), string(email))].into() })) }
+    }
+
     #[tokio::test]
     async fn test_get() {
         let key = Value {
             kind: Some(Kind::StringValue("test".to_string())),
         };
-        let key_bytes = key.encode_to_vec();
+        let key_bytes = key_order::encode(&key);
         let key_bytes_clone = key.encode_to_vec();
 
         let mut mock_index = MockIndexImpl::new();
@@ -314,6 +940,9 @@ mod tests {
                 id: key_bytes.clone(),
                 value: key_bytes_clone.clone(),
             }));
+        mock_index.expect_version()
+            .with(predicate::always())
+            .returning(|_| Ok(7));
         let mut mock_config_manager = MockConfigManagerImpl::new();
         mock_config_manager.expect_get_follower_addresses()
             .returning(|| Ok(vec![]));
@@ -324,8 +953,9 @@ mod tests {
             key: Some(key),
         });
 
-        let response = service.get(request).await.unwrap();
-        let key_value = response.into_inner().key_value.unwrap();
+        let response = service.get(request).await.unwrap().into_inner();
+        assert_eq!(response.version, 7);
+        let key_value = response.key_value.unwrap();
         let key = match key_value.key.unwrap().kind {
             Some(Kind::StringValue(s)) => s,
             _ => String::from(""),
@@ -377,6 +1007,9 @@ mod tests {
     #[tokio::test]
     async fn test_create() {
         let mut mock_index = MockIndexImpl::new();
+        mock_index.expect_version()
+            .with(predicate::always())
+            .returning(|_| Ok(1));
         mock_index.expect_insert()
             .with(predicate::always())
             .returning(move |_| Ok(()));
@@ -388,6 +1021,9 @@ mod tests {
                 key: Some(Value { kind: Some(Kind::StringValue("test".to_string())) }),
                 value: Some(Value { kind: Some(Kind::StringValue("value".to_string())) }),
             }),
+            ttl_ms: 0,
+            upsert: false,
+            leader_version: 0,
         });
 
         let response = service.create(request).await.unwrap();
@@ -400,6 +1036,67 @@ mod tests {
         assert_eq!(key, "test".to_string());
     }
 
+    #[tokio::test]
+    async fn test_create_with_group_commit_syncs_concurrent_writes_once() {
+        let mut mock_index = MockIndexImpl::new();
+        mock_index.expect_version()
+            .with(predicate::always())
+            .returning(|_| Ok(1));
+        mock_index.expect_insert()
+            .times(3)
+            .returning(move |_| Ok(()));
+        mock_index.expect_sync()
+            .times(1)
+            .returning(|| Ok(()));
+        let mock_config_manager = MockConfigManagerImpl::new();
+        let service = KeyValueStoreImpl::new(Box::new(mock_index), Box::new(mock_config_manager)).await
+            .with_group_commit(Duration::from_millis(50));
+        let request = |key: &str| Request::new(CreateRequest {
+            key_value: Some(KeyValue {
+                key: Some(Value { kind: Some(Kind::StringValue(key.to_string())) }),
+                value: Some(Value { kind: Some(Kind::StringValue("value".to_string())) }),
+            }),
+            ttl_ms: 0,
+            upsert: false,
+            leader_version: 0,
+        });
+
+        let (first, second, third) = tokio::join!(
+            service.create(request("first")),
+            service.create(request("second")),
+            service.create(request("third")),
+        );
+
+        assert!(first.is_ok() && second.is_ok() && third.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_create_fails_if_group_commit_fails() {
+        let mut mock_index = MockIndexImpl::new();
+        mock_index.expect_version()
+            .with(predicate::always())
+            .returning(|_| Ok(1));
+        mock_index.expect_insert()
+            .returning(move |_| Ok(()));
+        mock_index.expect_sync()
+            .returning(|| Err(anyhow::anyhow!("disk is gone")));
+        let mock_config_manager = MockConfigManagerImpl::new();
+        let service = KeyValueStoreImpl::new(Box::new(mock_index), Box::new(mock_config_manager)).await
+            .with_group_commit(Duration::from_millis(1));
+
+        let response = service.create(Request::new(CreateRequest {
+            key_value: Some(KeyValue {
+                key: Some(Value { kind: Some(Kind::StringValue("test".to_string())) }),
+                value: Some(Value { kind: Some(Kind::StringValue("value".to_string())) }),
+            }),
+            ttl_ms: 0,
+            upsert: false,
+            leader_version: 0,
+        })).await;
+
+        assert_eq!(response.err().unwrap().code(), tonic::Code::Internal);
+    }
+
     #[tokio::test]
     async fn test_create_no_key() {
         let mock_index = MockIndexImpl::new();
@@ -409,6 +1106,9 @@ mod tests {
 
         let request = Request::new(CreateRequest {
             key_value: None,
+            ttl_ms: 0,
+            upsert: false,
+            leader_version: 0,
         });
 
         let response = service.create(request).await;
@@ -420,6 +1120,9 @@ mod tests {
                 key: None,
                 value: Some(Value { kind: Some(Kind::StringValue("value".to_string())) }),
             }),
+            ttl_ms: 0,
+            upsert: false,
+            leader_version: 0,
         });
 
         let response = service.create(request).await;
@@ -448,6 +1151,9 @@ mod tests {
                 key: Some(key),
                 value: Some(Value { kind: Some(Kind::StringValue("value".to_string())) }),
             }),
+            ttl_ms: 0,
+            upsert: false,
+            leader_version: 0,
         });
 
         let response = service.create(request).await;
@@ -460,9 +1166,12 @@ mod tests {
         let key = Value {
             kind: Some(Kind::StringValue("test".to_string())),
         };
-        let key_bytes = key.encode_to_vec();
+        let key_bytes = key_order::encode(&key);
 
         let mut mock_index = MockIndexImpl::new();
+        mock_index.expect_version()
+            .with(predicate::always())
+            .returning(|_| Ok(1));
         mock_index.expect_update()
             .with(predicate::eq(key_bytes.clone()), predicate::always())
             .returning(move |_, _| Ok(()));
@@ -474,6 +1183,9 @@ mod tests {
                 key: Some(Value { kind: Some(Kind::StringValue("test".to_string())) }),
                 value: Some(Value { kind: Some(Kind::StringValue("value".to_string())) }),
             }),
+            ttl_ms: 0,
+            expected_version: 0,
+            leader_version: 0,
         });
 
         let response = service.update(request).await.unwrap();
@@ -495,6 +1207,9 @@ mod tests {
 
         let request = Request::new(UpdateRequest {
             key_value: None,
+            ttl_ms: 0,
+            expected_version: 0,
+            leader_version: 0,
         });
 
         let response = service.update(request).await;
@@ -506,6 +1221,9 @@ mod tests {
                 key: None,
                 value: Some(Value { kind: Some(Kind::StringValue("value".to_string())) }),
             }),
+            ttl_ms: 0,
+            expected_version: 0,
+            leader_version: 0,
         });
 
         let response = service.update(request).await;
@@ -534,6 +1252,9 @@ mod tests {
                 key: Some(key),
                 value: Some(Value { kind: Some(Kind::StringValue("value".to_string())) }),
             }),
+            ttl_ms: 0,
+            expected_version: 0,
+            leader_version: 0,
         });
 
         let response = service.update(request).await;
@@ -546,7 +1267,7 @@ mod tests {
         let key = Value {
             kind: Some(Kind::StringValue("test".to_string())),
         };
-        let key_bytes = key.encode_to_vec();
+        let key_bytes = key_order::encode(&key);
 
         let mut mock_index = MockIndexImpl::new();
         mock_index.expect_delete()
@@ -557,6 +1278,7 @@ mod tests {
 
         let request = Request::new(DeleteRequest {
             key: Some(Value { kind: Some(Kind::StringValue("test".to_string())) }),
+            expected_version: 0,
         });
 
         let response = service.delete(request).await.unwrap();
@@ -578,6 +1300,7 @@ mod tests {
 
         let request = Request::new(DeleteRequest {
             key: None,
+            expected_version: 0,
         });
 
         let response = service.delete(request).await;
@@ -603,10 +1326,362 @@ mod tests {
 
         let request = Request::new(DeleteRequest {
             key: Some(key),
+            expected_version: 0,
         });
 
         let response = service.delete(request).await;
         assert!(response.is_err());
         assert_eq!(response.err().unwrap().code(), tonic::Code::NotFound);
     }
+
+    #[tokio::test]
+    async fn test_vacuum() {
+        let mut mock_index = MockIndexImpl::new();
+        mock_index.expect_vacuum()
+            .times(1)
+            .returning(|| Ok(()));
+        let mock_config_manager = MockConfigManagerImpl::new();
+        let service = KeyValueStoreImpl::new(Box::new(mock_index), Box::new(mock_config_manager)).await;
+
+        let response = service.vacuum(Request::new(VacuumRequest {})).await;
+
+        assert!(response.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_vacuum_error() {
+        let mut mock_index = MockIndexImpl::new();
+        mock_index.expect_vacuum()
+            .returning(|| Err(anyhow::anyhow!("disk full")));
+        let mock_config_manager = MockConfigManagerImpl::new();
+        let service = KeyValueStoreImpl::new(Box::new(mock_index), Box::new(mock_config_manager)).await;
+
+        let response = service.vacuum(Request::new(VacuumRequest {})).await;
+
+        assert_eq!(response.unwrap_err().code(), tonic::Code::Internal);
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let mut mock_index = MockIndexImpl::new();
+        mock_index.expect_version()
+            .with(predicate::always())
+            .returning(|_| Ok(1));
+        mock_index.expect_snapshot()
+            .times(1)
+            .returning(|| Snapshot { xmax: 0 });
+        mock_index.expect_commit()
+            .withf(|transaction| transaction.snapshot() == &Snapshot { xmax: 0 })
+            .times(1)
+            .returning(|_| Ok(()));
+        let mock_config_manager = MockConfigManagerImpl::new();
+        let service = KeyValueStoreImpl::new(Box::new(mock_index), Box::new(mock_config_manager)).await;
+
+        let request = Request::new(TransactionRequest {
+            operations: vec![
+                TransactionOperation {
+                    operation: Some(Operation::Update(KeyValue {
+                        key: Some(Value { kind: Some(Kind::StringValue("from".to_string())) }),
+                        value: Some(Value { kind: Some(Kind::NumberValue(5.0)) }),
+                    })),
+                },
+                TransactionOperation {
+                    operation: Some(Operation::Delete(Value { kind: Some(Kind::StringValue("to".to_string())) })),
+                },
+            ],
+            leader_version: 0,
+        });
+
+        let response = service.transaction(request).await;
+
+        assert!(response.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rolls_back_invalid_operations() {
+        let mut mock_index = MockIndexImpl::new();
+        mock_index.expect_snapshot()
+            .returning(|| Snapshot { xmax: 0 });
+        mock_index.expect_release_snapshot()
+            .times(1)
+            .returning(|_| ());
+        mock_index.expect_commit().times(0);
+        let mock_config_manager = MockConfigManagerImpl::new();
+        let service = KeyValueStoreImpl::new(Box::new(mock_index), Box::new(mock_config_manager)).await;
+
+        let request = Request::new(TransactionRequest {
+            operations: vec![TransactionOperation { operation: None }],
+            leader_version: 0,
+        });
+
+        let response = service.transaction(request).await;
+
+        assert_eq!(response.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_conflict() {
+        let mut mock_index = MockIndexImpl::new();
+        mock_index.expect_snapshot()
+            .returning(|| Snapshot { xmax: 0 });
+        mock_index.expect_commit()
+            .returning(|_| Err(indexengine::index::IndexError::Conflict.into()));
+        let mock_config_manager = MockConfigManagerImpl::new();
+        let service = KeyValueStoreImpl::new(Box::new(mock_index), Box::new(mock_config_manager)).await;
+
+        let request = Request::new(TransactionRequest {
+            operations: vec![TransactionOperation {
+                operation: Some(Operation::Delete(Value { kind: Some(Kind::StringValue("key".to_string())) })),
+            }],
+            leader_version: 0,
+        });
+
+        let response = service.transaction(request).await;
+
+        assert_eq!(response.unwrap_err().code(), tonic::Code::Aborted);
+    }
+
+    #[tokio::test]
+    async fn test_scan_streams_range_in_pages() -> Result<()> {
+        use tokio_stream::StreamExt;
+
+        let number = |n: f64| Value { kind: Some(Kind::NumberValue(n)) };
+
+        let index_engine = memory_index_engine()?;
+        let service = KeyValueStoreImpl::new(index_engine, Box::new(MockConfigManagerImpl::new())).await;
+
+        let mut keys: Vec<Value> = (0..250).rev().map(|i| string(&format!("key{}", i))).collect();
+        keys.extend([string("b"), string("ab"), number(10.0), number(-1.0)]);
+        for key in keys {
+            service.create(Request::new(CreateRequest { key_value: Some(KeyValue { key: Some(key), value: Some(string("value")) }), ttl_ms: 0, upsert: false, leader_version: 0 })).await?;
+        }
+
+        let scan = |from: Option<Value>, to: Option<Value>, prefix: &str, limit: u32| Request::new(ScanRequest { from, to, prefix: prefix.to_string(), limit });
+        let keys = |request| async {
+            service.scan(request).await.unwrap().into_inner()
+                .map(|response| response.unwrap().key_value.unwrap().key.unwrap())
+                .collect::<Vec<_>>().await
+        };
+
+        assert_eq!(keys(scan(None, Some(string("b")), "", 3)).await, vec![number(-1.0), number(10.0), string("ab")]);
+        assert_eq!(keys(scan(Some(string("ab")), None, "", 0)).await.len(), 252);
+        let with_prefix = keys(scan(Some(string("key1")), None, "key", 0)).await;
+        assert_eq!(with_prefix.len(), 249);
+        assert_eq!(with_prefix.first(), Some(&string("key1")));
+        assert_eq!(with_prefix.last(), Some(&string("key99")));
+        assert_eq!(keys(scan(Some(string("key3")), Some(string("key4")), "key", 0)).await.len(), 11);
+        assert!(keys(scan(Some(string("c")), Some(string("b")), "", 0)).await.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_by_field_follows_writes() -> Result<()> {
+        use tokio_stream::StreamExt;
+
+        let key_value = |key: &str, email: &str| Some(KeyValue { key: Some(string(key)), value: Some(user(email)) });
+
+        let mut index_engine = memory_index_engine()?;
+        let secondary_index = SecondaryIndex::new("email", memory_file_handler(), memory_wal()?, index_engine.as_mut())?;
+        let service = KeyValueStoreImpl::new(index_engine, Box::new(MockConfigManagerImpl::new())).await
+            .with_secondary_indexes(vec![secondary_index]);
+
+        for key in ["c", "a", "b"] {
+            service.create(Request::new(CreateRequest { key_value: key_value(key, "x@y.z"), ttl_ms: 0, upsert: false, leader_version: 0 })).await?;
+        }
+        service.update(Request::new(UpdateRequest { key_value: key_value("b", "b@y.z"), ttl_ms: 0, expected_version: 0, leader_version: 0 })).await?;
+        service.delete(Request::new(DeleteRequest { key: Some(string("c")), expected_version: 0 })).await?;
+        service.transaction(Request::new(TransactionRequest {
+            operations: vec![
+                TransactionOperation { operation: Some(Operation::Create(key_value("d", "x@y.z").unwrap())) },
+                TransactionOperation { operation: Some(Operation::Update(key_value("d", "d@y.z").unwrap())) },
+                TransactionOperation { operation: Some(Operation::Create(key_value("e", "x@y.z").unwrap())) },
+            ],
+            leader_version: 0,
+        })).await?;
+        assert_eq!(service.create(Request::new(CreateRequest { key_value: key_value("a", "a@y.z"), ttl_ms: 0, upsert: false, leader_version: 0 })).await.unwrap_err().code(), tonic::Code::AlreadyExists);
+
+        let query = |value: &str, limit: u32| Request::new(QueryByFieldRequest { field_path: "email".to_string(), value: Some(string(value)), limit });
+        let keys: Vec<Value> = service.query_by_field(query("x@y.z", 0)).await?.into_inner()
+            .map(|response| response.unwrap().key_value.unwrap().key.unwrap())
+            .collect().await;
+        assert_eq!(keys, vec![string("a"), string("e")]);
+        assert_eq!(service.query_by_field(query("d@y.z", 1)).await?.into_inner().collect::<Vec<_>>().await.len(), 1);
+
+        // more keys than fit in a page
+        for i in (0..250).rev() {
+            service.create(Request::new(CreateRequest { key_value: key_value(&format!("key{:03}", i), "many@y.z"), ttl_ms: 0, upsert: false, leader_version: 0 })).await?;
+        }
+        let keys: Vec<Value> = service.query_by_field(query("many@y.z", 0)).await?.into_inner()
+            .map(|response| response.unwrap().key_value.unwrap().key.unwrap())
+            .collect().await;
+        assert_eq!(keys, (0..250).map(|i| string(&format!("key{:03}", i))).collect::<Vec<_>>());
+        assert_eq!(service.query_by_field(query("many@y.z", 120)).await?.into_inner().collect::<Vec<_>>().await.len(), 120);
+        assert_eq!(service.query_by_field(Request::new(QueryByFieldRequest { field_path: "name".to_string(), value: Some(string("a")), limit: 0 })).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ttl_is_rejected_if_the_index_engine_cant_expire_keys() {
+        let service = KeyValueStoreImpl::new(Box::new(MockIndexImpl::new()), Box::new(MockConfigManagerImpl::new())).await;
+        let key_value = Some(KeyValue { key: Some(string("a")), value: Some(string("value")) });
+
+        let create = service.create(Request::new(CreateRequest { key_value: key_value.clone(), ttl_ms: 1000, upsert: false, leader_version: 0 })).await;
+        assert_eq!(create.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let update = service.update(Request::new(UpdateRequest { key_value, ttl_ms: 1000, expected_version: 0, leader_version: 0 })).await;
+        assert_eq!(update.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_backup_copies_the_data_file_while_serving() -> Result<()> {
+        use storageengine::file_handler::FileHandlerImpl;
+
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let create = |key: &str| Request::new(CreateRequest { key_value: Some(KeyValue { key: Some(string(key)), value: Some(string("value")) }), ttl_ms: 0, upsert: false, leader_version: 0 });
+        let backup = |backup_file: &str| Request::new(BackupRequest { backup_file: path(backup_file) });
+
+        let storage_file_name = path("patrick.db");
+        let service = KeyValueStoreImpl::new(open_index_engine(&storage_file_name)?, Box::new(MockConfigManagerImpl::new())).await
+            .with_backup(&storage_file_name, None, None);
+        service.create(create("a")).await?;
+        service.backup(backup("backup.db")).await?;
+        service.create(create("b")).await?;
+        assert_eq!(service.backup(backup("backup.db")).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        // its checkpoint archives the records written after the first backup
+        service.backup(backup("later.db")).await?;
+
+        let restored_file_name = path("restored.db");
+        let archive_dir = storageengine::backup::wal_archive_dir_name(&storage_file_name);
+        storageengine::backup::restore(&path("backup.db"), &archive_dir, None, &mut FileHandlerImpl::new(&restored_file_name)?, None)?;
+        let mut restored = open_index_engine(&restored_file_name)?;
+        for key in ["a", "b"] {
+            restored.search(&key_order::encode(&string(key)))?;
+        }
+
+        let service = KeyValueStoreImpl::new(Box::new(MockIndexImpl::new()), Box::new(MockConfigManagerImpl::new())).await;
+        assert_eq!(service.backup(backup("memory.db")).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reap_expired_deletes_keys_after_their_ttl() -> Result<()> {
+        use tokio_stream::StreamExt;
+
+        let key_value = |key: &str, email: &str| Some(KeyValue { key: Some(string(key)), value: Some(user(email)) });
+
+        let mut index_engine = memory_index_engine()?;
+        let secondary_index = SecondaryIndex::new("email", memory_file_handler(), memory_wal()?, index_engine.as_mut())?;
+        let expiry_index = ExpiryIndex::new(memory_file_handler(), memory_wal()?, index_engine.as_mut())?;
+        let mut mock_config_manager = MockConfigManagerImpl::new();
+        mock_config_manager.expect_is_leader()
+            .returning(|| true);
+        mock_config_manager.expect_get_follower_addresses()
+            .returning(|| Ok(vec![]));
+        let service = KeyValueStoreImpl::new(index_engine, Box::new(mock_config_manager)).await
+            .with_secondary_indexes(vec![secondary_index])
+            .with_expiry_index(expiry_index);
+
+        service.create(Request::new(CreateRequest { key_value: key_value("a", "x@y.z"), ttl_ms: 1, upsert: false, leader_version: 0 })).await?;
+        service.create(Request::new(CreateRequest { key_value: key_value("b", "x@y.z"), ttl_ms: 0, upsert: false, leader_version: 0 })).await?;
+        service.create(Request::new(CreateRequest { key_value: key_value("c", "x@y.z"), ttl_ms: 1, upsert: false, leader_version: 0 })).await?;
+        service.update(Request::new(UpdateRequest { key_value: key_value("c", "c@y.z"), ttl_ms: 60_000, expected_version: 0, leader_version: 0 })).await?;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let get = |key: &str| Request::new(GetRequest { key: Some(string(key)) });
+        assert_eq!(service.get(get("a")).await.unwrap_err().code(), tonic::Code::NotFound);
+        let query = || Request::new(QueryByFieldRequest { field_path: "email".to_string(), value: Some(string("x@y.z")), limit: 0 });
+        assert_eq!(service.query_by_field(query()).await?.into_inner().collect::<Vec<_>>().await.len(), 1);
+
+        assert_eq!(service.reap_expired(10).await.unwrap(), 1);
+        assert_eq!(service.reap_expired(10).await.unwrap(), 0);
+        service.create(Request::new(CreateRequest { key_value: key_value("a", "a@y.z"), ttl_ms: 0, upsert: false, leader_version: 0 })).await?;
+        assert!(service.get(get("c")).await.is_ok());
+        assert_eq!(service.query_by_field(query()).await?.into_inner().collect::<Vec<_>>().await.len(), 1);
+        // the entries that were due are gone, only the later one of `c` is left
+        assert_eq!(service.expiry_index.lock().await.as_mut().unwrap().due(u64::MAX, usize::MAX)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_conditional_writes_check_version() -> Result<()> {
+
+        let key_value = |value: &str| Some(KeyValue { key: Some(string("a")), value: Some(string(value)) });
+
+        let index_engine = memory_index_engine()?;
+        let service = KeyValueStoreImpl::new(index_engine, Box::new(MockConfigManagerImpl::new())).await;
+        let get = || Request::new(GetRequest { key: Some(string("a")) });
+
+        service.create(Request::new(CreateRequest { key_value: key_value("first"), ttl_ms: 0, upsert: true, leader_version: 0 })).await?;
+        service.create(Request::new(CreateRequest { key_value: key_value("second"), ttl_ms: 0, upsert: true, leader_version: 0 })).await?;
+        let response = service.get(get()).await?.into_inner();
+        assert_eq!(response.key_value.unwrap().value, Some(string("second")));
+
+        let update = |value: &str, expected_version: u64| Request::new(UpdateRequest { key_value: key_value(value), ttl_ms: 0, expected_version, leader_version: 0 });
+        service.update(update("third", response.version)).await?;
+        assert_eq!(service.update(update("fourth", response.version)).await.unwrap_err().code(), tonic::Code::FailedPrecondition);
+        let delete = |expected_version: u64| Request::new(DeleteRequest { key: Some(string("a")), expected_version });
+        assert_eq!(service.delete(delete(response.version)).await.unwrap_err().code(), tonic::Code::FailedPrecondition);
+
+        let response = service.get(get()).await?.into_inner();
+        assert_eq!(response.key_value.unwrap().value, Some(string("third")));
+        service.delete(delete(response.version)).await?;
+        assert_eq!(service.get(get()).await.unwrap_err().code(), tonic::Code::NotFound);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_followers_write_with_the_version_of_the_leader() -> Result<()> {
+        async fn version(service: &KeyValueStoreImpl, key: &str) -> Result<u64> {
+            Ok(service.get(Request::new(GetRequest { key: Some(string(key)) })).await?.into_inner().version)
+        }
+        let key_value = |key: &str| Some(KeyValue { key: Some(string(key)), value: Some(string("value")) });
+        let node = |is_leader: bool| async move {
+            let mut mock_config_manager = MockConfigManagerImpl::new();
+            mock_config_manager.expect_is_leader()
+                .returning(move || is_leader);
+            mock_config_manager.expect_get_follower_addresses()
+                .returning(|| Ok(vec![]));
+            Ok::<_, anyhow::Error>(KeyValueStoreImpl::new(memory_index_engine()?, Box::new(mock_config_manager)).await)
+        };
+        let leader = node(true).await?;
+        let follower = node(false).await?;
+
+        // the follower is ahead of the leader with versions of its own
+        for key in ["x", "y", "z"] {
+            follower.create(Request::new(CreateRequest { key_value: key_value(key), ttl_ms: 0, upsert: false, leader_version: 0 })).await?;
+        }
+        leader.create(Request::new(CreateRequest { key_value: key_value("a"), ttl_ms: 0, upsert: false, leader_version: 0 })).await?;
+        let leader_version = version(&leader, "a").await?;
+        follower.create(Request::new(CreateRequest { key_value: key_value("a"), ttl_ms: 0, upsert: false, leader_version })).await?;
+        assert_eq!(version(&follower, "a").await?, leader_version);
+
+        leader.update(Request::new(UpdateRequest { key_value: key_value("a"), ttl_ms: 0, expected_version: leader_version, leader_version: 0 })).await?;
+        let leader_version = version(&leader, "a").await?;
+        follower.update(Request::new(UpdateRequest { key_value: key_value("a"), ttl_ms: 0, expected_version: 0, leader_version })).await?;
+        assert_eq!(version(&follower, "a").await?, leader_version);
+        // the version read from the follower is accepted by the leader
+        leader.update(Request::new(UpdateRequest { key_value: key_value("a"), ttl_ms: 0, expected_version: version(&follower, "a").await?, leader_version: 0 })).await?;
+
+        let transaction = |leader_version: u64| Request::new(TransactionRequest {
+            operations: vec![TransactionOperation { operation: Some(Operation::Create(key_value("b").unwrap())) }],
+            leader_version,
+        });
+        leader.transaction(transaction(0)).await?;
+        let leader_version = version(&leader, "b").await?;
+        follower.transaction(transaction(leader_version)).await?;
+        assert_eq!(version(&follower, "b").await?, leader_version);
+
+        // new versions of the follower continue behind the ones of the leader
+        follower.create(Request::new(CreateRequest { key_value: key_value("c"), ttl_ms: 0, upsert: false, leader_version: 0 })).await?;
+        assert!(version(&follower, "c").await? > leader_version);
+        let create = leader.create(Request::new(CreateRequest { key_value: key_value("d"), ttl_ms: 0, upsert: false, leader_version: 5 })).await;
+        assert_eq!(create.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,2 @@
+// what the server and the router share
+pub mod key_order;
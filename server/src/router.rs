@@ -8,11 +8,16 @@ use log::info;
 use prost::Message;
 use rand::Rng;
 use tokio::sync::Mutex;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio_stream::{Stream, StreamExt};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 use tonic::transport::Server;
 
 use configmanager::AddressManager;
-use key_value_store::{CreateRequest, CreateResponse, DeleteRequest, DeleteResponse, GetRequest, GetResponse, UpdateRequest, UpdateResponse};
+use server::key_order;
+use key_value_store::{BackupRequest, BackupResponse, CreateRequest, CreateResponse, DeleteRequest, DeleteResponse, GetRequest, GetResponse, KeyValue, QueryByFieldRequest, QueryByFieldResponse, ScanRequest, ScanResponse, TransactionRequest, TransactionResponse, UpdateRequest, UpdateResponse, VacuumRequest, VacuumResponse};
+use key_value_store::transaction_operation::Operation;
 use key_value_store::key_value_service_client::KeyValueServiceClient;
 use key_value_store::key_value_service_server::KeyValueService;
 
@@ -37,6 +42,12 @@ struct Args {
     service_registry_paths: String,
 }
 
+// responses of the partitions merged ahead of the client
+const MERGE_CHANNEL_SIZE: usize = 100;
+
+type ScanStream = ReceiverStream<Result<ScanResponse, Status>>;
+type QueryByFieldStream = ReceiverStream<Result<QueryByFieldResponse, Status>>;
+
 pub struct KeyValueServiceRouter {
     address_managers: Arc<Mutex<Vec<Box<dyn AddressManager>>>>,
 }
@@ -58,6 +69,7 @@ impl KeyValueService for KeyValueServiceRouter {
         let key_bytes = key_val.encode_to_vec();
 
         let hash = calculate_hash(&key_bytes);
+        // the followers write every key with the version of the leader, so any node can answer
         let address = get_random_address(&self.address_managers, hash).await?;
 
         let mut client = KeyValueServiceClient::connect(address).await
@@ -108,6 +120,103 @@ impl KeyValueService for KeyValueServiceRouter {
             .map_err(|_| Status::internal("Could not connect to address"))?;
         client.delete(request).await
     }
+
+    // every node compacts its own data file, so the request goes to leaders and followers of all partitions
+    async fn vacuum(&self, request: Request<VacuumRequest>) -> Result<Response<VacuumResponse>, Status> {
+        let request = request.into_inner();
+        let addresses = get_all_addresses(&self.address_managers).await?;
+
+        for address in addresses {
+            let mut client = KeyValueServiceClient::connect(address).await
+                .map_err(|_| Status::internal("Could not connect to address"))?;
+            client.vacuum(request.clone()).await?;
+        }
+
+        Ok(Response::new(VacuumResponse {}))
+    }
+
+    // every node keeps a data file of its own, so all of them write a backup
+    async fn backup(&self, request: Request<BackupRequest>) -> Result<Response<BackupResponse>, Status> {
+        let request = request.into_inner();
+        let addresses = get_all_addresses(&self.address_managers).await?;
+
+        for address in addresses {
+            let mut client = KeyValueServiceClient::connect(address).await
+                .map_err(|_| Status::internal("Could not connect to address"))?;
+            client.backup(request.clone()).await?;
+        }
+
+        Ok(Response::new(BackupResponse {}))
+    }
+
+    type ScanStream = ScanStream;
+
+    // every partition holds a part of the key space, so one node of each partition is scanned and the results merged
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<Self::ScanStream>, Status> {
+        let request = request.into_inner();
+        let partition_count = self.address_managers.lock().await.len();
+
+        let mut streams = Vec::new();
+        for partition in 0..partition_count {
+            let address = get_random_address(&self.address_managers, partition).await?;
+            let mut client = KeyValueServiceClient::connect(address).await
+                .map_err(|_| Status::internal("Could not connect to address"))?;
+            streams.push(client.scan(request.clone()).await?.into_inner());
+        }
+
+        let (tx, rx) = channel(MERGE_CHANNEL_SIZE);
+        tokio::spawn(merge_key_values(streams, request.limit, |response: &ScanResponse| response.key_value.as_ref(), tx));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type QueryByFieldStream = QueryByFieldStream;
+
+    // every partition indexes its own documents, so all of them are asked like for a scan
+    async fn query_by_field(&self, request: Request<QueryByFieldRequest>) -> Result<Response<Self::QueryByFieldStream>, Status> {
+        let request = request.into_inner();
+        let partition_count = self.address_managers.lock().await.len();
+
+        let mut streams = Vec::new();
+        for partition in 0..partition_count {
+            let address = get_random_address(&self.address_managers, partition).await?;
+            let mut client = KeyValueServiceClient::connect(address).await
+                .map_err(|_| Status::internal("Could not connect to address"))?;
+            streams.push(client.query_by_field(request.clone()).await?.into_inner());
+        }
+
+        let (tx, rx) = channel(MERGE_CHANNEL_SIZE);
+        tokio::spawn(merge_key_values(streams, request.limit, |response: &QueryByFieldResponse| response.key_value.as_ref(), tx));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    // partitions don't coordinate, so a transaction is only atomic if the leader of one partition applies all of it
+    async fn transaction(&self, request: Request<TransactionRequest>) -> Result<Response<TransactionResponse>, Status> {
+        let request = request.into_inner();
+        let hashes = request.operations.iter()
+            .map(|operation| {
+                let key_val = match &operation.operation {
+                    Some(Operation::Create(key_value)) | Some(Operation::Update(key_value)) => key_value.key.clone(),
+                    Some(Operation::Delete(key)) => Some(key.clone()),
+                    None => None,
+                };
+                key_val.map(|key_val| calculate_hash(&key_val.encode_to_vec()))
+                    .ok_or_else(|| ServerError::InvalidArgument("key must be set".to_string()))
+            })
+            .collect::<Result<Vec<usize>, ServerError>>()?;
+        let hash = *hashes.first()
+            .ok_or_else(|| ServerError::InvalidArgument("operations must not be empty".to_string()))?;
+
+        let partition_count = self.address_managers.lock().await.len();
+        if hashes.iter().any(|other| other % partition_count != hash % partition_count) {
+            return Err(ServerError::InvalidArgument("all keys of a transaction must belong to the same partition".to_string()).into());
+        }
+
+        let address = get_partitioned_leader_address(&self.address_managers, hash).await?;
+
+        let mut client = KeyValueServiceClient::connect(address).await
+            .map_err(|_| Status::internal("Could not connect to address"))?;
+        client.transaction(request).await
+    }
 }
 
 async fn get_random_address(address_managers: &Arc<Mutex<Vec<Box<dyn AddressManager>>>>, hash: usize) -> Result<String, Status> {
@@ -136,6 +245,59 @@ async fn get_partitioned_leader_address(address_managers: &Arc<Mutex<Vec<Box<dyn
     Ok(addresses)
 }
 
+async fn get_all_addresses(address_managers: &Arc<Mutex<Vec<Box<dyn AddressManager>>>>) -> Result<Vec<String>, Status> {
+    let address_managers = address_managers.lock().await;
+
+    let mut addresses = Vec::new();
+    for address_manager in address_managers.iter() {
+        addresses.extend(address_manager.get_all_addresses()
+            .map_err(|_| Status::internal("Could not get addresses"))?);
+    }
+
+    Ok(addresses)
+}
+
+// merges the streams of the partitions, each ordered by key, into `tx` and holds only the next response of
+// every stream. a key value without a key sorts first, a limit of 0 sends all of them
+async fn merge_key_values<S, R>(mut streams: Vec<S>, limit: u32, key_value: fn(&R) -> Option<&KeyValue>, tx: Sender<Result<R, Status>>)
+    where S: Stream<Item=Result<R, Status>> + Unpin
+{
+    let limit = if limit == 0 { usize::MAX } else { limit as usize };
+    let mut heads = Vec::new();
+    for (i, stream) in streams.iter_mut().enumerate() {
+        match stream.next().await {
+            Some(Ok(response)) => heads.push((encoded_key(key_value(&response)), i, response)),
+            Some(Err(status)) => {
+                let _ = tx.send(Err(status)).await;
+                return;
+            }
+            None => {}
+        }
+    }
+
+    for _ in 0..limit {
+        let Some(next) = heads.iter().enumerate().min_by(|(_, a), (_, b)| (&a.0, a.1).cmp(&(&b.0, b.1))).map(|(next, _)| next) else {
+            return;
+        };
+        let (_, i, response) = heads.swap_remove(next);
+        if tx.send(Ok(response)).await.is_err() {
+            return;
+        }
+        match streams[i].next().await {
+            Some(Ok(response)) => heads.push((encoded_key(key_value(&response)), i, response)),
+            Some(Err(status)) => {
+                let _ = tx.send(Err(status)).await;
+                return;
+            }
+            None => {}
+        }
+    }
+}
+
+fn encoded_key(key_value: Option<&KeyValue>) -> Option<Vec<u8>> {
+    key_value.and_then(|key_value| key_value.key.as_ref()).map(key_order::encode)
+}
+
 fn calculate_hash(key: &Vec<u8>) -> usize {
     let mut hasher = std::collections::hash_map::DefaultHasher::new();
     key.hash(&mut hasher);
@@ -275,4 +437,79 @@ mod tests {
         let result = get_partitioned_leader_address(&address_managers, 0).await;
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_get_all_addresses() {
+        let mut first_address_manager = MockAddressManager::new();
+        first_address_manager.expect_get_all_addresses().returning(|| Ok(vec!["localhost:50051".to_string(), "localhost:50052".to_string()]));
+        let mut second_address_manager = MockAddressManager::new();
+        second_address_manager.expect_get_all_addresses().returning(|| Ok(vec!["localhost:50053".to_string()]));
+
+        let address_managers = vec![
+            Box::new(first_address_manager) as Box<dyn AddressManager>,
+            Box::new(second_address_manager) as Box<dyn AddressManager>,
+        ];
+        let address_managers = Arc::new(Mutex::new(address_managers));
+
+        let addresses = get_all_addresses(&address_managers).await.unwrap();
+        assert_eq!(addresses, vec!["localhost:50051", "localhost:50052", "localhost:50053"]);
+    }
+
+    #[tokio::test]
+    async fn test_get_all_addresses_error() {
+        let mut mock_address_manager = MockAddressManager::new();
+        mock_address_manager.expect_get_all_addresses().returning(move || Err(anyhow!("error")));
+
+        let address_managers = vec![Box::new(mock_address_manager) as Box<dyn AddressManager>];
+        let address_managers = Arc::new(Mutex::new(address_managers));
+
+        let result = get_all_addresses(&address_managers).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rejects_keys_of_different_partitions() {
+        let address_managers = vec![
+            Box::new(MockAddressManager::new()) as Box<dyn AddressManager>,
+            Box::new(MockAddressManager::new()) as Box<dyn AddressManager>,
+        ];
+        let router = KeyValueServiceRouter::new(address_managers);
+
+        let key = |i: usize| prost_types::Value { kind: Some(prost_types::value::Kind::StringValue(format!("key{}", i))) };
+        let partition = |i: usize| calculate_hash(&key(i).encode_to_vec()) % 2;
+        let other = (1..).find(|i| partition(*i) != partition(0)).unwrap();
+        let request = Request::new(TransactionRequest {
+            operations: vec![
+                key_value_store::TransactionOperation { operation: Some(Operation::Delete(key(0))) },
+                key_value_store::TransactionOperation { operation: Some(Operation::Delete(key(other))) },
+            ],
+            leader_version: 0,
+        });
+
+        let result = router.transaction(request).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_merge_key_values() {
+        let response = |key: &str| ScanResponse {
+            key_value: Some(KeyValue {
+                key: Some(prost_types::Value { kind: Some(prost_types::value::Kind::StringValue(key.to_string())) }),
+                value: None,
+            }),
+        };
+        let merge = |limit: u32| async move {
+            let streams = vec![
+                tokio_stream::iter(vec![Ok(response("ab")), Ok(response("c"))]),
+                tokio_stream::iter(vec![]),
+                tokio_stream::iter(vec![Ok(response("b")), Ok(response("d"))]),
+            ];
+            let (tx, rx) = channel(1);
+            tokio::spawn(merge_key_values(streams, limit, |response: &ScanResponse| response.key_value.as_ref(), tx));
+            ReceiverStream::new(rx).map(Result::unwrap).collect::<Vec<_>>().await
+        };
+
+        assert_eq!(merge(0).await, vec![response("ab"), response("b"), response("c"), response("d")]);
+        assert_eq!(merge(2).await, vec![response("ab"), response("b")]);
+    }
 }
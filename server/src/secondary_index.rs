@@ -0,0 +1,183 @@
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+use anyhow::{ensure, Result};
+use prost::bytes::Bytes;
+use prost::Message;
+use prost_types::Value;
+use prost_types::value::Kind;
+
+use indexengine::bplus_tree::{check_entry_size, BPlusTree};
+use indexengine::index::Index;
+use storageengine::file_handler::FileHandler;
+use storageengine::wal::Wal;
+
+pub fn secondary_index_file_name(storage_file_name: &str, field_path: &str) -> String {
+    format!("{}.index.{}", storage_file_name, field_path)
+}
+
+// the field of a struct value at a dotted path like `user.email`, None if a part of the path is missing
+// or not a struct
+pub fn field<'a>(value: &'a Value, field_path: &str) -> Option<&'a Value> {
+    field_path.split('.').try_fold(value, |value, name| match &value.kind {
+        Some(Kind::StructValue(fields)) => fields.fields.get(name),
+        _ => None,
+    })
+}
+
+// the documents a secondary index covers are identified by their encoded key, like in the index engine
+type Entry = (Vec<u8>, Vec<u8>);
+
+// maps the encoded value of one field to the keys of the documents that have it. the b+tree is keyed by
+// (field value, key), so the keys of one field value are next to each other
+pub struct SecondaryIndex {
+    field_path: String,
+    tree: BPlusTree<Entry, ()>,
+}
+
+impl SecondaryIndex {
+    // the tree is built from the documents of `index_engine` if it is new or missed a change
+    pub fn new(field_path: &str, file_handler: Box<dyn FileHandler>, wal: Wal, index_engine: &mut dyn Index<Vec<u8>, Vec<u8>>) -> Result<Self> {
+        ensure!(!field_path.split('.').any(str::is_empty) && !field_path.contains(['/', '\\']),
+            "invalid field path {:?}, expected names separated by dots", field_path);
+
+        let mut secondary_index = Self {
+            field_path: field_path.to_string(),
+            tree: BPlusTree::new(file_handler, wal)?,
+        };
+        if secondary_index.tree.is_dirty() {
+            secondary_index.rebuild(index_engine)?;
+        }
+
+        Ok(secondary_index)
+    }
+
+    // builds the tree again from the documents of `index_engine`, like after the keys were migrated
+    pub fn rebuild(&mut self, index_engine: &mut dyn Index<Vec<u8>, Vec<u8>>) -> Result<()> {
+        let mut entries = BTreeSet::new();
+        for document in index_engine.scan((Bound::Unbounded, Bound::Unbounded), None)? {
+            let value = Value::decode(Bytes::from(document.value))?;
+            entries.extend(self.entry(document.id, Some(&value)));
+        }
+        self.tree.rebuild(entries.into_iter().map(|entry| (entry, ())))
+    }
+
+    pub fn field_path(&self) -> &str {
+        &self.field_path
+    }
+
+    fn entry(&self, key: Vec<u8>, value: Option<&Value>) -> Option<Entry> {
+        value.and_then(|value| field(value, &self.field_path))
+            .map(|field_value| (field_value.encode_to_vec(), key))
+    }
+
+    // fails if the field of the document is too big to be indexed, checked before the document is written
+    pub fn check(&self, key: &[u8], value: &Value) -> Result<()> {
+        if let Some(entry) = self.entry(key.to_vec(), Some(value)) {
+            check_entry_size(&entry, &())?;
+        }
+        Ok(())
+    }
+
+    // call before the documents change, so a crash before `apply` leaves the index to be rebuilt
+    pub fn mark_dirty(&mut self) -> Result<()> {
+        self.tree.mark_dirty()
+    }
+
+    // moves the keys of the documents that changed from their old to their new field values, `changes`
+    // holds the key with the value before and after the write, None if there is no document
+    pub fn apply(&mut self, changes: &[(Vec<u8>, Option<Value>, Option<Value>)]) -> Result<()> {
+        let mut tree_changes = Vec::new();
+        for (key, old, new) in changes {
+            let old = self.entry(key.clone(), old.as_ref());
+            let new = self.entry(key.clone(), new.as_ref());
+            if old == new {
+                continue;
+            }
+            tree_changes.extend(old.map(|old| (old, None)));
+            tree_changes.extend(new.map(|new| (new, Some(()))));
+        }
+        self.tree.apply(tree_changes)
+    }
+
+    // up to `limit` encoded keys of the documents whose field equals `field_value` in key order, the ones after
+    // the key `after` if it is set
+    pub fn keys(&mut self, field_value: &Value, after: Option<&[u8]>, limit: usize) -> Result<Vec<Vec<u8>>> {
+        let field_value = field_value.encode_to_vec();
+        let start = match after {
+            Some(after) => Bound::Excluded((field_value.clone(), after.to_vec())),
+            None => Bound::Included((field_value.clone(), Vec::new())),
+        };
+        let mut keys = Vec::new();
+        for entry in self.tree.range((start, Bound::Unbounded))?.take(limit) {
+            let ((value, key), ()) = entry?;
+            if value != field_value {
+                break;
+            }
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexengine::index::Document;
+
+    use super::*;
+    use crate::test_support::{memory_file_handler, memory_index_engine, memory_wal, string};
+
+    fn user(email: &str) -> Value {
+        let user = prost_types::Struct { fields: [("email".to_string(), string(email))].into() };
+        Value { kind: Some(Kind::StructValue(prost_types::Struct { fields: [("user".to_string(), Value { kind: Some(Kind::StructValue(user)) })].into() })) }
+    }
+
+    fn open(index_engine: &mut dyn Index<Vec<u8>, Vec<u8>>) -> Result<SecondaryIndex> {
+        SecondaryIndex::new("user.email", memory_file_handler(), memory_wal()?, index_engine)
+    }
+
+    #[test]
+    fn test_field_follows_path() {
+        assert_eq!(field(&user("a@b.c"), "user.email"), Some(&string("a@b.c")));
+        assert_eq!(field(&user("a@b.c"), "user.name"), None);
+        assert_eq!(field(&string("a@b.c"), "user"), None);
+    }
+
+    #[test]
+    fn test_new_index_is_built_from_documents() -> Result<()> {
+        let mut index_engine = memory_index_engine()?;
+        for (key, value) in [("1", user("a@b.c")), ("2", string("no struct")), ("3", user("a@b.c")), ("4", user("x@y.z"))] {
+            index_engine.insert(Document { id: string(key).encode_to_vec(), value: value.encode_to_vec() })?;
+        }
+
+        let mut secondary_index = open(index_engine.as_mut())?;
+
+        assert_eq!(secondary_index.keys(&string("a@b.c"), None, usize::MAX)?, vec![string("1").encode_to_vec(), string("3").encode_to_vec()]);
+        assert_eq!(secondary_index.keys(&string("a@b.c"), Some(&string("1").encode_to_vec()), 1)?, vec![string("3").encode_to_vec()]);
+        assert_eq!(secondary_index.keys(&string("a@b.c"), None, 1)?, vec![string("1").encode_to_vec()]);
+        assert_eq!(secondary_index.keys(&string("x@y.z"), None, usize::MAX)?, vec![string("4").encode_to_vec()]);
+        assert!(secondary_index.keys(&string("no struct"), None, usize::MAX)?.is_empty());
+        assert!(SecondaryIndex::new("user..email", memory_file_handler(), memory_wal()?, index_engine.as_mut()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_moves_keys_between_field_values() -> Result<()> {
+        let mut secondary_index = open(memory_index_engine()?.as_mut())?;
+        let key = string("1").encode_to_vec();
+
+        secondary_index.mark_dirty()?;
+        secondary_index.apply(&[(key.clone(), None, Some(user("a@b.c")))])?;
+        secondary_index.apply(&[(key.clone(), Some(user("a@b.c")), Some(user("x@y.z")))])?;
+
+        assert!(secondary_index.keys(&string("a@b.c"), None, usize::MAX)?.is_empty());
+        assert_eq!(secondary_index.keys(&string("x@y.z"), None, usize::MAX)?, vec![key.clone()]);
+        assert!(secondary_index.check(&key, &user(&"x".repeat(4096))).is_err());
+
+        secondary_index.apply(&[(key, Some(user("x@y.z")), None)])?;
+        assert!(secondary_index.keys(&string("x@y.z"), None, usize::MAX)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use std::env;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
@@ -8,18 +9,23 @@ use tonic::transport::Server;
 use configmanager::ConfigManager;
 
 use indexengine::index::Index;
-use indexengine::no_index::NoIndex;
 
 use crate::key_value_service_server::key_value_store::key_value_service_server::{KeyValueServiceServer};
 
 mod logging_middleware;
 mod key_value_service_server;
 mod error;
+mod key_migration;
+mod secondary_index;
+mod expiry_index;
+#[cfg(test)]
+mod test_support;
 
 #[derive(Debug, Clone)]
 enum IndexEngine {
     BTree,
     LSMTree,
+    LSMValueTree,
     NoIndex,
     HashMap,
 }
@@ -31,6 +37,7 @@ impl FromStr for IndexEngine {
         match s {
             "BTree" => Ok(IndexEngine::BTree),
             "LSMTree" => Ok(IndexEngine::LSMTree),
+            "LSMValueTree" => Ok(IndexEngine::LSMValueTree),
             "NoIndex" => Ok(IndexEngine::NoIndex),
             "HashMap" => Ok(IndexEngine::HashMap),
             _ => Err("no match"),
@@ -38,6 +45,29 @@ impl FromStr for IndexEngine {
     }
 }
 
+// where the storage engine keeps its files
+#[derive(Debug, Clone, PartialEq)]
+enum Storage {
+    File,
+    // reads the data file through a memory map
+    Mmap,
+    // nothing survives a restart
+    Memory,
+}
+
+impl FromStr for Storage {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(Storage::File),
+            "mmap" => Ok(Storage::Mmap),
+            "memory" => Ok(Storage::Memory),
+            _ => Err("no match"),
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -53,8 +83,43 @@ struct Args {
     leader_election_path: String,
     #[arg(long, default_value = "/services")]
     service_registry_path: String,
-    #[arg(long, default_value = "BTree")]
+    #[arg(long, default_value = "BTree", help = "BTree, LSMTree, LSMValueTree, NoIndex or HashMap. LSMValueTree can't expire keys, writes with a ttl are rejected")]
     index_engine: IndexEngine,
+    // file, mmap or memory. memory only works with the HashMap and NoIndex engines, the others keep files of their own
+    #[arg(long, default_value = "file")]
+    storage: Storage,
+    #[arg(long, default_value_t = storageengine::buffer_pool::DEFAULT_BUFFER_POOL_PAGES)]
+    buffer_pool_pages: usize,
+    // defaults to `<storage file>.sstables`
+    #[arg(long)]
+    ss_table_dir: Option<String>,
+    #[arg(long, default_value_t = indexengine::DEFAULT_MEMTABLE_SIZE)]
+    memtable_size: usize,
+    #[arg(long, default_value_t = indexengine::DEFAULT_BLOOM_FILTER_SIZE)]
+    bloom_filter_size: usize,
+    // bincode, json, msgpack or cbor, defaults to the codec the database was created with
+    #[arg(long)]
+    codec: Option<indexengine::codec::CodecKind>,
+    // none, lz4 or zstd, can only be chosen for a new database
+    #[arg(long)]
+    compression: Option<storageengine::compression::Compression>,
+    // aes-gcm or chacha20poly1305, can only be chosen for a new database and not with the LSM index engines
+    #[arg(long)]
+    encryption: Option<storageengine::encrypted_file_handler::Encryption>,
+    // holds the hex encoded key, defaults to the PATRICKDB_ENCRYPTION_KEY env var
+    #[arg(long)]
+    key_file: Option<String>,
+    // none, per_write or group_commit
+    #[arg(long, default_value = "per_write")]
+    durability: storageengine::wal::Durability,
+    // how long group commit waits for more writes to sync together
+    #[arg(long, default_value_t = 2)]
+    group_commit_window_ms: u64,
+    // dotted path of a field inside struct values that `QueryByField` can look up, can be repeated
+    #[arg(long)]
+    secondary_index: Vec<String>,
+    #[arg(long, default_value_t = 1000, help = "how often the leader deletes the keys whose ttl ran out, 0 turns it off. not run with LSMValueTree")]
+    ttl_reaper_interval_ms: u64,
 }
 
 #[tokio::main]
@@ -81,18 +146,132 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     log::info!("init storage engine");
     let storage_file_name = env::var("STORAGE_FILE_NAME").ok().unwrap_or(args.storage_file_name);
-    let file_handler = storageengine::file_handler::FileHandlerImpl::new(&storage_file_name)?;
-    let operations = storageengine::operations::DbOperationsImpl::new(Box::new(file_handler));
-    let index_engine: Box<dyn Index<Vec<u8>, Vec<u8>>> = match args.index_engine {
-        IndexEngine::BTree => indexengine::new_index_engine(indexengine::IndexEngine::BTree, Box::new(operations)).expect("failed to create btree"),
-        IndexEngine::LSMTree => indexengine::new_index_engine(indexengine::IndexEngine::LSM, Box::new(operations)).expect("failed to create lsm"),
-        IndexEngine::NoIndex => Box::new(NoIndex::new(Box::new(operations))),
-        IndexEngine::HashMap => indexengine::new_index_engine(indexengine::IndexEngine::HashMap, Box::new(operations)).expect("failed to create hashmap"),
+    let key = storageengine::encrypted_file_handler::EncryptionKey::load(args.key_file.as_deref())?;
+    if args.storage == Storage::Memory && !matches!(args.index_engine, IndexEngine::HashMap | IndexEngine::NoIndex) {
+        return Err(format!("{:?} index engine can't be used with memory storage", args.index_engine).into());
+    }
+    if args.storage == Storage::Memory && args.encryption.is_some() {
+        return Err("memory storage is never written to disk, it can't be encrypted".into());
+    }
+    if args.storage == Storage::Mmap && (args.encryption.is_some() || key.is_some()) {
+        return Err("the memory map would only see the encrypted data, use file storage".into());
+    }
+    if args.encryption.is_some() && matches!(args.index_engine, IndexEngine::LSMTree | IndexEngine::LSMValueTree) {
+        return Err(format!("the sstables of the {:?} index engine can't be encrypted, use another index engine", args.index_engine).into());
+    }
+    // keeps `cli` from opening the files while the server has them open
+    let _lock = match args.storage {
+        Storage::File | Storage::Mmap => Some(storageengine::file_handler::lock(&storage_file_name)?),
+        Storage::Memory => None,
+    };
+    let open_file_handler = |file_name: &str| -> anyhow::Result<Box<dyn storageengine::file_handler::FileHandler>> {
+        match args.storage {
+            Storage::File | Storage::Mmap => storageengine::encrypted_file_handler::open_file_handler(file_name, args.encryption, key.as_ref()),
+            Storage::Memory => Ok(Box::new(storageengine::memory_file_handler::MemoryFileHandler::new())),
+        }
+    };
+    let open_log_file_handler = |file_name: &str| -> anyhow::Result<Box<dyn storageengine::file_handler::FileHandler>> {
+        match args.storage {
+            Storage::File | Storage::Mmap => storageengine::encrypted_file_handler::open_log_file_handler(file_name, args.encryption, key.as_ref()),
+            Storage::Memory => Ok(Box::new(storageengine::memory_file_handler::MemoryFileHandler::new())),
+        }
+    };
+    // only the data file is read often enough to be worth a map
+    let mut file_handler = match args.storage {
+        Storage::Mmap => Box::new(storageengine::mmap_file_handler::MmapFileHandler::new(&storage_file_name)?),
+        _ => open_file_handler(&storage_file_name)?,
+    };
+    let mut index_engine_config = indexengine::IndexEngineConfig::new(&storage_file_name);
+    if let Some(ss_table_dir) = args.ss_table_dir {
+        index_engine_config.ss_table_dir = ss_table_dir;
+    }
+    index_engine_config.memtable_size = args.memtable_size;
+    index_engine_config.bloom_filter_size = args.bloom_filter_size;
+    index_engine_config.codec = args.codec;
+    index_engine_config.key = key.clone();
+    let index_engine = match args.index_engine {
+        IndexEngine::BTree => indexengine::IndexEngine::BTree,
+        IndexEngine::LSMTree => indexengine::IndexEngine::LSM(indexengine::LsmMode::Offsets),
+        IndexEngine::LSMValueTree => indexengine::IndexEngine::LSM(indexengine::LsmMode::Values),
+        IndexEngine::NoIndex => indexengine::IndexEngine::NoIndex,
+        IndexEngine::HashMap => indexengine::IndexEngine::HashMap,
+    };
+    indexengine::open_file_header(file_handler.as_mut(), &index_engine, &mut index_engine_config)?;
+    storageengine::compression::open_compression(file_handler.as_mut(), args.compression)?;
+    let encryption = file_handler.header().encryption.and_then(storageengine::encrypted_file_handler::Encryption::from_id);
+    let wal_file_handler = open_log_file_handler(&storageengine::wal::wal_file_name(&storage_file_name))?;
+    let mut wal = storageengine::wal::Wal::new(wal_file_handler)?;
+    // keeps archiving once a backup started it, see `backup::WalArchive`
+    let wal_archive_dir = storageengine::backup::wal_archive_dir_name(&storage_file_name);
+    if args.storage != Storage::Memory {
+        if let Some(wal_archive) = storageengine::backup::WalArchive::open_existing(&wal_archive_dir, key.clone())? {
+            wal.set_archive(wal_archive);
+        }
+    }
+    let transaction_file_handler = open_file_handler(&storageengine::transaction::transaction_file_name(&storage_file_name))?;
+    let transaction_manager = storageengine::transaction::TransactionManager::new(transaction_file_handler)?;
+    let mut operations = storageengine::operations::DbOperationsImpl::new(file_handler, wal, transaction_manager, args.buffer_pool_pages)?;
+    operations.set_durability(args.durability);
+    let mut index_engine: Box<dyn Index<Vec<u8>, Vec<u8>>> = indexengine::new_index_engine(index_engine, Box::new(operations), &index_engine_config).expect("failed to create index engine");
+    if !index_engine.can_expire() {
+        log::info!("the {:?} index engine can't expire keys, writes with a ttl are rejected", args.index_engine);
+    }
+    // the indexes over the keys are built again until the migration is recorded, they might refer to old keys
+    let migrating = key_migration::needs_migration(index_engine.as_ref());
+    if migrating {
+        let migrated = key_migration::migrate_legacy_keys(index_engine.as_mut())?;
+        log::info!("migrated {} keys to the ordered key encoding", migrated);
+    }
+    // like the b+tree of the index engine they are kept next to the storage file and encrypted like it
+    let open_tree_files = |file_name: &str| -> anyhow::Result<(Box<dyn storageengine::file_handler::FileHandler>, storageengine::wal::Wal)> {
+        let (file_handler, wal_file_handler): (Box<dyn storageengine::file_handler::FileHandler>, Box<dyn storageengine::file_handler::FileHandler>) = match args.storage {
+            Storage::File | Storage::Mmap => storageengine::encrypted_file_handler::open_derived_file_handlers(file_name, encryption, key.as_ref())?,
+            Storage::Memory => (
+                Box::new(storageengine::memory_file_handler::MemoryFileHandler::new()),
+                Box::new(storageengine::memory_file_handler::MemoryFileHandler::new()),
+            ),
+        };
+        Ok((file_handler, storageengine::wal::Wal::new(wal_file_handler)?))
     };
+    let mut secondary_indexes = Vec::new();
+    for field_path in &args.secondary_index {
+        let (file_handler, wal) = open_tree_files(&secondary_index::secondary_index_file_name(&storage_file_name, field_path))?;
+        let mut secondary_index = secondary_index::SecondaryIndex::new(field_path, file_handler, wal, index_engine.as_mut())?;
+        if migrating {
+            secondary_index.rebuild(index_engine.as_mut())?;
+        }
+        secondary_indexes.push(secondary_index);
+    }
+    let mut expiry_index = None;
+    if index_engine.can_expire() {
+        let (file_handler, wal) = open_tree_files(&expiry_index::expiry_index_file_name(&storage_file_name))?;
+        let mut index = expiry_index::ExpiryIndex::new(file_handler, wal, index_engine.as_mut())?;
+        if migrating {
+            index.rebuild(index_engine.as_mut())?;
+        }
+        expiry_index = Some(index);
+    }
+    if migrating {
+        key_migration::finish_migration(index_engine.as_mut())?;
+    }
     log::info!("finished init storage engine");
 
     let addr = server_address.parse()?;
-    let server = key_value_service_server::KeyValueStoreImpl::new(index_engine, Box::new(config_manager)).await;
+    let mut server = key_value_service_server::KeyValueStoreImpl::new(index_engine, Box::new(config_manager)).await
+        .with_secondary_indexes(secondary_indexes);
+    if let Some(expiry_index) = expiry_index {
+        server = server.with_expiry_index(expiry_index);
+    }
+    if args.storage != Storage::Memory {
+        server = server.with_backup(&storage_file_name, encryption, key.clone());
+    }
+    if args.durability == storageengine::wal::Durability::GroupCommit {
+        server = server.with_group_commit(Duration::from_millis(args.group_commit_window_ms));
+    }
+    let server = Arc::new(server);
+    if args.ttl_reaper_interval_ms > 0 {
+        server.clone().start_ttl_reaper(Duration::from_millis(args.ttl_reaper_interval_ms));
+    }
 
     let layer = tower::ServiceBuilder::new()
         // Apply middleware from tower
@@ -105,7 +284,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Server::builder()
         .layer(layer)
-        .add_service(KeyValueServiceServer::new(server))
+        .add_service(KeyValueServiceServer::from_arc(server))
         .serve(addr)
         .await?;
 
@@ -0,0 +1,37 @@
+use anyhow::Result;
+use prost_types::value::Kind;
+use prost_types::Value;
+
+use indexengine::index::Index;
+use storageengine::file_handler::{FileHandler, FileHandlerImpl};
+use storageengine::memory_file_handler::MemoryFileHandler;
+use storageengine::operations::DbOperationsImpl;
+use storageengine::transaction::{transaction_file_name, TransactionManager};
+use storageengine::wal::{wal_file_name, Wal};
+
+pub fn string(s: &str) -> Value {
+    Value { kind: Some(Kind::StringValue(s.to_string())) }
+}
+
+pub fn memory_file_handler() -> Box<dyn FileHandler> {
+    Box::new(MemoryFileHandler::new())
+}
+
+pub fn memory_wal() -> Result<Wal> {
+    Wal::new(memory_file_handler())
+}
+
+// a hashmap index engine over a data file, wal and transaction file in memory
+pub fn memory_index_engine() -> Result<Box<dyn Index<Vec<u8>, Vec<u8>>>> {
+    let transaction_manager = TransactionManager::new(memory_file_handler())?;
+    let db_operations = DbOperationsImpl::new(memory_file_handler(), memory_wal()?, transaction_manager, 16)?;
+    indexengine::new_index_engine(indexengine::IndexEngine::HashMap, Box::new(db_operations), &indexengine::IndexEngineConfig::new("unused"))
+}
+
+// a hashmap index engine over the data file `file_name`, with its wal and transaction file next to it
+pub fn open_index_engine(file_name: &str) -> Result<Box<dyn Index<Vec<u8>, Vec<u8>>>> {
+    let wal = Wal::new(Box::new(FileHandlerImpl::new(&wal_file_name(file_name))?))?;
+    let transaction_manager = TransactionManager::new(Box::new(FileHandlerImpl::new(&transaction_file_name(file_name))?))?;
+    let db_operations = DbOperationsImpl::new(Box::new(FileHandlerImpl::new(file_name)?), wal, transaction_manager, 16)?;
+    indexengine::new_index_engine(indexengine::IndexEngine::HashMap, Box::new(db_operations), &indexengine::IndexEngineConfig::new(file_name))
+}
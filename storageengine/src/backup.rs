@@ -0,0 +1,358 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::encrypted_file_handler::{self, Encryption, EncryptionKey};
+use crate::file_handler::{self, FileHandler};
+use crate::file_header::FileHeader;
+use crate::wal::{self, Wal};
+
+const SEGMENT_EXTENSION: &str = "wal";
+const VACUUM_EXTENSION: &str = "vacuum";
+
+pub fn wal_archive_dir_name(storage_file_name: &str) -> String {
+    format!("{}.wal_archive", storage_file_name)
+}
+
+pub fn backup_manifest_file_name(backup_file_name: &str) -> String {
+    format!("{}.backup", backup_file_name)
+}
+
+// written next to a backup of the data file
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct BackupManifest {
+    // the backup has every transaction before this one
+    pub transaction_id: u64,
+    // the first segment of the wal archive that isn't in the backup
+    pub first_segment: u64,
+}
+
+impl BackupManifest {
+    pub fn save(&self, backup_file_name: &str) -> Result<()> {
+        fs::write(backup_manifest_file_name(backup_file_name), bincode::serialize(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(backup_file_name: &str) -> Result<Self> {
+        let manifest_file_name = backup_manifest_file_name(backup_file_name);
+        let bytes = fs::read(&manifest_file_name).map_err(|e| anyhow!("failed to read {}: {}", manifest_file_name, e))?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+}
+
+// what the archive holds at one position
+#[derive(Debug, PartialEq)]
+enum Segment {
+    // the records of the wal at one checkpoint, stored as a wal file
+    Records(PathBuf),
+    // vacuum moved the rows, the records behind it don't fit older backups. holds the first transaction after it
+    Vacuum(u64),
+}
+
+// keeps the wal records truncated at every checkpoint in numbered segments, so a backup can be rolled forward.
+// archiving starts with the first backup of a database and goes on as long as the directory exists
+pub struct WalArchive {
+    dir: String,
+    // segments are encrypted like the wal they are copied from
+    key: Option<EncryptionKey>,
+    next_segment: u64,
+}
+
+impl WalArchive {
+    pub fn create(dir: &str, key: Option<EncryptionKey>) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Self::open(dir, key)
+    }
+
+    // None if nothing was archived into `dir` yet
+    pub fn open_existing(dir: &str, key: Option<EncryptionKey>) -> Result<Option<Self>> {
+        if !Path::new(dir).exists() {
+            return Ok(None);
+        }
+        Self::open(dir, key).map(Some)
+    }
+
+    fn open(dir: &str, key: Option<EncryptionKey>) -> Result<Self> {
+        let next_segment = segments(dir)?.last().map_or(0, |(number, _)| number + 1);
+        Ok(Self { dir: dir.to_string(), key, next_segment })
+    }
+
+    pub fn next_segment(&self) -> u64 {
+        self.next_segment
+    }
+
+    // copies the records of `wal` into the next segment. a wal that was archived right before a crash kept it
+    // from being truncated is still there on restart, it isn't archived twice
+    pub fn store(&mut self, wal: &mut dyn FileHandler) -> Result<()> {
+        let records = wal.read_all()?;
+        if records.is_empty() {
+            return Ok(());
+        }
+        if let Some(last) = self.next_segment.checked_sub(1) {
+            let last_path = self.segment_path(last, SEGMENT_EXTENSION);
+            if last_path.exists() && self.open_segment(&last_path, None)?.read_all()? == records {
+                return Ok(());
+            }
+        }
+
+        let path = self.segment_path(self.next_segment, SEGMENT_EXTENSION);
+        let tmp_path = path.with_extension("tmp");
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        let encryption = match wal.header().encryption {
+            Some(id) => Some(Encryption::from_id(id).ok_or_else(|| anyhow!("wal names an unknown encryption {}", id))?),
+            None => None,
+        };
+        let mut segment = self.open_segment(&tmp_path, encryption)?;
+        segment.append(&records)?;
+        segment.sync()?;
+        drop(segment);
+        fs::rename(&tmp_path, &path)?;
+        self.sync_dir()?;
+
+        self.next_segment += 1;
+        Ok(())
+    }
+
+    // notes that vacuum moved the rows, `next_transaction_id` is the first transaction behind it
+    pub fn mark_vacuum(&mut self, next_transaction_id: u64) -> Result<()> {
+        fs::write(self.segment_path(self.next_segment, VACUUM_EXTENSION), next_transaction_id.to_le_bytes())?;
+        self.sync_dir()?;
+
+        self.next_segment += 1;
+        Ok(())
+    }
+
+    fn segment_path(&self, number: u64, extension: &str) -> PathBuf {
+        Path::new(&self.dir).join(format!("{:020}.{}", number, extension))
+    }
+
+    fn open_segment(&self, path: &Path, encryption: Option<Encryption>) -> Result<Box<dyn FileHandler>> {
+        let path = path.to_str().ok_or_else(|| anyhow!("{:?} is not a valid file name", path))?;
+        encrypted_file_handler::open_file_handler(path, encryption, self.key.as_ref())
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+// the files holding records in the archive in `dir`, they are encrypted like the wal
+pub fn segment_file_names(dir: &str) -> Result<Vec<String>> {
+    if !Path::new(dir).exists() {
+        return Ok(Vec::new());
+    }
+
+    segments(dir)?.into_iter()
+        .filter_map(|(_, segment)| match segment {
+            Segment::Records(path) => Some(path.to_str().map(str::to_string).ok_or_else(|| anyhow!("{:?} is not a valid file name", path))),
+            Segment::Vacuum(_) => None,
+        })
+        .collect()
+}
+
+// the segments of the archive in `dir`, ordered by their number
+fn segments(dir: &str) -> Result<Vec<(u64, Segment)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let number = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok());
+        let (Some(number), Some(extension)) = (number, path.extension().and_then(|extension| extension.to_str())) else {
+            continue;
+        };
+
+        match extension {
+            SEGMENT_EXTENSION => segments.push((number, Segment::Records(path))),
+            VACUUM_EXTENSION => {
+                let bytes = fs::read(&path)?;
+                let next_transaction_id = u64::from_le_bytes(bytes.as_slice().try_into().map_err(|_| anyhow!("{:?} is corrupted", path))?);
+                segments.push((number, Segment::Vacuum(next_transaction_id)));
+            }
+            // a segment that was still being written
+            _ => continue,
+        }
+    }
+
+    segments.sort_by_key(|(number, _)| *number);
+    Ok(segments)
+}
+
+// writes the backup in `backup_file_name` to the empty `target` and rolls it forward with the archived records up
+// to and including transaction `to_transaction_id`, or all of them. returns the last transaction in `target`
+pub fn restore(backup_file_name: &str, archive_dir: &str, key: Option<&EncryptionKey>, target: &mut dyn FileHandler, to_transaction_id: Option<u64>) -> Result<Option<u64>> {
+    let manifest = BackupManifest::load(backup_file_name)?;
+    if to_transaction_id.is_some_and(|to_transaction_id| to_transaction_id + 1 < manifest.transaction_id) {
+        bail!("the backup already has the transactions up to {}, restore an older backup", manifest.transaction_id - 1);
+    }
+    if target.size()? > 0 {
+        bail!("the data file to restore into is not empty");
+    }
+
+    let mut backup = encrypted_file_handler::open_file_handler(backup_file_name, None, key)?;
+    let header = target.header();
+    target.set_header(FileHeader { encryption: header.encryption, ..backup.header() })?;
+    file_handler::copy_data(backup.as_mut(), target)?;
+    let mut last_transaction_id = manifest.transaction_id.checked_sub(1);
+
+    let mut expected_segment = manifest.first_segment;
+    'segments: for (number, segment) in segments(archive_dir)? {
+        if number < manifest.first_segment {
+            continue;
+        }
+        if number != expected_segment {
+            bail!("segment {} of the wal archive is missing", expected_segment);
+        }
+        expected_segment += 1;
+
+        match segment {
+            Segment::Vacuum(next_transaction_id) => {
+                if to_transaction_id.is_some_and(|to_transaction_id| to_transaction_id < next_transaction_id) {
+                    break;
+                }
+                bail!("the archive can't be replayed past the vacuum before transaction {}, restore a backup taken after it", next_transaction_id);
+            }
+            Segment::Records(path) => {
+                let path = path.to_str().ok_or_else(|| anyhow!("{:?} is not a valid file name", path))?;
+                let mut segment = Wal::new(encrypted_file_handler::open_file_handler(path, None, key)?)?;
+                for record in segment.records()? {
+                    // records of one transaction are logged one after the other
+                    if let Some(transaction_id) = record.transaction_id {
+                        if to_transaction_id.is_some_and(|to_transaction_id| transaction_id > to_transaction_id) {
+                            break 'segments;
+                        }
+                        last_transaction_id = last_transaction_id.max(Some(transaction_id));
+                    }
+                    wal::apply(target, &record)?;
+                }
+            }
+        }
+    }
+
+    target.sync()?;
+    Ok(last_transaction_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_handler::FileHandlerImpl;
+    use crate::operations::{DbOperations, DbOperationsImpl, RowIterator};
+    use crate::test_support::open_db_operations_with;
+
+    use super::*;
+
+    #[test]
+    fn restore_rolls_backup_forward_to_transaction() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        let backup_file_name = dir.path().join("backup.db").to_str().unwrap().to_string();
+        let archive_dir = wal_archive_dir_name(&file_name);
+
+        let mut db_operations = open_db_operations(&file_name, Some(WalArchive::create(&archive_dir, None)?))?;
+        insert(&mut db_operations, 1)?;
+        insert(&mut db_operations, 2)?;
+        let manifest = db_operations.backup(&mut FileHandlerImpl::new(&backup_file_name)?, None)?;
+        manifest.save(&backup_file_name)?;
+        let third = insert(&mut db_operations, 3)?;
+        insert(&mut db_operations, 4)?;
+        // a checkpoint archives the records
+        drop(db_operations);
+        drop(open_db_operations(&file_name, WalArchive::open_existing(&archive_dir, None)?)?);
+
+        for (to_transaction_id, expected) in [(Some(third), vec![vec![1], vec![2], vec![3]]), (None, vec![vec![1], vec![2], vec![3], vec![4]])] {
+            let restored_file_name = dir.path().join(format!("restored-{:?}.db", to_transaction_id)).to_str().unwrap().to_string();
+            let mut target = FileHandlerImpl::new(&restored_file_name)?;
+            let last = restore(&backup_file_name, &archive_dir, None, &mut target, to_transaction_id)?;
+            drop(target);
+
+            assert_eq!(last, to_transaction_id.or(Some(third + 1)));
+            assert_eq!(rows(&mut open_db_operations(&restored_file_name, None)?)?, expected);
+        }
+        let mut target = FileHandlerImpl::new(dir.path().join("too-old.db").to_str().unwrap())?;
+        assert!(restore(&backup_file_name, &archive_dir, None, &mut target, Some(manifest.transaction_id - 2)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn restore_stops_at_vacuum() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        let backup_file_name = dir.path().join("backup.db").to_str().unwrap().to_string();
+        let archive_dir = wal_archive_dir_name(&file_name);
+
+        let mut db_operations = open_db_operations(&file_name, Some(WalArchive::create(&archive_dir, None)?))?;
+        db_operations.backup(&mut FileHandlerImpl::new(&backup_file_name)?, None)?.save(&backup_file_name)?;
+        let first = insert(&mut db_operations, 1)?;
+        db_operations.vacuum()?;
+        insert(&mut db_operations, 2)?;
+        drop(db_operations);
+        drop(open_db_operations(&file_name, WalArchive::open_existing(&archive_dir, None)?)?);
+
+        let mut target = FileHandlerImpl::new(dir.path().join("before.db").to_str().unwrap())?;
+        assert_eq!(restore(&backup_file_name, &archive_dir, None, &mut target, Some(first))?, Some(first));
+        let mut target = FileHandlerImpl::new(dir.path().join("after.db").to_str().unwrap())?;
+        assert!(restore(&backup_file_name, &archive_dir, None, &mut target, None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn backup_starts_archiving_an_open_database() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        let backup_file_name = dir.path().join("backup.db").to_str().unwrap().to_string();
+        let archive_dir = wal_archive_dir_name(&file_name);
+
+        let mut db_operations = open_db_operations(&file_name, None)?;
+        assert!(db_operations.backup(&mut FileHandlerImpl::new(&backup_file_name)?, None).is_err());
+        insert(&mut db_operations, 1)?;
+        db_operations.backup(&mut FileHandlerImpl::new(&backup_file_name)?, Some(WalArchive::create(&archive_dir, None)?))?.save(&backup_file_name)?;
+        insert(&mut db_operations, 2)?;
+        drop(db_operations);
+        drop(open_db_operations(&file_name, WalArchive::open_existing(&archive_dir, None)?)?);
+
+        let restored_file_name = dir.path().join("restored.db").to_str().unwrap().to_string();
+        restore(&backup_file_name, &archive_dir, None, &mut FileHandlerImpl::new(&restored_file_name)?, None)?;
+        assert_eq!(rows(&mut open_db_operations(&restored_file_name, None)?)?, vec![vec![1], vec![2]]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_skips_wal_it_already_archived() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let archive_dir = dir.path().join("archive").to_str().unwrap().to_string();
+        let mut wal = FileHandlerImpl::new(dir.path().join("patrick.db.wal").to_str().unwrap())?;
+        let mut archive = WalArchive::create(&archive_dir, None)?;
+
+        archive.store(&mut wal)?;
+        assert_eq!(archive.next_segment(), 0);
+        wal.append(b"records")?;
+        archive.store(&mut wal)?;
+        archive.store(&mut wal)?;
+
+        assert_eq!(WalArchive::create(&archive_dir, None)?.next_segment(), 1);
+
+        Ok(())
+    }
+
+    fn open_db_operations(file_name: &str, archive: Option<WalArchive>) -> Result<DbOperationsImpl> {
+        open_db_operations_with(Box::new(FileHandlerImpl::new(file_name)?), file_name, archive, 16)
+    }
+
+    fn insert(db_operations: &mut DbOperationsImpl, value: u8) -> Result<u64> {
+        let transaction_id = db_operations.begin()?;
+        db_operations.insert(vec![value], transaction_id)?;
+        Ok(transaction_id)
+    }
+
+    fn rows(db_operations: &mut DbOperationsImpl) -> Result<Vec<Vec<u8>>> {
+        RowIterator::new(db_operations)
+            .map(|row| row.map(|(_, row)| row.data))
+            .collect()
+    }
+}
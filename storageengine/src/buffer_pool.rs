@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use crate::file_handler::{self, FileHandler};
+use crate::file_header::FileHeader;
+use crate::page::{Page, PAGE_SIZE, page_offset};
+use crate::wal::{self, WalRecord, WalWrite};
+
+pub const DEFAULT_BUFFER_POOL_PAGES: usize = 1024;
+
+struct Frame {
+    page: Page,
+    last_used: u64,
+    // changed by `apply_deferred` and not written to the file yet, such a page is never evicted
+    dirty: bool,
+}
+
+// keeps at most `capacity` pages of the data file in memory and evicts the least recently used one.
+// changes reach the file through `apply` right away, so evicted pages never need a write back. pages changed
+// by `apply_deferred` stay in memory until `write_back`, even if that takes more than `capacity` pages
+pub struct BufferPool {
+    file_handler: Box<dyn FileHandler>,
+    capacity: usize,
+    frames: HashMap<u64, Frame>,
+    clock: u64,
+    page_count: u64,
+}
+
+impl BufferPool {
+    pub fn new(mut file_handler: Box<dyn FileHandler>, capacity: usize) -> Result<Self> {
+        if capacity == 0 {
+            bail!("buffer pool needs room for at least one page");
+        }
+
+        let page_count = file_handler.size()? / PAGE_SIZE as u64;
+
+        Ok(Self {
+            file_handler,
+            capacity,
+            frames: HashMap::new(),
+            clock: 0,
+            page_count,
+        })
+    }
+
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    pub fn fetch(&mut self, page_id: u64) -> Result<&Page> {
+        if page_id >= self.page_count {
+            bail!("page {} is behind the last page {}", page_id, self.page_count);
+        }
+
+        self.clock += 1;
+        if !self.frames.contains_key(&page_id) {
+            let data = self.file_handler.read(page_offset(page_id), PAGE_SIZE as u64)?;
+            self.cache(page_id, Page::from_bytes(data)?, false);
+        }
+
+        let frame = self.frames.get_mut(&page_id).unwrap();
+        frame.last_used = self.clock;
+        Ok(&frame.page)
+    }
+
+    // writes whole pages of a logged record to the file and keeps the cached copies in sync
+    pub fn apply(&mut self, record: &WalRecord) -> Result<()> {
+        wal::apply(self.file_handler.as_mut(), record)?;
+
+        for write in &record.writes {
+            let first_page_id = write.offset / PAGE_SIZE as u64;
+            for (i, chunk) in write.data.chunks(PAGE_SIZE).enumerate() {
+                let page_id = first_page_id + i as u64;
+                self.page_count = self.page_count.max(page_id + 1);
+                if let Some(frame) = self.frames.get_mut(&page_id) {
+                    frame.page = Page::from_bytes(chunk.to_vec())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // like `apply`, but only changes the cached pages. the record is not synced to the log yet and the file
+    // must not get ahead of the log, so the pages are written by `write_back` once the log is synced
+    pub fn apply_deferred(&mut self, record: &WalRecord) -> Result<()> {
+        self.clock += 1;
+        for write in &record.writes {
+            let first_page_id = write.offset / PAGE_SIZE as u64;
+            for (i, chunk) in write.data.chunks(PAGE_SIZE).enumerate() {
+                let page_id = first_page_id + i as u64;
+                self.page_count = self.page_count.max(page_id + 1);
+                let page = Page::from_bytes(chunk.to_vec())?;
+                match self.frames.get_mut(&page_id) {
+                    Some(frame) => {
+                        frame.page = page;
+                        frame.dirty = true;
+                    }
+                    None => self.cache(page_id, page, true),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // writes the pages changed by `apply_deferred` in the order of the file, so it never has a gap
+    pub fn write_back(&mut self) -> Result<()> {
+        let mut page_ids: Vec<u64> = self.frames.iter()
+            .filter(|(_, frame)| frame.dirty)
+            .map(|(page_id, _)| *page_id)
+            .collect();
+        if page_ids.is_empty() {
+            return Ok(());
+        }
+        page_ids.sort_unstable();
+
+        let writes = page_ids.iter()
+            .map(|page_id| WalWrite { offset: page_offset(*page_id), data: self.frames[page_id].page.as_bytes().to_vec() })
+            .collect();
+        wal::apply(self.file_handler.as_mut(), &WalRecord { lsn: 0, writes, transaction_id: None })?;
+        for page_id in page_ids {
+            self.frames.get_mut(&page_id).unwrap().dirty = false;
+        }
+
+        Ok(())
+    }
+
+    pub fn header(&self) -> FileHeader {
+        self.file_handler.header()
+    }
+
+    pub fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.file_handler.set_header(header)
+    }
+
+    // appends the data of the file to `target`, pages changed by `apply_deferred` have to be written back first
+    pub fn copy_to(&mut self, target: &mut dyn FileHandler) -> Result<()> {
+        file_handler::copy_data(self.file_handler.as_mut(), target)
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.write_back()?;
+        self.file_handler.sync()
+    }
+
+    // replaces the whole file, see `FileHandler::rewrite`, and forgets every cached page
+    pub fn rewrite(&mut self, fill: &mut dyn FnMut(&mut dyn FileHandler, &mut dyn FileHandler) -> Result<()>) -> Result<()> {
+        self.write_back()?;
+        self.file_handler.rewrite(fill)?;
+        self.frames.clear();
+        self.page_count = self.file_handler.size()? / PAGE_SIZE as u64;
+
+        Ok(())
+    }
+
+    fn cache(&mut self, page_id: u64, page: Page, dirty: bool) {
+        if self.frames.len() >= self.capacity {
+            let least_recently_used = self.frames.iter()
+                .filter(|(_, frame)| !frame.dirty)
+                .min_by_key(|(_, frame)| frame.last_used)
+                .map(|(page_id, _)| *page_id);
+            if let Some(page_id) = least_recently_used {
+                self.frames.remove(&page_id);
+            }
+        }
+
+        self.frames.insert(page_id, Frame {
+            page,
+            last_used: self.clock,
+            dirty,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_handler::FileHandlerImpl;
+    use crate::wal::WalWrite;
+
+    use super::*;
+
+    #[test]
+    fn fetch_reads_pages_from_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("fetch_reads_pages_from_file.txt");
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+        let mut page = Page::new_slotted();
+        page.insert(&[1, 2, 3]).unwrap();
+        file_handler.append(Page::new_slotted().as_bytes())?;
+        file_handler.append(page.as_bytes())?;
+
+        let mut buffer_pool = BufferPool::new(Box::new(file_handler), 2)?;
+
+        assert_eq!(buffer_pool.page_count(), 2);
+        assert_eq!(buffer_pool.fetch(1)?.tuple(0), Some(&[1, 2, 3][..]));
+        assert!(buffer_pool.fetch(2).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn fetch_evicts_least_recently_used_page() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("fetch_evicts_least_recently_used_page.txt");
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+        for _ in 0..3 {
+            file_handler.append(Page::new_slotted().as_bytes())?;
+        }
+
+        let mut buffer_pool = BufferPool::new(Box::new(file_handler), 2)?;
+        buffer_pool.fetch(0)?;
+        buffer_pool.fetch(1)?;
+        buffer_pool.fetch(0)?;
+        buffer_pool.fetch(2)?;
+
+        assert_eq!(buffer_pool.frames.len(), 2);
+        assert!(buffer_pool.frames.contains_key(&0));
+        assert!(!buffer_pool.frames.contains_key(&1));
+        assert!(buffer_pool.frames.contains_key(&2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_writes_pages_and_refreshes_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("apply_writes_pages_and_refreshes_cache.txt");
+        let file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+        let mut buffer_pool = BufferPool::new(Box::new(file_handler), 2)?;
+
+        let mut page = Page::new_slotted();
+        buffer_pool.apply(&WalRecord {
+            lsn: 0,
+            writes: vec![WalWrite { offset: 0, data: page.as_bytes().to_vec() }],
+            transaction_id: None,
+        })?;
+        assert_eq!(buffer_pool.fetch(0)?.slot_count(), 0);
+
+        page.insert(&[1]).unwrap();
+        buffer_pool.apply(&WalRecord {
+            lsn: 1,
+            writes: vec![WalWrite { offset: 0, data: page.as_bytes().to_vec() }],
+            transaction_id: None,
+        })?;
+
+        assert_eq!(buffer_pool.page_count(), 1);
+        assert_eq!(buffer_pool.fetch(0)?.tuple(0), Some(&[1][..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_deferred_keeps_pages_until_write_back() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("apply_deferred_keeps_pages_until_write_back.txt");
+        let file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+        let mut buffer_pool = BufferPool::new(Box::new(file_handler), 1)?;
+
+        let writes = (0..3)
+            .map(|page_id| WalWrite { offset: page_offset(page_id), data: Page::new_slotted().as_bytes().to_vec() })
+            .collect();
+        buffer_pool.apply_deferred(&WalRecord { lsn: 0, writes, transaction_id: None })?;
+
+        // dirty pages can't be evicted, even if the pool is over capacity
+        assert_eq!(buffer_pool.frames.len(), 3);
+        assert_eq!(buffer_pool.page_count(), 3);
+        assert_eq!(buffer_pool.file_handler.size()?, 0);
+
+        buffer_pool.write_back()?;
+        assert_eq!(buffer_pool.file_handler.size()?, 3 * PAGE_SIZE as u64);
+        assert!(buffer_pool.frames.values().all(|frame| !frame.dirty));
+
+        Ok(())
+    }
+}
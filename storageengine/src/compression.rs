@@ -0,0 +1,154 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::file_handler::FileHandler;
+
+const ZSTD_LEVEL: i32 = 3;
+// the first byte of the data of a row in a compressed database, rows that don't get smaller are kept as they are
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+// how the data of the rows is compressed, chosen when the database is created and recorded in the file header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    // databases written before rows could be compressed store them as they are
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let compressed = match self {
+            Compression::None => return Ok(data),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(&data),
+            Compression::Zstd => zstd::bulk::compress(&data, ZSTD_LEVEL)?,
+        };
+
+        let (tag, payload) = if compressed.len() < data.len() { (COMPRESSED, compressed) } else { (RAW, data) };
+        let mut tagged = Vec::with_capacity(1 + payload.len());
+        tagged.push(tag);
+        tagged.extend_from_slice(&payload);
+        Ok(tagged)
+    }
+
+    pub fn decompress(self, data: Vec<u8>) -> Result<Vec<u8>> {
+        if self == Compression::None {
+            return Ok(data);
+        }
+
+        match data.split_first() {
+            Some((&RAW, payload)) => Ok(payload.to_vec()),
+            Some((&COMPRESSED, payload)) => match self {
+                Compression::Lz4 => Ok(lz4_flex::decompress_size_prepended(payload)?),
+                Compression::Zstd => Ok(zstd::stream::decode_all(payload)?),
+                Compression::None => unreachable!(),
+            },
+            _ => bail!("row data has no compression tag"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err("no match"),
+        }
+    }
+}
+
+// returns the compression recorded in the header of the data file and records `requested` (or none) if there is
+// none yet. the compression can only be chosen while the file has no rows
+pub fn open_compression(file_handler: &mut dyn FileHandler, requested: Option<Compression>) -> Result<Compression> {
+    let mut header = file_handler.header();
+    let recorded = match header.compression {
+        Some(id) => Some(Compression::from_id(id).ok_or_else(|| anyhow!("data file names an unknown compression {}", id))?),
+        None => None,
+    };
+
+    let compression = match (recorded, requested) {
+        (Some(recorded), Some(requested)) if recorded != requested => bail!("data file uses {:?} compression, not {:?}", recorded, requested),
+        (Some(recorded), _) => return Ok(recorded),
+        (None, Some(requested)) if file_handler.size()? > 0 && requested != Compression::None => bail!("data file already has uncompressed rows, it can't use {:?} compression", requested),
+        (None, requested) => requested.unwrap_or_default(),
+    };
+
+    header.compression = Some(compression.id());
+    file_handler.set_header(header)?;
+    Ok(compression)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_handler::FileHandlerImpl;
+
+    use super::*;
+
+    #[test]
+    fn every_compression_round_trips_data() -> Result<()> {
+        let data = br#"{"name": "patrick", "tags": ["db", "db", "db", "db", "db", "db", "db", "db"]}"#.to_vec();
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let compressed = compression.compress(data.clone())?;
+            assert_eq!(compression.decompress(compressed)?, data);
+        }
+        assert!(Compression::Zstd.compress(data.clone())?.len() < data.len());
+        // too short to get smaller, it is stored as it is
+        assert_eq!(Compression::Lz4.compress(vec![7])?, vec![RAW, 7]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_compression_records_compression_of_new_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        let mut file_handler = FileHandlerImpl::new(&file_name)?;
+
+        assert_eq!(open_compression(&mut file_handler, Some(Compression::Zstd))?, Compression::Zstd);
+        drop(file_handler);
+
+        let mut file_handler = FileHandlerImpl::new(&file_name)?;
+        assert_eq!(open_compression(&mut file_handler, None)?, Compression::Zstd);
+        assert!(open_compression(&mut file_handler, Some(Compression::Lz4)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_compression_keeps_files_with_rows_uncompressed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        let mut file_handler = FileHandlerImpl::new(&file_name)?;
+        file_handler.append(b"row")?;
+
+        assert!(open_compression(&mut file_handler, Some(Compression::Lz4)).is_err());
+        assert_eq!(open_compression(&mut file_handler, None)?, Compression::None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,975 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::file_handler::{FileHandler, FileHandlerImpl};
+use crate::file_header::FileHeader;
+use crate::page::PAGE_SIZE;
+use crate::wal;
+
+pub const KEY_SIZE: usize = 32;
+// holds the hex encoded key if no key file is given
+pub const KEY_ENV_VAR: &str = "PATRICKDB_ENCRYPTION_KEY";
+// the data is encrypted in blocks of a page, so reading a page decrypts one block
+const BLOCK_SIZE: u64 = PAGE_SIZE as u64;
+const NONCE_SIZE: u64 = 12;
+const TAG_SIZE: u64 = 16;
+// every stored block is its nonce followed by the encrypted data and its tag
+const STORED_BLOCK_SIZE: u64 = NONCE_SIZE + BLOCK_SIZE + TAG_SIZE;
+// a sealed record of an encrypted log is its length followed by its nonce, the encrypted data and its tag
+const LENGTH_SIZE: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Encryption {
+    pub fn id(self) -> u8 {
+        match self {
+            Encryption::Aes256Gcm => 1,
+            Encryption::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Encryption::Aes256Gcm),
+            2 => Some(Encryption::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Encryption {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "aes-gcm" => Ok(Encryption::Aes256Gcm),
+            "chacha20poly1305" => Ok(Encryption::ChaCha20Poly1305),
+            _ => Err("no match"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+
+// leaves the key out, so it doesn't end up in logs
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self(key)
+    }
+
+    // 64 hex digits
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() {
+            bail!("encryption key has to be {} hex digits", KEY_SIZE * 2);
+        }
+        let mut key = [0; KEY_SIZE];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| anyhow!("encryption key has to be hex encoded"))?;
+        }
+        Ok(Self(key))
+    }
+
+    // the key in `key_file`, or in `KEY_ENV_VAR` without a key file. None if neither is there
+    pub fn load(key_file: Option<&str>) -> Result<Option<Self>> {
+        match key_file {
+            Some(key_file) => Ok(Some(Self::from_hex(&fs::read_to_string(key_file).with_context(|| format!("failed to read key file {}", key_file))?)?)),
+            None => env::var(KEY_ENV_VAR).ok().map(|hex| Self::from_hex(&hex)).transpose(),
+        }
+    }
+}
+
+enum BlockCipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+impl BlockCipher {
+    fn new(encryption: Encryption, key: &EncryptionKey) -> Self {
+        match encryption {
+            Encryption::Aes256Gcm => BlockCipher::Aes256Gcm(Box::new(Aes256Gcm::new(&key.0.into()))),
+            Encryption::ChaCha20Poly1305 => BlockCipher::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new(&key.0.into()))),
+        }
+    }
+
+    // the index of the block is authenticated too, so blocks can't be swapped
+    fn encrypt(&self, index: u64, block: &[u8]) -> Result<Vec<u8>> {
+        let aad = index.to_le_bytes();
+        let payload = Payload { msg: block, aad: &aad };
+        let (nonce, ciphertext) = match self {
+            BlockCipher::Aes256Gcm(cipher) => {
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                (nonce.to_vec(), cipher.encrypt(&nonce, payload))
+            }
+            BlockCipher::ChaCha20Poly1305(cipher) => {
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                (nonce.to_vec(), cipher.encrypt(&nonce, payload))
+            }
+        };
+        let ciphertext = ciphertext.map_err(|_| anyhow!("failed to encrypt block {}", index))?;
+        Ok([nonce, ciphertext].concat())
+    }
+
+    fn decrypt(&self, index: u64, stored: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, ciphertext) = stored.split_at(NONCE_SIZE as usize);
+        let aad = index.to_le_bytes();
+        let payload = Payload { msg: ciphertext, aad: &aad };
+        let block = match self {
+            BlockCipher::Aes256Gcm(cipher) => cipher.decrypt(nonce.into(), payload),
+            BlockCipher::ChaCha20Poly1305(cipher) => cipher.decrypt(nonce.into(), payload),
+        };
+        // a wrong key looks the same as a damaged block
+        block.map_err(|_| anyhow!("block {} can't be decrypted, the key is wrong or the block is corrupted", index))
+    }
+}
+
+// the blocks of an encrypted file, offsets and sizes are the ones of the decrypted data
+struct Blocks<'a> {
+    inner: &'a mut dyn FileHandler,
+    cipher: &'a BlockCipher,
+}
+
+impl Blocks<'_> {
+    fn data_size(&mut self) -> Result<u64> {
+        let stored = self.inner.size()?;
+        let last = stored % STORED_BLOCK_SIZE;
+        if last != 0 && last <= NONCE_SIZE + TAG_SIZE {
+            bail!("encrypted file ends in a torn block");
+        }
+        Ok(stored / STORED_BLOCK_SIZE * BLOCK_SIZE + last.saturating_sub(NONCE_SIZE + TAG_SIZE))
+    }
+
+    // the last block can be shorter than `BLOCK_SIZE`
+    fn read_block(&mut self, index: u64, size: u64) -> Result<Vec<u8>> {
+        let length = (size - index * BLOCK_SIZE).min(BLOCK_SIZE);
+        let stored = self.inner.read(index * STORED_BLOCK_SIZE, NONCE_SIZE + length + TAG_SIZE)?;
+        self.cipher.decrypt(index, &stored)
+    }
+
+    fn write_block(&mut self, index: u64, block: &[u8]) -> Result<()> {
+        let stored = self.cipher.encrypt(index, block)?;
+        self.inner.update(index * STORED_BLOCK_SIZE, &stored)
+    }
+
+    fn read_data(&mut self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let size = self.data_size()?;
+        if offset + length > size {
+            bail!("can't read {} bytes at offset {} of an encrypted file of {} bytes", length, offset, size);
+        }
+
+        let mut data = Vec::with_capacity(length as usize);
+        let end = offset + length;
+        let mut position = offset;
+        while position < end {
+            let index = position / BLOCK_SIZE;
+            let block = self.read_block(index, size)?;
+            let start = (position - index * BLOCK_SIZE) as usize;
+            let stop = ((end - index * BLOCK_SIZE).min(BLOCK_SIZE)) as usize;
+            data.extend_from_slice(&block[start..stop]);
+            position = index * BLOCK_SIZE + stop as u64;
+        }
+        Ok(data)
+    }
+
+    // writing past the end fills the gap with zeros, like a plain file does
+    fn update_data(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let size = self.data_size()?;
+        let (offset, data) = if offset > size {
+            let mut padded = vec![0; (offset - size) as usize];
+            padded.extend_from_slice(data);
+            (size, padded)
+        } else {
+            (offset, data.to_vec())
+        };
+
+        let end = offset + data.len() as u64;
+        let mut position = offset;
+        while position < end {
+            let index = position / BLOCK_SIZE;
+            let block_start = index * BLOCK_SIZE;
+            let block_end = (block_start + BLOCK_SIZE).min(end.max(size));
+            // a block that is overwritten completely doesn't have to be decrypted first
+            let mut block = if block_start < size && (position > block_start || end < (block_start + BLOCK_SIZE).min(size)) {
+                self.read_block(index, size)?
+            } else {
+                Vec::new()
+            };
+            block.resize((block_end - block_start) as usize, 0);
+
+            let stop = end.min(block_start + BLOCK_SIZE);
+            let source = (position - offset) as usize..(stop - offset) as usize;
+            block[(position - block_start) as usize..(stop - block_start) as usize].copy_from_slice(&data[source]);
+            self.write_block(index, &block)?;
+            position = stop;
+        }
+        Ok(())
+    }
+
+    fn truncate_data(&mut self, new_size: u64) -> Result<()> {
+        let size = self.data_size()?;
+        if new_size >= size {
+            return self.update_data(size, &vec![0; (new_size - size) as usize]);
+        }
+
+        let index = new_size / BLOCK_SIZE;
+        let kept = (new_size % BLOCK_SIZE) as usize;
+        if kept == 0 {
+            return self.inner.truncate(index * STORED_BLOCK_SIZE);
+        }
+        let mut block = self.read_block(index, size)?;
+        block.truncate(kept);
+        self.inner.truncate(index * STORED_BLOCK_SIZE)?;
+        self.write_block(index, &block)
+    }
+}
+
+// encrypts the data of another file handler block by block with AES-GCM or ChaCha20-Poly1305. the cipher is recorded
+// in the header of the file, which stays readable
+pub struct EncryptedFileHandler {
+    inner: Box<dyn FileHandler>,
+    cipher: BlockCipher,
+}
+
+impl EncryptedFileHandler {
+    // an empty file is encrypted from now on, a file with data has to be encrypted with `encryption` already
+    pub fn new(mut inner: Box<dyn FileHandler>, encryption: Encryption, key: &EncryptionKey) -> Result<Self> {
+        let mut header = inner.header();
+        match header.encryption {
+            Some(id) if id == encryption.id() => {}
+            Some(id) => bail!("file is encrypted with {:?}, not {:?}", Encryption::from_id(id).ok_or_else(|| anyhow!("unknown encryption {}", id))?, encryption),
+            None if inner.size()? > 0 => bail!("file is stored in plaintext, it can't be opened encrypted"),
+            None => {
+                header.encryption = Some(encryption.id());
+                inner.set_header(header)?;
+            }
+        }
+
+        let mut file_handler = Self { inner, cipher: BlockCipher::new(encryption, key) };
+        file_handler.cut_torn_tail()?;
+        // a wrong key is reported right away instead of with the first read
+        let size = file_handler.blocks().data_size()?;
+        if size > 0 {
+            file_handler.blocks().read_block(0, size)?;
+        }
+        Ok(file_handler)
+    }
+
+    fn blocks(&mut self) -> Blocks<'_> {
+        Blocks { inner: self.inner.as_mut(), cipher: &self.cipher }
+    }
+
+    // a crash while a block is written can leave it torn. a torn last block is cut off like a torn wal tail, the wal
+    // writes its pages again on replay. a lone first block that can't be decrypted is left to the key check instead
+    fn cut_torn_tail(&mut self) -> Result<()> {
+        let stored = self.inner.size()?;
+        let last = stored % STORED_BLOCK_SIZE;
+        if last != 0 && last <= NONCE_SIZE + TAG_SIZE {
+            self.inner.truncate(stored - last)?;
+            return self.inner.sync();
+        }
+
+        let size = self.blocks().data_size()?;
+        let last_index = size.saturating_sub(1) / BLOCK_SIZE;
+        if last_index == 0 || self.blocks().read_block(last_index, size).is_ok() || self.blocks().read_block(0, size).is_err() {
+            return Ok(());
+        }
+        self.inner.truncate(last_index * STORED_BLOCK_SIZE)?;
+        self.inner.sync()
+    }
+
+    // encrypts every block again with `new_key`, the file is swapped atomically like a rewrite
+    pub fn rotate_key(&mut self, new_key: &EncryptionKey) -> Result<()> {
+        let encryption = Encryption::from_id(self.inner.header().encryption.unwrap_or_default()).ok_or_else(|| anyhow!("file has no encryption"))?;
+        let new_cipher = BlockCipher::new(encryption, new_key);
+        let cipher = &self.cipher;
+        self.inner.rewrite(&mut |old, replacement| {
+            let mut old = Blocks { inner: old, cipher };
+            let mut replacement = Blocks { inner: replacement, cipher: &new_cipher };
+            let size = old.data_size()?;
+            for index in 0..size.div_ceil(BLOCK_SIZE) {
+                let block = old.read_block(index, size)?;
+                replacement.write_block(index, &block)?;
+            }
+            Ok(())
+        })?;
+        self.cipher = new_cipher;
+        Ok(())
+    }
+}
+
+impl FileHandler for EncryptedFileHandler {
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        self.blocks().append(data)
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        self.blocks().read(offset, size)
+    }
+
+    fn read_all(&mut self) -> Result<Vec<u8>> {
+        self.blocks().read_all()
+    }
+
+    fn update(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.blocks().update(offset, data)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        self.blocks().size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.blocks().truncate(size)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    // `fill` sees the decrypted data of both files
+    fn rewrite(&mut self, fill: &mut dyn FnMut(&mut dyn FileHandler, &mut dyn FileHandler) -> Result<()>) -> Result<()> {
+        let cipher = &self.cipher;
+        self.inner.rewrite(&mut |old, replacement| {
+            fill(&mut Blocks { inner: old, cipher }, &mut Blocks { inner: replacement, cipher })
+        })
+    }
+
+    fn header(&self) -> FileHeader {
+        self.inner.header()
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.inner.set_header(header)
+    }
+}
+
+impl FileHandler for Blocks<'_> {
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        let offset = self.data_size()?;
+        self.update_data(offset, data)?;
+        Ok(offset)
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        self.read_data(offset, size)
+    }
+
+    fn read_all(&mut self) -> Result<Vec<u8>> {
+        let size = self.data_size()?;
+        self.read_data(0, size)
+    }
+
+    fn update(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.update_data(offset, data)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        self.data_size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.truncate_data(size)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn rewrite(&mut self, _: &mut dyn FnMut(&mut dyn FileHandler, &mut dyn FileHandler) -> Result<()>) -> Result<()> {
+        bail!("the files of a rewrite can't be rewritten themselves")
+    }
+
+    fn header(&self) -> FileHeader {
+        self.inner.header()
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.inner.set_header(header)
+    }
+}
+
+// a sealed record of an encrypted log, offsets are the ones of the decrypted data
+#[derive(Clone, Copy)]
+struct Record {
+    offset: u64,
+    stored_offset: u64,
+    length: u64,
+}
+
+impl Record {
+    fn stored_end(&self) -> u64 {
+        self.stored_offset + LENGTH_SIZE + NONCE_SIZE + self.length + TAG_SIZE
+    }
+}
+
+// the records of an encrypted log, offsets and sizes are the ones of the decrypted data
+struct Log<'a> {
+    inner: &'a mut dyn FileHandler,
+    cipher: &'a BlockCipher,
+    records: &'a mut Vec<Record>,
+}
+
+impl Log<'_> {
+    // reads the records of the file. a torn or damaged record ends the log like a torn frame ends the wal and is cut
+    // off, only the first record has to decrypt so a wrong key isn't taken for a torn log
+    fn load(&mut self) -> Result<()> {
+        let stored = self.inner.read_all()?;
+        self.records.clear();
+        let mut position = 0;
+        let mut offset = 0;
+        while let Some(length) = stored.get(position..position + LENGTH_SIZE as usize) {
+            let length = u32::from_le_bytes(length.try_into()?) as u64;
+            let start = position + LENGTH_SIZE as usize;
+            let Some(sealed) = stored.get(start..start + (NONCE_SIZE + length + TAG_SIZE) as usize) else { break };
+            if length == 0 {
+                break;
+            }
+            match self.cipher.decrypt(offset, sealed) {
+                Ok(_) => {}
+                Err(e) if self.records.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+
+            let record = Record { offset, stored_offset: position as u64, length };
+            position = record.stored_end() as usize;
+            offset += length;
+            self.records.push(record);
+        }
+
+        if position < stored.len() {
+            self.inner.truncate(position as u64)?;
+            self.inner.sync()?;
+        }
+        Ok(())
+    }
+
+    fn data_size(&self) -> u64 {
+        self.records.last().map_or(0, |record| record.offset + record.length)
+    }
+
+    fn read_record(&mut self, record: Record) -> Result<Vec<u8>> {
+        let stored = self.inner.read(record.stored_offset + LENGTH_SIZE, NONCE_SIZE + record.length + TAG_SIZE)?;
+        self.cipher.decrypt(record.offset, &stored)
+    }
+
+    fn append_record(&mut self, data: &[u8]) -> Result<u64> {
+        let offset = self.data_size();
+        if data.is_empty() {
+            return Ok(offset);
+        }
+        let length = u32::try_from(data.len()).map_err(|_| anyhow!("can't append {} bytes to an encrypted log at once", data.len()))?;
+        let sealed = self.cipher.encrypt(offset, data)?;
+        let stored_offset = self.inner.append(&[&length.to_le_bytes()[..], &sealed].concat())?;
+        self.records.push(Record { offset, stored_offset, length: data.len() as u64 });
+        Ok(offset)
+    }
+
+    fn read_data(&mut self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let size = self.data_size();
+        if offset + length > size {
+            bail!("can't read {} bytes at offset {} of an encrypted log of {} bytes", length, offset, size);
+        }
+
+        let end = offset + length;
+        let first = self.records.partition_point(|record| record.offset + record.length <= offset);
+        let mut data = Vec::with_capacity(length as usize);
+        for index in first..self.records.len() {
+            let record = self.records[index];
+            if record.offset >= end {
+                break;
+            }
+            let plain = self.read_record(record)?;
+            let start = (offset.max(record.offset) - record.offset) as usize;
+            let stop = (end.min(record.offset + record.length) - record.offset) as usize;
+            data.extend_from_slice(&plain[start..stop]);
+        }
+        Ok(data)
+    }
+
+    // cutting at a record boundary just drops the records after it. cutting into a record writes the whole log again,
+    // so a crash can't lose the part of the record that is kept
+    fn truncate_data(&mut self, new_size: u64) -> Result<()> {
+        let size = self.data_size();
+        if new_size > size {
+            bail!("an encrypted log can't be extended by truncating it");
+        }
+        let kept = self.records.partition_point(|record| record.offset + record.length <= new_size);
+        let Some(&cut) = self.records.get(kept) else { return Ok(()) };
+        if cut.offset == new_size {
+            self.inner.truncate(cut.stored_offset)?;
+            self.records.truncate(kept);
+            return Ok(());
+        }
+
+        let prefix = self.read_data(cut.offset, new_size - cut.offset)?;
+        let stored = self.inner.read(0, cut.stored_offset)?;
+        let cipher = self.cipher;
+        let mut records = self.records[..kept].to_vec();
+        self.inner.rewrite(&mut |_, replacement| {
+            replacement.append(&stored)?;
+            records.truncate(kept);
+            Log { inner: replacement, cipher, records: &mut records }.append_record(&prefix).map(|_| ())
+        })?;
+        *self.records = records;
+        Ok(())
+    }
+}
+
+impl FileHandler for Log<'_> {
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        self.append_record(data)
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        self.read_data(offset, size)
+    }
+
+    fn read_all(&mut self) -> Result<Vec<u8>> {
+        let size = self.data_size();
+        self.read_data(0, size)
+    }
+
+    fn update(&mut self, _: u64, _: &[u8]) -> Result<()> {
+        bail!("an encrypted log is only appended to, its records are never written again")
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.data_size())
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.truncate_data(size)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn rewrite(&mut self, _: &mut dyn FnMut(&mut dyn FileHandler, &mut dyn FileHandler) -> Result<()>) -> Result<()> {
+        bail!("the files of a rewrite can't be rewritten themselves")
+    }
+
+    fn header(&self) -> FileHeader {
+        self.inner.header()
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.inner.set_header(header)
+    }
+}
+
+// encrypts a file that is only appended to and truncated, like the wal. every append is sealed in its own record
+// instead of being merged into the last block, so a crash can only tear the record that was being appended and never
+// data that was synced before
+pub struct EncryptedLogFileHandler {
+    inner: Box<dyn FileHandler>,
+    cipher: BlockCipher,
+    records: Vec<Record>,
+}
+
+impl EncryptedLogFileHandler {
+    // an empty file is encrypted from now on, a file with data has to be encrypted with `encryption` already. a file
+    // that was encrypted in blocks is moved into a single record first
+    pub fn new(mut inner: Box<dyn FileHandler>, encryption: Encryption, key: &EncryptionKey) -> Result<Self> {
+        let mut header = inner.header();
+        if header.sealed_records != Some(true) {
+            // checks the key, cuts a torn block and records the encryption of an empty file
+            let mut blocks = EncryptedFileHandler::new(inner, encryption, key)?;
+            let data = blocks.read_all()?;
+            inner = blocks.inner;
+            header = inner.header();
+            header.sealed_records = Some(true);
+            if !data.is_empty() {
+                let cipher = BlockCipher::new(encryption, key);
+                inner.rewrite(&mut |_, replacement| {
+                    replacement.set_header(header)?;
+                    Log { inner: replacement, cipher: &cipher, records: &mut Vec::new() }.append_record(&data).map(|_| ())
+                })?;
+            }
+            inner.set_header(header)?;
+        } else if header.encryption != Some(encryption.id()) {
+            bail!("file is encrypted with {:?}, not {:?}", header.encryption.and_then(Encryption::from_id), encryption);
+        }
+
+        let mut file_handler = Self { inner, cipher: BlockCipher::new(encryption, key), records: Vec::new() };
+        file_handler.log().load()?;
+        Ok(file_handler)
+    }
+
+    fn log(&mut self) -> Log<'_> {
+        Log { inner: self.inner.as_mut(), cipher: &self.cipher, records: &mut self.records }
+    }
+
+    // seals every record again with `new_key`, the file is swapped atomically like a rewrite
+    pub fn rotate_key(&mut self, new_key: &EncryptionKey) -> Result<()> {
+        let encryption = Encryption::from_id(self.inner.header().encryption.unwrap_or_default()).ok_or_else(|| anyhow!("file has no encryption"))?;
+        let new_cipher = BlockCipher::new(encryption, new_key);
+        let (cipher, records) = (&self.cipher, &self.records);
+        let mut new_records = Vec::new();
+        self.inner.rewrite(&mut |old, replacement| {
+            let mut old = Log { inner: old, cipher, records: &mut records.clone() };
+            let mut replacement = Log { inner: replacement, cipher: &new_cipher, records: &mut new_records };
+            replacement.records.clear();
+            for &record in records {
+                let data = old.read_record(record)?;
+                replacement.append_record(&data)?;
+            }
+            Ok(())
+        })?;
+        self.cipher = new_cipher;
+        self.records = new_records;
+        Ok(())
+    }
+}
+
+impl FileHandler for EncryptedLogFileHandler {
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        self.log().append(data)
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        self.log().read(offset, size)
+    }
+
+    fn read_all(&mut self) -> Result<Vec<u8>> {
+        self.log().read_all()
+    }
+
+    fn update(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.log().update(offset, data)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        self.log().size()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.log().truncate(size)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    // `fill` sees the decrypted data of both files
+    fn rewrite(&mut self, fill: &mut dyn FnMut(&mut dyn FileHandler, &mut dyn FileHandler) -> Result<()>) -> Result<()> {
+        let (cipher, records) = (&self.cipher, &self.records);
+        let mut new_records = Vec::new();
+        self.inner.rewrite(&mut |old, replacement| {
+            new_records.clear();
+            fill(&mut Log { inner: old, cipher, records: &mut records.clone() }, &mut Log { inner: replacement, cipher, records: &mut new_records })
+        })?;
+        self.records = new_records;
+        Ok(())
+    }
+
+    fn header(&self) -> FileHeader {
+        self.inner.header()
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.inner.set_header(header)
+    }
+}
+
+fn recorded_encryption(file_name: &str, header: FileHeader) -> Result<Option<Encryption>> {
+    match header.encryption {
+        Some(id) => Ok(Some(Encryption::from_id(id).ok_or_else(|| anyhow!("{} names an unknown encryption {}", file_name, id))?)),
+        None => Ok(None),
+    }
+}
+
+// opens a file of the storage engine, encrypted if its header says so or `encryption` is chosen for a new file
+pub fn open_file_handler(file_name: &str, encryption: Option<Encryption>, key: Option<&EncryptionKey>) -> Result<Box<dyn FileHandler>> {
+    open(file_name, encryption, key, false)
+}
+
+// like `open_file_handler`, for files that are only appended to and truncated like the wal. they are encrypted in
+// sealed records, see `EncryptedLogFileHandler`
+pub fn open_log_file_handler(file_name: &str, encryption: Option<Encryption>, key: Option<&EncryptionKey>) -> Result<Box<dyn FileHandler>> {
+    open(file_name, encryption, key, true)
+}
+
+// opens a file that is built again from the data file when it is missing, like the b+tree of an index, and its wal.
+// both are encrypted like the data file. files that can't be opened that way, because they were written in plaintext
+// or before the key was rotated, are removed and built again
+pub fn open_derived_file_handlers(file_name: &str, encryption: Option<Encryption>, key: Option<&EncryptionKey>) -> Result<(Box<dyn FileHandler>, Box<dyn FileHandler>)> {
+    let wal_file_name = wal::wal_file_name(file_name);
+    let key = key.filter(|_| encryption.is_some());
+    let open = || -> Result<(Box<dyn FileHandler>, Box<dyn FileHandler>)> {
+        Ok((open_file_handler(file_name, encryption, key)?, open_log_file_handler(&wal_file_name, encryption, key)?))
+    };
+
+    match open() {
+        Err(_) if encryption.is_some() => {
+            for name in [file_name, &wal_file_name] {
+                if Path::new(name).exists() {
+                    fs::remove_file(name)?;
+                }
+            }
+            open()
+        }
+        opened => opened,
+    }
+}
+
+fn open(file_name: &str, encryption: Option<Encryption>, key: Option<&EncryptionKey>, log: bool) -> Result<Box<dyn FileHandler>> {
+    let file_handler = FileHandlerImpl::new(file_name)?;
+    let header = file_handler.header();
+    let recorded = recorded_encryption(file_name, header)?;
+
+    match (recorded.or(encryption), key) {
+        (None, None) => Ok(Box::new(file_handler)),
+        (None, Some(_)) => bail!("{} is not encrypted, the encryption can only be chosen for a new database", file_name),
+        (Some(_), None) => bail!("{} is encrypted, but no key was given", file_name),
+        (Some(recorded_or_chosen), Some(key)) => {
+            let encryption = encryption.unwrap_or(recorded_or_chosen);
+            // the header decides for files that exist, so a segment of the wal archive opens like any other file
+            let encrypted: Box<dyn FileHandler> = if log || header.sealed_records == Some(true) {
+                Box::new(EncryptedLogFileHandler::new(Box::new(file_handler), encryption, key).with_context(|| format!("failed to open {}", file_name))?)
+            } else {
+                Box::new(EncryptedFileHandler::new(Box::new(file_handler), encryption, key).with_context(|| format!("failed to open {}", file_name))?)
+            };
+            Ok(encrypted)
+        }
+    }
+}
+
+// opens the encrypted `file_name` with `key` and encrypts it again with `new_key` if there is one
+fn reencrypt(file_name: &str, encryption: Encryption, key: &EncryptionKey, new_key: Option<&EncryptionKey>) -> Result<()> {
+    let file_handler = Box::new(FileHandlerImpl::new(file_name)?);
+    if file_handler.header().sealed_records == Some(true) {
+        let mut encrypted = EncryptedLogFileHandler::new(file_handler, encryption, key)?;
+        new_key.map_or(Ok(()), |new_key| encrypted.rotate_key(new_key))
+    } else {
+        let mut encrypted = EncryptedFileHandler::new(file_handler, encryption, key)?;
+        new_key.map_or(Ok(()), |new_key| encrypted.rotate_key(new_key))
+    }
+}
+
+// encrypts `file_name` with `new_key` instead of `key`. a file that already uses `new_key` is left alone, so an
+// interrupted rotation of several files can be run again
+pub fn rotate_key(file_name: &str, key: &EncryptionKey, new_key: &EncryptionKey) -> Result<()> {
+    let Some(encryption) = recorded_encryption(file_name, FileHandlerImpl::new(file_name)?.header())? else { bail!("{} is not encrypted", file_name) };
+
+    if let Err(e) = reencrypt(file_name, encryption, key, Some(new_key)) {
+        if reencrypt(file_name, encryption, new_key, None).is_ok() {
+            return Ok(());
+        }
+        return Err(e.context(format!("failed to open {}", file_name)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wal::{Wal, WalWrite};
+
+    use super::*;
+
+    fn open(file_name: &str, encryption: Encryption, key: u8) -> Result<EncryptedFileHandler> {
+        EncryptedFileHandler::new(Box::new(FileHandlerImpl::new(file_name)?), encryption, &EncryptionKey::new([key; KEY_SIZE]))
+    }
+
+    #[test]
+    fn encrypted_file_handler_reads_back_what_it_wrote() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for encryption in [Encryption::Aes256Gcm, Encryption::ChaCha20Poly1305] {
+            let file_name = dir.path().join(format!("{:?}.db", encryption)).to_str().unwrap().to_string();
+            let data = (0..10_000).map(|i| i as u8).collect::<Vec<u8>>();
+
+            let mut file_handler = open(&file_name, encryption, 1)?;
+            assert_eq!(file_handler.append(&data)?, 0);
+            assert_eq!(file_handler.append(b"Hello, world!")?, 10_000);
+            file_handler.update(4090, b"across blocks")?;
+            drop(file_handler);
+
+            let mut file_handler = open(&file_name, encryption, 1)?;
+            assert_eq!(file_handler.size()?, 10_013);
+            assert_eq!(file_handler.read(4090, 13)?, b"across blocks");
+            assert_eq!(file_handler.read(10_000, 13)?, b"Hello, world!");
+            assert_eq!(&file_handler.read_all()?[..4090], &data[..4090]);
+
+            file_handler.truncate(5000)?;
+            assert_eq!(file_handler.size()?, 5000);
+            assert_eq!(file_handler.read(4990, 10)?, &data[4990..5000]);
+
+            // the data never shows up in the file itself
+            let stored = fs::read(&file_name)?;
+            assert!(!stored.windows(13).any(|window| window == b"across blocks"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_file_handler_rejects_wrong_key_and_plaintext_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        open(&file_name, Encryption::Aes256Gcm, 1)?.append(b"Hello, world!")?;
+
+        assert!(open(&file_name, Encryption::Aes256Gcm, 2).is_err());
+        assert!(open(&file_name, Encryption::ChaCha20Poly1305, 1).is_err());
+        assert!(open_file_handler(&file_name, None, None).is_err());
+
+        let plain_file_name = dir.path().join("plain.db").to_str().unwrap().to_string();
+        FileHandlerImpl::new(&plain_file_name)?.append(b"Hello, world!")?;
+        assert!(open(&plain_file_name, Encryption::Aes256Gcm, 1).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_key_encrypts_file_with_new_key() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        let data = (0..5000).map(|i| i as u8).collect::<Vec<u8>>();
+        open(&file_name, Encryption::ChaCha20Poly1305, 1)?.append(&data)?;
+
+        rotate_key(&file_name, &EncryptionKey::new([1; KEY_SIZE]), &EncryptionKey::new([2; KEY_SIZE]))?;
+        // running it again after an interruption is fine
+        rotate_key(&file_name, &EncryptionKey::new([1; KEY_SIZE]), &EncryptionKey::new([2; KEY_SIZE]))?;
+
+        assert!(open(&file_name, Encryption::ChaCha20Poly1305, 1).is_err());
+        assert_eq!(open(&file_name, Encryption::ChaCha20Poly1305, 2)?.read_all()?, data);
+
+        Ok(())
+    }
+
+    // cuts `bytes` off the end of the file as a crash in the middle of a write would
+    fn tear(file_name: &str, bytes: u64) -> Result<()> {
+        let file = fs::OpenOptions::new().write(true).open(file_name)?;
+        file.set_len(file.metadata()?.len() - bytes)?;
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_wal_keeps_synced_records_when_the_last_one_is_torn() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db.wal").to_str().unwrap().to_string();
+        let key = EncryptionKey::new([1; KEY_SIZE]);
+        let mut wal = Wal::new(open_log_file_handler(&file_name, Some(Encryption::Aes256Gcm), Some(&key))?)?;
+        wal.log(vec![WalWrite { offset: 0, data: vec![1, 2, 3] }])?;
+        wal.log(vec![WalWrite { offset: 3, data: vec![4; 100] }])?;
+        drop(wal);
+
+        tear(&file_name, 50)?;
+
+        let mut wal = Wal::new(open_log_file_handler(&file_name, None, Some(&key))?)?;
+        let records = wal.records()?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].writes[0].data, vec![1, 2, 3]);
+
+        // new records go behind the synced one
+        wal.log(vec![WalWrite { offset: 3, data: vec![7] }])?;
+        let mut wal = Wal::new(open_log_file_handler(&file_name, None, Some(&key))?)?;
+        let records = wal.records()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].writes[0].data, vec![7]);
+
+        // a wrong key is still told apart from a torn log
+        assert!(open_log_file_handler(&file_name, None, Some(&EncryptionKey::new([2; KEY_SIZE]))).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_file_handler_cuts_torn_last_block() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        let data = (0..10_000).map(|i| i as u8).collect::<Vec<u8>>();
+        open(&file_name, Encryption::Aes256Gcm, 1)?.append(&data)?;
+
+        tear(&file_name, 100)?;
+        let mut file_handler = open(&file_name, Encryption::Aes256Gcm, 1)?;
+        assert_eq!(file_handler.read_all()?, &data[..2 * BLOCK_SIZE as usize]);
+
+        file_handler.append(&data[..BLOCK_SIZE as usize])?;
+        drop(file_handler);
+        tear(&file_name, STORED_BLOCK_SIZE - 10)?;
+        assert_eq!(open(&file_name, Encryption::Aes256Gcm, 1)?.size()?, 2 * BLOCK_SIZE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_log_file_handler_moves_blocks_into_records() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db.wal").to_str().unwrap().to_string();
+        let key = EncryptionKey::new([1; KEY_SIZE]);
+        let data = (0..5000).map(|i| i as u8).collect::<Vec<u8>>();
+        open(&file_name, Encryption::ChaCha20Poly1305, 1)?.append(&data)?;
+
+        let mut file_handler = open_log_file_handler(&file_name, None, Some(&key))?;
+        assert_eq!(file_handler.header().sealed_records, Some(true));
+        assert_eq!(file_handler.read_all()?, data);
+        file_handler.append(b"Hello, world!")?;
+
+        // cutting into a record keeps its start
+        file_handler.truncate(5005)?;
+        assert_eq!(file_handler.read(4990, 15)?, [&data[4990..], b"Hello"].concat());
+        drop(file_handler);
+
+        rotate_key(&file_name, &key, &EncryptionKey::new([2; KEY_SIZE]))?;
+        let mut file_handler = open_log_file_handler(&file_name, None, Some(&EncryptionKey::new([2; KEY_SIZE])))?;
+        assert_eq!(file_handler.size()?, 5005);
+        assert_eq!(&file_handler.read_all()?[..5000], data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn derived_files_are_built_again_unless_they_open_with_the_key() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db.bplustree").to_str().unwrap().to_string();
+        let key = EncryptionKey::new([1; KEY_SIZE]);
+        FileHandlerImpl::new(&file_name)?.append(b"Hello, world!")?;
+
+        // written in plaintext before the database was encrypted
+        let (mut file_handler, _) = open_derived_file_handlers(&file_name, Some(Encryption::Aes256Gcm), Some(&key))?;
+        assert_eq!(file_handler.size()?, 0);
+        file_handler.append(b"Hello, world!")?;
+        drop(file_handler);
+
+        let (mut file_handler, _) = open_derived_file_handlers(&file_name, Some(Encryption::Aes256Gcm), Some(&key))?;
+        assert_eq!(file_handler.read_all()?, b"Hello, world!");
+        drop(file_handler);
+
+        // written before the key was rotated
+        let (mut file_handler, _) = open_derived_file_handlers(&file_name, Some(Encryption::Aes256Gcm), Some(&EncryptionKey::new([2; KEY_SIZE])))?;
+        assert_eq!(file_handler.size()?, 0);
+        assert_eq!(file_handler.header().encryption, Some(Encryption::Aes256Gcm.id()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_is_read_from_hex() -> Result<()> {
+        let key = EncryptionKey::from_hex(&format!("{}\n", "0a".repeat(KEY_SIZE)))?;
+
+        assert_eq!(key.0, [10; KEY_SIZE]);
+        assert!(EncryptionKey::from_hex("0a0a").is_err());
+        assert!(EncryptionKey::from_hex(&"zz".repeat(KEY_SIZE)).is_err());
+
+        Ok(())
+    }
+}
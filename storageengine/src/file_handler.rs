@@ -1,54 +1,137 @@
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+
+use crate::file_header::{FileHeader, HEADER_SIZE};
+use crate::migration;
 
 pub trait FileHandler: Send + Sync {
+    // offsets and sizes leave out the file header
     fn append(&mut self, data: &[u8]) -> Result<u64>;
     fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>>;
     fn read_all(&mut self) -> Result<Vec<u8>>;
     fn update(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+    fn size(&mut self) -> Result<u64>;
+    fn truncate(&mut self, size: u64) -> Result<()>;
+    // makes every write so far durable
+    fn sync(&mut self) -> Result<()>;
+    // `fill` copies what should survive from the current file into an empty replacement,
+    // the replacement is swapped in atomically afterwards and dropped if `fill` fails
+    fn rewrite(&mut self, fill: &mut dyn FnMut(&mut dyn FileHandler, &mut dyn FileHandler) -> Result<()>) -> Result<()>;
+    fn header(&self) -> FileHeader;
+    // writes the header and makes it durable
+    fn set_header(&mut self, header: FileHeader) -> Result<()>;
+}
+
+// how much of a file is copied at once
+const COPY_CHUNK_SIZE: u64 = 1 << 20;
+
+// appends the data of `source` to `target` without holding all of it in memory
+pub fn copy_data(source: &mut dyn FileHandler, target: &mut dyn FileHandler) -> Result<()> {
+    let size = source.size()?;
+    let mut offset = 0;
+    while offset < size {
+        let chunk = source.read(offset, COPY_CHUNK_SIZE.min(size - offset))?;
+        target.append(&chunk)?;
+        offset += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+pub fn lock_file_name(file_name: &str) -> String {
+    format!("{}.lock", file_name)
+}
+
+// keeps other processes from opening the database in `file_name` as long as the returned file is open, fails if
+// one of them holds it already
+pub fn lock(file_name: &str) -> Result<File> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(lock_file_name(file_name))?;
+    file.try_lock().map_err(|e| anyhow!("{} is in use by another process: {}", file_name, e))?;
+    Ok(file)
 }
 
 pub struct FileHandlerImpl {
     writer: BufWriter<File>,
     filename: String,
+    header: FileHeader,
+    // where the data starts, 0 until a file without header is migrated
+    data_offset: u64,
 }
 
 impl FileHandlerImpl {
+    // creates the file with a header, or checks the header of an existing file and migrates it if it is older
     pub fn new(filename: &str) -> Result<Self> {
         let file = OpenOptions::new()
-            .write(true)
             .read(true)
             .append(true)
             .create(true)
             .open(filename)?;
 
-        Ok(Self {
+        let mut file_handler = Self {
             writer: BufWriter::new(file),
             filename: filename.to_string(),
-        })
+            header: FileHeader::new(),
+            data_offset: HEADER_SIZE,
+        };
+        let size = fs::metadata(filename)?.len();
+        if size == 0 {
+            file_handler.write_header()?;
+            return Ok(file_handler);
+        }
+
+        let mut bytes = vec![0; size.min(HEADER_SIZE) as usize];
+        File::open(filename)?.read_exact(&mut bytes)?;
+        match FileHeader::from_bytes(&bytes)? {
+            Some(header) => file_handler.header = header,
+            // written before files had a header
+            None => {
+                file_handler.header = FileHeader { version: 0, ..FileHeader::new() };
+                file_handler.data_offset = 0;
+            }
+        }
+        migration::migrate(&mut file_handler)?;
+
+        Ok(file_handler)
+    }
+
+    pub(crate) fn file_name(&self) -> &str {
+        &self.filename
+    }
+
+    // where the data starts in the file
+    pub(crate) fn data_offset(&self) -> u64 {
+        self.data_offset
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.filename)?;
+        file.write_all(&self.header.to_bytes()?)?;
+        file.sync_data()?;
+        Ok(())
     }
 }
 
 impl FileHandler for FileHandlerImpl {
     fn append(&mut self, data: &[u8]) -> Result<u64> {
-        let offset = self.writer.stream_position()?;
+        // the file might have been extended through `update`, so always ask for the real end
+        let offset = self.writer.seek(SeekFrom::End(0))?;
         self.writer.write_all(data)?;
         self.writer.flush()?;
-        Ok(offset)
+        Ok(offset - self.data_offset)
     }
 
     fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
         let mut file = OpenOptions::new()
-            .write(true)
             .read(true)
-            .append(true)
-            .create(true)
             .open(&self.filename)?;
 
-        file.seek(SeekFrom::Start(offset))?;
+        file.seek(SeekFrom::Start(self.data_offset + offset))?;
         let mut buf = vec![0; size as usize];
         file.read_exact(&mut buf)?;
         Ok(buf)
@@ -56,14 +139,11 @@ impl FileHandler for FileHandlerImpl {
 
     fn read_all(&mut self) -> Result<Vec<u8>> {
         let mut f = OpenOptions::new()
-            .write(true)
             .read(true)
-            .append(true)
-            .create(true)
-            .open(&self.filename)
-            .unwrap();
+            .open(&self.filename)?;
         let metadata = fs::metadata(&self.filename)?;
-        let mut buffer = vec![0; metadata.len() as usize];
+        f.seek(SeekFrom::Start(self.data_offset))?;
+        let mut buffer = vec![0; (metadata.len() - self.data_offset) as usize];
         f.read_exact(&mut buffer).expect("buffer overflow");
 
         Ok(buffer)
@@ -74,18 +154,96 @@ impl FileHandler for FileHandlerImpl {
             .write(true)
             .read(true)
             .open(&self.filename)?;
-        file.seek(SeekFrom::Start(offset))?;
+        file.seek(SeekFrom::Start(self.data_offset + offset))?;
         file.write_all(data)?;
         file.flush()?;
 
         Ok(())
     }
+
+    fn size(&mut self) -> Result<u64> {
+        self.writer.flush()?;
+        Ok(fs::metadata(&self.filename)?.len() - self.data_offset)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().set_len(self.data_offset + size)?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn rewrite(&mut self, fill: &mut dyn FnMut(&mut dyn FileHandler, &mut dyn FileHandler) -> Result<()>) -> Result<()> {
+        self.writer.flush()?;
+
+        let replacement_name = format!("{}.rewrite", self.filename);
+        // left over by a rewrite that crashed before the rename
+        if Path::new(&replacement_name).exists() {
+            fs::remove_file(&replacement_name)?;
+        }
+
+        let mut replacement = FileHandlerImpl::new(&replacement_name)?;
+        if let Err(e) = replacement.set_header(self.header).and_then(|_| fill(self, &mut replacement)).and_then(|_| replacement.sync()) {
+            drop(replacement);
+            fs::remove_file(&replacement_name)?;
+            return Err(e);
+        }
+        drop(replacement);
+
+        fs::rename(&replacement_name, &self.filename)?;
+        // the rename is only durable once the directory entry is synced
+        let directory = match Path::new(&self.filename).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => Path::new(".").to_path_buf(),
+        };
+        File::open(directory)?.sync_all()?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.filename)?;
+        self.writer = BufWriter::new(file);
+        self.data_offset = HEADER_SIZE;
+
+        Ok(())
+    }
+
+    fn header(&self) -> FileHeader {
+        self.header
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.header = header;
+        // a file without header gets it when it is migrated
+        if self.data_offset == 0 {
+            return Ok(());
+        }
+        self.write_header()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn lock_fails_while_it_is_held() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("lock_fails_while_it_is_held.db").to_str().unwrap().to_string();
+
+        let held = lock(&file_name)?;
+        assert!(lock(&file_name).is_err());
+        drop(held);
+        lock(&file_name)?;
+
+        Ok(())
+    }
+
     #[test]
     fn append_writes_data_to_file_and_returns_offset() -> Result<()> {
         let dir = tempfile::tempdir()?;
@@ -153,4 +311,117 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn append_after_update_past_end_returns_end_offset() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("append_after_update_past_end_returns_end_offset.txt");
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+
+        let data = b"Hello, world!";
+        file_handler.append(data)?;
+        file_handler.update(data.len() as u64, data)?;
+
+        let offset = file_handler.append(data)?;
+
+        assert_eq!(offset, 2 * data.len() as u64);
+        assert_eq!(file_handler.size()?, 3 * data.len() as u64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_shrinks_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("truncate_shrinks_file.txt");
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+
+        file_handler.append(b"Hello, world!")?;
+        file_handler.sync()?;
+        file_handler.truncate(5)?;
+
+        assert_eq!(file_handler.size()?, 5);
+        assert_eq!(file_handler.read_all()?, b"Hello");
+
+        let offset = file_handler.append(b"!")?;
+        assert_eq!(offset, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_replaces_file_with_filled_copy() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("rewrite_replaces_file_with_filled_copy.txt");
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+        file_handler.append(b"Hello, world!")?;
+
+        file_handler.rewrite(&mut |old, replacement| {
+            let data = old.read(7, 5)?;
+            replacement.append(&data)?;
+            Ok(())
+        })?;
+
+        assert_eq!(file_handler.read_all()?, b"world");
+        file_handler.append(b"!")?;
+        assert_eq!(file_handler.read_all()?, b"world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_keeps_file_if_fill_fails() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("rewrite_keeps_file_if_fill_fails.txt");
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+        file_handler.append(b"Hello, world!")?;
+
+        let result = file_handler.rewrite(&mut |_, replacement| {
+            replacement.append(b"Hello")?;
+            anyhow::bail!("fill failed")
+        });
+
+        assert!(result.is_err());
+        assert_eq!(file_handler.read_all()?, b"Hello, world!");
+        assert!(!Path::new(&format!("{}.rewrite", file_path.to_str().unwrap())).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_writes_header_and_reopen_reads_it() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("new_writes_header_and_reopen_reads_it.db");
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+        let header = FileHeader { codec: Some(1), index_engine: Some(2), ..FileHeader::new() };
+        file_handler.append(b"Hello, world!")?;
+        file_handler.set_header(header)?;
+        drop(file_handler);
+
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+
+        assert_eq!(file_handler.header(), header);
+        assert_eq!(file_handler.read_all()?, b"Hello, world!");
+        assert_eq!(fs::metadata(&file_path)?.len(), HEADER_SIZE + 13);
+
+        file_handler.rewrite(&mut |_, replacement| {
+            replacement.append(b"world")?;
+            Ok(())
+        })?;
+        assert_eq!(FileHandlerImpl::new(file_path.to_str().unwrap())?.header(), header);
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_rejects_file_of_newer_version() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("new_rejects_file_of_newer_version.db");
+        let header = FileHeader { version: crate::file_header::FORMAT_VERSION + 1, ..FileHeader::new() };
+        fs::write(&file_path, header.to_bytes()?)?;
+
+        assert!(FileHandlerImpl::new(file_path.to_str().unwrap()).is_err());
+
+        Ok(())
+    }
 }
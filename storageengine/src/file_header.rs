@@ -0,0 +1,99 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+// the first bytes of every file written by `FileHandlerImpl`
+pub const MAGIC: &[u8; 8] = b"PATRICKD";
+// the version of the layout written by this build, files of older versions are migrated when they are opened
+pub const FORMAT_VERSION: u32 = 1;
+// the header takes the same room in every file, so fields can be added without moving the data
+pub const HEADER_SIZE: u64 = 64;
+const CHECKSUM_SIZE: usize = 4;
+
+// describes a file. the storage engine reads the version, the compression and the encryption, the index engine
+// records its codec and itself. None means it wasn't recorded yet. the header itself is never encrypted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileHeader {
+    pub version: u32,
+    pub codec: Option<u8>,
+    pub index_engine: Option<u8>,
+    // how the rows are compressed, see `compression::Compression`
+    pub compression: Option<u8>,
+    // the cipher the file is encrypted with, see `encrypted_file_handler::Encryption`
+    pub encryption: Option<u8>,
+    // set if an encrypted file keeps every append in a sealed record instead of in blocks, see
+    // `encrypted_file_handler::EncryptedLogFileHandler`
+    pub sealed_records: Option<bool>,
+    // how the server encodes the keys of the documents, None until it records the encoding it migrated the keys to
+    pub key_encoding: Option<u8>,
+}
+
+impl FileHeader {
+    pub fn new() -> Self {
+        Self { version: FORMAT_VERSION, ..Default::default() }
+    }
+
+    // magic, checksum and the fields, zero padded to `HEADER_SIZE`
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let fields = bincode::serialize(self)?;
+        let start = MAGIC.len() + CHECKSUM_SIZE;
+        if start + fields.len() > HEADER_SIZE as usize {
+            bail!("file header doesn't fit into {} bytes", HEADER_SIZE);
+        }
+
+        let mut bytes = vec![0; HEADER_SIZE as usize];
+        bytes[..MAGIC.len()].copy_from_slice(MAGIC);
+        bytes[start..start + fields.len()].copy_from_slice(&fields);
+        let checksum = crc32c::crc32c(&bytes[start..]);
+        bytes[MAGIC.len()..start].copy_from_slice(&checksum.to_le_bytes());
+        Ok(bytes)
+    }
+
+    // None if the bytes don't start with the magic, files written before the header have none
+    pub fn from_bytes(bytes: &[u8]) -> Result<Option<Self>> {
+        if bytes.len() < HEADER_SIZE as usize || &bytes[..MAGIC.len()] != MAGIC {
+            return Ok(None);
+        }
+
+        let start = MAGIC.len() + CHECKSUM_SIZE;
+        let checksum = u32::from_le_bytes(bytes[MAGIC.len()..start].try_into()?);
+        if crc32c::crc32c(&bytes[start..HEADER_SIZE as usize]) != checksum {
+            bail!("file header is corrupted");
+        }
+        // fields added later are read from the zero padding of older headers, so they start as None
+        let header: FileHeader = bincode::deserialize(&bytes[start..HEADER_SIZE as usize])?;
+        if header.version == 0 || header.version > FORMAT_VERSION {
+            bail!("file has format version {}, this build reads up to version {}", header.version, FORMAT_VERSION);
+        }
+        Ok(Some(header))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips_through_bytes() -> Result<()> {
+        let header = FileHeader { codec: Some(1), index_engine: Some(3), compression: Some(2), ..FileHeader::new() };
+
+        let bytes = header.to_bytes()?;
+
+        assert_eq!(bytes.len(), HEADER_SIZE as usize);
+        assert_eq!(FileHeader::from_bytes(&bytes)?, Some(header));
+        assert_eq!(FileHeader::from_bytes(b"no header here")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn header_rejects_corruption_and_newer_versions() -> Result<()> {
+        let mut bytes = FileHeader::new().to_bytes()?;
+        bytes[HEADER_SIZE as usize - 1] ^= 1;
+        assert!(FileHeader::from_bytes(&bytes).is_err());
+
+        let newer = FileHeader { version: FORMAT_VERSION + 1, ..FileHeader::new() };
+        assert!(FileHeader::from_bytes(&newer.to_bytes()?).is_err());
+
+        Ok(())
+    }
+}
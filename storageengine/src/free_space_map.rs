@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+
+// pages with less free space than this are considered full and are not tracked
+const MIN_TRACKED_FREE_SPACE: usize = 32;
+
+// remembers how many bytes are left in the pages seen so far, so inserts can fill up
+// existing pages before new ones are allocated
+#[derive(Default)]
+pub struct FreeSpaceMap {
+    free_space: BTreeMap<u64, usize>,
+}
+
+impl FreeSpaceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, page_id: u64, free_space: usize) {
+        if free_space < MIN_TRACKED_FREE_SPACE {
+            self.free_space.remove(&page_id);
+        } else {
+            self.free_space.insert(page_id, free_space);
+        }
+    }
+
+    // first page with room for `size` more bytes
+    pub fn find(&self, size: usize) -> Option<u64> {
+        self.free_space.iter()
+            .find(|(_, free_space)| **free_space >= size)
+            .map(|(page_id, _)| *page_id)
+    }
+
+    pub fn clear(&mut self) {
+        self.free_space.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_returns_first_page_with_enough_space() {
+        let mut free_space_map = FreeSpaceMap::new();
+        free_space_map.update(0, 100);
+        free_space_map.update(1, 1000);
+        free_space_map.update(2, 2000);
+
+        assert_eq!(free_space_map.find(50), Some(0));
+        assert_eq!(free_space_map.find(500), Some(1));
+        assert_eq!(free_space_map.find(1500), Some(2));
+        assert_eq!(free_space_map.find(3000), None);
+    }
+
+    #[test]
+    fn update_forgets_full_pages() {
+        let mut free_space_map = FreeSpaceMap::new();
+        free_space_map.update(0, 100);
+        free_space_map.update(0, 10);
+
+        assert_eq!(free_space_map.find(1), None);
+    }
+}
@@ -1,2 +1,17 @@
+pub mod backup;
+pub mod buffer_pool;
+pub mod compression;
+pub mod encrypted_file_handler;
 pub mod file_handler;
+pub mod file_header;
+pub mod free_space_map;
+pub mod memory_file_handler;
+pub mod migration;
+pub mod mmap_file_handler;
 pub mod operations;
+pub mod page;
+pub mod transaction;
+pub mod wal;
+
+#[cfg(test)]
+mod test_support;
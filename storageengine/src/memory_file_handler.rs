@@ -0,0 +1,167 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::file_handler::{FileHandler, FileHandlerImpl};
+use crate::file_header::FileHeader;
+
+// keeps the file in memory, for tests and databases that don't have to outlive the process
+pub struct MemoryFileHandler {
+    data: Vec<u8>,
+    header: FileHeader,
+}
+
+impl MemoryFileHandler {
+    pub fn new() -> Self {
+        Self { data: Vec::new(), header: FileHeader::new() }
+    }
+
+    // loads a file written by `FileHandlerImpl` or `snapshot`
+    pub fn load(file_name: &str) -> Result<Self> {
+        let mut file_handler = FileHandlerImpl::new(file_name)?;
+        Ok(Self { data: file_handler.read_all()?, header: file_handler.header() })
+    }
+
+    // writes the data to `file_name` the way `FileHandlerImpl` does, an older file is replaced atomically
+    pub fn snapshot(&self, file_name: &str) -> Result<()> {
+        let tmp_file_name = format!("{}.snapshot", file_name);
+        if Path::new(&tmp_file_name).exists() {
+            fs::remove_file(&tmp_file_name)?;
+        }
+
+        let mut file_handler = FileHandlerImpl::new(&tmp_file_name)?;
+        file_handler.set_header(self.header)?;
+        file_handler.append(&self.data)?;
+        file_handler.sync()?;
+        drop(file_handler);
+        fs::rename(&tmp_file_name, file_name)?;
+        Ok(())
+    }
+}
+
+impl Default for MemoryFileHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileHandler for MemoryFileHandler {
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        let offset = self.data.len() as u64;
+        self.data.extend_from_slice(data);
+        Ok(offset)
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        match self.data.get(offset as usize..(offset + size) as usize) {
+            Some(data) => Ok(data.to_vec()),
+            None => bail!("can't read {} bytes at offset {} of {} bytes", size, offset, self.data.len()),
+        }
+    }
+
+    fn read_all(&mut self) -> Result<Vec<u8>> {
+        Ok(self.data.clone())
+    }
+
+    // writing past the end fills the gap with zeros, like a file does
+    fn update(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let end = offset as usize + data.len();
+        if end > self.data.len() {
+            self.data.resize(end, 0);
+        }
+        self.data[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.data.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn rewrite(&mut self, fill: &mut dyn FnMut(&mut dyn FileHandler, &mut dyn FileHandler) -> Result<()>) -> Result<()> {
+        let mut replacement = MemoryFileHandler { data: Vec::new(), header: self.header };
+        fill(self, &mut replacement)?;
+        self.data = replacement.data;
+        Ok(())
+    }
+
+    fn header(&self) -> FileHeader {
+        self.header
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.header = header;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::operations::DbOperations;
+    use crate::test_support::memory_db_operations;
+
+    use super::*;
+
+    #[test]
+    fn memory_file_handler_behaves_like_a_file() -> Result<()> {
+        let mut file_handler = MemoryFileHandler::new();
+
+        assert_eq!(file_handler.append(b"Hello, world!")?, 0);
+        file_handler.update(7, b"Rust!!")?;
+        file_handler.update(15, b"?")?;
+        assert_eq!(file_handler.read(0, 13)?, b"Hello, Rust!!");
+        assert_eq!(file_handler.size()?, 16);
+        assert!(file_handler.read(10, 10).is_err());
+
+        file_handler.truncate(5)?;
+        file_handler.rewrite(&mut |old, replacement| {
+            replacement.append(&old.read(1, 4)?)?;
+            Ok(())
+        })?;
+        assert_eq!(file_handler.read_all()?, b"ello");
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_can_be_opened_as_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("patrick.db").to_str().unwrap().to_string();
+        let mut file_handler = MemoryFileHandler::new();
+        file_handler.append(b"Hello, world!")?;
+        file_handler.set_header(FileHeader { codec: Some(1), ..FileHeader::new() })?;
+
+        file_handler.snapshot(&file_name)?;
+
+        let mut on_disk = FileHandlerImpl::new(&file_name)?;
+        assert_eq!(on_disk.read_all()?, b"Hello, world!");
+        assert_eq!(on_disk.header().codec, Some(1));
+        assert_eq!(MemoryFileHandler::load(&file_name)?.read_all()?, b"Hello, world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn db_operations_run_in_memory() -> Result<()> {
+        let mut db_operations = memory_db_operations()?;
+
+        let transaction_id = db_operations.begin()?;
+        let offset_size = db_operations.insert(vec![1, 2, 3], transaction_id)?;
+        let transaction_id = db_operations.begin()?;
+        let offset_size = db_operations.update_with_offset(&offset_size, vec![4, 5], transaction_id)?;
+        let remapping = db_operations.vacuum()?;
+
+        assert_eq!(db_operations.read_with_offset(&remapping[&offset_size.offset])?.data, vec![4, 5]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result};
+
+use crate::file_handler::{self, FileHandler, FileHandlerImpl};
+use crate::file_header::FORMAT_VERSION;
+
+// upgrades a file of version `from` to the next version in place
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    pub migrate: fn(&mut FileHandlerImpl) -> Result<()>,
+}
+
+// one migration for every version before `FORMAT_VERSION`, in order
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { from: 0, description: "write the file header in front of the data", migrate: add_header },
+];
+
+// runs the migrations a file of an older version needs, every one of them leaves the file at the next version
+pub fn migrate(file_handler: &mut FileHandlerImpl) -> Result<()> {
+    while file_handler.header().version < FORMAT_VERSION {
+        let version = file_handler.header().version;
+        let migration = MIGRATIONS.iter()
+            .find(|migration| migration.from == version)
+            .ok_or_else(|| anyhow!("no migration for format version {}", version))?;
+        (migration.migrate)(file_handler)?;
+    }
+    Ok(())
+}
+
+// files of version 0 start with the data, the rewrite copies it behind a header
+fn add_header(file_handler: &mut FileHandlerImpl) -> Result<()> {
+    let mut header = file_handler.header();
+    header.version = 1;
+    file_handler.set_header(header)?;
+    file_handler.rewrite(&mut |old, replacement| file_handler::copy_data(old, replacement))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::file_header::{HEADER_SIZE, MAGIC};
+
+    use super::*;
+
+    #[test]
+    fn migrations_cover_every_older_version() {
+        for version in 0..FORMAT_VERSION {
+            assert_eq!(MIGRATIONS.iter().filter(|migration| migration.from == version).count(), 1);
+        }
+    }
+
+    #[test]
+    fn opening_file_without_header_adds_it() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("opening_file_without_header_adds_it.db");
+        fs::write(&file_path, b"Hello, world!")?;
+
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+
+        assert_eq!(file_handler.header().version, FORMAT_VERSION);
+        assert_eq!(file_handler.read_all()?, b"Hello, world!");
+        assert_eq!(file_handler.append(b"!")?, 13);
+        let data = fs::read(&file_path)?;
+        assert_eq!(&data[..MAGIC.len()], MAGIC);
+        assert_eq!(&data[HEADER_SIZE as usize..], b"Hello, world!!");
+
+        Ok(())
+    }
+}
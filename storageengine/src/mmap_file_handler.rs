@@ -0,0 +1,154 @@
+use std::fs::File;
+
+use anyhow::{bail, Result};
+use memmap2::Mmap;
+
+use crate::file_handler::{FileHandler, FileHandlerImpl};
+use crate::file_header::FileHeader;
+
+// serves reads from a memory map of the file instead of opening it for every read. writes still go through
+// `FileHandlerImpl`, so they are as durable as before, and the map is renewed once a read goes past its end
+pub struct MmapFileHandler {
+    file_handler: FileHandlerImpl,
+    map: Mmap,
+}
+
+impl MmapFileHandler {
+    pub fn new(filename: &str) -> Result<Self> {
+        let file_handler = FileHandlerImpl::new(filename)?;
+        let map = Self::map(&file_handler)?;
+        Ok(Self { file_handler, map })
+    }
+
+    fn map(file_handler: &FileHandlerImpl) -> Result<Mmap> {
+        let file = File::open(file_handler.file_name())?;
+        // the map is only read, and it is renewed before a truncate or rewrite shrinks the file under it
+        Ok(unsafe { Mmap::map(&file)? })
+    }
+
+    fn remap(&mut self) -> Result<()> {
+        self.map = Self::map(&self.file_handler)?;
+        Ok(())
+    }
+
+    // the data without copying it, the slice lives until the next write
+    pub fn read_slice(&mut self, offset: u64, size: u64) -> Result<&[u8]> {
+        let start = self.file_handler.data_offset() + offset;
+        let end = start + size;
+        if end > self.map.len() as u64 {
+            // the file grew since it was mapped
+            self.remap()?;
+        }
+        if end > self.map.len() as u64 {
+            bail!("can't read {} bytes at offset {} of {} bytes", size, offset, self.map.len() as u64 - self.file_handler.data_offset());
+        }
+        Ok(&self.map[start as usize..end as usize])
+    }
+}
+
+impl FileHandler for MmapFileHandler {
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        self.file_handler.append(data)
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        Ok(self.read_slice(offset, size)?.to_vec())
+    }
+
+    fn read_all(&mut self) -> Result<Vec<u8>> {
+        let size = self.file_handler.size()?;
+        self.read(0, size)
+    }
+
+    fn update(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.file_handler.update(offset, data)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        self.file_handler.size()
+    }
+
+    // reading a mapped page past the end of the file is fatal, so the map shrinks with the file
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.file_handler.truncate(size)?;
+        self.remap()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file_handler.sync()
+    }
+
+    fn rewrite(&mut self, fill: &mut dyn FnMut(&mut dyn FileHandler, &mut dyn FileHandler) -> Result<()>) -> Result<()> {
+        self.file_handler.rewrite(fill)?;
+        self.remap()
+    }
+
+    fn header(&self) -> FileHeader {
+        self.file_handler.header()
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.file_handler.set_header(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::operations::DbOperations;
+    use crate::test_support::open_db_operations_with;
+
+    use super::*;
+
+    #[test]
+    fn reads_see_appends_and_updates() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("reads_see_appends_and_updates.db").to_str().unwrap().to_string();
+        let mut file_handler = MmapFileHandler::new(&file_name)?;
+
+        file_handler.append(b"Hello, world!")?;
+        assert_eq!(file_handler.read_slice(7, 5)?, b"world");
+        file_handler.update(7, b"Rust!")?;
+        assert_eq!(file_handler.read(0, 12)?, b"Hello, Rust!");
+        assert_eq!(file_handler.append(&[1; 10000])?, 13);
+        assert_eq!(file_handler.read(10_000, 13)?, [1; 13]);
+        assert!(file_handler.read(10_000, 100).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn map_follows_truncate_and_rewrite() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("map_follows_truncate_and_rewrite.db").to_str().unwrap().to_string();
+        let mut file_handler = MmapFileHandler::new(&file_name)?;
+        file_handler.append(&[7; 10000])?;
+
+        file_handler.truncate(5)?;
+        assert!(file_handler.read(0, 6).is_err());
+        file_handler.rewrite(&mut |old, replacement| {
+            replacement.append(&old.read(0, 2)?)?;
+            Ok(())
+        })?;
+
+        assert_eq!(file_handler.read_all()?, [7; 2]);
+        assert_eq!(FileHandlerImpl::new(&file_name)?.read_all()?, [7; 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn db_operations_read_through_map() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("db_operations_read_through_map.db").to_str().unwrap().to_string();
+        let open = || open_db_operations_with(Box::new(MmapFileHandler::new(&file_name)?), &file_name, None, 16);
+
+        let mut db_operations = open()?;
+        let transaction_id = db_operations.begin()?;
+        let offset_size = db_operations.insert(vec![1, 2, 3], transaction_id)?;
+        drop(db_operations);
+
+        assert_eq!(open()?.read_with_offset(&offset_size)?.data, vec![1, 2, 3]);
+
+        Ok(())
+    }
+}
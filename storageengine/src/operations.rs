@@ -1,9 +1,32 @@
-use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::backup::{BackupManifest, WalArchive};
+use crate::buffer_pool::BufferPool;
+use crate::compression::Compression;
 use crate::file_handler::FileHandler;
+use crate::file_header::FileHeader;
+use crate::free_space_map::FreeSpaceMap;
+use crate::page::{MAX_TUPLE_SIZE, overflow_span, overflow_tuple, Page, PAGE_SIZE, page_offset, PageKind, row_offset, split_row_offset};
+use crate::transaction::{Snapshot, TransactionManager};
+use crate::wal::{self, Durability, Wal, WalWrite};
 
 pub const NONE_SENTINEL: u64 = u64::MAX;
+// `Header::expires_at` of rows that are kept until they are deleted
+pub const NEVER_EXPIRES: u64 = 0;
+// once the wal grows beyond this size the data file is synced and the wal is truncated
+const WAL_CHECKPOINT_SIZE: u64 = 4 * 1024 * 1024;
+// every stored row starts with the crc32c of its serialized bytes
+const ROW_CHECKSUM_SIZE: usize = 4;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("Corrupted row at offset {offset}")]
+    Corrupted { offset: u64 },
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct Header {
@@ -13,55 +36,428 @@ pub struct Header {
     // stores the ID of the transaction that deleted it (if it has been deleted)
     pub tuple_length: u64,
     // This field indicates the total length of the tuple, including the header and the data
-    pub table_oid: u64,
-    // This is the object identifier of the table to which the tuple belongs.
+    pub expires_at: u64,
+    // milliseconds since the unix epoch after which reads don't see the row, NEVER_EXPIRES for most rows.
+    // this was the never used table oid, so older rows never expire
     pub ctid: u64,
-    // This field is a physical location identifier for the tuple within its table.
+    // offset of the previous version of this row, NONE_SENTINEL for the first version
     pub cmin: u64,
     // This field stores the ID of the transaction that created this version of the row.
     pub cmax: u64, // This field stores the ID of the transaction that deleted it (if it has been deleted).
 }
 
+impl Header {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != NEVER_EXPIRES && self.expires_at <= now
+    }
+}
+
+// the time `Header::expires_at` is compared with
+pub fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_millis() as u64)
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct Row {
     pub header: Header,
     pub data: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+// `offset` addresses a row by page and slot, see `page::row_offset`
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct OffsetSize {
     pub offset: u64,
     pub size: u64,
 }
 
+#[derive(Default, PartialEq, Debug)]
+pub struct RowBatch {
+    pub rows: Vec<(OffsetSize, Row)>,
+    // page to continue the scan with, `None` once the end of the file is reached
+    pub next_page_id: Option<u64>,
+}
+
+// one change of a `DbOperations::write_batch`
+#[derive(PartialEq, Debug)]
+pub enum RowWrite {
+    Insert { data: Vec<u8> },
+    // continues the version chain of a deleted row
+    Reinsert { deleted_offset_size: OffsetSize, data: Vec<u8> },
+    Update { old_offset_size: OffsetSize, data: Vec<u8> },
+    Delete { offset_size: OffsetSize },
+}
+
 pub trait DbOperations: Send + Sync {
     fn insert(&mut self, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
     fn read_with_offset(&mut self, offset_size: &OffsetSize) -> Result<Row>;
-    fn read_all(&mut self) -> Result<Vec<Row>>;
+    // returns the rows of one page, start with page 0 and follow `next_page_id`
+    fn read_page(&mut self, page_id: u64) -> Result<RowBatch>;
     fn update_with_offset(&mut self, old_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
     fn delete_with_offset(&mut self, offset_size: &OffsetSize, transaction_id: u64) -> Result<()>;
+    // inserts a row that continues the version chain of a deleted row
+    fn reinsert_with_offset(&mut self, deleted_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize>;
+    // follows the version chain starting at `offset_size` to the version visible in the snapshot
+    fn read_visible(&mut self, offset_size: &OffsetSize, snapshot: &Snapshot) -> Result<Option<Row>>;
+    // applies all writes under one transaction id, either all or none of them survive a crash;
+    // returns the row every write left behind, for deletes that is the deleted row
+    // the rows it inserts expire at `expires_at`, see `Header::expires_at`
+    fn write_batch(&mut self, writes: Vec<RowWrite>, transaction_id: u64, expires_at: u64) -> Result<Vec<OffsetSize>>;
+    fn begin(&mut self) -> Result<u64>;
+    // see `TransactionManager::pin`
+    fn pin_transaction_id(&mut self, transaction_id: Option<u64>) -> Result<()>;
+    fn snapshot(&mut self) -> Snapshot;
+    fn release_snapshot(&mut self, snapshot: &Snapshot);
+    // drops row versions no snapshot can see anymore and returns the new location of every kept row keyed by its old offset
+    fn vacuum(&mut self) -> Result<HashMap<u64, OffsetSize>>;
+    // makes every write done so far durable, writes are only synced on their own with `Durability::PerWrite`
+    fn sync(&mut self) -> Result<()>;
+    // copies the data file into the empty `target` in between two writes. the wal is archived into `archive` from
+    // then on unless it is archived already, the records archived from the checkpoint on roll the copy forward,
+    // see `backup::restore`
+    fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest>;
+    // the header of the data file
+    fn header(&self) -> FileHeader;
+    fn set_header(&mut self, header: FileHeader) -> Result<()>;
 }
 
-pub struct DbOperationsImpl {
-    file_handler: Box<dyn FileHandler>,
+// iterates over all rows page by page, so only one page worth of rows is held in memory
+pub struct RowIterator<'a> {
+    db_operations: &'a mut dyn DbOperations,
+    next_page_id: Option<u64>,
+    rows: std::vec::IntoIter<(OffsetSize, Row)>,
 }
 
-impl DbOperationsImpl {
-    pub fn new(file_handler: Box<dyn FileHandler>) -> Self {
+impl<'a> RowIterator<'a> {
+    pub fn new(db_operations: &'a mut dyn DbOperations) -> Self {
+        Self {
+            db_operations,
+            next_page_id: Some(0),
+            rows: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for RowIterator<'_> {
+    type Item = Result<(OffsetSize, Row)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.rows.next() {
+                return Some(Ok(row));
+            }
+
+            let page_id = self.next_page_id?;
+            match self.db_operations.read_page(page_id) {
+                Ok(batch) => {
+                    self.next_page_id = batch.next_page_id;
+                    self.rows = batch.rows.into_iter();
+                }
+                Err(e) => {
+                    self.next_page_id = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// appends freshly packed pages to a file, used to build the data file while vacuuming.
+// without a file it only works out where each tuple would end up
+struct PageWriter<'a> {
+    file_handler: Option<&'a mut dyn FileHandler>,
+    page_count: u64,
+    page: Page,
+}
+
+impl<'a> PageWriter<'a> {
+    fn new(file_handler: Option<&'a mut dyn FileHandler>) -> Self {
         Self {
             file_handler,
+            page_count: 0,
+            page: Page::new_slotted(),
+        }
+    }
+
+    fn write(&mut self, tuple: &[u8]) -> Result<u64> {
+        if tuple.len() > MAX_TUPLE_SIZE {
+            // the overflow run has to start behind the page that is still being filled
+            self.flush()?;
+            let page_id = self.page_count;
+            for page in Page::new_overflow(tuple) {
+                if let Some(file_handler) = self.file_handler.as_mut() {
+                    file_handler.append(page.as_bytes())?;
+                }
+                self.page_count += 1;
+            }
+            return Ok(row_offset(page_id, 0));
+        }
+
+        let slot = match self.page.insert(tuple) {
+            Some(slot) => slot,
+            None => {
+                self.flush()?;
+                self.page.insert(tuple)
+                    .ok_or_else(|| anyhow!("empty page has no room for {} bytes", tuple.len()))?
+            }
+        };
+
+        Ok(row_offset(self.page_count, slot))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.page.slot_count() == 0 {
+            return Ok(());
+        }
+
+        let page = std::mem::replace(&mut self.page, Page::new_slotted());
+        if let Some(file_handler) = self.file_handler.as_mut() {
+            file_handler.append(page.as_bytes())?;
         }
+        self.page_count += 1;
+
+        Ok(())
     }
 }
 
-impl DbOperations for DbOperationsImpl {
-    fn insert(&mut self, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize> {
+pub struct DbOperationsImpl {
+    buffer_pool: BufferPool,
+    wal: Wal,
+    free_space_map: FreeSpaceMap,
+    // pages changed by the running operation, they are logged and written together
+    staged_pages: BTreeMap<u64, Page>,
+    page_count: u64,
+    transaction_manager: TransactionManager,
+    // the data of the rows is compressed with it, the rows are decompressed when they are read
+    compression: Compression,
+    durability: Durability,
+}
+
+impl DbOperationsImpl {
+    pub fn new(mut file_handler: Box<dyn FileHandler>, mut wal: Wal, transaction_manager: TransactionManager, buffer_pool_pages: usize) -> Result<Self> {
+        Self::recover(file_handler.as_mut(), &mut wal)?;
+        let compression = match file_handler.header().compression {
+            Some(id) => Compression::from_id(id).ok_or_else(|| anyhow!("data file names an unknown compression {}", id))?,
+            None => Compression::None,
+        };
+
+        let buffer_pool = BufferPool::new(file_handler, buffer_pool_pages)?;
+        let page_count = buffer_pool.page_count();
+        let mut db_operations = Self {
+            buffer_pool,
+            wal,
+            free_space_map: FreeSpaceMap::new(),
+            staged_pages: BTreeMap::new(),
+            page_count,
+            transaction_manager,
+            compression,
+            durability: Durability::default(),
+        };
+
+        // only the last page is read up front, other pages show up in the free space map once they are read
+        if page_count > 0 {
+            db_operations.page(page_count - 1)?;
+        }
+
+        // data written before transaction ids were persisted, continue behind the newest id in it
+        if db_operations.transaction_manager.next_transaction_id() == 0 && page_count > 0 {
+            let mut newest_transaction_id = None;
+            for row in RowIterator::new(&mut db_operations) {
+                let (_, row) = row?;
+                for transaction_id in [row.header.xmin, row.header.xmax] {
+                    if transaction_id != NONE_SENTINEL {
+                        newest_transaction_id = newest_transaction_id.max(Some(transaction_id));
+                    }
+                }
+            }
+            if let Some(transaction_id) = newest_transaction_id {
+                db_operations.transaction_manager.advance_past(transaction_id)?;
+            }
+        }
+
+        Ok(db_operations)
+    }
+
+    // replays every complete record of the wal, records that did not make it fully into the wal
+    // were never applied to the data file and are therefore rolled back by dropping them
+    fn recover(file_handler: &mut dyn FileHandler, wal: &mut Wal) -> Result<()> {
+        for record in wal.records()? {
+            wal::apply(file_handler, &record)?;
+        }
+
+        file_handler.sync()?;
+        wal.truncate()
+    }
+
+    // with anything but `Durability::PerWrite` the wal is not synced per write and the changed pages are kept
+    // in the buffer pool until `sync` or the next checkpoint, so the data file never gets ahead of the wal
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    fn checkpoint(&mut self) -> Result<()> {
+        self.buffer_pool.sync()?;
+        self.wal.truncate()
+    }
+
+    // runs `operation` on staged pages and logs all pages it changed as one wal record
+    // before any of them touches the data file
+    fn atomically<T>(&mut self, transaction_id: u64, operation: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let result = operation(self).and_then(|value| {
+            self.flush_staged(transaction_id)?;
+            Ok(value)
+        });
+        if result.is_err() {
+            self.discard_staged();
+        }
+
+        result
+    }
+
+    fn flush_staged(&mut self, transaction_id: u64) -> Result<()> {
+        let writes = self.staged_writes();
+        let record = self.wal.append(writes, Some(transaction_id))?;
+        if self.durability == Durability::PerWrite {
+            self.wal.sync()?;
+            self.buffer_pool.apply(&record)?;
+        } else {
+            self.buffer_pool.apply_deferred(&record)?;
+        }
+
+        if self.wal.size()? >= WAL_CHECKPOINT_SIZE {
+            self.checkpoint()?;
+        }
+
+        Ok(())
+    }
+
+    fn staged_writes(&mut self) -> Vec<WalWrite> {
+        std::mem::take(&mut self.staged_pages)
+            .into_iter()
+            .map(|(page_id, page)| WalWrite {
+                offset: page_offset(page_id),
+                data: page.into_bytes(),
+            })
+            .collect()
+    }
+
+    fn discard_staged(&mut self) {
+        for page_id in std::mem::take(&mut self.staged_pages).keys() {
+            // forget what the staged copy claimed, the page is registered again once it is read
+            self.free_space_map.update(*page_id, 0);
+        }
+        self.page_count = self.buffer_pool.page_count();
+    }
+
+    // returns the staged copy if the running operation already changed the page
+    fn page(&mut self, page_id: u64) -> Result<Page> {
+        if let Some(page) = self.staged_pages.get(&page_id) {
+            return Ok(page.clone());
+        }
+
+        let page = self.buffer_pool.fetch(page_id)?.clone();
+        self.free_space_map.update(page_id, page.free_space());
+        Ok(page)
+    }
+
+    fn stage_page(&mut self, page_id: u64, page: Page) {
+        self.free_space_map.update(page_id, page.free_space());
+        self.page_count = self.page_count.max(page_id + 1);
+        self.staged_pages.insert(page_id, page);
+    }
+
+    fn read_tuple(&mut self, offset: u64) -> Result<Vec<u8>> {
+        let (page_id, slot) = split_row_offset(offset);
+        let page = self.page(page_id)?;
+        match page.kind()? {
+            PageKind::Slotted => page.tuple(slot)
+                .map(|tuple| tuple.to_vec())
+                .ok_or_else(|| anyhow!("no row at offset {}", offset)),
+            PageKind::Overflow => {
+                let span = overflow_span(page.overflow_length());
+                let mut pages = vec![page];
+                for continuation_page_id in page_id + 1..page_id + span {
+                    pages.push(self.page(continuation_page_id)?);
+                }
+                overflow_tuple(&pages)
+            }
+            PageKind::OverflowContinuation => bail!("no row at offset {}", offset),
+        }
+    }
+
+    fn stage_tuple(&mut self, tuple: &[u8]) -> Result<u64> {
+        if tuple.len() > MAX_TUPLE_SIZE {
+            let page_id = self.page_count;
+            for (i, page) in Page::new_overflow(tuple).into_iter().enumerate() {
+                self.stage_page(page_id + i as u64, page);
+            }
+            return Ok(row_offset(page_id, 0));
+        }
+
+        let page_id = self.free_space_map.find(tuple.len()).unwrap_or(self.page_count);
+        let mut page = if page_id == self.page_count {
+            Page::new_slotted()
+        } else {
+            self.page(page_id)?
+        };
+        let slot = page.insert(tuple)
+            .ok_or_else(|| anyhow!("page {} has no room for {} bytes", page_id, tuple.len()))?;
+        self.stage_page(page_id, page);
+
+        Ok(row_offset(page_id, slot))
+    }
+
+    // overwrites a tuple with one of the same length
+    fn stage_tuple_update(&mut self, offset: u64, tuple: &[u8]) -> Result<()> {
+        let (page_id, slot) = split_row_offset(offset);
+        let mut page = self.page(page_id)?;
+        match page.kind()? {
+            PageKind::Slotted => {
+                page.update(slot, tuple)?;
+                self.stage_page(page_id, page);
+            }
+            PageKind::Overflow => {
+                if page.overflow_length() != tuple.len() {
+                    bail!("row at offset {} has {} bytes, got {}", offset, page.overflow_length(), tuple.len());
+                }
+                for (i, page) in Page::new_overflow(tuple).into_iter().enumerate() {
+                    self.stage_page(page_id + i as u64, page);
+                }
+            }
+            PageKind::OverflowContinuation => bail!("no row at offset {}", offset),
+        }
+
+        Ok(())
+    }
+
+    fn stage_insert(&mut self, data: Vec<u8>, transaction_id: u64, previous_offset: u64, expires_at: u64) -> Result<OffsetSize> {
+        let tuple = Self::serialize_row(self.compression.compress(data)?, transaction_id, previous_offset, expires_at)?;
+        let offset = self.stage_tuple(&tuple)?;
+
+        Ok(OffsetSize {
+            offset,
+            size: tuple.len() as u64,
+        })
+    }
+
+    fn stage_delete(&mut self, offset_size: &OffsetSize, transaction_id: u64) -> Result<()> {
+        // the data is written back as it is, so it isn't decompressed
+        let tuple = self.read_tuple(offset_size.offset)?;
+        let mut row = Self::decode_row(offset_size.offset, &tuple)?;
+        if row.header.xmax != NONE_SENTINEL {
+            bail!("row at offset {} was already deleted by transaction {}", offset_size.offset, row.header.xmax);
+        }
+        row.header.xmax = transaction_id;
+        row.header.cmax = transaction_id;
+        self.stage_tuple_update(offset_size.offset, &Self::encode_row(&row)?)
+    }
+
+    fn serialize_row(data: Vec<u8>, transaction_id: u64, previous_offset: u64, expires_at: u64) -> Result<Vec<u8>> {
         let mut header = Header {
             xmin: transaction_id,
             xmax: NONE_SENTINEL,
             tuple_length: 0, // This will be updated later
-            table_oid: 0,
-            ctid: 0,
+            expires_at,
+            ctid: previous_offset,
             cmin: transaction_id,
             cmax: NONE_SENTINEL,
         };
@@ -78,49 +474,283 @@ impl DbOperations for DbOperationsImpl {
             data,
         };
 
-        let buf = bincode::serialize(&row)?;
-        let offset = self.file_handler.append(&buf)?;
+        Self::encode_row(&row)
+    }
 
-        Ok(OffsetSize {
-            offset,
-            size: buf.len() as u64,
-        })
+    // copies the rows still visible to some snapshot into `replacement` in their current order.
+    // the first pass only works out the new offsets, so the second one can repoint the version chains
+    fn copy_live_rows(file_handler: &mut dyn FileHandler, replacement: &mut dyn FileHandler, horizon: u64) -> Result<HashMap<u64, OffsetSize>> {
+        let mut remapping = HashMap::new();
+        let mut page_writer = PageWriter::new(None);
+        Self::for_each_kept_row(file_handler, horizon, |offset, tuple, _| {
+            let new_offset = page_writer.write(tuple)?;
+            remapping.insert(offset, OffsetSize {
+                offset: new_offset,
+                size: tuple.len() as u64,
+            });
+            Ok(())
+        })?;
+
+        let mut page_writer = PageWriter::new(Some(replacement));
+        Self::for_each_kept_row(file_handler, horizon, |_, _, mut row| {
+            if row.header.ctid != NONE_SENTINEL {
+                // the previous version is gone once no snapshot can see it
+                row.header.ctid = remapping.get(&row.header.ctid).map_or(NONE_SENTINEL, |offset_size| offset_size.offset);
+            }
+            page_writer.write(&Self::encode_row(&row)?)?;
+            Ok(())
+        })?;
+        page_writer.flush()?;
+
+        Ok(remapping)
     }
 
-    fn read_with_offset(&mut self, offset_size: &OffsetSize) -> Result<Row> {
-        let buf = self.file_handler.read(offset_size.offset, offset_size.size)?;
-        let row = bincode::deserialize::<Row>(&buf[0..])?;
+    fn for_each_kept_row(file_handler: &mut dyn FileHandler, horizon: u64, mut f: impl FnMut(u64, &[u8], Row) -> Result<()>) -> Result<()> {
+        let page_count = file_handler.size()? / PAGE_SIZE as u64;
+
+        let mut page_id = 0;
+        while page_id < page_count {
+            let page = Page::from_bytes(file_handler.read(page_offset(page_id), PAGE_SIZE as u64)?)?;
+            let mut tuples = Vec::new();
+            match page.kind().map_err(|_| StorageError::Corrupted { offset: row_offset(page_id, 0) })? {
+                PageKind::Slotted => {
+                    for slot in 0..page.slot_count() {
+                        let offset = row_offset(page_id, slot);
+                        let tuple = page.tuple(slot).ok_or(StorageError::Corrupted { offset })?;
+                        tuples.push((offset, tuple.to_vec()));
+                    }
+                    page_id += 1;
+                }
+                PageKind::Overflow => {
+                    let span = overflow_span(page.overflow_length());
+                    let mut pages = vec![page];
+                    for continuation_page_id in page_id + 1..page_id + span {
+                        pages.push(Page::from_bytes(file_handler.read(page_offset(continuation_page_id), PAGE_SIZE as u64)?)?);
+                    }
+                    tuples.push((row_offset(page_id, 0), overflow_tuple(&pages)?));
+                    page_id += span;
+                }
+                PageKind::OverflowContinuation => return Err(StorageError::Corrupted { offset: row_offset(page_id, 0) }.into()),
+            }
+
+            for (offset, tuple) in tuples {
+                let row = Self::decode_row(offset, &tuple)?;
+                // deleted before the oldest snapshot in use, nobody can see it anymore
+                if row.header.xmax != NONE_SENTINEL && row.header.xmax < horizon {
+                    continue;
+                }
+
+                f(offset, &tuple, row)?;
+            }
+        }
 
+        Ok(())
+    }
+
+    fn encode_row(row: &Row) -> Result<Vec<u8>> {
+        let payload = bincode::serialize(row)?;
+        let mut tuple = Vec::with_capacity(ROW_CHECKSUM_SIZE + payload.len());
+        tuple.extend_from_slice(&crc32c::crc32c(&payload).to_le_bytes());
+        tuple.extend_from_slice(&payload);
+
+        Ok(tuple)
+    }
+
+    // a row whose checksum does not match is reported instead of being skipped, so a scan
+    // never ends early on a torn or damaged row
+    fn decode_row(offset: u64, tuple: &[u8]) -> Result<Row> {
+        if tuple.len() < ROW_CHECKSUM_SIZE {
+            return Err(StorageError::Corrupted { offset }.into());
+        }
+
+        let (checksum, payload) = tuple.split_at(ROW_CHECKSUM_SIZE);
+        if crc32c::crc32c(payload).to_le_bytes() != checksum {
+            return Err(StorageError::Corrupted { offset }.into());
+        }
+
+        bincode::deserialize::<Row>(payload).map_err(|_| StorageError::Corrupted { offset }.into())
+    }
+
+    // decodes a row and decompresses its data, for rows that are handed out
+    fn load_row(&self, offset: u64, tuple: &[u8]) -> Result<Row> {
+        let mut row = Self::decode_row(offset, tuple)?;
+        row.data = self.compression.decompress(row.data).map_err(|_| StorageError::Corrupted { offset })?;
         Ok(row)
     }
+}
+
+impl DbOperations for DbOperationsImpl {
+    fn insert(&mut self, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize> {
+        self.atomically(transaction_id, |db_operations| db_operations.stage_insert(data, transaction_id, NONE_SENTINEL, NEVER_EXPIRES))
+    }
+
+    fn read_with_offset(&mut self, offset_size: &OffsetSize) -> Result<Row> {
+        let tuple = self.read_tuple(offset_size.offset)?;
+        self.load_row(offset_size.offset, &tuple)
+    }
 
-    fn read_all(&mut self) -> Result<Vec<Row>> {
-        let buf = self.file_handler.read_all()?;
-        let mut pos = 0;
-        let mut rows: Vec<Row> = Vec::new();
-        while let Ok(row) = bincode::deserialize::<Row>(&buf[pos..]) {
-            pos += bincode::serialized_size(&row)? as usize;
-            rows.push(row);
+    fn read_page(&mut self, page_id: u64) -> Result<RowBatch> {
+        if page_id >= self.page_count {
+            return Ok(RowBatch::default());
         }
 
-        Ok(rows)
+        let page = self.page(page_id)?;
+        let mut rows = Vec::new();
+        let kind = page.kind().map_err(|_| StorageError::Corrupted { offset: row_offset(page_id, 0) })?;
+        let next_page_id = match kind {
+            PageKind::Slotted => {
+                for slot in 0..page.slot_count() {
+                    let offset = row_offset(page_id, slot);
+                    let tuple = page.tuple(slot).ok_or(StorageError::Corrupted { offset })?;
+                    rows.push((OffsetSize { offset, size: tuple.len() as u64 }, self.load_row(offset, tuple)?));
+                }
+                page_id + 1
+            }
+            PageKind::Overflow => {
+                let offset = row_offset(page_id, 0);
+                let tuple = self.read_tuple(offset)?;
+                rows.push((OffsetSize { offset, size: tuple.len() as u64 }, self.load_row(offset, &tuple)?));
+                page_id + overflow_span(page.overflow_length())
+            }
+            PageKind::OverflowContinuation => bail!("page {} is in the middle of an overflow run", page_id),
+        };
+
+        Ok(RowBatch {
+            rows,
+            next_page_id: (next_page_id < self.page_count).then_some(next_page_id),
+        })
     }
 
+    // marking the old version and inserting the new one are logged as one record,
+    // so after a crash either both or none of them are visible
     fn update_with_offset(&mut self, old_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize> {
-        let mut row = self.read_with_offset(old_offset_size)?;
-        row.header.xmax = transaction_id;
-        self.file_handler.update(old_offset_size.offset, &bincode::serialize(&row)?)?;
-        let new_offset_size = self.insert(data, transaction_id)?;
-
-        Ok(new_offset_size)
+        self.atomically(transaction_id, |db_operations| {
+            db_operations.stage_delete(old_offset_size, transaction_id)?;
+            db_operations.stage_insert(data, transaction_id, old_offset_size.offset, NEVER_EXPIRES)
+        })
     }
 
     fn delete_with_offset(&mut self, offset_size: &OffsetSize, transaction_id: u64) -> Result<()> {
-        let mut row = self.read_with_offset(offset_size)?;
-        row.header.xmax = transaction_id;
-        self.file_handler.update(offset_size.offset, &bincode::serialize(&row)?)?;
+        self.atomically(transaction_id, |db_operations| db_operations.stage_delete(offset_size, transaction_id))
+    }
 
-        Ok(())
+    fn reinsert_with_offset(&mut self, deleted_offset_size: &OffsetSize, data: Vec<u8>, transaction_id: u64) -> Result<OffsetSize> {
+        self.atomically(transaction_id, |db_operations| db_operations.stage_insert(data, transaction_id, deleted_offset_size.offset, NEVER_EXPIRES))
+    }
+
+    fn read_visible(&mut self, offset_size: &OffsetSize, snapshot: &Snapshot) -> Result<Option<Row>> {
+        let mut offset = offset_size.offset;
+        loop {
+            let tuple = self.read_tuple(offset)?;
+            let row = Self::decode_row(offset, &tuple)?;
+            if snapshot.is_visible(&row.header) {
+                return self.load_row(offset, &tuple).map(Some);
+            }
+            // older versions were deleted even earlier, so none of them is visible either
+            if row.header.xmax != NONE_SENTINEL && row.header.xmax < snapshot.xmax {
+                return Ok(None);
+            }
+            if row.header.ctid == NONE_SENTINEL {
+                return Ok(None);
+            }
+
+            offset = row.header.ctid;
+        }
+    }
+
+    fn write_batch(&mut self, writes: Vec<RowWrite>, transaction_id: u64, expires_at: u64) -> Result<Vec<OffsetSize>> {
+        self.atomically(transaction_id, |db_operations| {
+            writes.into_iter()
+                .map(|write| match write {
+                    RowWrite::Insert { data } => db_operations.stage_insert(data, transaction_id, NONE_SENTINEL, expires_at),
+                    RowWrite::Reinsert { deleted_offset_size, data } => db_operations.stage_insert(data, transaction_id, deleted_offset_size.offset, expires_at),
+                    RowWrite::Update { old_offset_size, data } => {
+                        db_operations.stage_delete(&old_offset_size, transaction_id)?;
+                        db_operations.stage_insert(data, transaction_id, old_offset_size.offset, expires_at)
+                    }
+                    RowWrite::Delete { offset_size } => {
+                        db_operations.stage_delete(&offset_size, transaction_id)?;
+                        Ok(offset_size)
+                    }
+                })
+                .collect()
+        })
+    }
+
+    fn begin(&mut self) -> Result<u64> {
+        self.transaction_manager.begin()
+    }
+
+    fn pin_transaction_id(&mut self, transaction_id: Option<u64>) -> Result<()> {
+        self.transaction_manager.pin(transaction_id)
+    }
+
+    fn snapshot(&mut self) -> Snapshot {
+        self.transaction_manager.snapshot()
+    }
+
+    fn release_snapshot(&mut self, snapshot: &Snapshot) {
+        self.transaction_manager.release_snapshot(snapshot)
+    }
+
+    fn vacuum(&mut self) -> Result<HashMap<u64, OffsetSize>> {
+        // every logged change has to be in the data file before it is copied, the wal can not
+        // be replayed against the new file since the pages moved
+        self.checkpoint()?;
+
+        // the archived records only fit the old layout, backups have to be taken after the vacuum
+        let next_transaction_id = self.transaction_manager.next_transaction_id();
+        if let Some(archive) = self.wal.archive() {
+            archive.mark_vacuum(next_transaction_id)?;
+        }
+
+        let horizon = self.transaction_manager.horizon();
+        let mut remapping = HashMap::new();
+        self.buffer_pool.rewrite(&mut |file_handler, replacement| {
+            remapping = Self::copy_live_rows(file_handler, replacement, horizon)?;
+            Ok(())
+        })?;
+
+        self.page_count = self.buffer_pool.page_count();
+        self.free_space_map.clear();
+        if self.page_count > 0 {
+            self.page(self.page_count - 1)?;
+        }
+
+        Ok(remapping)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.wal.sync()?;
+        self.buffer_pool.write_back()
+    }
+
+    fn header(&self) -> FileHeader {
+        self.buffer_pool.header()
+    }
+
+    fn set_header(&mut self, header: FileHeader) -> Result<()> {
+        self.buffer_pool.set_header(header)
+    }
+
+    fn backup(&mut self, target: &mut dyn FileHandler, archive: Option<WalArchive>) -> Result<BackupManifest> {
+        if self.wal.archive().is_none() {
+            match archive {
+                Some(archive) => self.wal.set_archive(archive),
+                None => bail!("a backup can't be rolled forward without a wal archive"),
+            }
+        }
+        // the dirty pages of the buffer pool are written before the data file is copied
+        self.checkpoint()?;
+        let header = target.header();
+        target.set_header(FileHeader { encryption: header.encryption, ..self.buffer_pool.header() })?;
+        self.buffer_pool.copy_to(target)?;
+        target.sync()?;
+
+        Ok(BackupManifest {
+            transaction_id: self.transaction_manager.next_transaction_id(),
+            first_segment: self.wal.archive().map_or(0, |archive| archive.next_segment()),
+        })
     }
 }
 
@@ -128,6 +758,8 @@ impl DbOperations for DbOperationsImpl {
 mod tests {
 
     use crate::file_handler::FileHandlerImpl;
+    use crate::test_support::{open_db_operations, open_db_operations_with};
+    use crate::transaction::transaction_file_name;
 
     use super::*;
 
@@ -135,8 +767,7 @@ mod tests {
     fn insert_adds_row_and_returns_offset_size() -> Result<()> {
         let dir = tempfile::tempdir()?;
         let file_path = dir.path().join("insert_adds_row_and_returns_offset_size.txt");
-        let file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
-        let mut db_operations = DbOperationsImpl::new(Box::new(file_handler));
+        let mut db_operations = open_db_operations(file_path.to_str().unwrap())?;
 
         let data = vec![1, 2, 3, 4];
         let transaction_id = 1;
@@ -152,8 +783,8 @@ mod tests {
 
         assert!(new_result.is_ok());
         let new_offset_size = new_result.unwrap();
-        // new offset should be on old offset + old size
-        assert_eq!(new_offset_size.offset, offset_size.size);
+        // new row should be in the next slot of the same page
+        assert_eq!(new_offset_size.offset, row_offset(0, 1));
 
         Ok(())
     }
@@ -162,8 +793,7 @@ mod tests {
     fn read_with_offset_returns_row() -> Result<()> {
         let dir = tempfile::tempdir()?;
         let file_path = dir.path().join("read_with_offset_returns_row.txt");
-        let file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
-        let mut db_operations = DbOperationsImpl::new(Box::new(file_handler));
+        let mut db_operations = open_db_operations(file_path.to_str().unwrap())?;
 
         let data = vec![1, 2, 3, 4];
         let transaction_id = 1;
@@ -177,11 +807,10 @@ mod tests {
     }
 
     #[test]
-    fn read_all_returns_all_rows() -> Result<()> {
+    fn row_iterator_returns_all_rows() -> Result<()> {
         let dir = tempfile::tempdir()?;
-        let file_path = dir.path().join("read_all_returns_all_rows.txt");
-        let file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
-        let mut db_operations = DbOperationsImpl::new(Box::new(file_handler));
+        let file_path = dir.path().join("row_iterator_returns_all_rows.txt");
+        let mut db_operations = open_db_operations(file_path.to_str().unwrap())?;
 
         let data1 = vec![1, 2, 3, 4];
         let transaction_id1 = 1;
@@ -191,7 +820,7 @@ mod tests {
         let transaction_id2 = 2;
         db_operations.insert(data2, transaction_id2)?;
 
-        let rows = db_operations.read_all()?;
+        let rows = read_all(&mut db_operations)?;
 
         assert_eq!(rows.len(), 2);
         assert_eq!(rows[0].data, vec![1, 2, 3, 4]);
@@ -204,8 +833,7 @@ mod tests {
     fn update_with_offset_updates_row() -> Result<()> {
         let dir = tempfile::tempdir()?;
         let file_path = dir.path().join("update_with_offset_updates_row.txt");
-        let file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
-        let mut db_operations = DbOperationsImpl::new(Box::new(file_handler));
+        let mut db_operations = open_db_operations(file_path.to_str().unwrap())?;
 
         let data = vec![1, 2, 3, 4];
         let transaction_id = 1;
@@ -226,8 +854,7 @@ mod tests {
     fn delete_with_offset_deletes_row() -> Result<()> {
         let dir = tempfile::tempdir()?;
         let file_path = dir.path().join("delete_with_offset_deletes_row.txt");
-        let file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
-        let mut db_operations = DbOperationsImpl::new(Box::new(file_handler));
+        let mut db_operations = open_db_operations(file_path.to_str().unwrap())?;
 
         let data = vec![1, 2, 3, 4];
         let transaction_id = 1;
@@ -242,4 +869,374 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn insert_spills_into_new_pages() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("insert_spills_into_new_pages.txt");
+        let file_name = file_path.to_str().unwrap();
+        let mut db_operations = open_db_operations(file_name)?;
+
+        let mut offset_sizes = Vec::new();
+        for i in 0..100_u8 {
+            offset_sizes.push(db_operations.insert(vec![i; 200], i as u64)?);
+        }
+        let big_row = (0..10_000).map(|i| i as u8).collect::<Vec<u8>>();
+        let big_offset_size = db_operations.insert(big_row.clone(), 100)?;
+        db_operations.insert(vec![1, 2, 3], 101)?;
+
+        assert!(split_row_offset(offset_sizes[99].offset).0 > 0);
+        assert_eq!(db_operations.read_with_offset(&offset_sizes[42])?.data, vec![42; 200]);
+        assert_eq!(db_operations.read_with_offset(&big_offset_size)?.data, big_row);
+
+        // a buffer pool of a single page still serves every row after reopening
+        drop(db_operations);
+        let mut db_operations = open_db_operations_with(Box::new(FileHandlerImpl::new(file_name)?), file_name, None, 1)?;
+        let rows = read_all(&mut db_operations)?;
+
+        // the small row fills up the page in front of the overflow pages
+        assert_eq!(rows.len(), 102);
+        assert_eq!(rows[100].data, vec![1, 2, 3]);
+        assert_eq!(rows[101].data, big_row);
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_fills_up_last_page_after_reopen() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("insert_fills_up_last_page_after_reopen.txt");
+        let file_name = file_path.to_str().unwrap();
+        let mut db_operations = open_db_operations(file_name)?;
+        db_operations.insert(vec![1, 2, 3, 4], 1)?;
+        drop(db_operations);
+
+        let mut db_operations = open_db_operations(file_name)?;
+        let offset_size = db_operations.insert(vec![5, 6, 7, 8], 2)?;
+
+        assert_eq!(offset_size.offset, row_offset(0, 1));
+        assert_eq!(db_operations.buffer_pool.page_count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_replays_update_logged_before_crash() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("new_replays_update_logged_before_crash.txt");
+        let file_name = file_path.to_str().unwrap();
+        let mut db_operations = open_db_operations(file_name)?;
+        let offset_size = db_operations.insert(vec![1, 2, 3, 4], 1)?;
+
+        // log an update but crash before it reaches the data file
+        db_operations.stage_delete(&offset_size, 2)?;
+        db_operations.stage_insert(vec![5, 6, 7, 8], 2, offset_size.offset, NEVER_EXPIRES)?;
+        let writes = db_operations.staged_writes();
+        db_operations.wal.log(writes)?;
+        drop(db_operations);
+
+        let mut db_operations = open_db_operations(file_name)?;
+        let rows = read_all(&mut db_operations)?;
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].header.xmax, 2);
+        assert_eq!(rows[1].data, vec![5, 6, 7, 8]);
+        assert_eq!(db_operations.wal.size()?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_rolls_back_torn_wal_record() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("new_rolls_back_torn_wal_record.txt");
+        let file_name = file_path.to_str().unwrap();
+        let mut db_operations = open_db_operations(file_name)?;
+        let offset_size = db_operations.insert(vec![1, 2, 3, 4], 1)?;
+
+        // the crash happened while the update was written to the wal
+        db_operations.stage_delete(&offset_size, 2)?;
+        let writes = db_operations.staged_writes();
+        db_operations.wal.log(writes)?;
+        let mut wal_file = FileHandlerImpl::new(&wal::wal_file_name(file_name))?;
+        let wal_size = wal_file.size()?;
+        wal_file.truncate(wal_size - 1)?;
+        drop(db_operations);
+
+        let mut db_operations = open_db_operations(file_name)?;
+        let rows = read_all(&mut db_operations)?;
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].header.xmax, NONE_SENTINEL);
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_row_is_reported_instead_of_ending_the_scan() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("corrupted_row_is_reported_instead_of_ending_the_scan.txt");
+        let file_name = file_path.to_str().unwrap();
+        let mut db_operations = open_db_operations(file_name)?;
+        let offset_size = db_operations.insert(vec![1, 2, 3, 4], 1)?;
+        db_operations.insert(vec![5, 6, 7, 8], 2)?;
+        drop(db_operations);
+        // reopening checkpoints the wal, so replaying it cannot repair the damage below
+        drop(open_db_operations(file_name)?);
+
+        // the first row is stored at the end of the page, flip its last byte
+        let mut file_handler = FileHandlerImpl::new(file_name)?;
+        let byte = file_handler.read(PAGE_SIZE as u64 - 1, 1)?[0];
+        file_handler.update(PAGE_SIZE as u64 - 1, &[!byte])?;
+
+        let mut db_operations = open_db_operations(file_name)?;
+        let error = db_operations.read_with_offset(&offset_size).unwrap_err();
+        assert!(matches!(error.downcast_ref::<StorageError>(), Some(StorageError::Corrupted { offset: 0 })));
+
+        let error = read_all(&mut db_operations).unwrap_err();
+        assert!(matches!(error.downcast_ref::<StorageError>(), Some(StorageError::Corrupted { offset: 0 })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_drops_dead_rows_and_remaps_live_ones() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("vacuum_drops_dead_rows_and_remaps_live_ones.txt");
+        let file_name = file_path.to_str().unwrap();
+        let mut db_operations = open_db_operations(file_name)?;
+
+        let mut offset_sizes = Vec::new();
+        for i in 0..40_u8 {
+            let transaction_id = db_operations.begin()?;
+            offset_sizes.push(db_operations.insert(vec![i; 500], transaction_id)?);
+        }
+        let big_row = (0..10_000).map(|i| i as u8).collect::<Vec<u8>>();
+        let transaction_id = db_operations.begin()?;
+        let big_offset_size = db_operations.insert(big_row.clone(), transaction_id)?;
+        let transaction_id = db_operations.begin()?;
+        for offset_size in &offset_sizes[..30] {
+            db_operations.delete_with_offset(offset_size, transaction_id)?;
+        }
+        let transaction_id = db_operations.begin()?;
+        let updated_offset_size = db_operations.update_with_offset(&offset_sizes[35], vec![1, 2, 3], transaction_id)?;
+        let page_count = db_operations.buffer_pool.page_count();
+
+        let remapping = db_operations.vacuum()?;
+
+        assert_eq!(remapping.len(), 11);
+        assert!(db_operations.buffer_pool.page_count() < page_count);
+        assert!(!remapping.contains_key(&offset_sizes[0].offset));
+        assert!(!remapping.contains_key(&offset_sizes[35].offset));
+        assert_eq!(db_operations.read_with_offset(&remapping[&offset_sizes[30].offset])?.data, vec![30; 500]);
+        assert_eq!(db_operations.read_with_offset(&remapping[&updated_offset_size.offset])?.data, vec![1, 2, 3]);
+        assert_eq!(db_operations.read_with_offset(&remapping[&big_offset_size.offset])?.data, big_row);
+
+        // the vacuumed file is used after reopening and keeps accepting inserts
+        drop(db_operations);
+        let mut db_operations = open_db_operations(file_name)?;
+        let transaction_id = db_operations.begin()?;
+        db_operations.insert(vec![7; 10], transaction_id)?;
+        let rows = read_all(&mut db_operations)?;
+        assert_eq!(rows.len(), 12);
+        assert!(rows.iter().all(|row| row.header.xmax == NONE_SENTINEL));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_visible_follows_version_chain() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("read_visible_follows_version_chain.txt");
+        let mut db_operations = open_db_operations(file_path.to_str().unwrap())?;
+
+        let before_insert = db_operations.snapshot();
+        let transaction_id = db_operations.begin()?;
+        let first = db_operations.insert(vec![1], transaction_id)?;
+        let after_insert = db_operations.snapshot();
+        let transaction_id = db_operations.begin()?;
+        let second = db_operations.update_with_offset(&first, vec![2], transaction_id)?;
+        let after_update = db_operations.snapshot();
+        let transaction_id = db_operations.begin()?;
+        db_operations.delete_with_offset(&second, transaction_id)?;
+        let after_delete = db_operations.snapshot();
+        let transaction_id = db_operations.begin()?;
+        let third = db_operations.reinsert_with_offset(&second, vec![3], transaction_id)?;
+        let after_reinsert = db_operations.snapshot();
+
+        let data = |row: Option<Row>| row.map(|row| row.data);
+        assert_eq!(data(db_operations.read_visible(&third, &before_insert)?), None);
+        assert_eq!(data(db_operations.read_visible(&third, &after_insert)?), Some(vec![1]));
+        assert_eq!(data(db_operations.read_visible(&third, &after_update)?), Some(vec![2]));
+        assert_eq!(data(db_operations.read_visible(&third, &after_delete)?), None);
+        assert_eq!(data(db_operations.read_visible(&third, &after_reinsert)?), Some(vec![3]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_keeps_versions_visible_to_snapshots_in_use() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("vacuum_keeps_versions_visible_to_snapshots_in_use.txt");
+        let mut db_operations = open_db_operations(file_path.to_str().unwrap())?;
+
+        let transaction_id = db_operations.begin()?;
+        let first = db_operations.insert(vec![1], transaction_id)?;
+        let transaction_id = db_operations.begin()?;
+        let second = db_operations.update_with_offset(&first, vec![2], transaction_id)?;
+        let snapshot = db_operations.snapshot();
+        let transaction_id = db_operations.begin()?;
+        let third = db_operations.update_with_offset(&second, vec![3], transaction_id)?;
+
+        let remapping = db_operations.vacuum()?;
+
+        // the first version is gone, the chain of the newest one now ends at the second
+        assert_eq!(remapping.len(), 2);
+        let third = &remapping[&third.offset];
+        assert_eq!(db_operations.read_visible(third, &snapshot)?.map(|row| row.data), Some(vec![2]));
+        assert_eq!(db_operations.read_with_offset(&remapping[&second.offset])?.header.ctid, NONE_SENTINEL);
+
+        db_operations.release_snapshot(&snapshot);
+        let remapping = db_operations.vacuum()?;
+        assert_eq!(remapping.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_batch_applies_all_or_none_of_the_writes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("write_batch_applies_all_or_none_of_the_writes.txt");
+        let mut db_operations = open_db_operations(file_path.to_str().unwrap())?;
+        let transaction_id = db_operations.begin()?;
+        let first = db_operations.insert(vec![1], transaction_id)?;
+        let second = db_operations.insert(vec![2], transaction_id)?;
+
+        let transaction_id = db_operations.begin()?;
+        let offset_sizes = db_operations.write_batch(vec![
+            RowWrite::Update { old_offset_size: first.clone(), data: vec![3] },
+            RowWrite::Delete { offset_size: second.clone() },
+            RowWrite::Insert { data: vec![4] },
+        ], transaction_id, NEVER_EXPIRES)?;
+
+        assert_eq!(offset_sizes.len(), 3);
+        assert_eq!(offset_sizes[1], second);
+        assert_eq!(db_operations.read_with_offset(&offset_sizes[0])?.header.ctid, first.offset);
+        let live = |db_operations: &mut DbOperationsImpl| -> Result<Vec<Vec<u8>>> {
+            Ok(read_all(db_operations)?.into_iter().filter(|row| row.header.xmax == NONE_SENTINEL).map(|row| row.data).collect())
+        };
+        assert_eq!(live(&mut db_operations)?, vec![vec![3], vec![4]]);
+
+        // the second row is already deleted, so the insert before it is rolled back as well
+        let transaction_id = db_operations.begin()?;
+        let result = db_operations.write_batch(vec![
+            RowWrite::Insert { data: vec![5] },
+            RowWrite::Delete { offset_size: second },
+        ], transaction_id, NEVER_EXPIRES);
+
+        assert!(result.is_err());
+        assert_eq!(live(&mut db_operations)?, vec![vec![3], vec![4]]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_batch_sets_expiry_of_written_rows() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("write_batch_sets_expiry_of_written_rows.txt");
+        let mut db_operations = open_db_operations(file_path.to_str().unwrap())?;
+        let transaction_id = db_operations.begin()?;
+        let offset_size = db_operations.insert(vec![1], transaction_id)?;
+
+        let transaction_id = db_operations.begin()?;
+        let offset_sizes = db_operations.write_batch(vec![
+            RowWrite::Update { old_offset_size: offset_size.clone(), data: vec![2] },
+            RowWrite::Insert { data: vec![3] },
+        ], transaction_id, 1000)?;
+
+        let header = db_operations.read_with_offset(&offset_sizes[0])?.header;
+        assert_eq!(header.expires_at, 1000);
+        assert!(header.is_expired(1000) && !header.is_expired(999));
+        assert_eq!(db_operations.read_with_offset(&offset_sizes[1])?.header.expires_at, 1000);
+        assert!(!db_operations.read_with_offset(&offset_size)?.header.is_expired(unix_millis()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_continues_behind_transaction_ids_in_data_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("new_continues_behind_transaction_ids_in_data_file.txt");
+        let file_name = file_path.to_str().unwrap();
+        let mut db_operations = open_db_operations(file_name)?;
+        let offset_size = db_operations.insert(vec![1], 41)?;
+        db_operations.delete_with_offset(&offset_size, 42)?;
+        drop(db_operations);
+        std::fs::remove_file(transaction_file_name(file_name))?;
+
+        let mut db_operations = open_db_operations(file_name)?;
+
+        assert_eq!(db_operations.begin()?, 43);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_rows_are_decompressed_when_read() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("compressed_rows_are_decompressed_when_read.db").to_str().unwrap().to_string();
+        let mut file_handler = FileHandlerImpl::new(&file_name)?;
+        crate::compression::open_compression(&mut file_handler, Some(Compression::Zstd))?;
+        drop(file_handler);
+        let data = vec![42_u8; 10000];
+
+        let mut db_operations = open_db_operations(&file_name)?;
+        let transaction_id = db_operations.begin()?;
+        let first = db_operations.insert(data.clone(), transaction_id)?;
+        let second = db_operations.insert(vec![1, 2, 3], transaction_id)?;
+        let transaction_id = db_operations.begin()?;
+        db_operations.delete_with_offset(&second, transaction_id)?;
+
+        assert!(first.size < 100);
+        assert_eq!(db_operations.read_with_offset(&first)?.data, data);
+        assert_eq!(db_operations.read_with_offset(&second)?.data, vec![1, 2, 3]);
+
+        let remapping = db_operations.vacuum()?;
+        let mut db_operations = open_db_operations(&file_name)?;
+        assert_eq!(db_operations.read_with_offset(&remapping[&first.offset])?.data, data);
+        assert_eq!(read_all(&mut db_operations)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn group_commit_keeps_data_file_behind_wal_until_sync() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("group_commit_keeps_data_file_behind_wal_until_sync.db").to_str().unwrap().to_string();
+        let mut db_operations = open_db_operations(&file_name)?;
+        db_operations.set_durability(Durability::GroupCommit);
+
+        let transaction_id = db_operations.begin()?;
+        let first = db_operations.insert(vec![1, 2, 3], transaction_id)?;
+        assert_eq!(db_operations.read_with_offset(&first)?.data, vec![1, 2, 3]);
+        assert_eq!(FileHandlerImpl::new(&file_name)?.size()?, 0);
+
+        db_operations.sync()?;
+        assert_eq!(FileHandlerImpl::new(&file_name)?.size()?, PAGE_SIZE as u64);
+
+        // a write that was never synced is still replayed from the wal once the process is gone
+        let second = db_operations.insert(vec![4, 5], transaction_id)?;
+        drop(db_operations);
+        let mut db_operations = open_db_operations(&file_name)?;
+        assert_eq!(db_operations.read_with_offset(&first)?.data, vec![1, 2, 3]);
+        assert_eq!(db_operations.read_with_offset(&second)?.data, vec![4, 5]);
+
+        Ok(())
+    }
+
+    fn read_all(db_operations: &mut DbOperationsImpl) -> Result<Vec<Row>> {
+        RowIterator::new(db_operations)
+            .map(|row| row.map(|(_, row)| row))
+            .collect()
+    }
 }
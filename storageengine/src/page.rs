@@ -0,0 +1,291 @@
+use anyhow::{bail, Result};
+
+pub const PAGE_SIZE: usize = 4096;
+// kind (1) + reserved (1) + slot count (2) + free start (2) + free end (2) + overflow length (4)
+pub const PAGE_HEADER_SIZE: usize = 12;
+// bytes of an overflow tuple stored per page of the run
+const OVERFLOW_PAYLOAD_SIZE: usize = PAGE_SIZE - PAGE_HEADER_SIZE;
+// offset (2) + length (2) of a tuple inside the page
+const SLOT_SIZE: usize = 4;
+// tuples bigger than this do not fit into a slotted page and are stored in overflow pages
+pub const MAX_TUPLE_SIZE: usize = PAGE_SIZE - PAGE_HEADER_SIZE - SLOT_SIZE;
+
+// rows are addressed by page and slot, encoded as `page_id * PAGE_SIZE + slot`
+pub fn row_offset(page_id: u64, slot: u16) -> u64 {
+    page_id * PAGE_SIZE as u64 + slot as u64
+}
+
+pub fn split_row_offset(offset: u64) -> (u64, u16) {
+    (offset / PAGE_SIZE as u64, (offset % PAGE_SIZE as u64) as u16)
+}
+
+pub fn page_offset(page_id: u64) -> u64 {
+    page_id * PAGE_SIZE as u64
+}
+
+// number of pages an overflow tuple of the given length occupies, including the head page
+pub fn overflow_span(tuple_length: usize) -> u64 {
+    tuple_length.div_ceil(OVERFLOW_PAYLOAD_SIZE) as u64
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum PageKind {
+    // header, slot array growing forward and tuples growing backward from the end of the page
+    Slotted,
+    // first page of a tuple that is too big for a slotted page
+    Overflow,
+    // following pages of such a tuple, each one carries the next part of it
+    OverflowContinuation,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Page {
+    data: Vec<u8>,
+}
+
+impl Page {
+    pub fn new_slotted() -> Self {
+        let mut page = Self {
+            data: vec![0; PAGE_SIZE],
+        };
+        page.set_u16(4, PAGE_HEADER_SIZE as u16);
+        page.set_u16(6, PAGE_SIZE as u16);
+
+        page
+    }
+
+    // splits a tuple that is too big for a slotted page into a head page and continuation pages
+    pub fn new_overflow(tuple: &[u8]) -> Vec<Self> {
+        tuple.chunks(OVERFLOW_PAYLOAD_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut data = vec![0; PAGE_SIZE];
+                data[0] = if i == 0 { 1 } else { 2 };
+                data[8..12].copy_from_slice(&(tuple.len() as u32).to_le_bytes());
+                data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+                Self { data }
+            })
+            .collect()
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        if data.len() != PAGE_SIZE {
+            bail!("page has {} bytes instead of {}", data.len(), PAGE_SIZE);
+        }
+
+        Ok(Self { data })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn kind(&self) -> Result<PageKind> {
+        match self.data[0] {
+            0 => Ok(PageKind::Slotted),
+            1 => Ok(PageKind::Overflow),
+            2 => Ok(PageKind::OverflowContinuation),
+            kind => bail!("unknown page kind {}", kind),
+        }
+    }
+
+    pub fn slot_count(&self) -> u16 {
+        self.get_u16(2)
+    }
+
+    // total length of the tuple stored in an overflow run
+    pub fn overflow_length(&self) -> usize {
+        u32::from_le_bytes(self.data[8..12].try_into().unwrap()) as usize
+    }
+
+    // bytes available for one more tuple, the slot it needs is already subtracted
+    pub fn free_space(&self) -> usize {
+        if !matches!(self.kind(), Ok(PageKind::Slotted)) {
+            return 0;
+        }
+
+        let free = self.free_end().saturating_sub(self.free_start());
+        free.saturating_sub(SLOT_SIZE)
+    }
+
+    pub fn insert(&mut self, tuple: &[u8]) -> Option<u16> {
+        if tuple.len() > self.free_space() {
+            return None;
+        }
+
+        let slot = self.slot_count();
+        let tuple_start = self.free_end() - tuple.len();
+        self.data[tuple_start..tuple_start + tuple.len()].copy_from_slice(tuple);
+
+        let slot_start = self.free_start();
+        self.set_u16(slot_start, tuple_start as u16);
+        self.set_u16(slot_start + 2, tuple.len() as u16);
+
+        self.set_u16(2, slot + 1);
+        self.set_u16(4, (slot_start + SLOT_SIZE) as u16);
+        self.set_u16(6, tuple_start as u16);
+
+        Some(slot)
+    }
+
+    pub fn tuple(&self, slot: u16) -> Option<&[u8]> {
+        let (start, length) = self.slot(slot)?;
+        Some(&self.data[start..start + length])
+    }
+
+    // overwrites a tuple in place, the new tuple must have the same length
+    pub fn update(&mut self, slot: u16, tuple: &[u8]) -> Result<()> {
+        let (start, length) = match self.slot(slot) {
+            Some(slot) => slot,
+            None => bail!("slot {} does not exist", slot),
+        };
+        if length != tuple.len() {
+            bail!("tuple in slot {} has {} bytes, got {}", slot, length, tuple.len());
+        }
+
+        self.data[start..start + length].copy_from_slice(tuple);
+        Ok(())
+    }
+
+    fn slot(&self, slot: u16) -> Option<(usize, usize)> {
+        if !matches!(self.kind(), Ok(PageKind::Slotted)) || slot >= self.slot_count() {
+            return None;
+        }
+
+        let slot_start = PAGE_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        let start = self.get_u16(slot_start) as usize;
+        let length = self.get_u16(slot_start + 2) as usize;
+        if start + length > PAGE_SIZE {
+            return None;
+        }
+
+        Some((start, length))
+    }
+
+    fn free_start(&self) -> usize {
+        self.get_u16(4) as usize
+    }
+
+    fn free_end(&self) -> usize {
+        self.get_u16(6) as usize
+    }
+
+    fn get_u16(&self, pos: usize) -> u16 {
+        u16::from_le_bytes([self.data[pos], self.data[pos + 1]])
+    }
+
+    fn set_u16(&mut self, pos: usize, value: u16) {
+        self.data[pos..pos + 2].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+// concatenates the tuple of an overflow run from its pages
+pub fn overflow_tuple(pages: &[Page]) -> Result<Vec<u8>> {
+    let head = match pages.first() {
+        Some(head) if head.kind()? == PageKind::Overflow => head,
+        _ => bail!("overflow run does not start with an overflow page"),
+    };
+
+    let length = head.overflow_length();
+    if pages.len() as u64 != overflow_span(length) {
+        bail!("overflow run of {} bytes needs {} pages, got {}", length, overflow_span(length), pages.len());
+    }
+
+    let mut tuple = Vec::with_capacity(length);
+    for (i, page) in pages.iter().enumerate() {
+        if i > 0 && page.kind()? != PageKind::OverflowContinuation {
+            bail!("overflow run is interrupted after {} pages", i);
+        }
+        let chunk_length = (length - tuple.len()).min(OVERFLOW_PAYLOAD_SIZE);
+        tuple.extend_from_slice(&page.data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + chunk_length]);
+    }
+
+    Ok(tuple)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_stores_tuples_in_slots() -> Result<()> {
+        let mut page = Page::new_slotted();
+
+        assert_eq!(page.insert(&[1, 2, 3]), Some(0));
+        assert_eq!(page.insert(&[4, 5]), Some(1));
+
+        assert_eq!(page.slot_count(), 2);
+        assert_eq!(page.tuple(0), Some(&[1, 2, 3][..]));
+        assert_eq!(page.tuple(1), Some(&[4, 5][..]));
+        assert_eq!(page.tuple(2), None);
+        assert_eq!(page.free_space(), PAGE_SIZE - PAGE_HEADER_SIZE - 3 * SLOT_SIZE - 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_returns_none_if_page_is_full() {
+        let mut page = Page::new_slotted();
+
+        assert_eq!(page.insert(&vec![7; MAX_TUPLE_SIZE]), Some(0));
+        assert_eq!(page.free_space(), 0);
+        assert_eq!(page.insert(&[1]), None);
+    }
+
+    #[test]
+    fn update_overwrites_tuple_of_same_length() -> Result<()> {
+        let mut page = Page::new_slotted();
+        page.insert(&[1, 2, 3]).unwrap();
+
+        page.update(0, &[4, 5, 6])?;
+
+        assert_eq!(page.tuple(0), Some(&[4, 5, 6][..]));
+        assert!(page.update(0, &[1]).is_err());
+        assert!(page.update(1, &[1, 2, 3]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn page_survives_round_trip_through_bytes() -> Result<()> {
+        let mut page = Page::new_slotted();
+        page.insert(&[1, 2, 3]).unwrap();
+
+        let page = Page::from_bytes(page.as_bytes().to_vec())?;
+
+        assert_eq!(page.tuple(0), Some(&[1, 2, 3][..]));
+        assert!(Page::from_bytes(vec![0; 10]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn overflow_pages_hold_tuples_bigger_than_a_page() -> Result<()> {
+        let tuple: Vec<u8> = (0..3 * PAGE_SIZE).map(|i| i as u8).collect();
+
+        let pages = Page::new_overflow(&tuple);
+
+        assert_eq!(pages.len() as u64, overflow_span(tuple.len()));
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages[0].kind()?, PageKind::Overflow);
+        assert_eq!(pages[1].kind()?, PageKind::OverflowContinuation);
+        assert_eq!(pages[3].free_space(), 0);
+        assert_eq!(overflow_tuple(&pages)?, tuple);
+        assert!(overflow_tuple(&pages[1..]).is_err());
+        assert!(overflow_tuple(&pages[..2]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn row_offset_encodes_page_and_slot() {
+        let offset = row_offset(3, 17);
+
+        assert_eq!(split_row_offset(offset), (3, 17));
+        assert_eq!(page_offset(3), 3 * PAGE_SIZE as u64);
+    }
+}
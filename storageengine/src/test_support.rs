@@ -0,0 +1,30 @@
+use anyhow::Result;
+
+use crate::backup::WalArchive;
+use crate::file_handler::{FileHandler, FileHandlerImpl};
+use crate::memory_file_handler::MemoryFileHandler;
+use crate::operations::DbOperationsImpl;
+use crate::transaction::{transaction_file_name, TransactionManager};
+use crate::wal::{self, Wal};
+
+// the data file `file_name` with its wal and transaction file next to it
+pub fn open_db_operations(file_name: &str) -> Result<DbOperationsImpl> {
+    open_db_operations_with(Box::new(FileHandlerImpl::new(file_name)?), file_name, None, 16)
+}
+
+// like `open_db_operations`, but the data file is read through `file_handler` and the wal archived into `archive`
+pub fn open_db_operations_with(file_handler: Box<dyn FileHandler>, file_name: &str, archive: Option<WalArchive>, buffer_pool_pages: usize) -> Result<DbOperationsImpl> {
+    let mut wal = Wal::new(Box::new(FileHandlerImpl::new(&wal::wal_file_name(file_name))?))?;
+    if let Some(archive) = archive {
+        wal.set_archive(archive);
+    }
+    let transaction_manager = TransactionManager::new(Box::new(FileHandlerImpl::new(&transaction_file_name(file_name))?))?;
+    DbOperationsImpl::new(file_handler, wal, transaction_manager, buffer_pool_pages)
+}
+
+// a data file, wal and transaction file in memory
+pub fn memory_db_operations() -> Result<DbOperationsImpl> {
+    let wal = Wal::new(Box::new(MemoryFileHandler::new()))?;
+    let transaction_manager = TransactionManager::new(Box::new(MemoryFileHandler::new()))?;
+    DbOperationsImpl::new(Box::new(MemoryFileHandler::new()), wal, transaction_manager, 16)
+}
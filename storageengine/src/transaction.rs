@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::file_handler::FileHandler;
+use crate::operations::{Header, NONE_SENTINEL};
+
+// transaction ids are persisted in blocks, so only every n-th `begin` has to touch the disk
+const RESERVED_TRANSACTION_IDS: u64 = 1024;
+
+pub fn transaction_file_name(storage_file_name: &str) -> String {
+    format!("{}.txn", storage_file_name)
+}
+
+// every transaction below `xmax` committed before the snapshot was taken
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub xmax: u64,
+}
+
+impl Snapshot {
+    // a row version is visible if it was created before the snapshot and not deleted before it
+    pub fn is_visible(&self, header: &Header) -> bool {
+        header.xmin < self.xmax && (header.xmax == NONE_SENTINEL || header.xmax >= self.xmax)
+    }
+}
+
+pub struct TransactionManager {
+    file_handler: Box<dyn FileHandler>,
+    next_transaction_id: u64,
+    // ids below this one might have been handed out before a crash and are never reused
+    reserved_until: u64,
+    // xmax of every snapshot still in use and how often it was taken
+    snapshots: BTreeMap<u64, usize>,
+    // handed out by `begin` instead of a new id, see `pin`
+    pinned: Option<u64>,
+}
+
+impl TransactionManager {
+    pub fn new(mut file_handler: Box<dyn FileHandler>) -> Result<Self> {
+        let reserved_until = if file_handler.size()? >= 8 {
+            u64::from_le_bytes(file_handler.read(0, 8)?.try_into().unwrap())
+        } else {
+            0
+        };
+
+        Ok(Self {
+            file_handler,
+            next_transaction_id: reserved_until,
+            reserved_until,
+            snapshots: BTreeMap::new(),
+            pinned: None,
+        })
+    }
+
+    pub fn next_transaction_id(&self) -> u64 {
+        self.next_transaction_id
+    }
+
+    pub fn begin(&mut self) -> Result<u64> {
+        if let Some(transaction_id) = self.pinned {
+            return Ok(transaction_id);
+        }
+        if self.next_transaction_id >= self.reserved_until {
+            self.reserve(self.next_transaction_id + RESERVED_TRANSACTION_IDS)?;
+        }
+
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id += 1;
+        Ok(transaction_id)
+    }
+
+    // makes sure ids up to and including `transaction_id` are never handed out again
+    pub fn advance_past(&mut self, transaction_id: u64) -> Result<()> {
+        if transaction_id < self.next_transaction_id {
+            return Ok(());
+        }
+
+        self.next_transaction_id = transaction_id + 1;
+        if self.next_transaction_id > self.reserved_until {
+            self.reserve(self.next_transaction_id)?;
+        }
+
+        Ok(())
+    }
+
+    // `begin` hands out `transaction_id` until it is unpinned with None, so a follower writes a replicated change
+    // under the id of the leader. new ids continue behind it
+    pub fn pin(&mut self, transaction_id: Option<u64>) -> Result<()> {
+        if let Some(transaction_id) = transaction_id {
+            self.advance_past(transaction_id)?;
+        }
+        self.pinned = transaction_id;
+
+        Ok(())
+    }
+
+    pub fn snapshot(&mut self) -> Snapshot {
+        let snapshot = Snapshot {
+            xmax: self.next_transaction_id,
+        };
+        *self.snapshots.entry(snapshot.xmax).or_default() += 1;
+
+        snapshot
+    }
+
+    pub fn release_snapshot(&mut self, snapshot: &Snapshot) {
+        if let Some(count) = self.snapshots.get_mut(&snapshot.xmax) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&snapshot.xmax);
+            }
+        }
+    }
+
+    // row versions deleted by a transaction below the horizon are invisible to every snapshot
+    pub fn horizon(&self) -> u64 {
+        self.snapshots.keys().next().copied().unwrap_or(self.next_transaction_id)
+    }
+
+    fn reserve(&mut self, reserved_until: u64) -> Result<()> {
+        let data = reserved_until.to_le_bytes();
+        if self.file_handler.size()? == 0 {
+            self.file_handler.append(&data)?;
+        } else {
+            self.file_handler.update(0, &data)?;
+        }
+        self.file_handler.sync()?;
+        self.reserved_until = reserved_until;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_handler::FileHandlerImpl;
+
+    use super::*;
+
+    #[test]
+    fn begin_hands_out_increasing_ids_across_restarts() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("begin_hands_out_increasing_ids_across_restarts.txn");
+        let file_name = file_path.to_str().unwrap();
+        let mut transaction_manager = TransactionManager::new(Box::new(FileHandlerImpl::new(file_name)?))?;
+
+        assert_eq!(transaction_manager.begin()?, 0);
+        assert_eq!(transaction_manager.begin()?, 1);
+        drop(transaction_manager);
+
+        // ids reserved before the restart are skipped
+        let mut transaction_manager = TransactionManager::new(Box::new(FileHandlerImpl::new(file_name)?))?;
+        assert_eq!(transaction_manager.begin()?, RESERVED_TRANSACTION_IDS);
+
+        transaction_manager.advance_past(5000)?;
+        assert_eq!(transaction_manager.begin()?, 5001);
+
+        transaction_manager.pin(Some(7000))?;
+        assert_eq!(transaction_manager.begin()?, 7000);
+        assert_eq!(transaction_manager.begin()?, 7000);
+        transaction_manager.pin(None)?;
+        assert_eq!(transaction_manager.begin()?, 7001);
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_sees_rows_committed_before_it() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("snapshot_sees_rows_committed_before_it.txn");
+        let mut transaction_manager = TransactionManager::new(Box::new(FileHandlerImpl::new(file_path.to_str().unwrap())?))?;
+        let created = transaction_manager.begin()?;
+        let snapshot = transaction_manager.snapshot();
+        let deleted = transaction_manager.begin()?;
+
+        let header = |xmin, xmax| Header { xmin, xmax, tuple_length: 0, expires_at: 0, ctid: NONE_SENTINEL, cmin: xmin, cmax: xmax };
+
+        assert!(snapshot.is_visible(&header(created, NONE_SENTINEL)));
+        assert!(snapshot.is_visible(&header(created, deleted)));
+        assert!(!snapshot.is_visible(&header(deleted, NONE_SENTINEL)));
+        assert!(!transaction_manager.snapshot().is_visible(&header(created, deleted)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn horizon_is_oldest_snapshot_in_use() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("horizon_is_oldest_snapshot_in_use.txn");
+        let mut transaction_manager = TransactionManager::new(Box::new(FileHandlerImpl::new(file_path.to_str().unwrap())?))?;
+        transaction_manager.begin()?;
+        let old_snapshot = transaction_manager.snapshot();
+        transaction_manager.begin()?;
+        let new_snapshot = transaction_manager.snapshot();
+
+        assert_eq!(transaction_manager.horizon(), old_snapshot.xmax);
+        transaction_manager.release_snapshot(&old_snapshot);
+        assert_eq!(transaction_manager.horizon(), new_snapshot.xmax);
+        transaction_manager.release_snapshot(&new_snapshot);
+        assert_eq!(transaction_manager.horizon(), transaction_manager.next_transaction_id());
+
+        Ok(())
+    }
+}
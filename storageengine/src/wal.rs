@@ -0,0 +1,314 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::backup::WalArchive;
+use crate::file_handler::FileHandler;
+
+// length + crc32c of the payload
+const FRAME_HEADER_SIZE: usize = 8;
+
+pub fn wal_file_name(storage_file_name: &str) -> String {
+    format!("{}.wal", storage_file_name)
+}
+
+// when a logged write is synced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    // the log is only synced on checkpoints, acknowledged writes can be lost on power failure
+    None,
+    // every write is synced before it is acknowledged
+    #[default]
+    PerWrite,
+    // the caller syncs writes in batches, see `DbOperations::sync`
+    GroupCommit,
+}
+
+impl FromStr for Durability {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Durability::None),
+            "per_write" => Ok(Durability::PerWrite),
+            "group_commit" => Ok(Durability::GroupCommit),
+            _ => Err("no match"),
+        }
+    }
+}
+
+// physical change of the data file: write `data` starting at `offset`
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct WalWrite {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+// all writes of one operation, a record is either completely in the log or not at all
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct WalRecord {
+    pub lsn: u64,
+    pub writes: Vec<WalWrite>,
+    // the transaction the writes belong to, point in time restore stops in front of later transactions
+    pub transaction_id: Option<u64>,
+}
+
+// records logged before they named their transaction end behind the writes
+#[derive(Deserialize)]
+struct LegacyWalRecord {
+    lsn: u64,
+    writes: Vec<WalWrite>,
+}
+
+pub struct Wal {
+    file_handler: Box<dyn FileHandler>,
+    next_lsn: u64,
+    // gets the records before they are truncated
+    archive: Option<WalArchive>,
+}
+
+impl Wal {
+    pub fn new(file_handler: Box<dyn FileHandler>) -> Result<Self> {
+        let mut wal = Self {
+            file_handler,
+            next_lsn: 0,
+            archive: None,
+        };
+
+        // cut off a torn tail, otherwise new records would be appended behind garbage
+        let (records, valid_size) = wal.read_records()?;
+        if wal.file_handler.size()? > valid_size {
+            wal.file_handler.truncate(valid_size)?;
+            wal.file_handler.sync()?;
+        }
+        wal.next_lsn = records.last().map_or(0, |record| record.lsn + 1);
+
+        Ok(wal)
+    }
+
+    // every record is archived before it is truncated from now on
+    pub fn set_archive(&mut self, archive: WalArchive) {
+        self.archive = Some(archive);
+    }
+
+    pub fn archive(&mut self) -> Option<&mut WalArchive> {
+        self.archive.as_mut()
+    }
+
+    // appends the record and syncs it, only afterwards the data file may be touched
+    pub fn log(&mut self, writes: Vec<WalWrite>) -> Result<WalRecord> {
+        let record = self.append(writes, None)?;
+        self.file_handler.sync()?;
+        Ok(record)
+    }
+
+    // appends the record without syncing it, the data file may only be touched after `sync`
+    pub fn append(&mut self, writes: Vec<WalWrite>, transaction_id: Option<u64>) -> Result<WalRecord> {
+        let record = WalRecord {
+            lsn: self.next_lsn,
+            writes,
+            transaction_id,
+        };
+
+        let payload = bincode::serialize(&record)?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32c::crc32c(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        self.file_handler.append(&frame)?;
+        self.next_lsn += 1;
+
+        Ok(record)
+    }
+
+    // returns all complete records, a torn or corrupted frame ends the log
+    pub fn records(&mut self) -> Result<Vec<WalRecord>> {
+        Ok(self.read_records()?.0)
+    }
+
+    fn read_records(&mut self) -> Result<(Vec<WalRecord>, u64)> {
+        let buf = self.file_handler.read_all()?;
+        let mut pos = 0;
+        let mut records = Vec::new();
+        while let Some((record, frame_size)) = Self::decode_frame(&buf[pos..]) {
+            pos += frame_size;
+            records.push(record);
+        }
+
+        Ok((records, pos as u64))
+    }
+
+    fn decode_frame(buf: &[u8]) -> Option<(WalRecord, usize)> {
+        if buf.len() < FRAME_HEADER_SIZE {
+            return None;
+        }
+
+        let length = u32::from_le_bytes(buf[0..4].try_into().ok()?) as usize;
+        let checksum = u32::from_le_bytes(buf[4..8].try_into().ok()?);
+        let payload = buf.get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + length)?;
+        if crc32c::crc32c(payload) != checksum {
+            return None;
+        }
+
+        // a legacy payload ends before the transaction id, so it never decodes as a current record
+        let record = bincode::deserialize::<WalRecord>(payload)
+            .or_else(|_| bincode::deserialize::<LegacyWalRecord>(payload).map(|record| WalRecord {
+                lsn: record.lsn,
+                writes: record.writes,
+                transaction_id: None,
+            }))
+            .ok()?;
+        Some((record, FRAME_HEADER_SIZE + length))
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file_handler.sync()
+    }
+
+    // drops all records, the caller has to make sure they reached the data file
+    pub fn truncate(&mut self) -> Result<()> {
+        if let Some(archive) = &mut self.archive {
+            archive.store(self.file_handler.as_mut())?;
+        }
+        self.file_handler.truncate(0)?;
+        self.file_handler.sync()
+    }
+
+    pub fn size(&mut self) -> Result<u64> {
+        self.file_handler.size()
+    }
+}
+
+// replays a record against the data file, writes are physical so replaying twice is harmless
+pub fn apply(file_handler: &mut dyn FileHandler, record: &WalRecord) -> Result<()> {
+    for write in &record.writes {
+        let size = file_handler.size()?;
+        if write.offset < size {
+            file_handler.update(write.offset, &write.data)?;
+            continue;
+        }
+        if write.offset > size {
+            bail!("wal record {} writes at {} behind the end of the data file {}", record.lsn, write.offset, size);
+        }
+
+        file_handler.append(&write.data)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_handler::FileHandlerImpl;
+
+    use super::*;
+
+    #[test]
+    fn log_appends_records_with_increasing_lsn() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("log_appends_records_with_increasing_lsn.wal");
+        let mut wal = Wal::new(Box::new(FileHandlerImpl::new(file_path.to_str().unwrap())?))?;
+
+        wal.log(vec![WalWrite { offset: 0, data: vec![1, 2, 3] }])?;
+        wal.log(vec![WalWrite { offset: 3, data: vec![4] }, WalWrite { offset: 0, data: vec![5] }])?;
+
+        let records = wal.records()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].lsn, 0);
+        assert_eq!(records[1].lsn, 1);
+        assert_eq!(records[1].writes[1], WalWrite { offset: 0, data: vec![5] });
+
+        // reopening continues with the next lsn
+        let mut wal = Wal::new(Box::new(FileHandlerImpl::new(file_path.to_str().unwrap())?))?;
+        let record = wal.log(vec![])?;
+        assert_eq!(record.lsn, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn records_ignores_torn_frame() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("records_ignores_torn_frame.wal");
+        let mut wal = Wal::new(Box::new(FileHandlerImpl::new(file_path.to_str().unwrap())?))?;
+        wal.log(vec![WalWrite { offset: 0, data: vec![1, 2, 3] }])?;
+        wal.log(vec![WalWrite { offset: 3, data: vec![4, 5, 6] }])?;
+
+        // cut the last frame in half as a crash in the middle of the append would
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+        let size = file_handler.size()?;
+        file_handler.truncate(size - 4)?;
+
+        let records = wal.records()?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].writes[0].data, vec![1, 2, 3]);
+
+        // reopening drops the torn frame so new records stay readable
+        let mut wal = Wal::new(Box::new(FileHandlerImpl::new(file_path.to_str().unwrap())?))?;
+        wal.log(vec![WalWrite { offset: 3, data: vec![7] }])?;
+        let records = wal.records()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].lsn, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn records_reads_legacy_records_without_transaction_id() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("records_reads_legacy_records_without_transaction_id.wal");
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+        let payload = bincode::serialize(&(7_u64, vec![WalWrite { offset: 0, data: vec![1] }]))?;
+        file_handler.append(&(payload.len() as u32).to_le_bytes())?;
+        file_handler.append(&crc32c::crc32c(&payload).to_le_bytes())?;
+        file_handler.append(&payload)?;
+
+        let mut wal = Wal::new(Box::new(file_handler))?;
+        wal.append(vec![WalWrite { offset: 1, data: vec![2] }], Some(3))?;
+
+        let records = wal.records()?;
+        assert_eq!(records[0], WalRecord { lsn: 7, writes: vec![WalWrite { offset: 0, data: vec![1] }], transaction_id: None });
+        assert_eq!(records[1].lsn, 8);
+        assert_eq!(records[1].transaction_id, Some(3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_removes_all_records() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("truncate_removes_all_records.wal");
+        let mut wal = Wal::new(Box::new(FileHandlerImpl::new(file_path.to_str().unwrap())?))?;
+        wal.log(vec![WalWrite { offset: 0, data: vec![1, 2, 3] }])?;
+
+        wal.truncate()?;
+
+        assert!(wal.records()?.is_empty());
+        assert_eq!(wal.size()?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_updates_and_appends() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("apply_updates_and_appends.txt");
+        let mut file_handler = FileHandlerImpl::new(file_path.to_str().unwrap())?;
+        file_handler.append(&[1, 2, 3])?;
+
+        let record = WalRecord {
+            lsn: 0,
+            writes: vec![WalWrite { offset: 1, data: vec![9] }, WalWrite { offset: 3, data: vec![4, 5] }],
+            transaction_id: None,
+        };
+        apply(&mut file_handler, &record)?;
+        // replaying is idempotent
+        apply(&mut file_handler, &record)?;
+
+        assert_eq!(file_handler.read_all()?, vec![1, 9, 3, 4, 5]);
+
+        Ok(())
+    }
+}